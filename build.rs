@@ -0,0 +1,27 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+// カーネルのアドレスが変わらないようにシンボル表は常に同じサイズで埋め込む
+const KSYM_CAPACITY: usize = 128 * 1024;
+
+fn main() {
+    println!("cargo:rerun-if-changed=ksyms.txt");
+
+    let mut table = fs::read("ksyms.txt").unwrap_or_default();
+    if table.len() > KSYM_CAPACITY {
+        println!(
+            "cargo:warning=ksyms.txt is larger than {} bytes, truncating",
+            KSYM_CAPACITY
+        );
+        let end = table[..KSYM_CAPACITY]
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |i| i + 1);
+        table.truncate(end);
+    }
+    table.resize(KSYM_CAPACITY, 0);
+
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("ksyms.bin");
+    fs::write(out, table).unwrap();
+}
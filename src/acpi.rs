@@ -0,0 +1,239 @@
+// ACPI テーブルの読み出し。AML は実行せず、FADT / FACS と DSDT / SSDT の中の
+// 名前やパッケージを探すだけ
+
+use crate::arch;
+use alloc::vec::Vec;
+
+// 物理アドレスの範囲をそのまま読む
+fn physical(addr: u64, len: usize) -> Option<&'static [u8]> {
+    let virt = arch::phys_to_virt(addr)?;
+    if !arch::is_range_mapped(virt, len as u64, false) {
+        return None;
+    }
+    Some(unsafe { core::slice::from_raw_parts(virt as *const u8, len) })
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    read_u32(bytes, offset) as u64 | (read_u32(bytes, offset + 4) as u64) << 32
+}
+
+// EBDA の先頭 1 KiB と BIOS 領域から "RSD PTR " を探す。
+// ACPI 2.0 以降なら XSDT のアドレスを含む 36 バイトを返す
+fn find_rsdp() -> Option<&'static [u8]> {
+    let ebda = physical(0x40e, 2).map(|bytes| (read_u16(bytes, 0) as u64) << 4);
+    let areas = [(ebda.unwrap_or(0), 1024), (0xe0000, 0x20000)];
+    for (start, len) in areas {
+        let area = match physical(start, len) {
+            Some(area) if start != 0 => area,
+            _ => continue,
+        };
+        for offset in (0..=len - 36).step_by(16) {
+            let candidate = &area[offset..];
+            if &candidate[..8] != b"RSD PTR " || !checksum_ok(&candidate[..20]) {
+                continue;
+            }
+            if candidate[15] >= 2 && checksum_ok(&candidate[..36]) {
+                return Some(&candidate[..36]);
+            }
+            return Some(&candidate[..20]);
+        }
+    }
+    None
+}
+
+fn table_at(addr: u64) -> Option<&'static [u8]> {
+    let header = physical(addr, 36)?;
+    let table = physical(addr, read_u32(header, 4) as usize)?;
+    checksum_ok(table).then_some(table)
+}
+
+// RSDT / XSDT に並んでいるテーブルすべて
+fn tables() -> impl Iterator<Item = &'static [u8]> {
+    let root = find_rsdp().and_then(|rsdp| match rsdp.len() {
+        36 if read_u64(rsdp, 24) != 0 => Some((table_at(read_u64(rsdp, 24))?, 8)),
+        _ => Some((table_at(read_u32(rsdp, 16) as u64)?, 4)),
+    });
+
+    root.into_iter().flat_map(|(root, entry_size)| {
+        (36..root.len() - entry_size + 1)
+            .step_by(entry_size)
+            .map(move |offset| match entry_size {
+                8 => read_u64(root, offset),
+                _ => read_u32(root, offset) as u64,
+            })
+            .filter_map(table_at)
+    })
+}
+
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    tables().find(|table| &table[..4] == signature)
+}
+
+pub struct SleepInfo {
+    pub pm1a_control: u16,
+    pub pm1b_control: u16,
+    pub sleep_type_a: u16,
+    pub sleep_type_b: u16,
+    // FACS の物理アドレス。起床ベクタを書き込む
+    pub facs: u64,
+}
+
+// DSDT の AML から Name(\_Sx, Package() {a, b, ...}) を探して SLP_TYP を取り出す
+fn sleep_types(aml: &[u8], state: u8) -> Option<(u16, u16)> {
+    let name = [b'_', b'S', b'0' + state, b'_'];
+    let position = (1..aml.len().saturating_sub(4)).find(|&index| {
+        aml[index..index + 4] == name
+            && aml[index + 4] == 0x12
+            && (aml[index - 1] == 0x08
+                || (index >= 2 && aml[index - 2] == 0x08 && aml[index - 1] == b'\\'))
+    })?;
+
+    // PackageOp の後ろは PkgLength (先頭バイトの上位 2 ビットが追加バイト数) と要素数
+    let mut index = position + 5;
+    index += ((*aml.get(index)? & 0xc0) >> 6) as usize + 2;
+    let mut element = || -> Option<u16> {
+        if *aml.get(index)? == 0x0a {
+            index += 1;
+        }
+        let value = *aml.get(index)? as u16;
+        index += 1;
+        Some(value)
+    };
+    let a = element()?;
+    let b = element()?;
+    Some((a, b))
+}
+
+fn dsdt() -> Option<&'static [u8]> {
+    let fadt = find_table(b"FACP")?;
+    match fadt.len() {
+        len if len >= 148 && read_u64(fadt, 140) != 0 => table_at(read_u64(fadt, 140)),
+        _ => table_at(read_u32(fadt, 40) as u64),
+    }
+}
+
+// DSDT と SSDT の AML 本体
+fn definition_blocks() -> impl Iterator<Item = &'static [u8]> {
+    dsdt()
+        .into_iter()
+        .chain(tables().filter(|table| &table[..4] == b"SSDT"))
+        .map(|table| &table[36..])
+}
+
+pub fn revision() -> Option<u8> {
+    find_table(b"FACP").map(|fadt| fadt[8])
+}
+
+// FADT の Preferred_PM_Profile
+pub fn pm_profile() -> Option<&'static str> {
+    let fadt = find_table(b"FACP")?;
+    Some(match fadt[45] {
+        1 => "desktop",
+        2 => "mobile",
+        3 => "workstation",
+        4 => "enterprise server",
+        5 => "SOHO server",
+        6 => "appliance PC",
+        7 => "performance server",
+        8 => "tablet",
+        _ => "unspecified",
+    })
+}
+
+// \_S0 から \_S5 のうちファームウェアが宣言しているもの
+pub fn sleep_states() -> Vec<u8> {
+    let aml = match dsdt() {
+        Some(dsdt) => &dsdt[36..],
+        None => return Vec::new(),
+    };
+    (0..=5)
+        .filter(|&state| sleep_types(aml, state).is_some())
+        .collect()
+}
+
+// "PNP0C0A" のような EISA ID を AML の DWord 表現に詰める
+fn eisa_id(id: &str) -> Option<[u8; 4]> {
+    let bytes = id.as_bytes();
+    if bytes.len() != 7 || !bytes[..3].iter().all(u8::is_ascii_uppercase) {
+        return None;
+    }
+    let vendor = bytes[..3]
+        .iter()
+        .fold(0u16, |value, &c| value << 5 | (c - 0x40) as u16);
+    let product = u16::from_str_radix(&id[3..], 16).ok()?;
+    let [v0, v1] = vendor.to_be_bytes();
+    let [p0, p1] = product.to_be_bytes();
+    Some([v0, v1, p0, p1])
+}
+
+// _HID が id のデバイスが宣言されているか。EISA ID と文字列の両方の書き方を見る
+pub fn has_device(id: &str) -> bool {
+    let mut dword = [0x0c; 5];
+    let eisa = eisa_id(id);
+    if let Some(eisa) = eisa {
+        dword[1..].copy_from_slice(&eisa);
+    }
+    definition_blocks().any(|aml| {
+        aml.windows(4).enumerate().any(|(index, window)| {
+            if window != b"_HID" {
+                return false;
+            }
+            let value = &aml[index + 4..];
+            (eisa.is_some() && value.starts_with(&dword))
+                || (value.first() == Some(&0x0d) && value[1..].starts_with(id.as_bytes()))
+        })
+    })
+}
+
+pub fn sleep_info(state: u8) -> Result<SleepInfo, &'static str> {
+    let fadt = find_table(b"FACP").ok_or("ACPI FADT not found")?;
+    if fadt.len() < 116 {
+        return Err("ACPI FADT is too short");
+    }
+    let facs = match fadt.len() {
+        len if len >= 140 && read_u64(fadt, 132) != 0 => read_u64(fadt, 132),
+        _ => read_u32(fadt, 36) as u64,
+    };
+    let dsdt = dsdt().ok_or("ACPI DSDT not found")?;
+    let (sleep_type_a, sleep_type_b) =
+        sleep_types(&dsdt[36..], state).ok_or("sleep state not supported by firmware")?;
+
+    Ok(SleepInfo {
+        pm1a_control: read_u32(fadt, 64) as u16,
+        pm1b_control: read_u32(fadt, 68) as u16,
+        sleep_type_a,
+        sleep_type_b,
+        facs,
+    })
+}
+
+// FACS の起床ベクタを設定する。64 ビットのベクタは使わないので 0 にする
+pub fn set_waking_vector(facs: u64, vector: u32) -> Result<(), &'static str> {
+    let table = physical(facs, 32).ok_or("ACPI FACS not mapped")?;
+    if &table[..4] != b"FACS" || read_u32(table, 4) < 32 {
+        return Err("ACPI FACS not found");
+    }
+    let base = arch::phys_to_virt(facs).ok_or("ACPI FACS not mapped")? as *mut u8;
+    unsafe {
+        (base.add(12) as *mut u32).write_volatile(vector);
+        (base.add(24) as *mut u64).write_volatile(0);
+    }
+    Ok(())
+}
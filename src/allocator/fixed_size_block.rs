@@ -1,11 +1,16 @@
 use core::alloc::{GlobalAlloc, Layout};
 use core::ptr::null_mut;
 
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+// 解放するときに 0 で埋めるブロックの数の上限。確保の中で確保できないので固定の表にする
+const SCRUB_SLOTS: usize = 16;
 
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    // 解放時に 0 で埋めるブロックの先頭。0 は空き
+    scrub: [usize; SCRUB_SLOTS],
 }
 
 impl FixedSizeBlockAllocator {
@@ -14,12 +19,57 @@ impl FixedSizeBlockAllocator {
         FixedSizeBlockAllocator {
             list_heads: [EMPTY; BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            scrub: [0; SCRUB_SLOTS],
         }
     }
 
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.fallback_allocator.init(heap_start, heap_size);
     }
+
+    pub fn free_block_counts(&self) -> [usize; BLOCK_SIZES.len()] {
+        let mut counts = [0; BLOCK_SIZES.len()];
+        for (index, head) in self.list_heads.iter().enumerate() {
+            let mut node = head.as_deref();
+            while let Some(current) = node {
+                counts[index] += 1;
+                node = current.next.as_deref();
+            }
+        }
+        counts
+    }
+
+    pub fn fallback_used(&self) -> usize {
+        self.fallback_allocator.used()
+    }
+
+    pub fn fallback_free(&self) -> usize {
+        self.fallback_allocator.free()
+    }
+
+    // 表が埋まっていれば false
+    pub fn mark_scrub(&mut self, ptr: usize) -> bool {
+        if self.scrub.contains(&ptr) {
+            return true;
+        }
+        match self.scrub.iter_mut().find(|slot| **slot == 0) {
+            Some(slot) => {
+                *slot = ptr;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn take_scrub(&mut self, ptr: usize) -> bool {
+        match self.scrub.iter_mut().find(|slot| **slot == ptr) {
+            Some(slot) => {
+                *slot = 0;
+                true
+            }
+            None => false,
+        }
+    }
 }
 
 struct ListNode {
@@ -28,6 +78,7 @@ struct ListNode {
 
 unsafe impl GlobalAlloc for super::Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        super::count_allocation();
         let mut allocator = self.lock();
         match list_index(&layout) {
             Some(index) => match allocator.list_heads[index].take() {
@@ -56,6 +107,9 @@ unsafe impl GlobalAlloc for super::Locked<FixedSizeBlockAllocator> {
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
+        if allocator.take_scrub(ptr as usize) {
+            core::ptr::write_bytes(ptr, 0, layout.size());
+        }
         match list_index(&layout) {
             Some(index) => {
                 let new_node = ListNode {
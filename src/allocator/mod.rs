@@ -1,49 +1,76 @@
 pub mod fixed_size_block;
 
+use crate::{arch, klog, rand};
+use core::sync::atomic::{AtomicUsize, Ordering};
 use fixed_size_block::FixedSizeBlockAllocator;
 use spin::Mutex;
-use x86_64::{
-    structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
-    },
-    VirtAddr,
-};
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
 
+// 起動ごとにヒープを HEAP_START からずらす幅 (1 GiB、ページ単位)。ヒープのアドレスを
+// 決め打ちしたバグが毎回同じ場所で隠れないように
+const HEAP_SLIDE: usize = 1 << 30;
+
 #[global_allocator]
 static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
 
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
-
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
-        }
-    }
+static HEAP_BASE: AtomicUsize = AtomicUsize::new(HEAP_START);
+
+pub fn init_heap() -> Result<(), &'static str> {
+    let start = HEAP_START + rand::boot_offset(HEAP_SLIDE, 4096);
+    arch::map_range(start as u64, HEAP_SIZE as u64, true)?;
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.lock().init(start, HEAP_SIZE);
     }
+    HEAP_BASE.store(start, Ordering::Relaxed);
+    klog!(crate::log::Level::Info, "Heap at {:#x}", start);
 
     Ok(())
 }
 
+// ずらした後のヒープの先頭
+pub fn heap_start() -> usize {
+    HEAP_BASE.load(Ordering::Relaxed)
+}
+
+// 起動してからの確保の回数。前後の差でコマンドがどれだけヒープを使ったかを見る
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+fn count_allocation() {
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub struct HeapStats {
+    pub heap_size: usize,
+    pub fallback_used: usize,
+    pub fallback_free: usize,
+    pub free_blocks: [usize; fixed_size_block::BLOCK_SIZES.len()],
+    pub allocations: usize,
+}
+
+pub fn stats() -> HeapStats {
+    let allocator = ALLOCATOR.lock();
+    HeapStats {
+        heap_size: HEAP_SIZE,
+        fallback_used: allocator.fallback_used(),
+        fallback_free: allocator.fallback_free(),
+        free_blocks: allocator.free_block_counts(),
+        allocations: allocations(),
+    }
+}
+
+// ptr から始まるブロックを、解放されるときに 0 で埋めるようにする。印を付けられるのは
+// SCRUB_SLOTS 個までで、埋まっていれば false を返す。realloc で移った先には印は付かない
+pub fn scrub_on_free(ptr: *const u8) -> bool {
+    ALLOCATOR.lock().mark_scrub(ptr as usize)
+}
+
+pub fn allocations() -> usize {
+    ALLOCATIONS.load(Ordering::Relaxed)
+}
+
 pub struct Locked<A> {
     inner: Mutex<A>,
 }
@@ -0,0 +1,130 @@
+// アーキテクチャ依存のコードはこの下に置く。
+// shell や filesystem、allocator などは Arch トレイトとこのファイルの関数だけを使い、
+// 別のアーキテクチャに移すときは Arch を実装したモジュールを足して Current を切り替える。
+// 割り込みコントローラや I/O ポート (hal::port) のような PC 固有のものは x86 を直接参照する
+
+use bootloader::BootInfo;
+
+#[cfg(target_arch = "x86_64")]
+pub mod x86;
+
+#[cfg(target_arch = "x86_64")]
+pub use self::x86::X86 as Current;
+
+pub trait Arch {
+    // セグメントや割り込みテーブルなど CPU 自体の設定
+    fn init_cpu();
+    fn init_interrupt_controller();
+    // ブートローダーが用意したページテーブルを引き継ぎ、書き込めるページを実行できなくする
+    fn init_memory(boot_info: &'static BootInfo);
+    // 物理フレームを割り当てて仮想アドレスの範囲をマップする。データ用なので実行はできない
+    fn map_range(start: u64, len: u64, writable: bool) -> Result<(), &'static str>;
+    // map_range でマップした範囲を読み出し専用にして実行できるようにする (カーネル拡張のコード)
+    fn make_executable(start: u64, len: u64) -> Result<(), &'static str>;
+    fn is_range_mapped(start: u64, len: u64, writable: bool) -> bool;
+    // デバイスに渡す、物理的に連続したページを取る。物理アドレスを返す
+    fn allocate_dma(pages: usize) -> Result<u64, &'static str>;
+    fn phys_to_virt(phys: u64) -> Option<u64>;
+
+    fn enable_interrupts();
+    fn disable_interrupts();
+    fn interrupts_enabled() -> bool;
+    // 割り込みハンドラ (例外を含む) の中を実行している
+    fn in_interrupt() -> bool;
+    // 割り込みを許可して次の割り込みまで止まる
+    fn wait_for_interrupt();
+    fn halt();
+    fn cycle_counter() -> u64;
+    // 呼び出し元のフレームポインタ。[fp] に前のフレーム、[fp + 8] に戻り先がある
+    fn frame_pointer() -> u64;
+    fn stack_pointer() -> u64;
+    // スタックポインタを stack に付け替えて entry を呼ぶ。今のスタックの中身は捨てる
+    unsafe fn call_on_stack(stack: u64, entry: extern "C" fn() -> !) -> !;
+}
+
+pub fn init_cpu() {
+    Current::init_cpu();
+}
+
+pub fn init_interrupt_controller() {
+    Current::init_interrupt_controller();
+}
+
+pub fn init_memory(boot_info: &'static BootInfo) {
+    Current::init_memory(boot_info);
+}
+
+pub fn map_range(start: u64, len: u64, writable: bool) -> Result<(), &'static str> {
+    Current::map_range(start, len, writable)
+}
+
+pub fn make_executable(start: u64, len: u64) -> Result<(), &'static str> {
+    Current::make_executable(start, len)
+}
+
+pub fn is_range_mapped(start: u64, len: u64, writable: bool) -> bool {
+    Current::is_range_mapped(start, len, writable)
+}
+
+pub fn allocate_dma(pages: usize) -> Result<u64, &'static str> {
+    Current::allocate_dma(pages)
+}
+
+pub fn phys_to_virt(phys: u64) -> Option<u64> {
+    Current::phys_to_virt(phys)
+}
+
+pub fn enable_interrupts() {
+    Current::enable_interrupts();
+}
+
+pub fn disable_interrupts() {
+    Current::disable_interrupts();
+}
+
+pub fn interrupts_enabled() -> bool {
+    Current::interrupts_enabled()
+}
+
+pub fn in_interrupt() -> bool {
+    Current::in_interrupt()
+}
+
+pub fn wait_for_interrupt() {
+    Current::wait_for_interrupt();
+}
+
+pub fn halt() {
+    Current::halt();
+}
+
+pub fn cycle_counter() -> u64 {
+    Current::cycle_counter()
+}
+
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    Current::frame_pointer()
+}
+
+#[inline(always)]
+pub fn stack_pointer() -> u64 {
+    Current::stack_pointer()
+}
+
+// 呼び出し側は、stack から上のフレームに二度と戻らないことを保証する
+pub unsafe fn call_on_stack(stack: u64, entry: extern "C" fn() -> !) -> ! {
+    Current::call_on_stack(stack, entry)
+}
+
+pub fn without_interrupts<F: FnOnce() -> R, R>(f: F) -> R {
+    let enabled = Current::interrupts_enabled();
+    if enabled {
+        Current::disable_interrupts();
+    }
+    let result = f();
+    if enabled {
+        Current::enable_interrupts();
+    }
+    result
+}
@@ -0,0 +1,126 @@
+// ローカル APIC のタイマー。PIT は一度に 50 ms 余りしか数えられないので、アイドルのときは
+// PIT を止めてこのタイマーのワンショットで次の締め切りまで眠る (time::idle)。
+// 周波数は決まっていないので、起動時に PIT のティックで数えて決める。
+// 割り込みは今まで通り PIC から受け、APIC から来るのはこのタイマーだけ
+use super::{cpu, memory};
+use crate::device::{self, DeviceClass, Resource};
+use crate::{arch, time};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::model_specific::Msr;
+
+// PIC の 32..48 と重ならないところ
+pub const TIMER_VECTOR: u8 = 0x30;
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS: u64 = 0x000f_ffff_f000;
+
+// レジスタのオフセット
+const EOI: u64 = 0xb0;
+const SPURIOUS: u64 = 0xf0;
+const LVT_TIMER: u64 = 0x320;
+const INITIAL_COUNT: u64 = 0x380;
+const CURRENT_COUNT: u64 = 0x390;
+const DIVIDE_CONFIG: u64 = 0x3e0;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+// 128 分周。バスが 1 GHz でも 32 ビットのカウンタで 9 分ほど眠れる
+const DIVIDE_BY_128: u32 = 0b1010;
+// 校正に使うティック数 (100 Hz で 0.1 秒)
+const CALIBRATION_TICKS: u64 = 10;
+
+// レジスタの仮想アドレス。0 ならまだ使えない
+static BASE: AtomicU64 = AtomicU64::new(0);
+// 1 ティックあたりのカウント。0 なら校正していない
+static COUNTS_PER_TICK: AtomicU64 = AtomicU64::new(0);
+
+fn read(register: u64) -> u32 {
+    let addr = BASE.load(Ordering::Relaxed) + register;
+    unsafe { core::ptr::read_volatile(addr as *const u32) }
+}
+
+fn write(register: u64, value: u32) {
+    let addr = BASE.load(Ordering::Relaxed) + register;
+    unsafe { core::ptr::write_volatile(addr as *mut u32, value) };
+}
+
+// ソフトウェアで有効にして、タイマーは止めておく。スリープから戻ったときもこれをやり直す
+fn setup() {
+    write(SPURIOUS, SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32);
+    write(DIVIDE_CONFIG, DIVIDE_BY_128);
+    write(LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u32);
+    write(INITIAL_COUNT, 0);
+}
+
+// PIT の割り込みが動き出してから呼ぶ
+pub fn init() -> Result<(), &'static str> {
+    if !cpu::has_apic() {
+        return Err("no local APIC");
+    }
+    if !arch::interrupts_enabled() {
+        return Err("interrupts are disabled");
+    }
+    let mut msr = Msr::new(IA32_APIC_BASE);
+    let base = unsafe { msr.read() };
+    let phys = base & APIC_BASE_ADDRESS;
+    BASE.store(memory::map_mmio(phys)?, Ordering::Relaxed);
+    unsafe { msr.write(base | APIC_BASE_ENABLE) };
+    setup();
+
+    // ティックの境目から CALIBRATION_TICKS の間に減った数を数える
+    let start = time::ticks();
+    while time::ticks() == start {
+        arch::halt();
+    }
+    let begin = time::ticks();
+    write(INITIAL_COUNT, u32::MAX);
+    while time::ticks() < begin + CALIBRATION_TICKS {
+        arch::halt();
+    }
+    let counted = (u32::MAX - read(CURRENT_COUNT)) as u64;
+    let elapsed = time::ticks() - begin;
+    write(INITIAL_COUNT, 0);
+    let per_tick = counted / elapsed;
+    if per_tick == 0 {
+        return Err("APIC timer does not count");
+    }
+    COUNTS_PER_TICK.store(per_tick, Ordering::Relaxed);
+
+    let platform = device::find("platform").ok_or("platform bus not registered")?;
+    device::register(
+        "lapic",
+        DeviceClass::Timer,
+        Some(platform),
+        &[Resource::Memory {
+            start: phys,
+            len: 0x1000,
+        }],
+    );
+    crate::power::register_pm(|| Ok(()), setup);
+    Ok(())
+}
+
+// 1 ティックあたりのカウント。0 なら APIC のタイマーは使えない
+pub fn counts_per_tick() -> u64 {
+    COUNTS_PER_TICK.load(Ordering::Relaxed)
+}
+
+// counts を数え終えたら TIMER_VECTOR の割り込みを 1 回起こす
+pub fn start_one_shot(counts: u32) {
+    write(LVT_TIMER, TIMER_VECTOR as u32);
+    write(INITIAL_COUNT, counts.max(1));
+}
+
+// タイマーを止めて、残りのカウントを返す。満了していれば 0
+pub fn stop() -> u32 {
+    let remaining = read(CURRENT_COUNT);
+    write(LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u32);
+    write(INITIAL_COUNT, 0);
+    remaining
+}
+
+pub fn end_of_interrupt() {
+    write(EOI, 0);
+}
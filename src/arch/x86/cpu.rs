@@ -0,0 +1,177 @@
+// CPUID と MSR から読める CPU の情報 (周波数と温度)。
+// MSR は対応していないと #GP になるので、必ず CPUID のビットを確かめてから読む
+
+use crate::{arch, time};
+use alloc::string::String;
+use core::arch::x86_64::{__cpuid, CpuidResult};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::model_specific::Msr;
+
+const IA32_MPERF: u32 = 0xe7;
+const IA32_APERF: u32 = 0xe8;
+const IA32_THERM_STATUS: u32 = 0x19c;
+const MSR_TEMPERATURE_TARGET: u32 = 0x1a2;
+
+// 計測に使うティック数 (100 Hz で 0.1 秒)
+const SAMPLE_TICKS: u64 = 10;
+
+static TSC_MHZ: AtomicU64 = AtomicU64::new(0);
+
+// 新しいコンパイラでは __cpuid は unsafe ではなくなった
+#[allow(unused_unsafe)]
+fn cpuid(leaf: u32) -> CpuidResult {
+    unsafe { __cpuid(leaf) }
+}
+
+fn max_leaf() -> u32 {
+    cpuid(0).eax
+}
+
+fn register_text(registers: &[u32]) -> String {
+    registers
+        .iter()
+        .flat_map(|register| register.to_le_bytes())
+        .filter(|&byte| byte != 0)
+        .map(char::from)
+        .collect()
+}
+
+pub fn vendor() -> String {
+    let leaf = cpuid(0);
+    register_text(&[leaf.ebx, leaf.edx, leaf.ecx])
+}
+
+pub fn brand() -> Option<String> {
+    if cpuid(0x8000_0000).eax < 0x8000_0004 {
+        return None;
+    }
+    let mut registers = alloc::vec::Vec::new();
+    for leaf in 0x8000_0002..=0x8000_0004 {
+        let result = cpuid(leaf);
+        registers.extend_from_slice(&[result.eax, result.ebx, result.ecx, result.edx]);
+    }
+    Some(String::from(register_text(&registers).trim()))
+}
+
+// (family, model, stepping)
+pub fn signature() -> (u32, u32, u32) {
+    let eax = cpuid(1).eax;
+    let mut family = eax >> 8 & 0xf;
+    let mut model = eax >> 4 & 0xf;
+    if family == 0xf {
+        family += eax >> 20 & 0xff;
+    }
+    if family == 0x6 || family >= 0xf {
+        model += (eax >> 16 & 0xf) << 4;
+    }
+    (family, model, eax & 0xf)
+}
+
+// CPUID 0x16 の (基本, 最大) 周波数。仮想マシンでは大抵 0
+pub fn nominal_mhz() -> Option<(u32, u32)> {
+    if max_leaf() < 0x16 {
+        return None;
+    }
+    let leaf = cpuid(0x16);
+    match (leaf.eax & 0xffff, leaf.ebx & 0xffff) {
+        (0, _) => None,
+        (base, max) => Some((base, max)),
+    }
+}
+
+fn has_aperf_mperf() -> bool {
+    max_leaf() >= 6 && cpuid(6).ecx & 1 != 0
+}
+
+// ローカル APIC (タイマーを tickless のアイドルに使う)
+pub fn has_apic() -> bool {
+    cpuid(1).edx & (1 << 9) != 0
+}
+
+// カーネルがユーザーのページを実行しない (SMEP)、勝手に読み書きしない (SMAP) ための機能
+pub fn has_smep() -> bool {
+    max_leaf() >= 7 && cpuid(7).ebx & (1 << 7) != 0
+}
+
+pub fn has_smap() -> bool {
+    max_leaf() >= 7 && cpuid(7).ebx & (1 << 20) != 0
+}
+
+fn has_thermal_sensor() -> bool {
+    max_leaf() >= 6 && cpuid(6).eax & 1 != 0
+}
+
+fn read_msr(msr: u32) -> u64 {
+    unsafe { Msr::new(msr).read() }
+}
+
+pub struct Frequency {
+    pub tsc_mhz: u64,
+    // APERF/MPERF の比から求めた実効周波数
+    pub effective_mhz: Option<u64>,
+}
+
+// PIT のティックを基準に TSC を数える。割り込みが止まっていると測れない
+pub fn measure_frequency() -> Option<Frequency> {
+    if !arch::interrupts_enabled() {
+        return None;
+    }
+    let start_tick = time::ticks();
+    while time::ticks() == start_tick {
+        arch::halt();
+    }
+
+    let aperf_mperf = has_aperf_mperf();
+    let counters = || {
+        if aperf_mperf {
+            (read_msr(IA32_APERF), read_msr(IA32_MPERF))
+        } else {
+            (0, 0)
+        }
+    };
+    let begin = time::ticks();
+    let tsc_begin = arch::cycle_counter();
+    let (aperf_begin, mperf_begin) = counters();
+    while time::ticks() < begin + SAMPLE_TICKS {
+        arch::halt();
+    }
+    let tsc_end = arch::cycle_counter();
+    let (aperf_end, mperf_end) = counters();
+    let elapsed = time::ticks() - begin;
+
+    let tsc_mhz = (tsc_end - tsc_begin) * time::TICK_HZ / elapsed / 1_000_000;
+    TSC_MHZ.store(tsc_mhz, Ordering::Relaxed);
+    let mperf = mperf_end.wrapping_sub(mperf_begin);
+    let effective_mhz =
+        (aperf_mperf && mperf != 0).then(|| tsc_mhz * aperf_end.wrapping_sub(aperf_begin) / mperf);
+    Some(Frequency {
+        tsc_mhz,
+        effective_mhz,
+    })
+}
+
+// 前回測った TSC の周波数。まだ測っていなければ測る
+pub fn tsc_mhz() -> Option<u64> {
+    match TSC_MHZ.load(Ordering::Relaxed) {
+        0 => measure_frequency().map(|frequency| frequency.tsc_mhz),
+        mhz => Some(mhz),
+    }
+}
+
+// (現在の温度, TjMax)。デジタル温度センサーは TjMax からの差を返す
+pub fn temperature() -> Option<(u64, u64)> {
+    if !has_thermal_sensor() || vendor() != "GenuineIntel" {
+        return None;
+    }
+    let status = read_msr(IA32_THERM_STATUS);
+    if status & 1 << 31 == 0 {
+        return None;
+    }
+    // TEMPERATURE_TARGET は Core 世代以降にしかないので、読めない世代は 100 とみなす
+    let tj_max = match signature() {
+        (6, model, _) if model >= 0x1a => read_msr(MSR_TEMPERATURE_TARGET) >> 16 & 0xff,
+        _ => 100,
+    };
+    let below = status >> 16 & 0x7f;
+    Some((tj_max.saturating_sub(below), tj_max))
+}
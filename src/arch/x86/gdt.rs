@@ -51,3 +51,15 @@ pub fn init() {
         load_tss(GDT.1.tss_selector);
     }
 }
+
+// S3 から戻ったときに読み直す。TSS 記述子は busy のまま残っているので、
+// ltr の前に available に戻す
+pub fn reload() {
+    let index = GDT.1.tss_selector.index() as usize;
+    let table = x86_64::instructions::tables::sgdt();
+    unsafe {
+        let access = (table.base.as_u64() as *mut u8).add(index * 8 + 5);
+        access.write_volatile(access.read_volatile() & !0x02);
+    }
+    init();
+}
@@ -0,0 +1,219 @@
+use super::{apic, gdt};
+use crate::device::{self, DeviceClass};
+use crate::hal::port::PortRange;
+use crate::println;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use pic8259::ChainedPics;
+use spin::Mutex;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+pub static PICS: Mutex<ChainedPics> =
+    Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+pub enum InterruptIndex {
+    Timer = PIC_1_OFFSET,
+    Keyboard,
+    Rtc = PIC_2_OFFSET,
+    Mouse = PIC_2_OFFSET + 4,
+}
+
+impl InterruptIndex {
+    pub fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    pub fn as_usize(self) -> usize {
+        usize::from(self.as_u8())
+    }
+}
+
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.debug.set_handler_fn(debug_handler);
+        idt.page_fault.set_handler_fn(page_fault_handler);
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(double_fault_handler)
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+
+        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
+        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Rtc.as_usize()].set_handler_fn(rtc_interrupt_handler);
+        idt[InterruptIndex::Mouse.as_usize()].set_handler_fn(mouse_interrupt_handler);
+        idt[usize::from(apic::TIMER_VECTOR)].set_handler_fn(apic_timer_interrupt_handler);
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(apic_spurious_interrupt_handler);
+
+        idt
+    };
+}
+
+pub fn init_idt() {
+    IDT.load();
+}
+
+// 実行中の割り込みハンドラの数。例外の中で割り込みが入ると 2 以上になる
+static DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+pub fn in_interrupt() -> bool {
+    DEPTH.load(Ordering::Relaxed) > 0
+}
+
+fn handler<R>(f: impl FnOnce() -> R) -> R {
+    DEPTH.fetch_add(1, Ordering::Relaxed);
+    let result = f();
+    DEPTH.fetch_sub(1, Ordering::Relaxed);
+    result
+}
+
+// コマンドとデータ (マスク) の 2 ポートずつ。初期化は pic8259 が行う
+static PIC_1_PORTS: PortRange = unsafe { PortRange::new("pic", 0x20, 2) };
+static PIC_2_PORTS: PortRange = unsafe { PortRange::new("pic", 0xa0, 2) };
+
+pub fn register_devices(bus: usize) -> Result<(), &'static str> {
+    PIC_1_PORTS.claim()?;
+    PIC_2_PORTS.claim()?;
+    device::register(
+        "pic",
+        DeviceClass::InterruptController,
+        Some(bus),
+        &[PIC_1_PORTS.resource(), PIC_2_PORTS.resource()],
+    );
+    crate::power::register_pm(save_pic_masks, restore_pics);
+    Ok(())
+}
+
+// スリープから戻ると PIC は BIOS の設定になっているので、初期化し直してマスクを戻す
+static PIC_MASKS: Mutex<(u8, u8)> = Mutex::new((0xff, 0xff));
+
+fn save_pic_masks() -> Result<(), &'static str> {
+    *PIC_MASKS.lock() = (PIC_1_PORTS.read(1), PIC_2_PORTS.read(1));
+    Ok(())
+}
+
+fn restore_pics() {
+    let (master, slave) = *PIC_MASKS.lock();
+    unsafe { PICS.lock().initialize() };
+    PIC_1_PORTS.write(1, master);
+    PIC_2_PORTS.write(1, slave);
+}
+
+// スレーブ側の IRQ ならカスケードの IRQ2 も開ける
+pub fn unmask_irq(irq: u8) {
+    let (ports, bit) = if irq < 8 {
+        (&PIC_1_PORTS, irq)
+    } else {
+        unmask_irq(2);
+        (&PIC_2_PORTS, irq - 8)
+    };
+    let mask: u8 = ports.read(1);
+    ports.write(1, mask & !(1 << bit));
+}
+
+extern "x86-interrupt" fn breakpoint_handler(mut stack_frame: InterruptStackFrame) {
+    handler(|| {
+        if crate::gdbstub::is_attached() {
+            crate::gdbstub::handle_breakpoint(&mut stack_frame);
+            return;
+        }
+        println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    })
+}
+
+extern "x86-interrupt" fn debug_handler(mut stack_frame: InterruptStackFrame) {
+    handler(|| {
+        if crate::gdbstub::is_attached() {
+            crate::gdbstub::handle_debug(&mut stack_frame);
+            return;
+        }
+        println!("EXCEPTION: DEBUG\n{:#?}", stack_frame);
+    })
+}
+
+// 割り込みの深さは数えない。シェルのコマンドの中で起きたものはコマンドの panic として
+// 扱い、プロンプトに戻れるようにする
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    panic!(
+        "EXCEPTION: PAGE FAULT at {:#x} ({:?})\n{:#?}",
+        Cr2::read().as_u64(),
+        error_code,
+        stack_frame
+    );
+}
+
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) -> ! {
+    DEPTH.fetch_add(1, Ordering::Relaxed);
+    println!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    handler(|| {
+        crate::time::tick();
+
+        unsafe {
+            PICS.lock()
+                .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+        }
+    })
+}
+
+extern "x86-interrupt" fn apic_timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    handler(|| {
+        crate::time::handle_apic_timer();
+        apic::end_of_interrupt();
+    })
+}
+
+// 見せかけの割り込みには EOI を送らない
+extern "x86-interrupt" fn apic_spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
+extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    handler(|| {
+        crate::keyboard::handle_keyboard_interrupt();
+
+        unsafe {
+            PICS.lock()
+                .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+        }
+    })
+}
+
+extern "x86-interrupt" fn rtc_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    handler(|| {
+        crate::time::handle_rtc_interrupt();
+
+        unsafe {
+            PICS.lock()
+                .notify_end_of_interrupt(InterruptIndex::Rtc.as_u8());
+        }
+    })
+}
+
+extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    handler(|| {
+        crate::mouse::handle_interrupt();
+
+        unsafe {
+            PICS.lock()
+                .notify_end_of_interrupt(InterruptIndex::Mouse.as_u8());
+        }
+    })
+}
@@ -0,0 +1,454 @@
+use alloc::vec::Vec;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use bootloader::BootInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::{
+    structures::paging::{
+        mapper::TranslateResult, FrameAllocator, Mapper, OffsetPageTable, Page, PageTable,
+        PageTableFlags, PhysFrame, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+static MAPPER: Mutex<Option<(OffsetPageTable<'static>, BootInfoFrameAllocator)>> = Mutex::new(None);
+// 1 MiB 未満のフレーム。リアルモードから戻ってくるコード (sleep.rs) を置く
+static LOW_FRAME: AtomicU64 = AtomicU64::new(0);
+
+pub struct BootInfoFrameAllocator {
+    memory_map: &'static MemoryMap,
+    next: usize,
+}
+
+impl BootInfoFrameAllocator {
+    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+        BootInfoFrameAllocator {
+            memory_map,
+            next: 0,
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.usable_frames().nth(self.next);
+        self.next += 1;
+        frame
+    }
+}
+
+impl BootInfoFrameAllocator {
+    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
+        let regions = self.memory_map.iter();
+        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
+        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
+        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
+        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+}
+
+pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    OffsetPageTable::new(level_4_table, physical_memory_offset)
+}
+
+pub fn init_mapper(boot_info: &'static BootInfo) {
+    // NX ビットを使えるようにし、カーネルモードでも読み出し専用のページに書けないようにする
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+    let mapper = unsafe { init(VirtAddr::new(boot_info.physical_memory_offset)) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    // フレームは低いアドレスから順に配られるので、最初に取れば 1 MiB 未満になる
+    if let Some(frame) = frame_allocator.allocate_frame() {
+        let addr = frame.start_address().as_u64();
+        if addr != 0 && addr < 0x10_0000 {
+            LOW_FRAME.store(addr, Ordering::Relaxed);
+        }
+    }
+    *MAPPER.lock() = Some((mapper, frame_allocator));
+}
+
+// 予約した低位フレームを仮想アドレス = 物理アドレスでマップして返す。中のコードを
+// 実行するので読み出し専用にする。書き込みは物理メモリのマップを通して行う
+pub fn identity_map_low_frame() -> Result<u64, &'static str> {
+    let addr = LOW_FRAME.load(Ordering::Relaxed);
+    if addr == 0 {
+        return Err("no free frame below 1 MiB");
+    }
+    let mapped = match translate(VirtAddr::new(addr)) {
+        Some((phys, _)) if phys.as_u64() == addr => true,
+        Some(_) => return Err("low memory is already mapped elsewhere"),
+        None => false,
+    };
+
+    let mut guard = MAPPER.lock();
+    let (mapper, frame_allocator) = guard.as_mut().ok_or("paging not initialized")?;
+    let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(addr));
+    let flags = PageTableFlags::PRESENT;
+    unsafe {
+        // ブートローダが恒等マップしていたなら、enforce_wx で付いた NX を外す
+        if mapped {
+            mapper
+                .update_flags(
+                    Page::<Size4KiB>::containing_address(VirtAddr::new(addr)),
+                    flags,
+                )
+                .map_err(|_| "cannot make low memory executable")?
+                .flush();
+        } else {
+            mapper
+                .identity_map(frame, flags, frame_allocator)
+                .map_err(|_| "failed to map page")?
+                .flush();
+        }
+    }
+    Ok(addr)
+}
+
+// 物理的に連続した count 枚のフレームを取って、先頭の物理アドレスを返す。
+// フレームは低い方から順に配られるので、使用可能領域の境目で途切れたら取り直す。
+// 途中まで取ったフレームは返せないので捨てる
+pub fn allocate_contiguous_frames(count: usize) -> Result<u64, &'static str> {
+    let mut guard = MAPPER.lock();
+    let (_, frame_allocator) = guard.as_mut().ok_or("paging not initialized")?;
+    let mut start = frame_allocator
+        .allocate_frame()
+        .ok_or("out of physical memory")?
+        .start_address()
+        .as_u64();
+    let mut taken = 1;
+    while taken < count {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or("out of physical memory")?
+            .start_address()
+            .as_u64();
+        if frame == start + taken as u64 * 4096 {
+            taken += 1;
+        } else {
+            start = frame;
+            taken = 1;
+        }
+    }
+    Ok(start)
+}
+
+pub fn map_range(start: u64, len: u64, writable: bool) -> Result<(), &'static str> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = start.checked_add(len - 1).ok_or("address out of range")?;
+    let first = VirtAddr::try_new(start).map_err(|_| "address out of range")?;
+    let last = VirtAddr::try_new(end).map_err(|_| "address out of range")?;
+
+    let mut guard = MAPPER.lock();
+    let (mapper, frame_allocator) = guard.as_mut().ok_or("paging not initialized")?;
+    // ここでマップするのはヒープやスタックなどのデータだけなので、実行はさせない
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+    if writable {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    let pages = Page::<Size4KiB>::range_inclusive(
+        Page::containing_address(first),
+        Page::containing_address(last),
+    );
+    for page in pages {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or("out of physical memory")?;
+        unsafe {
+            mapper
+                .map_to(page, frame, flags, frame_allocator)
+                .map_err(|_| "failed to map page")?
+                .flush();
+        }
+    }
+    Ok(())
+}
+
+// デバイスのレジスタ (1 ページ) を物理メモリのマップと同じ仮想アドレスで読み書きできるようにして、
+// その仮想アドレスを返す。ブートローダーは RAM の範囲しかマップしないことがあるので、
+// 無ければキャッシュしないページとして足す
+pub fn map_mmio(phys: u64) -> Result<u64, &'static str> {
+    let offset = physical_memory_offset().as_u64();
+    if offset == 0 {
+        return Err("paging not initialized");
+    }
+    let virt = offset.checked_add(phys).ok_or("address out of range")?;
+    let addr = VirtAddr::try_new(virt).map_err(|_| "address out of range")?;
+    match translate(addr) {
+        Some((mapped, _)) if mapped.as_u64() == phys => return Ok(virt),
+        Some(_) => return Err("address is already mapped elsewhere"),
+        None => {}
+    }
+
+    let mut guard = MAPPER.lock();
+    let (mapper, frame_allocator) = guard.as_mut().ok_or("paging not initialized")?;
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::NO_EXECUTE;
+    unsafe {
+        mapper
+            .map_to(
+                Page::<Size4KiB>::containing_address(addr),
+                PhysFrame::containing_address(PhysAddr::new(phys)),
+                flags,
+                frame_allocator,
+            )
+            .map_err(|_| "failed to map page")?
+            .flush();
+    }
+    Ok(virt)
+}
+
+// マップ済みの範囲を読み出し専用にして実行できるようにする。コードを書き終えてから呼ぶので、
+// 書き込める間に実行されることはない
+pub fn make_executable(start: u64, len: u64) -> Result<(), &'static str> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = start.checked_add(len - 1).ok_or("address out of range")?;
+    let first = VirtAddr::try_new(start).map_err(|_| "address out of range")?;
+    let last = VirtAddr::try_new(end).map_err(|_| "address out of range")?;
+
+    let mut guard = MAPPER.lock();
+    let (mapper, _) = guard.as_mut().ok_or("paging not initialized")?;
+    let pages = Page::<Size4KiB>::range_inclusive(
+        Page::containing_address(first),
+        Page::containing_address(last),
+    );
+    for page in pages {
+        unsafe {
+            mapper
+                .update_flags(page, PageTableFlags::PRESENT)
+                .map_err(|_| "page not mapped")?
+                .flush();
+        }
+    }
+    Ok(())
+}
+
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_table_frame, _) = Cr3::read();
+    let phys = level_4_table_frame.start_address();
+    let virt = physical_memory_offset + phys.as_u64();
+    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+    &mut *page_table_ptr
+}
+
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+pub fn translate(addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+    let offset = physical_memory_offset();
+    if offset.as_u64() == 0 {
+        return None;
+    }
+
+    let mapper = unsafe { OffsetPageTable::new(active_level_4_table(offset), offset) };
+    match mapper.translate(addr) {
+        TranslateResult::Mapped {
+            frame,
+            offset,
+            flags,
+        } => Some((frame.start_address() + offset, flags)),
+        _ => None,
+    }
+}
+
+// [start, start + len) の全ページがマップされているか
+pub fn is_range_mapped(start: u64, len: u64, writable: bool) -> bool {
+    let required = if writable {
+        PageTableFlags::WRITABLE
+    } else {
+        PageTableFlags::empty()
+    };
+    range_has_flags(start, len, required)
+}
+
+// [start, start + len) の全ページがマップされていて、有効なフラグに required が全部あるか
+pub fn range_has_flags(start: u64, len: u64, required: PageTableFlags) -> bool {
+    if len == 0 {
+        return true;
+    }
+    let end = match start.checked_add(len - 1) {
+        Some(end) => end,
+        None => return false,
+    };
+
+    let mut page = start & !0xfff;
+    loop {
+        let addr = match VirtAddr::try_new(page) {
+            Ok(addr) => addr,
+            Err(_) => return false,
+        };
+        match translate(addr) {
+            Some((_, flags)) if flags.contains(required) => {}
+            _ => return false,
+        }
+        if end - page < 4096 {
+            return true;
+        }
+        page += 4096;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MappedRange {
+    pub start: u64,
+    pub size: u64,
+    pub phys: PhysAddr,
+    pub flags: PageTableFlags,
+}
+
+impl MappedRange {
+    pub fn end(&self) -> u64 {
+        self.start + self.size
+    }
+}
+
+// 有効なフラグは全レベルの AND (NX のみ OR)
+fn effective_flags(parent: PageTableFlags, entry: PageTableFlags) -> PageTableFlags {
+    let inherited = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let mut flags = entry & !inherited | (entry & parent & inherited);
+    if parent.contains(PageTableFlags::NO_EXECUTE) {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+fn canonical(addr: u64) -> u64 {
+    if addr & (1 << 47) != 0 {
+        addr | 0xffff_0000_0000_0000
+    } else {
+        addr
+    }
+}
+
+pub fn mapped_ranges() -> Vec<MappedRange> {
+    let offset = physical_memory_offset();
+    let mut ranges: Vec<MappedRange> = Vec::new();
+    if offset.as_u64() == 0 {
+        return ranges;
+    }
+
+    let table_at =
+        |phys: PhysAddr| -> &PageTable { unsafe { &*(offset + phys.as_u64()).as_ptr() } };
+    let mut push = |start: u64, size: u64, phys: PhysAddr, flags: PageTableFlags| {
+        if let Some(last) = ranges.last_mut() {
+            if last.end() == start && last.phys + last.size == phys && last.flags == flags {
+                last.size += size;
+                return;
+            }
+        }
+        ranges.push(MappedRange {
+            start,
+            size,
+            phys,
+            flags,
+        });
+    };
+
+    let all = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let l4_table = unsafe { active_level_4_table(offset) };
+    for (i4, e4) in l4_table.iter().enumerate() {
+        if !e4.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let f4 = effective_flags(all, e4.flags());
+        for (i3, e3) in table_at(e4.addr()).iter().enumerate() {
+            if !e3.flags().contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+            let f3 = effective_flags(f4, e3.flags());
+            let base3 = canonical(((i4 as u64) << 39) | ((i3 as u64) << 30));
+            if e3.flags().contains(PageTableFlags::HUGE_PAGE) {
+                push(base3, 1 << 30, e3.addr(), f3);
+                continue;
+            }
+            for (i2, e2) in table_at(e3.addr()).iter().enumerate() {
+                if !e2.flags().contains(PageTableFlags::PRESENT) {
+                    continue;
+                }
+                let f2 = effective_flags(f3, e2.flags());
+                let base2 = base3 | ((i2 as u64) << 21);
+                if e2.flags().contains(PageTableFlags::HUGE_PAGE) {
+                    push(base2, 1 << 21, e2.addr(), f2);
+                    continue;
+                }
+                for (i1, e1) in table_at(e2.addr()).iter().enumerate() {
+                    if e1.flags().contains(PageTableFlags::PRESENT) {
+                        let f1 = effective_flags(f2, e1.flags());
+                        push(base2 | ((i1 as u64) << 12), 4096, e1.addr(), f1);
+                    }
+                }
+            }
+        }
+    }
+
+    ranges
+}
+
+// 書き込めて実行もできる範囲。W^X が守られていれば空になる
+pub fn wx_violations() -> Vec<MappedRange> {
+    mapped_ranges()
+        .into_iter()
+        .filter(|range| {
+            range.flags.contains(PageTableFlags::WRITABLE)
+                && !range.flags.contains(PageTableFlags::NO_EXECUTE)
+        })
+        .collect()
+}
+
+// 書き込めて実行もできるページに NX を付け、付けたエントリの数を返す。ブートローダは
+// カーネルのコードを読み出し専用で、スタックや物理メモリのマップを実行可能でマップするので、
+// 起動時に一度これを呼んでデータのページを実行できないようにする
+pub fn enforce_wx() -> usize {
+    let offset = physical_memory_offset();
+    if offset.as_u64() == 0 {
+        return 0;
+    }
+    let _guard = MAPPER.lock();
+    let all = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let fixed = unsafe { enforce_table(offset, active_level_4_table(offset), 4, all) };
+    x86_64::instructions::tlb::flush_all();
+    fixed
+}
+
+unsafe fn enforce_table(
+    offset: VirtAddr,
+    table: &mut PageTable,
+    level: u8,
+    parent: PageTableFlags,
+) -> usize {
+    let mut fixed = 0;
+    for entry in table.iter_mut() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let effective = effective_flags(parent, flags);
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            if effective.contains(PageTableFlags::WRITABLE)
+                && !effective.contains(PageTableFlags::NO_EXECUTE)
+            {
+                entry.set_flags(flags | PageTableFlags::NO_EXECUTE);
+                fixed += 1;
+            }
+        } else {
+            let child = &mut *(offset + entry.addr().as_u64()).as_mut_ptr::<PageTable>();
+            fixed += enforce_table(offset, child, level - 1, effective);
+        }
+    }
+    fixed
+}
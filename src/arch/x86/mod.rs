@@ -0,0 +1,112 @@
+pub mod apic;
+pub mod cpu;
+pub mod gdt;
+pub mod interrupts;
+pub mod memory;
+pub mod sleep;
+pub mod usercopy;
+
+use super::Arch;
+use bootloader::BootInfo;
+use core::arch::asm;
+use x86_64::instructions::interrupts as cpu_interrupts;
+
+pub struct X86;
+
+impl Arch for X86 {
+    fn init_cpu() {
+        gdt::init();
+        interrupts::init_idt();
+    }
+
+    fn init_interrupt_controller() {
+        unsafe { interrupts::PICS.lock().initialize() };
+    }
+
+    fn init_memory(boot_info: &'static BootInfo) {
+        memory::init_mapper(boot_info);
+        let fixed = memory::enforce_wx();
+        crate::klog!(
+            crate::log::Level::Info,
+            "W^X: made {} writable mappings non-executable",
+            fixed
+        );
+    }
+
+    fn map_range(start: u64, len: u64, writable: bool) -> Result<(), &'static str> {
+        memory::map_range(start, len, writable)
+    }
+
+    fn make_executable(start: u64, len: u64) -> Result<(), &'static str> {
+        memory::make_executable(start, len)
+    }
+
+    fn is_range_mapped(start: u64, len: u64, writable: bool) -> bool {
+        memory::is_range_mapped(start, len, writable)
+    }
+
+    fn allocate_dma(pages: usize) -> Result<u64, &'static str> {
+        memory::allocate_contiguous_frames(pages)
+    }
+
+    fn phys_to_virt(phys: u64) -> Option<u64> {
+        match memory::physical_memory_offset().as_u64() {
+            0 => None,
+            offset => offset.checked_add(phys),
+        }
+    }
+
+    fn enable_interrupts() {
+        cpu_interrupts::enable();
+    }
+
+    fn disable_interrupts() {
+        cpu_interrupts::disable();
+    }
+
+    fn interrupts_enabled() -> bool {
+        cpu_interrupts::are_enabled()
+    }
+
+    fn in_interrupt() -> bool {
+        interrupts::in_interrupt()
+    }
+
+    fn wait_for_interrupt() {
+        cpu_interrupts::enable_and_hlt();
+    }
+
+    fn halt() {
+        x86_64::instructions::hlt();
+    }
+
+    fn cycle_counter() -> u64 {
+        unsafe { core::arch::x86_64::_rdtsc() }
+    }
+
+    #[inline(always)]
+    fn frame_pointer() -> u64 {
+        let rbp: u64;
+        unsafe { asm!("mov {}, rbp", out(reg) rbp) };
+        rbp
+    }
+
+    #[inline(always)]
+    fn stack_pointer() -> u64 {
+        let rsp: u64;
+        unsafe { asm!("mov {}, rsp", out(reg) rsp) };
+        rsp
+    }
+
+    unsafe fn call_on_stack(stack: u64, entry: extern "C" fn() -> !) -> ! {
+        // call で戻り先が積まれたあと 16 バイト境界から 8 ずれた状態になるようにそろえる
+        asm!(
+            "mov rsp, {stack}",
+            "xor rbp, rbp",
+            "call {entry}",
+            stack = in(reg) stack & !0xf,
+            entry = in(reg) entry,
+            options(noreturn)
+        )
+    }
+}
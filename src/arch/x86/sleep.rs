@@ -0,0 +1,198 @@
+// S3 (suspend to RAM) の入口と戻り道
+//
+// 眠る前に callee-saved レジスタと rsp を退避し、ファームウェアには
+// 1 MiB 未満に置いたトランポリンを起床ベクタとして渡す。起きるとファームウェアは
+// リアルモードでトランポリンに飛ぶので、そこから保存してあった CR0/CR3/CR4/EFER を
+// 戻して直接ロングモードに入り、s3_resume で退避した rsp から s3_suspend の続きに戻る。
+
+use super::{gdt, interrupts, memory};
+use core::arch::{asm, global_asm};
+use x86_64::registers::control::{Cr0, Cr3, Cr4};
+use x86_64::registers::model_specific::Efer;
+
+global_asm!(
+    r#"
+    .pushsection .bss
+    .align 8
+s3_saved_rsp:
+    .quad 0
+    .popsection
+
+    .global s3_suspend
+s3_suspend:
+    push rbx
+    push rbp
+    push r12
+    push r13
+    push r14
+    push r15
+    sub rsp, 8
+    mov [rip + s3_saved_rsp], rsp
+    call rdi
+    // ここに戻ったら眠れなかった。rax は enter の戻り値のまま
+    add rsp, 8
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+    ret
+
+    .global s3_resume
+s3_resume:
+    mov rsp, [rip + s3_saved_rsp]
+    add rsp, 8
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+    xor eax, eax
+    ret
+
+    .global s3_trampoline_start
+    .global s3_trampoline_end
+    .global s3_gdt
+    .global s3_gdt_base
+    .global s3_long_target
+    .global s3_long
+    .global s3_cr0
+    .global s3_cr3
+    .global s3_cr4
+    .global s3_efer
+    .global s3_resume_target
+    .code16
+s3_trampoline_start:
+    cli
+    mov ax, cs
+    mov ds, ax
+    lgdt [S3_GDT_POINTER]
+    mov eax, [S3_CR4]
+    mov cr4, eax
+    mov eax, [S3_CR3]
+    mov cr3, eax
+    mov ecx, 0xc0000080
+    mov eax, [S3_EFER]
+    xor edx, edx
+    wrmsr
+    mov eax, [S3_CR0]
+    mov cr0, eax
+    // jmp far 0x08:s3_long (オフセットは物理アドレスに書き換える)
+    .byte 0x66, 0xea
+s3_long_target:
+    .long 0
+    .word 0x08
+
+    .code64
+s3_long:
+    xor eax, eax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov fs, ax
+    mov gs, ax
+    mov rax, [rip + s3_resume_target]
+    jmp rax
+
+    // アクセス済みビットを立てておく。トランポリンのページは読み出し専用なので、
+    // ページングを有効にした後に CPU がこのビットを書きに来ると落ちる
+    .align 8
+s3_gdt:
+    .quad 0
+    .quad 0x00af9b000000ffff
+    .quad 0x00cf93000000ffff
+s3_gdt_pointer:
+    .word 23
+s3_gdt_base:
+    .long 0
+    .align 4
+s3_cr0:
+    .long 0
+s3_cr3:
+    .long 0
+s3_cr4:
+    .long 0
+s3_efer:
+    .long 0
+    .align 8
+s3_resume_target:
+    .quad 0
+s3_trampoline_end:
+
+    // リアルモードでは DS = CS なので、先頭からのオフセットで参照する
+    .set S3_GDT_POINTER, s3_gdt_pointer - s3_trampoline_start
+    .set S3_CR0, s3_cr0 - s3_trampoline_start
+    .set S3_CR3, s3_cr3 - s3_trampoline_start
+    .set S3_CR4, s3_cr4 - s3_trampoline_start
+    .set S3_EFER, s3_efer - s3_trampoline_start
+"#
+);
+
+extern "C" {
+    fn s3_suspend(enter: extern "C" fn() -> u64) -> u64;
+    fn s3_resume();
+    static s3_trampoline_start: u8;
+    static s3_trampoline_end: u8;
+    static s3_gdt: u8;
+    static s3_gdt_base: u8;
+    static s3_long_target: u8;
+    static s3_long: u8;
+    static s3_cr0: u8;
+    static s3_cr3: u8;
+    static s3_cr4: u8;
+    static s3_efer: u8;
+    static s3_resume_target: u8;
+}
+
+fn offset_of(symbol: &u8) -> usize {
+    symbol as *const u8 as usize - unsafe { &s3_trampoline_start as *const u8 as usize }
+}
+
+// トランポリンを低位フレームに写し、今の CPU 状態を書き込む。戻り値は起床ベクタ
+pub fn prepare_trampoline() -> Result<u32, &'static str> {
+    let phys = memory::identity_map_low_frame()?;
+    let cr3 = Cr3::read().0.start_address().as_u64();
+    if cr3 > u32::MAX as u64 {
+        return Err("page table is above 4 GiB");
+    }
+
+    unsafe {
+        let start = &s3_trampoline_start as *const u8;
+        let len = &s3_trampoline_end as *const u8 as usize - start as usize;
+        // 恒等マップは実行用で読み出し専用なので、物理メモリのマップから書く
+        let target = (memory::physical_memory_offset() + phys).as_mut_ptr::<u8>();
+        core::ptr::copy_nonoverlapping(start, target, len);
+
+        let patch32 = |symbol: &u8, value: u64| {
+            (target.add(offset_of(symbol)) as *mut u32).write_unaligned(value as u32)
+        };
+        patch32(&s3_gdt_base, phys + offset_of(&s3_gdt) as u64);
+        patch32(&s3_long_target, phys + offset_of(&s3_long) as u64);
+        patch32(&s3_cr0, Cr0::read_raw());
+        patch32(&s3_cr3, cr3);
+        patch32(&s3_cr4, Cr4::read_raw());
+        // LMA は読み出し専用なので落としておく
+        patch32(&s3_efer, Efer::read_raw() & !(1 << 10));
+        (target.add(offset_of(&s3_resume_target)) as *mut u64)
+            .write_unaligned(s3_resume as *const () as u64);
+    }
+    Ok(phys as u32)
+}
+
+// 眠る直前にキャッシュを書き戻す。電源が落ちるとキャッシュの中身は消える
+pub fn flush_caches() {
+    unsafe { asm!("wbinvd", options(nostack, preserves_flags)) };
+}
+
+// enter が眠りに入れなかったときはその戻り値 (0 以外) を返す。
+// 起きてきたときはセグメントと IDT を読み直してから 0 を返す
+pub fn suspend(enter: extern "C" fn() -> u64) -> u64 {
+    let status = unsafe { s3_suspend(enter) };
+    if status == 0 {
+        gdt::reload();
+        interrupts::init_idt();
+    }
+    status
+}
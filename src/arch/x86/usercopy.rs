@@ -0,0 +1,97 @@
+// ユーザー空間のメモリを読み書きするための関数。SMEP と SMAP を有効にすると、
+// カーネルはユーザーのページを実行できず、stac から clac の間でしか読み書きできなくなる。
+// システムコールがユーザーのポインタを受け取るときは、直接触らずに必ずここを通す。
+// 今の呼び出し元は syscall の user_bytes と sys_read_at (ユーザープロセスはまだ無い)
+use super::{cpu, memory};
+use crate::klog;
+use crate::log::Level;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::structures::paging::PageTableFlags;
+
+// ユーザー空間は下半分 (正準アドレスの上限まで)
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+static SMAP: AtomicBool = AtomicBool::new(false);
+
+// ユーザーのページがあるとカーネルのコードまで止まるので、念のため確かめてから有効にする
+pub fn init() -> Result<(), &'static str> {
+    let user_mapped = memory::mapped_ranges()
+        .iter()
+        .any(|range| range.flags.contains(PageTableFlags::USER_ACCESSIBLE));
+    if user_mapped {
+        return Err("kernel pages are user-accessible");
+    }
+    let mut flags = Cr4Flags::empty();
+    if cpu::has_smep() {
+        flags |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+    }
+    if cpu::has_smap() {
+        flags |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+        SMAP.store(true, Ordering::Relaxed);
+    }
+    unsafe { Cr4::update(|cr4| cr4.insert(flags)) };
+    klog!(
+        Level::Info,
+        "SMEP {}, SMAP {}",
+        if cpu::has_smep() { "on" } else { "unsupported" },
+        if cpu::has_smap() { "on" } else { "unsupported" }
+    );
+    Ok(())
+}
+
+// [addr, addr + len) が全部ユーザー空間にあり、ユーザーのページとしてマップされているか
+fn check_range(addr: u64, len: usize, writable: bool) -> Result<(), &'static str> {
+    let end = addr
+        .checked_add(len as u64)
+        .ok_or("bad address: range overflows")?;
+    if end > USER_END {
+        return Err("bad address: not in user space");
+    }
+    let mut required = PageTableFlags::USER_ACCESSIBLE;
+    if writable {
+        required |= PageTableFlags::WRITABLE;
+    }
+    if !memory::range_has_flags(addr, len as u64, required) {
+        return Err("bad address: not mapped for the user");
+    }
+    Ok(())
+}
+
+// SMAP が有効なら、f の間だけユーザーのページに触れるようにする。
+// stac と clac は SMAP の無い CPU では #UD になるので使わない
+fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    let smap = SMAP.load(Ordering::Relaxed);
+    if smap {
+        unsafe { asm!("stac", options(nostack)) };
+    }
+    let result = f();
+    if smap {
+        unsafe { asm!("clac", options(nostack)) };
+    }
+    result
+}
+
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), &'static str> {
+    check_range(src, dst.len(), false)?;
+    // 長さ 0 ならポインタは何でもよい (0 でも) ので、触らずに終える
+    if dst.is_empty() {
+        return Ok(());
+    }
+    with_user_access(|| unsafe {
+        core::ptr::copy_nonoverlapping(src as *const u8, dst.as_mut_ptr(), dst.len())
+    });
+    Ok(())
+}
+
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), &'static str> {
+    check_range(dst, src.len(), true)?;
+    if src.is_empty() {
+        return Ok(());
+    }
+    with_user_access(|| unsafe {
+        core::ptr::copy_nonoverlapping(src.as_ptr(), dst as *mut u8, src.len())
+    });
+    Ok(())
+}
@@ -0,0 +1,85 @@
+use crate::allocator::{self, fixed_size_block::BLOCK_SIZES};
+use crate::println;
+use crate::rand::XorShift64;
+use alloc::alloc::{alloc, dealloc, Layout};
+use alloc::vec::Vec;
+
+const ITERATIONS: u64 = 1000;
+const CHURN_SLOTS: usize = 64;
+const CHURN_ROUNDS: usize = 2000;
+
+fn rdtsc() -> u64 {
+    crate::arch::cycle_counter()
+}
+
+pub fn run_all() {
+    println!("{:>8} {:>12} {:>12}", "size", "alloc(cyc)", "free(cyc)");
+    for &size in BLOCK_SIZES.iter().chain([4096usize].iter()) {
+        let (alloc_cycles, free_cycles) = bench_size_class(size);
+        println!("{:>8} {:>12} {:>12}", size, alloc_cycles, free_cycles);
+    }
+    println!();
+    bench_churn();
+}
+
+fn bench_size_class(size: usize) -> (u64, u64) {
+    let layout = Layout::from_size_align(size, 8).unwrap();
+    let mut alloc_total = 0;
+    let mut free_total = 0;
+
+    for _ in 0..ITERATIONS {
+        let start = rdtsc();
+        let ptr = unsafe { alloc(layout) };
+        let allocated = rdtsc();
+        if ptr.is_null() {
+            println!("bench: allocation of {} bytes failed", size);
+            return (0, 0);
+        }
+        unsafe { dealloc(ptr, layout) };
+        let freed = rdtsc();
+
+        alloc_total += allocated - start;
+        free_total += freed - allocated;
+    }
+
+    (alloc_total / ITERATIONS, free_total / ITERATIONS)
+}
+
+fn bench_churn() {
+    let mut rng = XorShift64::new(0);
+    let mut slots: Vec<Option<Vec<u8>>> = Vec::new();
+    slots.resize_with(CHURN_SLOTS, || None);
+
+    let before = allocator::stats();
+    let start = rdtsc();
+    for _ in 0..CHURN_ROUNDS {
+        let slot = rng.below(CHURN_SLOTS);
+        if slots[slot].is_some() {
+            slots[slot] = None;
+        } else {
+            let size = 1 + rng.below(1536);
+            slots[slot] = Some(Vec::with_capacity(size));
+        }
+    }
+    let elapsed = rdtsc() - start;
+    let during = allocator::stats();
+    drop(slots);
+    let after = allocator::stats();
+
+    println!(
+        "churn: {} rounds, {} cycles/op",
+        CHURN_ROUNDS,
+        elapsed / CHURN_ROUNDS as u64
+    );
+    println!(
+        "fallback heap used: {} -> {} -> {} bytes",
+        before.fallback_used, during.fallback_used, after.fallback_used
+    );
+    let cached: usize = after
+        .free_blocks
+        .iter()
+        .zip(BLOCK_SIZES.iter())
+        .map(|(count, size)| count * size)
+        .sum();
+    println!("bytes held in free lists after churn: {}", cached);
+}
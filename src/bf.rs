@@ -0,0 +1,205 @@
+// Brainfuck インタプリタ。ファイルシステムにあるプログラムを読んで、画面とキーボードで
+// 入出力する。画面に出すときはタスクとして少しずつ進め、入力を待つ間はキーを
+// シェルから handle_key に回してもらう (top やページャと同じ)。
+// 出力をキャプチャしているときやパイプの入力があるときは、その場で最後まで動かす
+use crate::task::{self, TaskState};
+use crate::{keyboard, print, println};
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use pc_keyboard::DecodedKey;
+use spin::Mutex;
+
+const TAPE_SIZE: usize = 30000;
+// タスクの 1 回で進める命令数
+const STEPS_PER_RUN: usize = 10_000;
+// その場で動かすときは止められないので、これだけ進めたら打ち切る
+const MAX_STEPS: u64 = 100_000_000;
+
+enum Status {
+    Running,
+    NeedInput,
+    Done,
+    Failed(&'static str),
+}
+
+struct Machine {
+    code: Vec<u8>,
+    // [ と ] の対応する位置
+    jumps: Vec<usize>,
+    pc: usize,
+    tape: Vec<u8>,
+    pointer: usize,
+    input: VecDeque<u8>,
+    // これ以上入力が来ない (パイプの入力を読み切った)。, は 0 を読む
+    end_of_input: bool,
+    steps: u64,
+}
+
+impl Machine {
+    fn new(source: &[u8]) -> Result<Machine, &'static str> {
+        let code: Vec<u8> = source
+            .iter()
+            .copied()
+            .filter(|byte| b"+-<>[].,".contains(byte))
+            .collect();
+        let mut jumps = vec![0; code.len()];
+        let mut open = Vec::new();
+        for (index, &op) in code.iter().enumerate() {
+            match op {
+                b'[' => open.push(index),
+                b']' => {
+                    let start = open.pop().ok_or("unmatched ']'")?;
+                    jumps[start] = index;
+                    jumps[index] = start;
+                }
+                _ => {}
+            }
+        }
+        if !open.is_empty() {
+            return Err("unmatched '['");
+        }
+        Ok(Machine {
+            code,
+            jumps,
+            pc: 0,
+            tape: vec![0; TAPE_SIZE],
+            pointer: 0,
+            input: VecDeque::new(),
+            end_of_input: false,
+            steps: 0,
+        })
+    }
+
+    // 最大 steps 命令を実行し、出力を output に足す
+    fn run(&mut self, steps: usize, output: &mut String) -> Status {
+        for _ in 0..steps {
+            let op = match self.code.get(self.pc) {
+                Some(&op) => op,
+                None => return Status::Done,
+            };
+            match op {
+                b'+' => self.tape[self.pointer] = self.tape[self.pointer].wrapping_add(1),
+                b'-' => self.tape[self.pointer] = self.tape[self.pointer].wrapping_sub(1),
+                b'>' if self.pointer + 1 < TAPE_SIZE => self.pointer += 1,
+                b'<' if self.pointer > 0 => self.pointer -= 1,
+                b'>' | b'<' => return Status::Failed("pointer moved off the tape"),
+                b'.' => output.push(self.tape[self.pointer] as char),
+                b',' => match self.input.pop_front() {
+                    Some(byte) => self.tape[self.pointer] = byte,
+                    None if self.end_of_input => self.tape[self.pointer] = 0,
+                    None => return Status::NeedInput,
+                },
+                b'[' if self.tape[self.pointer] == 0 => self.pc = self.jumps[self.pc],
+                b']' if self.tape[self.pointer] != 0 => self.pc = self.jumps[self.pc],
+                _ => {}
+            }
+            self.pc += 1;
+            self.steps += 1;
+        }
+        Status::Running
+    }
+}
+
+static RUNNING: Mutex<Option<Machine>> = Mutex::new(None);
+
+pub fn is_running() -> bool {
+    RUNNING.lock().is_some()
+}
+
+// input があればそれを入力にして、その場で最後まで動かす。終了ステータスを返す
+pub fn run_to_end(source: &[u8], input: Option<String>) -> i32 {
+    let mut machine = match Machine::new(source) {
+        Ok(machine) => machine,
+        Err(e) => {
+            println!("bf: {}", e);
+            return 2;
+        }
+    };
+    machine.input.extend(input.unwrap_or_default().bytes());
+    machine.end_of_input = true;
+
+    let mut output = String::new();
+    loop {
+        let status = machine.run(STEPS_PER_RUN, &mut output);
+        print!("{}", output);
+        output.clear();
+        match status {
+            Status::Done => return 0,
+            Status::Failed(e) => {
+                println!("\nbf: {}", e);
+                return 1;
+            }
+            _ if machine.steps >= MAX_STEPS => {
+                println!("\nbf: stopped after {} steps", MAX_STEPS);
+                return 1;
+            }
+            _ => {}
+        }
+    }
+}
+
+// タスクとして動かし始める。終わったらプロンプトを出し直す
+pub fn start(source: &[u8]) -> Result<(), &'static str> {
+    let machine = Machine::new(source)?;
+    {
+        let mut running = RUNNING.lock();
+        if running.is_some() {
+            return Err("another program is running");
+        }
+        *running = Some(machine);
+    }
+    task::spawn("bf", step);
+    Ok(())
+}
+
+fn step() -> TaskState {
+    let mut output = String::new();
+    let status = match RUNNING.lock().as_mut() {
+        Some(machine) => machine.run(STEPS_PER_RUN, &mut output),
+        // Ctrl+C で止められた
+        None => return TaskState::Done,
+    };
+    print!("{}", output);
+    match status {
+        Status::Running => TaskState::Running,
+        Status::NeedInput => TaskState::Idle,
+        Status::Done => finish(None),
+        Status::Failed(e) => finish(Some(e)),
+    }
+}
+
+fn finish(error: Option<&str>) -> TaskState {
+    *RUNNING.lock() = None;
+    match error {
+        Some(e) => println!("\nbf: {}", e),
+        None => println!(),
+    }
+    keyboard::start_shell();
+    TaskState::Done
+}
+
+// 実行中のキー入力。文字は画面に返しながら入力に足し、Ctrl+C で止める。
+// 止めたら true を返すので、シェルがプロンプトを出す
+pub fn handle_key(key: DecodedKey) -> bool {
+    let mut running = RUNNING.lock();
+    let machine = match running.as_mut() {
+        Some(machine) => machine,
+        None => return true,
+    };
+    match key {
+        DecodedKey::Unicode('\u{03}') => {
+            *running = None;
+            println!("^C");
+            return true;
+        }
+        DecodedKey::Unicode(c) if c == '\n' || !c.is_control() => {
+            print!("{}", c);
+            let mut bytes = [0; 4];
+            machine.input.extend(c.encode_utf8(&mut bytes).bytes());
+        }
+        _ => {}
+    }
+    false
+}
@@ -0,0 +1,179 @@
+use crate::device::{self, DeviceClass};
+use crate::filesystem;
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+pub const BLOCK_SIZE: usize = 512;
+
+pub trait BlockDevice: Send {
+    fn block_count(&self) -> u64;
+    fn read_block(&self, index: u64, buffer: &mut [u8]) -> Result<(), &'static str>;
+    fn write_block(&mut self, index: u64, buffer: &[u8]) -> Result<(), &'static str>;
+    // losetup などで表示する説明
+    fn describe(&self) -> String;
+}
+
+struct Entry {
+    name: String,
+    device_id: usize,
+    device: Box<dyn BlockDevice>,
+}
+
+static DEVICES: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+
+pub fn register(prefix: &str, device: Box<dyn BlockDevice>) -> String {
+    let mut devices = DEVICES.lock();
+    let index = (0..)
+        .find(|i| {
+            let name = format!("{}{}", prefix, i);
+            !devices.iter().any(|entry| entry.name == name)
+        })
+        .unwrap_or(0);
+    let name = format!("{}{}", prefix, index);
+    let device_id = device::register(&name, DeviceClass::Storage, device::find("virtual"), &[]);
+    devices.push(Entry {
+        name: name.clone(),
+        device_id,
+        device,
+    });
+    name
+}
+
+pub fn unregister(name: &str) -> Result<(), &'static str> {
+    let mut devices = DEVICES.lock();
+    let index = devices
+        .iter()
+        .position(|entry| entry.name == name)
+        .ok_or("No such block device")?;
+    device::unregister(devices[index].device_id)?;
+    devices.remove(index);
+    Ok(())
+}
+
+pub fn exists(name: &str) -> bool {
+    DEVICES.lock().iter().any(|entry| entry.name == name)
+}
+
+// "/dev/loop0" のようなパスならデバイス名を返す
+pub fn device_for_path(path: &str) -> Option<&str> {
+    path.strip_prefix("/dev/").filter(|name| exists(name))
+}
+
+pub fn list() -> Vec<(String, u64, String)> {
+    DEVICES
+        .lock()
+        .iter()
+        .map(|entry| {
+            (
+                entry.name.clone(),
+                entry.device.block_count(),
+                entry.device.describe(),
+            )
+        })
+        .collect()
+}
+
+// バイト単位の読み書き。ブロック境界にかからない部分は読み出してから書き戻す
+pub fn read_at(name: &str, offset: u64, len: usize) -> Result<Vec<u8>, &'static str> {
+    let devices = DEVICES.lock();
+    let entry = devices
+        .iter()
+        .find(|entry| entry.name == name)
+        .ok_or("No such block device")?;
+    let size = entry.device.block_count() * BLOCK_SIZE as u64;
+    let end = size.min(offset.saturating_add(len as u64));
+
+    let mut data = Vec::new();
+    let mut block = vec![0; BLOCK_SIZE];
+    let mut position = offset;
+    while position < end {
+        entry
+            .device
+            .read_block(position / BLOCK_SIZE as u64, &mut block)?;
+        let start = (position % BLOCK_SIZE as u64) as usize;
+        let take = (BLOCK_SIZE - start).min((end - position) as usize);
+        data.extend_from_slice(&block[start..start + take]);
+        position += take as u64;
+    }
+    Ok(data)
+}
+
+pub fn write_at(name: &str, offset: u64, data: &[u8]) -> Result<(), &'static str> {
+    let mut devices = DEVICES.lock();
+    let entry = devices
+        .iter_mut()
+        .find(|entry| entry.name == name)
+        .ok_or("No such block device")?;
+    let size = entry.device.block_count() * BLOCK_SIZE as u64;
+    if offset.saturating_add(data.len() as u64) > size {
+        return Err("No space left on device");
+    }
+
+    let mut block = vec![0; BLOCK_SIZE];
+    let mut written = 0;
+    while written < data.len() {
+        let position = offset + written as u64;
+        let index = position / BLOCK_SIZE as u64;
+        let start = (position % BLOCK_SIZE as u64) as usize;
+        let take = (BLOCK_SIZE - start).min(data.len() - written);
+        if take < BLOCK_SIZE {
+            entry.device.read_block(index, &mut block)?;
+        }
+        block[start..start + take].copy_from_slice(&data[written..written + take]);
+        entry.device.write_block(index, &block)?;
+        written += take;
+    }
+    Ok(())
+}
+
+// RAM ファイルシステム上のファイルをそのままディスクイメージとして見せる
+struct LoopDevice {
+    backing: String,
+    blocks: u64,
+}
+
+impl BlockDevice for LoopDevice {
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_block(&self, index: u64, buffer: &mut [u8]) -> Result<(), &'static str> {
+        if index >= self.blocks {
+            return Err("Block out of range");
+        }
+        let data = filesystem::read_file_at(&self.backing, index * BLOCK_SIZE as u64, BLOCK_SIZE)?;
+        buffer[..data.len()].copy_from_slice(&data);
+        buffer[data.len()..].fill(0);
+        Ok(())
+    }
+
+    fn write_block(&mut self, index: u64, buffer: &[u8]) -> Result<(), &'static str> {
+        if index >= self.blocks {
+            return Err("Block out of range");
+        }
+        filesystem::write_file_at(&self.backing, index * BLOCK_SIZE as u64, buffer)
+    }
+
+    fn describe(&self) -> String {
+        self.backing.clone()
+    }
+}
+
+pub fn attach_loop(path: &str) -> Result<String, &'static str> {
+    let (size, _) = filesystem::file_size(path)?;
+    let blocks = size / BLOCK_SIZE as u64;
+    if blocks == 0 {
+        return Err("File is smaller than one block");
+    }
+    Ok(register(
+        "loop",
+        Box::new(LoopDevice {
+            backing: String::from(path),
+            blocks,
+        }),
+    ))
+}
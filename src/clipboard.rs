@@ -0,0 +1,19 @@
+// カーネル全体で共有するテキストのクリップボード
+use alloc::string::String;
+use spin::Mutex;
+
+static CLIPBOARD: Mutex<String> = Mutex::new(String::new());
+
+pub fn set(text: &str) {
+    let mut clipboard = CLIPBOARD.lock();
+    clipboard.clear();
+    clipboard.push_str(text);
+}
+
+pub fn get() -> String {
+    CLIPBOARD.lock().clone()
+}
+
+pub fn clear() {
+    CLIPBOARD.lock().clear();
+}
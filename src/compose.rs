@@ -0,0 +1,117 @@
+// デッドキーと Compose キー。続けて打った 2 つの文字を 1 つのアクセント付き文字などにまとめる。
+// デッドキーはレイアウトが us-intl のときだけ ' ` ^ ~ " が受け持ち、次の文字と合わさる
+// (空白なら記号そのもの)。Compose は右 Alt で、続く 2 文字を SEQUENCES か ACCENTS で引く。
+// できた文字は vga_buffer::to_cp437 で画面のフォントに直される
+use alloc::vec;
+use alloc::vec::Vec;
+use pc_keyboard::{DecodedKey, KeyCode};
+
+pub const COMPOSE_KEY: KeyCode = KeyCode::AltRight;
+
+const DEAD_KEYS: &str = "'`^~\"";
+
+// (アクセント, 元の文字, 合わせた文字)
+const ACCENTS: &[(char, &str, &str)] = &[
+    ('\'', "aeiouyAEIOUYcC", "áéíóúýÁÉÍÓÚÝçÇ"),
+    ('`', "aeiouAEIOU", "àèìòùÀÈÌÒÙ"),
+    ('^', "aeiouAEIOU", "âêîôûÂÊÎÔÛ"),
+    ('~', "anoANO", "ãñõÃÑÕ"),
+    ('"', "aeiouyAEIOU", "äëïöüÿÄËÏÖÜ"),
+    (',', "cC", "çÇ"),
+];
+
+// Compose で使う、アクセント以外の組み合わせ。逆順でもよい
+const SEQUENCES: &[(&str, char)] = &[
+    ("ae", 'æ'),
+    ("AE", 'Æ'),
+    ("ss", 'ß'),
+    ("o/", 'ø'),
+    ("O/", 'Ø'),
+    ("oa", 'å'),
+    ("OA", 'Å'),
+    ("!!", '¡'),
+    ("??", '¿'),
+    ("<<", '«'),
+    (">>", '»'),
+    ("+-", '±'),
+    ("12", '½'),
+    ("14", '¼'),
+    ("oo", '°'),
+    ("c/", '¢'),
+    ("l-", '£'),
+    ("y=", '¥'),
+    ("2^", '²'),
+];
+
+fn accent(accent: char, base: char) -> Option<char> {
+    let (_, bases, composed) = ACCENTS.iter().find(|(c, _, _)| *c == accent)?;
+    let index = bases.chars().position(|c| c == base)?;
+    composed.chars().nth(index)
+}
+
+fn sequence(first: char, second: char) -> Option<char> {
+    SEQUENCES.iter().find_map(|(pair, result)| {
+        let mut chars = pair.chars();
+        let (a, b) = (chars.next()?, chars.next()?);
+        if (a, b) == (first, second) || (b, a) == (first, second) {
+            Some(*result)
+        } else {
+            None
+        }
+    })
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Idle,
+    Dead(char),
+    Compose,
+    ComposeFirst(char),
+}
+
+pub struct Composer {
+    state: State,
+}
+
+impl Composer {
+    pub const fn new() -> Composer {
+        Composer { state: State::Idle }
+    }
+
+    // デコードしたキーを 1 つ受け取り、シェルに渡すキーを返す (合成の途中なら空)
+    pub fn feed(&mut self, key: DecodedKey, dead_keys: bool) -> Vec<DecodedKey> {
+        let state = core::mem::replace(&mut self.state, State::Idle);
+        let c = match key {
+            DecodedKey::RawKey(code) if code == COMPOSE_KEY => {
+                self.state = State::Compose;
+                return Vec::new();
+            }
+            DecodedKey::Unicode(c) if !c.is_control() => c,
+            // 制御文字や特殊キーは合成を取りやめてそのまま渡す
+            _ => return vec![key],
+        };
+
+        match state {
+            State::Idle if dead_keys && DEAD_KEYS.contains(c) => {
+                self.state = State::Dead(c);
+                Vec::new()
+            }
+            State::Idle => vec![key],
+            State::Dead(dead) if c == ' ' || c == dead => vec![DecodedKey::Unicode(dead)],
+            State::Dead(dead) => match accent(dead, c) {
+                Some(composed) => vec![DecodedKey::Unicode(composed)],
+                None => vec![DecodedKey::Unicode(dead), key],
+            },
+            State::Compose => {
+                self.state = State::ComposeFirst(c);
+                Vec::new()
+            }
+            // 知らない組み合わせは何も出さない
+            State::ComposeFirst(first) => accent(first, c)
+                .or_else(|| accent(c, first))
+                .or_else(|| sequence(first, c))
+                .map(|composed| vec![DecodedKey::Unicode(composed)])
+                .unwrap_or_default(),
+        }
+    }
+}
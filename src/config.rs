@@ -0,0 +1,176 @@
+use crate::log::{self, Level};
+use crate::vga_buffer::{self, Console};
+use crate::{filesystem, keyboard, klog};
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+pub const CONFIG_PATH: &str = "/etc/system.conf";
+
+const DEFAULT_CONFIG: &str = "\
+timezone=9
+keyboard=us
+console=vga
+prompt=\"$ \"
+loglevel=info
+screensaver=5
+clock=on
+histsize=100
+linemax=1024
+autopage=on
+";
+
+// 0 や極端な値にすると入力できなくなったり履歴でヒープを使い切ったりする
+const MAX_HISTORY_SIZE: usize = 1000;
+const LINE_MAX_RANGE: core::ops::RangeInclusive<usize> = 64..=8192;
+
+static CONFIG: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+pub fn load() {
+    let text = match filesystem::read_file(CONFIG_PATH) {
+        Ok(content) => String::from_utf8_lossy(&content).into_owned(),
+        Err(_) => {
+            let _ = filesystem::create_directory("/etc", true);
+            if let Err(e) = filesystem::write_file(CONFIG_PATH, DEFAULT_CONFIG.as_bytes(), false) {
+                klog!(Level::Warn, "config: cannot write {}: {}", CONFIG_PATH, e);
+            }
+            String::from(DEFAULT_CONFIG)
+        }
+    };
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_line(line) {
+            Some((key, value)) => {
+                // 反映できなかった値は覚えない。シェルなどが後から get で読むため
+                match apply(&key, &value) {
+                    Ok(()) => {
+                        CONFIG.lock().insert(key, value);
+                    }
+                    Err(e) => klog!(Level::Warn, "config: line {}: {}: {}", number + 1, key, e),
+                }
+            }
+            None => klog!(
+                Level::Warn,
+                "config: line {}: expected key=value",
+                number + 1
+            ),
+        }
+    }
+
+    klog!(Level::Info, "Configuration loaded from {}", CONFIG_PATH);
+}
+
+fn parse_line(line: &str) -> Option<(String, String)> {
+    let (key, value) = line.split_once('=')?;
+    let key = key.trim();
+    let value = value.trim();
+    let value = value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value);
+    if key.is_empty() {
+        return None;
+    }
+    Some((String::from(key), String::from(value)))
+}
+
+// シェル以外の設定はここで反映する
+fn apply(key: &str, value: &str) -> Result<(), &'static str> {
+    match key {
+        "timezone" => {
+            let offset = value
+                .parse::<i8>()
+                .ok()
+                .filter(|offset| (-12..=14).contains(offset))
+                .ok_or("timezone must be an hour offset between -12 and 14")?;
+            crate::time::set_utc_offset(offset);
+            Ok(())
+        }
+        "keyboard" => keyboard::set_layout(value),
+        "console" => {
+            let console = Console::from_name(value).ok_or("console must be vga, serial or both")?;
+            vga_buffer::set_console(console);
+            Ok(())
+        }
+        "loglevel" => {
+            let level = Level::from_name(value).ok_or("unknown log level")?;
+            log::set_level(level);
+            Ok(())
+        }
+        #[cfg(feature = "net")]
+        "ipaddr" => crate::net::set_address(value),
+        #[cfg(feature = "net")]
+        "gateway" => crate::net::set_gateway(value),
+        "prompt" => Ok(()),
+        "histsize" => {
+            value
+                .parse::<usize>()
+                .ok()
+                .filter(|size| *size <= MAX_HISTORY_SIZE)
+                .ok_or("histsize must be a number of commands from 0 (no history) to 1000")?;
+            Ok(())
+        }
+        "autopage" => match value {
+            "on" | "off" => Ok(()),
+            _ => Err("autopage must be on or off"),
+        },
+        "linemax" => {
+            value
+                .parse::<usize>()
+                .ok()
+                .filter(|max| LINE_MAX_RANGE.contains(max))
+                .ok_or("linemax must be a number of bytes from 64 to 8192")?;
+            Ok(())
+        }
+        "screensaver" => value
+            .parse::<u64>()
+            .ok()
+            .and_then(|minutes| crate::screensaver::set_timeout_minutes(minutes).ok())
+            .ok_or("screensaver must be a number of minutes (0 disables it)"),
+        "clock" => {
+            match value {
+                "on" => crate::time::start_clock(),
+                "off" => crate::time::stop_clock(),
+                _ => return Err("clock must be on or off"),
+            }
+            Ok(())
+        }
+        _ => Err("unknown key"),
+    }
+}
+
+pub fn get(key: &str) -> Option<String> {
+    CONFIG.lock().get(key).cloned()
+}
+
+pub fn entries() -> Vec<(String, String)> {
+    CONFIG
+        .lock()
+        .iter()
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+pub fn set(key: &str, value: &str) -> Result<(), &'static str> {
+    apply(key, value)?;
+    CONFIG.lock().insert(String::from(key), String::from(value));
+    save()
+}
+
+fn save() -> Result<(), &'static str> {
+    let mut text = String::new();
+    for (key, value) in CONFIG.lock().iter() {
+        if value.starts_with(' ') || value.ends_with(' ') {
+            text.push_str(&format!("{}=\"{}\"\n", key, value));
+        } else {
+            text.push_str(&format!("{}={}\n", key, value));
+        }
+    }
+    filesystem::write_file(CONFIG_PATH, text.as_bytes(), false)
+}
@@ -0,0 +1,142 @@
+use crate::println;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceClass {
+    Bus,
+    InterruptController,
+    Timer,
+    Clock,
+    Input,
+    Display,
+    Serial,
+    Storage,
+    Network,
+    Other,
+}
+
+impl DeviceClass {
+    pub fn name(self) -> &'static str {
+        match self {
+            DeviceClass::Bus => "bus",
+            DeviceClass::InterruptController => "irq-controller",
+            DeviceClass::Timer => "timer",
+            DeviceClass::Clock => "clock",
+            DeviceClass::Input => "input",
+            DeviceClass::Display => "display",
+            DeviceClass::Serial => "serial",
+            DeviceClass::Storage => "storage",
+            DeviceClass::Network => "network",
+            DeviceClass::Other => "other",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    IoPorts { start: u16, len: u16 },
+    Irq(u8),
+    Memory { start: u64, len: u64 },
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Resource::IoPorts { start, len } if len <= 1 => write!(f, "io {:#x}", start),
+            Resource::IoPorts { start, len } => {
+                write!(f, "io {:#x}-{:#x}", start, start + len - 1)
+            }
+            Resource::Irq(irq) => write!(f, "irq {}", irq),
+            Resource::Memory { start, len } => {
+                write!(f, "mem {:#x}-{:#x}", start, start + len - 1)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Device {
+    pub id: usize,
+    pub name: String,
+    pub class: DeviceClass,
+    pub parent: Option<usize>,
+    pub resources: Vec<Resource>,
+}
+
+static DEVICES: Mutex<Vec<Device>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+pub fn register(
+    name: &str,
+    class: DeviceClass,
+    parent: Option<usize>,
+    resources: &[Resource],
+) -> usize {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    DEVICES.lock().push(Device {
+        id,
+        name: String::from(name),
+        class,
+        parent,
+        resources: resources.to_vec(),
+    });
+    id
+}
+
+// 子を持つデバイスは外せない
+pub fn unregister(id: usize) -> Result<(), &'static str> {
+    let mut devices = DEVICES.lock();
+    if devices.iter().any(|device| device.parent == Some(id)) {
+        return Err("device has children");
+    }
+    devices.retain(|device| device.id != id);
+    Ok(())
+}
+
+pub fn find(name: &str) -> Option<usize> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|device| device.name == name)
+        .map(|device| device.id)
+}
+
+pub fn list() -> Vec<Device> {
+    DEVICES.lock().clone()
+}
+
+pub fn print_tree() {
+    let devices = list();
+    for device in devices.iter().filter(|device| device.parent.is_none()) {
+        print_subtree(&devices, device, 0);
+    }
+}
+
+fn print_subtree(devices: &[Device], device: &Device, depth: usize) {
+    let mut resources = String::new();
+    for resource in device.resources.iter() {
+        if !resources.is_empty() {
+            resources.push_str(", ");
+        }
+        resources.push_str(&alloc::format!("{}", resource));
+    }
+
+    println!(
+        "{:indent$}{} [{}] {}",
+        "",
+        device.name,
+        device.class.name(),
+        resources,
+        indent = depth * 2
+    );
+    for child in devices
+        .iter()
+        .filter(|child| child.parent == Some(device.id))
+    {
+        print_subtree(devices, child, depth + 1);
+    }
+}
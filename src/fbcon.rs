@@ -0,0 +1,559 @@
+// フレームバッファのコンソール (graphics 機能)。VGA を 640x480 16 色のモード 12h に切り替えて、
+// 8x16 の文字を 80 桁 30 行で描く。半角の文字はテキストモードのフォント (VGA のプレーン 2 に
+// 載っているもの) をそのまま使い、かなや漢字などの全角の文字は 2 桁ぶんの 16x16 で描く。
+// 全角のグリフはカーネルの中の表に持ち、GNU Unifont の .hex 形式のファイルから読み込む。
+// 表に無い全角の文字は四角で出す。エスケープシーケンスと色は vga_buffer の Writer が解釈して、
+// 文字を 1 つずつここに渡す
+use crate::vga_buffer::{VGA_PORTS, WRITER};
+use crate::{arch, filesystem, fwcfg};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+pub const COLUMNS: usize = 80;
+pub const ROWS: usize = 30;
+const CELL_HEIGHT: usize = 16;
+// 1 ラインのバイト数 (1 バイトで 8 ドット)
+const LINE_BYTES: usize = COLUMNS;
+const FRAMEBUFFER: u64 = 0xa0000;
+// 黒地に黄色。テキストモードの既定と同じ
+const DEFAULT_COLOR: u8 = 0x0e;
+
+// VGA_PORTS (0x3c0 から) の中の位置
+const ATTRIBUTE_INDEX: u16 = 0x00;
+const ATTRIBUTE_READ: u16 = 0x01;
+const MISC_WRITE: u16 = 0x02;
+const SEQUENCER_INDEX: u16 = 0x04;
+const SEQUENCER_DATA: u16 = 0x05;
+const MISC_READ: u16 = 0x0c;
+const GRAPHICS_INDEX: u16 = 0x0e;
+const GRAPHICS_DATA: u16 = 0x0f;
+const CRTC_INDEX: u16 = 0x14;
+const CRTC_DATA: u16 = 0x15;
+const INPUT_STATUS: u16 = 0x1a;
+
+const MAP_MASK: u8 = 0x02;
+const MEMORY_MODE: u8 = 0x04;
+const READ_MAP: u8 = 0x04;
+const GRAPHICS_MODE: u8 = 0x05;
+const GRAPHICS_MISC: u8 = 0x06;
+
+#[derive(Clone, Copy)]
+struct Mode {
+    misc: u8,
+    sequencer: [u8; 5],
+    crtc: [u8; 25],
+    graphics: [u8; 9],
+    attribute: [u8; 21],
+}
+
+// 640x480 16 色。パレットはテキストモードと同じ色になる
+const MODE_12H: Mode = Mode {
+    misc: 0xe3,
+    sequencer: [0x03, 0x01, 0x08, 0x00, 0x06],
+    crtc: [
+        0x5f, 0x4f, 0x50, 0x82, 0x54, 0x80, 0x0b, 0x3e, 0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0xea, 0x0c, 0xdf, 0x28, 0x00, 0xe7, 0x04, 0xe3, 0xff,
+    ],
+    graphics: [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0x0f, 0xff],
+    attribute: [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x14, 0x07, 0x38, 0x39, 0x3a, 0x3b, 0x3c, 0x3d, 0x3e,
+        0x3f, 0x01, 0x00, 0x0f, 0x00, 0x00,
+    ],
+};
+
+fn read_indexed(index_port: u16, data_port: u16, index: u8) -> u8 {
+    VGA_PORTS.write(index_port, index);
+    VGA_PORTS.read(data_port)
+}
+
+fn write_indexed(index_port: u16, data_port: u16, index: u8, value: u8) {
+    VGA_PORTS.write(index_port, index);
+    VGA_PORTS.write(data_port, value);
+}
+
+// 今のモードのレジスタを読み出す。戻るときに使う
+fn save_mode() -> Mode {
+    let mut mode = Mode {
+        misc: VGA_PORTS.read(MISC_READ),
+        ..MODE_12H
+    };
+    for (i, value) in mode.sequencer.iter_mut().enumerate() {
+        *value = read_indexed(SEQUENCER_INDEX, SEQUENCER_DATA, i as u8);
+    }
+    for (i, value) in mode.crtc.iter_mut().enumerate() {
+        *value = read_indexed(CRTC_INDEX, CRTC_DATA, i as u8);
+    }
+    for (i, value) in mode.graphics.iter_mut().enumerate() {
+        *value = read_indexed(GRAPHICS_INDEX, GRAPHICS_DATA, i as u8);
+    }
+    for (i, value) in mode.attribute.iter_mut().enumerate() {
+        // 入力ステータスを読むとアトリビュートのポートがインデックス待ちに戻る
+        let _: u8 = VGA_PORTS.read(INPUT_STATUS);
+        VGA_PORTS.write(ATTRIBUTE_INDEX, i as u8);
+        *value = VGA_PORTS.read(ATTRIBUTE_READ);
+    }
+    let _: u8 = VGA_PORTS.read(INPUT_STATUS);
+    VGA_PORTS.write(ATTRIBUTE_INDEX, 0x20u8);
+    mode
+}
+
+fn set_mode(mode: &Mode) {
+    VGA_PORTS.write(MISC_WRITE, mode.misc);
+    for (i, &value) in mode.sequencer.iter().enumerate() {
+        write_indexed(SEQUENCER_INDEX, SEQUENCER_DATA, i as u8, value);
+    }
+    // CRTC の 0 から 7 の書き込み禁止を外してから書く
+    let mut crtc = mode.crtc;
+    crtc[0x03] |= 0x80;
+    crtc[0x11] &= !0x80;
+    write_indexed(CRTC_INDEX, CRTC_DATA, 0x03, crtc[0x03]);
+    write_indexed(CRTC_INDEX, CRTC_DATA, 0x11, crtc[0x11]);
+    for (i, &value) in crtc.iter().enumerate() {
+        write_indexed(CRTC_INDEX, CRTC_DATA, i as u8, value);
+    }
+    for (i, &value) in mode.graphics.iter().enumerate() {
+        write_indexed(GRAPHICS_INDEX, GRAPHICS_DATA, i as u8, value);
+    }
+    for (i, &value) in mode.attribute.iter().enumerate() {
+        let _: u8 = VGA_PORTS.read(INPUT_STATUS);
+        VGA_PORTS.write(ATTRIBUTE_INDEX, i as u8);
+        VGA_PORTS.write(ATTRIBUTE_INDEX, value);
+    }
+    // 最後にパレットを使うように戻すと画面が出る
+    let _: u8 = VGA_PORTS.read(INPUT_STATUS);
+    VGA_PORTS.write(ATTRIBUTE_INDEX, 0x20u8);
+}
+
+fn framebuffer() -> Option<*mut u8> {
+    arch::phys_to_virt(FRAMEBUFFER).map(|virt| virt as *mut u8)
+}
+
+// 全角のグリフの表。ヒープは小さいので静的に持ち、読み込んだら文字コード順に並べておく
+const WIDE_GLYPHS: usize = 3072;
+type WideGlyph = [u8; 2 * CELL_HEIGHT];
+
+struct Glyphs {
+    len: usize,
+    entries: [(u32, WideGlyph); WIDE_GLYPHS],
+}
+
+impl Glyphs {
+    fn find(&self, c: char) -> Option<&WideGlyph> {
+        let entries = &self.entries[..self.len];
+        entries
+            .binary_search_by_key(&(c as u32), |(code, _)| *code)
+            .ok()
+            .map(|index| &entries[index].1)
+    }
+}
+
+static GLYPHS: Mutex<Glyphs> = Mutex::new(Glyphs {
+    len: 0,
+    entries: [(0, [0; 2 * CELL_HEIGHT]); WIDE_GLYPHS],
+});
+
+// 2 桁ぶんの幅で描く文字 (かな、漢字、全角の記号や英数字)
+pub fn is_wide(c: char) -> bool {
+    matches!(
+        c as u32,
+        0x3000..=0x30ff | 0x3400..=0x4dbf | 0x4e00..=0x9fff | 0xf900..=0xfaff | 0xff01..=0xff60
+            | 0xffe0..=0xffe6
+    )
+}
+
+// .hex の 1 行 ("4E00:" に続けて 16 進 64 桁) を読む。半角 (32 桁) のグリフは使わない
+fn parse_hex_line(line: &[u8]) -> Option<(u32, WideGlyph)> {
+    let line = core::str::from_utf8(line).ok()?.trim();
+    let (code, bitmap) = line.split_once(':')?;
+    let code = u32::from_str_radix(code, 16).ok()?;
+    if bitmap.len() != 4 * CELL_HEIGHT || !is_wide(char::from_u32(code)?) {
+        return None;
+    }
+    let mut glyph = [0; 2 * CELL_HEIGHT];
+    for (i, byte) in glyph.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(bitmap.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some((code, glyph))
+}
+
+// ファイルを少しずつ受け取って行に分け、表に入れていく
+struct Loader {
+    line: [u8; 80],
+    len: usize,
+    // 長すぎる行の残りを読み捨てている
+    overflow: bool,
+    started: bool,
+    dropped: usize,
+}
+
+impl Loader {
+    fn new() -> Loader {
+        Loader {
+            line: [0; 80],
+            len: 0,
+            overflow: false,
+            started: false,
+            dropped: 0,
+        }
+    }
+
+    fn feed(&mut self, chunk: &[u8]) {
+        let mut glyphs = GLYPHS.lock();
+        if !self.started {
+            glyphs.len = 0;
+            self.started = true;
+        }
+        for &byte in chunk {
+            if byte != b'\n' {
+                match self.line.get_mut(self.len) {
+                    Some(slot) => {
+                        *slot = byte;
+                        self.len += 1;
+                    }
+                    None => self.overflow = true,
+                }
+                continue;
+            }
+            let parsed = if self.overflow {
+                None
+            } else {
+                parse_hex_line(&self.line[..self.len])
+            };
+            if let Some(entry) = parsed {
+                if glyphs.len < WIDE_GLYPHS {
+                    let index = glyphs.len;
+                    glyphs.entries[index] = entry;
+                    glyphs.len += 1;
+                } else {
+                    self.dropped += 1;
+                }
+            }
+            self.len = 0;
+            self.overflow = false;
+        }
+    }
+
+    // (読み込んだ数, 表に入り切らなかった数)
+    fn finish(mut self) -> (usize, usize) {
+        self.feed(b"\n");
+        let mut glyphs = GLYPHS.lock();
+        let len = glyphs.len;
+        glyphs.entries[..len].sort_unstable_by_key(|(code, _)| *code);
+        (len, self.dropped)
+    }
+}
+
+// 全角のグリフを読み込み直す。/host のファイルはヒープを通さずにデバイスから直接読む
+pub fn load_font(path: &str) -> Result<(usize, usize), &'static str> {
+    let mut loader = Loader::new();
+    if !fwcfg::read_with(path, |chunk| loader.feed(chunk)) {
+        let content = filesystem::read_file(path)?;
+        loader.feed(&content);
+    }
+    Ok(loader.finish())
+}
+
+pub fn glyph_count() -> usize {
+    GLYPHS.lock().len
+}
+
+fn wide_half(c: char, half: usize) -> [u8; CELL_HEIGHT] {
+    let glyphs = GLYPHS.lock();
+    let mut rows = [0; CELL_HEIGHT];
+    match glyphs.find(c) {
+        Some(glyph) => {
+            for (y, row) in rows.iter_mut().enumerate() {
+                *row = glyph[2 * y + half];
+            }
+        }
+        // 四角を描く
+        None => {
+            let side = if half == 0 { 0x40 } else { 0x02 };
+            let edge = if half == 0 { 0x7f } else { 0xfe };
+            for row in rows[2..CELL_HEIGHT - 2].iter_mut() {
+                *row = side;
+            }
+            rows[1] = edge;
+            rows[CELL_HEIGHT - 2] = edge;
+        }
+    }
+    rows
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Glyph {
+    // コードページ 437 の文字
+    Narrow(u8),
+    // 全角の文字の左半分。右のセルは Continuation になる
+    Wide(char),
+    Continuation,
+}
+
+#[derive(Clone, Copy)]
+struct Cell {
+    glyph: Glyph,
+    // 上位 4 ビットが背景、下位 4 ビットが前景 (テキストモードと同じ並び)
+    color: u8,
+}
+
+const BLANK_GLYPH: Glyph = Glyph::Narrow(b' ');
+
+struct Console {
+    cells: [[Cell; COLUMNS]; ROWS],
+    row: usize,
+    column: usize,
+    // テキストモードのフォント。プレーン 2 は画面に使うので切り替える前に写しておく
+    font: [[u8; CELL_HEIGHT]; 256],
+    text_mode: Option<Mode>,
+}
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static CONSOLE: Mutex<Console> = Mutex::new(Console {
+    cells: [[Cell {
+        glyph: BLANK_GLYPH,
+        color: 0,
+    }; COLUMNS]; ROWS],
+    row: 0,
+    column: 0,
+    font: [[0; CELL_HEIGHT]; 256],
+    text_mode: None,
+});
+
+impl Console {
+    fn bitmap(&self, row: usize, column: usize) -> [u8; CELL_HEIGHT] {
+        match self.cells[row][column].glyph {
+            Glyph::Narrow(byte) => self.font[byte as usize],
+            Glyph::Wide(c) => wide_half(c, 0),
+            Glyph::Continuation => match column.checked_sub(1).map(|left| self.cells[row][left]) {
+                Some(Cell {
+                    glyph: Glyph::Wide(c),
+                    ..
+                }) => wide_half(c, 1),
+                _ => [0; CELL_HEIGHT],
+            },
+        }
+    }
+
+    // プレーンごとに、前景か背景の色にそのビットが立っていればドットを置く
+    fn draw_cell(&self, row: usize, column: usize, inverted: bool) {
+        let framebuffer = match framebuffer() {
+            Some(framebuffer) => framebuffer,
+            None => return,
+        };
+        let bitmap = self.bitmap(row, column);
+        let color = self.cells[row][column].color;
+        let color = if inverted {
+            color.rotate_left(4)
+        } else {
+            color
+        };
+        let (foreground, background) = (color & 0x0f, color >> 4);
+        for plane in 0..4 {
+            write_indexed(SEQUENCER_INDEX, SEQUENCER_DATA, MAP_MASK, 1 << plane);
+            let foreground = foreground >> plane & 1 == 1;
+            let background = background >> plane & 1 == 1;
+            for (y, &bits) in bitmap.iter().enumerate() {
+                let byte = match (foreground, background) {
+                    (true, true) => 0xff,
+                    (true, false) => bits,
+                    (false, true) => !bits,
+                    (false, false) => 0,
+                };
+                let offset = (row * CELL_HEIGHT + y) * LINE_BYTES + column;
+                unsafe { core::ptr::write_volatile(framebuffer.add(offset), byte) };
+            }
+        }
+        write_indexed(SEQUENCER_INDEX, SEQUENCER_DATA, MAP_MASK, 0x0f);
+    }
+
+    fn draw_cursor(&self, visible: bool) {
+        if self.column < COLUMNS {
+            self.draw_cell(self.row, self.column, visible);
+        }
+    }
+
+    fn redraw(&self) {
+        for row in 0..ROWS {
+            for column in 0..COLUMNS {
+                self.draw_cell(row, column, false);
+            }
+        }
+        self.draw_cursor(true);
+    }
+
+    fn clear(&mut self, color: u8) {
+        let blank = Cell {
+            glyph: BLANK_GLYPH,
+            color,
+        };
+        self.cells = [[blank; COLUMNS]; ROWS];
+        self.row = 0;
+        self.column = 0;
+        self.redraw();
+    }
+
+    fn new_line(&mut self, color: u8) {
+        if self.row >= ROWS - 1 {
+            self.cells.copy_within(1.., 0);
+            self.cells[ROWS - 1] = [Cell {
+                glyph: BLANK_GLYPH,
+                color,
+            }; COLUMNS];
+            self.column = 0;
+            self.redraw();
+        } else {
+            self.row += 1;
+            self.column = 0;
+        }
+    }
+
+    // 全角の文字の片側だけを上書きするときは、もう片側を空白にする
+    fn vacate(&mut self, column: usize) {
+        let row = self.row;
+        let partner = match self.cells[row][column].glyph {
+            Glyph::Wide(_) => column + 1,
+            Glyph::Continuation => column.wrapping_sub(1),
+            Glyph::Narrow(_) => return,
+        };
+        if let Some(cell) = self.cells[row].get_mut(partner) {
+            cell.glyph = BLANK_GLYPH;
+            self.draw_cell(row, partner, false);
+        }
+    }
+
+    fn write_char(&mut self, c: char, color: u8) {
+        match c {
+            '\n' => self.new_line(color),
+            '\r' => self.column = 0,
+            // 端末と同じく 1 桁戻る。全角の文字を戻るときは書く側が 2 回送る
+            '\u{08}' => self.column = self.column.saturating_sub(1),
+            c => {
+                let wide = is_wide(c);
+                let width = if wide { 2 } else { 1 };
+                if self.column + width > COLUMNS {
+                    self.new_line(color);
+                }
+                let (row, column) = (self.row, self.column);
+                for offset in 0..width {
+                    self.vacate(column + offset);
+                }
+                if wide {
+                    self.cells[row][column] = Cell {
+                        glyph: Glyph::Wide(c),
+                        color,
+                    };
+                    self.cells[row][column + 1] = Cell {
+                        glyph: Glyph::Continuation,
+                        color,
+                    };
+                    self.draw_cell(row, column + 1, false);
+                } else {
+                    let byte = crate::vga_buffer::to_cp437(c).unwrap_or(0xfe);
+                    self.cells[row][column] = Cell {
+                        glyph: Glyph::Narrow(byte),
+                        color,
+                    };
+                }
+                self.draw_cell(row, column, false);
+                self.column += width;
+            }
+        }
+    }
+
+    // モード 12h にしたあと、まだ残っているプレーン 2 のフォントを写す
+    fn save_font(&mut self, framebuffer: *mut u8) {
+        write_indexed(GRAPHICS_INDEX, GRAPHICS_DATA, READ_MAP, 2);
+        for (index, glyph) in self.font.iter_mut().enumerate() {
+            for (y, row) in glyph.iter_mut().enumerate() {
+                *row = unsafe { core::ptr::read_volatile(framebuffer.add(index * 32 + y)) };
+            }
+        }
+        write_indexed(GRAPHICS_INDEX, GRAPHICS_DATA, READ_MAP, 0);
+    }
+
+    // テキストモードに戻したあと、画面に使って消えたフォントをプレーン 2 に書き戻す
+    fn restore_font(&self, framebuffer: *mut u8, text_mode: &Mode) {
+        write_indexed(SEQUENCER_INDEX, SEQUENCER_DATA, MAP_MASK, 0x04);
+        write_indexed(SEQUENCER_INDEX, SEQUENCER_DATA, MEMORY_MODE, 0x06);
+        write_indexed(GRAPHICS_INDEX, GRAPHICS_DATA, READ_MAP, 2);
+        write_indexed(GRAPHICS_INDEX, GRAPHICS_DATA, GRAPHICS_MODE, 0x00);
+        write_indexed(GRAPHICS_INDEX, GRAPHICS_DATA, GRAPHICS_MISC, 0x04);
+        for (index, glyph) in self.font.iter().enumerate() {
+            for y in 0..32 {
+                let row = glyph.get(y).copied().unwrap_or(0);
+                unsafe { core::ptr::write_volatile(framebuffer.add(index * 32 + y), row) };
+            }
+        }
+        let sequencer = &text_mode.sequencer;
+        let graphics = &text_mode.graphics;
+        for index in [MAP_MASK, MEMORY_MODE] {
+            write_indexed(
+                SEQUENCER_INDEX,
+                SEQUENCER_DATA,
+                index,
+                sequencer[index as usize],
+            );
+        }
+        for index in [READ_MAP, GRAPHICS_MODE, GRAPHICS_MISC] {
+            write_indexed(
+                GRAPHICS_INDEX,
+                GRAPHICS_DATA,
+                index,
+                graphics[index as usize],
+            );
+        }
+    }
+}
+
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+// vga_buffer の Writer から呼ばれる。color はテキストモードの属性バイト
+pub fn write_char(c: char, color: u8) {
+    let mut console = CONSOLE.lock();
+    console.draw_cursor(false);
+    console.write_char(c, color);
+    console.draw_cursor(true);
+}
+
+pub fn clear(color: u8) {
+    CONSOLE.lock().clear(color);
+}
+
+// 切り替えの途中に出力が混ざらないよう、WRITER を握ったまま行う
+pub fn enable() -> Result<(), &'static str> {
+    let framebuffer = framebuffer().ok_or("video memory is not mapped")?;
+    arch::without_interrupts(|| {
+        let _writer = WRITER.lock();
+        let mut console = CONSOLE.lock();
+        if is_active() {
+            return Err("already enabled");
+        }
+        console.text_mode = Some(save_mode());
+        set_mode(&MODE_12H);
+        console.save_font(framebuffer);
+        console.clear(DEFAULT_COLOR);
+        ACTIVE.store(true, Ordering::Relaxed);
+        Ok(())
+    })
+}
+
+pub fn disable() -> Result<(), &'static str> {
+    let framebuffer = framebuffer().ok_or("video memory is not mapped")?;
+    arch::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let console = CONSOLE.lock();
+        let text_mode = match console.text_mode {
+            Some(mode) if is_active() => mode,
+            _ => return Err("not enabled"),
+        };
+        ACTIVE.store(false, Ordering::Relaxed);
+        set_mode(&text_mode);
+        console.restore_font(framebuffer, &text_mode);
+        drop(console);
+        // テキストの画面も上書きされているので消して始める
+        writer.clear_screen();
+        Ok(())
+    })
+}
@@ -1,18 +1,203 @@
+use crate::name::Name;
+use crate::{fwcfg, procfs, swap, time};
 use alloc::collections::BTreeMap;
+use alloc::format;
 use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use spin::Mutex;
 
+// ファイルの中身はエクステント単位で持ち、書かれていない穴はゼロとして読む
+#[derive(Clone, Default)]
+pub struct FileData {
+    extents: BTreeMap<u64, Vec<u8>>,
+    len: u64,
+    // 追い出し中のエクステント (先頭, 元の長さ, 圧縮データ)
+    swapped: Vec<(u64, usize, Vec<u8>)>,
+    accessed: u64,
+    // 圧縮が効かなかったので次に書かれるまで追い出さない
+    incompressible: bool,
+}
+
+impl FileData {
+    pub fn from_bytes(bytes: &[u8]) -> FileData {
+        let mut data = FileData::default();
+        // 先頭からの書き込みは溢れない
+        let _ = data.write(0, bytes);
+        data
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // 実際にヒープに載っているバイト数 (追い出し中は圧縮後の大きさ)
+    pub fn allocated(&self) -> u64 {
+        let resident: u64 = self
+            .extents
+            .values()
+            .map(|extent| extent.len() as u64)
+            .sum();
+        resident + self.swapped_size().1
+    }
+
+    pub fn is_swapped(&self) -> bool {
+        !self.swapped.is_empty()
+    }
+
+    // (元の大きさ, 圧縮後の大きさ)
+    pub fn swapped_size(&self) -> (u64, u64) {
+        self.swapped.iter().fold((0, 0), |(raw, packed), extent| {
+            (raw + extent.1 as u64, packed + extent.2.len() as u64)
+        })
+    }
+
+    // エクステントを 1 つずつ圧縮して、ピーク時のメモリ使用量を抑える
+    pub fn swap_out(&mut self) -> bool {
+        if self.is_swapped() || self.incompressible {
+            return false;
+        }
+
+        let starts: Vec<u64> = self.extents.keys().copied().collect();
+        for start in starts {
+            let extent = &self.extents[&start];
+            let packed = swap::compress(extent);
+            if packed.len() >= extent.len() * 7 / 8 {
+                continue;
+            }
+            let extent = self.extents.remove(&start).unwrap_or_default();
+            self.swapped.push((start, extent.len(), packed));
+        }
+
+        if self.swapped.is_empty() {
+            self.incompressible = true;
+            return false;
+        }
+        swap::count_out();
+        true
+    }
+
+    fn fault_in(&mut self) {
+        self.accessed = time::ticks();
+        if !self.is_swapped() {
+            return;
+        }
+        for (start, len, packed) in core::mem::take(&mut self.swapped) {
+            match swap::decompress(&packed, len) {
+                Ok(extent) => {
+                    self.extents.insert(start, extent);
+                }
+                Err(e) => panic!("swap: cannot restore file contents: {}", e),
+            }
+        }
+        swap::count_in();
+    }
+
+    pub fn read(&mut self, offset: u64, len: usize) -> Vec<u8> {
+        self.fault_in();
+        self.read_resident(offset, len)
+    }
+
+    // スナップショットと共有しているときは書き換えずに読む。追い出し中なら写しに戻して読む
+    pub fn read_shared(&self, offset: u64, len: usize) -> Vec<u8> {
+        if self.is_swapped() {
+            return self.clone().read(offset, len);
+        }
+        self.read_resident(offset, len)
+    }
+
+    fn read_resident(&self, offset: u64, len: usize) -> Vec<u8> {
+        let end = self.len.min(offset.saturating_add(len as u64));
+        if offset >= end {
+            return Vec::new();
+        }
+
+        let mut buffer = alloc::vec![0; (end - offset) as usize];
+        for (&start, extent) in self.extents.range(..end) {
+            let extent_end = start + extent.len() as u64;
+            if extent_end <= offset {
+                continue;
+            }
+            let from = start.max(offset);
+            let to = extent_end.min(end);
+            buffer[(from - offset) as usize..(to - offset) as usize]
+                .copy_from_slice(&extent[(from - start) as usize..(to - start) as usize]);
+        }
+        buffer
+    }
+
+    pub fn to_vec(&mut self) -> Vec<u8> {
+        self.read(0, self.len as usize)
+    }
+
+    // 重なるか接するエクステントは 1 つにまとめる。終わりが u64 を超える書き込みは断る
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), &'static str> {
+        let end = offset
+            .checked_add(data.len() as u64)
+            .ok_or("File too large")?;
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.fault_in();
+        self.incompressible = false;
+        let touching: Vec<u64> = self
+            .extents
+            .range(..=end)
+            .filter(|(&start, extent)| start + extent.len() as u64 >= offset)
+            .map(|(&start, _)| start)
+            .collect();
+
+        let start = touching.first().map_or(offset, |&first| first.min(offset));
+        let mut merged = Vec::new();
+        for key in touching {
+            let extent = self.extents.remove(&key).unwrap_or_default();
+            if merged.is_empty() && key == start {
+                merged = extent;
+            } else {
+                let at = (key - start) as usize;
+                merged.resize(merged.len().max(at + extent.len()), 0);
+                merged[at..at + extent.len()].copy_from_slice(&extent);
+            }
+        }
+
+        let at = (offset - start) as usize;
+        merged.resize(merged.len().max(at + data.len()), 0);
+        merged[at..at + data.len()].copy_from_slice(data);
+        self.extents.insert(start, merged);
+        self.len = self.len.max(end);
+        Ok(())
+    }
+
+    pub fn set_len(&mut self, len: u64) {
+        self.fault_in();
+        let cut: Vec<u64> = self.extents.range(len..).map(|(&start, _)| start).collect();
+        for start in cut {
+            self.extents.remove(&start);
+        }
+        if let Some((&start, extent)) = self.extents.range_mut(..len).next_back() {
+            extent.truncate((len - start) as usize);
+        }
+        self.len = len;
+    }
+}
+
+// 子は Arc で持ち、スナップショットと共有する。書き換えるときは Arc::make_mut で
+// その節点だけを写す (コピーオンライト)
 #[derive(Clone)]
 pub enum FSNode {
     File {
-        content: Vec<u8>,
+        content: FileData,
         created: u64,
         modified: u64,
     },
     Directory {
-        entries: BTreeMap<String, FSNode>,
+        entries: BTreeMap<Name, Arc<FSNode>>,
         created: u64,
         modified: u64,
     },
@@ -21,8 +206,8 @@ pub enum FSNode {
 lazy_static! {
     static ref FS_ROOT: Mutex<FSNode> = Mutex::new(FSNode::Directory {
         entries: BTreeMap::new(),
-        created: 0,
-        modified: 0,
+        created: time::now(),
+        modified: time::now(),
     });
 }
 
@@ -30,136 +215,403 @@ lazy_static! {
     static ref CURRENT_PATH: Mutex<Vec<String>> = Mutex::new(Vec::new());
 }
 
-pub fn list_current_directory() -> Vec<(String, bool)> {
-    let current_path = CURRENT_PATH.lock();
-    let fs = FS_ROOT.lock();
-    let mut current = &*fs;
+pub struct Mount {
+    pub path: Vec<String>,
+    pub fstype: &'static str,
+    pub read_only: bool,
+}
 
-    for dir in current_path.iter() {
-        if let FSNode::Directory { ref entries, .. } = current {
-            if let Some(next) = entries.get(dir) {
-                current = next;
-            } else {
-                return Vec::new();
-            }
+lazy_static! {
+    static ref MOUNTS: Mutex<Vec<Mount>> = Mutex::new(alloc::vec![Mount {
+        path: Vec::new(),
+        fstype: "ramfs",
+        read_only: false,
+    }]);
+}
+
+// cd - の戻り先と pushd/popd のスタック
+static PREVIOUS_PATH: Mutex<Option<Vec<String>>> = Mutex::new(None);
+static DIRECTORY_STACK: Mutex<Vec<Vec<String>>> = Mutex::new(Vec::new());
+
+// panic から立ち直るとき用。捨てたフレームが握っていたロックを解放する
+pub unsafe fn recover() {
+    FS_ROOT.force_unlock();
+    CURRENT_PATH.force_unlock();
+    MOUNTS.force_unlock();
+    PREVIOUS_PATH.force_unlock();
+    DIRECTORY_STACK.force_unlock();
+    SNAPSHOTS.force_unlock();
+    LISTINGS.force_unlock();
+}
+
+// ディレクトリの中身の一覧 (名前, ディレクトリか)。BTreeMap の順なので名前順に並んでいる
+pub type Listing = Arc<Vec<(Name, bool)>>;
+
+// 最近作った一覧を覚えておく。補完はキーを押すたびに一覧を引くので、毎回作らずに済ませる。
+// 名前が増えたり減ったりする操作のたびに全部捨てる
+const LISTING_CACHE_SIZE: usize = 8;
+static LISTINGS: Mutex<Vec<(Vec<String>, Listing)>> = Mutex::new(Vec::new());
+// 捨てるたびに増やす。作っている間に捨てられた一覧を覚えないようにする
+static LISTING_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+fn invalidate_listings() {
+    LISTING_GENERATION.fetch_add(1, Ordering::Relaxed);
+    LISTINGS.lock().clear();
+}
+
+fn listing(parts: Vec<String>) -> Result<Listing, &'static str> {
+    if let Some((_, listing)) = LISTINGS.lock().iter().find(|(path, _)| *path == parts) {
+        return Ok(listing.clone());
+    }
+    let generation = LISTING_GENERATION.load(Ordering::Relaxed);
+    let dirs: Vec<&str> = parts.iter().map(String::as_str).collect();
+    let listing: Listing = Arc::new(
+        directory_entries(&FS_ROOT.lock(), &dirs)?
+            .iter()
+            .map(|(name, node)| (name.clone(), is_directory(node)))
+            .collect(),
+    );
+    let mut listings = LISTINGS.lock();
+    if generation == LISTING_GENERATION.load(Ordering::Relaxed) {
+        if listings.len() == LISTING_CACHE_SIZE {
+            listings.remove(0);
         }
+        listings.push((parts, listing.clone()));
     }
+    Ok(listing)
+}
 
-    let mut result = Vec::new();
-    if let FSNode::Directory {
-        entries: ref dir_entries,
-        ..
-    } = current
-    {
-        for (name, node) in dir_entries.iter() {
-            result.push((name.clone(), matches!(node, FSNode::Directory { .. })));
+pub fn list_current_directory() -> Listing {
+    listing(get_current_path()).unwrap_or_default()
+}
+
+pub fn list_path(path: &str) -> Result<Listing, &'static str> {
+    listing(resolve_path(path))
+}
+
+// path より下にあるファイルの絶対パスを名前順に全部返す。ディレクトリはたどるだけで含めない
+pub fn walk_files(path: &str) -> Result<Vec<String>, &'static str> {
+    let mut files = Vec::new();
+    let mut pending = Vec::new();
+    pending.push(resolve_path(path));
+    while let Some(dir) = pending.pop() {
+        for (name, is_directory) in list_path(&format_path(&dir))?.iter() {
+            let mut child = dir.clone();
+            child.push(String::from(name.as_str()));
+            if *is_directory {
+                pending.push(child);
+            } else {
+                files.push(format_path(&child));
+            }
         }
     }
+    files.sort();
+    Ok(files)
+}
 
-    result.sort();
-    result
+pub fn list_directory() -> Listing {
+    listing(Vec::new()).unwrap_or_default()
 }
 
-pub fn list_directory() -> Vec<(String, bool)> {
-    let fs = FS_ROOT.lock();
-    let mut result = Vec::new();
+// parents が偽なら親ディレクトリが必要で、既存なら失敗する (mkdir -p 相当は真)
+pub fn create_directory(path: &str, parents: bool) -> Result<(), &'static str> {
+    let parts = resolve_path(path);
+    check_writable(&parts)?;
+    if parts.is_empty() {
+        return if parents { Ok(()) } else { Err("File exists") };
+    }
 
-    if let FSNode::Directory {
-        entries: ref dir_entries,
-        ..
-    } = *fs
-    {
-        for (name, node) in dir_entries.iter() {
-            result.push((name.clone(), matches!(node, FSNode::Directory { .. })));
+    let mut fs = FS_ROOT.lock();
+    let mut current = &mut *fs;
+    for (index, part) in parts.iter().enumerate() {
+        let last = index == parts.len() - 1;
+        let entries = match current {
+            FSNode::Directory { entries, .. } => entries,
+            FSNode::File { .. } => return Err("Not a directory"),
+        };
+
+        match entries.get(part.as_str()).map(Arc::as_ref) {
+            Some(FSNode::File { .. }) if last => return Err("File exists"),
+            Some(FSNode::File { .. }) => return Err("Not a directory"),
+            Some(FSNode::Directory { .. }) if last && !parents => return Err("File exists"),
+            Some(FSNode::Directory { .. }) => {}
+            None if !last && !parents => return Err("No such file or directory"),
+            None => {
+                entries.insert(
+                    Name::from(part),
+                    Arc::new(FSNode::Directory {
+                        entries: BTreeMap::new(),
+                        created: time::now(),
+                        modified: time::now(),
+                    }),
+                );
+                invalidate_listings();
+            }
         }
+        current = entries
+            .get_mut(part.as_str())
+            .map(Arc::make_mut)
+            .ok_or("Failed to create directory")?;
     }
+    Ok(())
+}
 
-    result.sort();
-    result
+// resolve_path で解決した絶対パスを (親ディレクトリの要素, 名前) に分ける
+fn split_path(path: &str) -> Result<(Vec<String>, String), &'static str> {
+    let mut parts = resolve_path(path);
+    let name = parts.pop().ok_or("Invalid path")?;
+    Ok((parts, name))
 }
 
-pub fn create_directory(path: &str) -> Result<(), &'static str> {
-    let mut fs = FS_ROOT.lock();
-    let parts: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+fn is_directory(node: &FSNode) -> bool {
+    matches!(node, FSNode::Directory { .. })
+}
 
-    fn create_dir_recursive(node: &mut FSNode, parts: &[&str]) -> Result<(), &'static str> {
-        if parts.is_empty() {
-            return Ok(());
+// 書き換えるためにたどる。途中のディレクトリがスナップショットと共有されていれば写す
+fn directory_entries_mut<'a>(
+    root: &'a mut FSNode,
+    dirs: &[&str],
+) -> Result<&'a mut BTreeMap<Name, Arc<FSNode>>, &'static str> {
+    let mut current = root;
+    for &dir in dirs {
+        if let FSNode::Directory {
+            ref mut entries, ..
+        } = current
+        {
+            current = entries
+                .get_mut(dir)
+                .map(Arc::make_mut)
+                .ok_or("Directory not found")?;
+        } else {
+            return Err("Path component is not a directory");
         }
+    }
 
-        match node {
+    if let FSNode::Directory {
+        ref mut entries, ..
+    } = current
+    {
+        Ok(entries)
+    } else {
+        Err("Not a directory")
+    }
+}
+
+// 読むだけのときはこちらを使い、共有している節点を写さない
+fn directory_entries<'a>(
+    root: &'a FSNode,
+    dirs: &[&str],
+) -> Result<&'a BTreeMap<Name, Arc<FSNode>>, &'static str> {
+    let mut current = root;
+    for &dir in dirs {
+        match current {
             FSNode::Directory { entries, .. } => {
-                let part = parts[0];
-                if !entries.contains_key(part) {
-                    entries.insert(
-                        String::from(part),
-                        FSNode::Directory {
-                            entries: BTreeMap::new(),
-                            created: 0,
-                            modified: 0,
-                        },
-                    );
-                }
+                current = entries
+                    .get(dir)
+                    .map(Arc::as_ref)
+                    .ok_or("Directory not found")?;
+            }
+            FSNode::File { .. } => return Err("Path component is not a directory"),
+        }
+    }
+    match current {
+        FSNode::Directory { entries, .. } => Ok(entries),
+        FSNode::File { .. } => Err("Not a directory"),
+    }
+}
 
-                if let Some(next) = entries.get_mut(part) {
-                    create_dir_recursive(next, &parts[1..])
-                } else {
-                    Err("Failed to create directory")
-                }
+// カレントディレクトリを基準に . と .. を解決した絶対パスの要素を返す
+pub fn resolve_path(path: &str) -> Vec<String> {
+    let mut parts = if path.starts_with('/') {
+        Vec::new()
+    } else {
+        CURRENT_PATH.lock().clone()
+    };
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
             }
-            _ => Err("Not a directory"),
+            part => parts.push(String::from(part)),
         }
     }
+    parts
+}
+
+// 既存なら更新時刻だけ更新し、無ければ create が真のときだけ作る。作ったら true
+pub fn touch(path: &str, create: bool) -> Result<bool, &'static str> {
+    let parts = resolve_path(path);
+    check_writable(&parts)?;
+    let (name, dirs) = parts.split_last().ok_or("Invalid path")?;
+    let dirs: Vec<&str> = dirs.iter().map(String::as_str).collect();
 
-    create_dir_recursive(&mut fs, &parts)
+    let mut fs = FS_ROOT.lock();
+    let entries = directory_entries_mut(&mut fs, &dirs)?;
+    match entries.get_mut(name.as_str()).map(Arc::make_mut) {
+        Some(FSNode::File { modified, .. }) | Some(FSNode::Directory { modified, .. }) => {
+            *modified = time::now();
+            Ok(false)
+        }
+        None if create => {
+            entries.insert(
+                Name::from(name),
+                Arc::new(FSNode::File {
+                    content: FileData::default(),
+                    created: time::now(),
+                    modified: time::now(),
+                }),
+            );
+            invalidate_listings();
+            Ok(true)
+        }
+        None => Ok(false),
+    }
 }
 
 pub fn read_file(path: &str) -> Result<Vec<u8>, &'static str> {
+    if let Some(content) = procfs::generate(path).or_else(|| fwcfg::read(path)) {
+        return Ok(content);
+    }
+    // 穴だらけのファイルは論理サイズが大きくても場所を取らないが、読むと全部が 0 で埋まる。
+    // ヒープに入らない大きさなら確保で panic する前に断る (read_file_at なら少しずつ読める)
+    let (size, _) = file_size(path)?;
+    if size > swap::free_memory() as u64 {
+        return Err("File too large to read at once");
+    }
+    read_node(path, 0, size as usize)
+}
+
+pub fn read_file_at(path: &str, offset: u64, len: usize) -> Result<Vec<u8>, &'static str> {
+    if let Some(content) = procfs::generate(path).or_else(|| fwcfg::read(path)) {
+        let start = (offset as usize).min(content.len());
+        let end = start.saturating_add(len).min(content.len());
+        return Ok(content[start..end].to_vec());
+    }
+    read_node(path, offset, len)
+}
+
+// スナップショットと共有している節点を通るなら、写さずに読む
+fn read_node(path: &str, offset: u64, len: usize) -> Result<Vec<u8>, &'static str> {
+    let (dirs, name) = split_path(path)?;
+    let dirs: Vec<&str> = dirs.iter().map(String::as_str).collect();
+    let mut fs = FS_ROOT.lock();
+    if let Some(FSNode::File { content, .. }) = shared_node(&fs, &dirs, &name) {
+        return Ok(content.read_shared(offset, len));
+    }
+    let entries = directory_entries_mut(&mut fs, &dirs)?;
+    match entries.get_mut(name.as_str()).map(Arc::make_mut) {
+        Some(FSNode::File { content, .. }) => Ok(content.read(offset, len)),
+        _ => Err("File not found"),
+    }
+}
+
+// dirs/name の節点。途中も含めてどこも共有されていなければ None
+fn shared_node<'a>(root: &'a FSNode, dirs: &[&str], name: &str) -> Option<&'a FSNode> {
+    let mut current = root;
+    let mut shared = false;
+    for &part in dirs.iter().chain(core::iter::once(&name)) {
+        let next = match current {
+            FSNode::Directory { entries, .. } => entries.get(part)?,
+            FSNode::File { .. } => return None,
+        };
+        shared |= Arc::strong_count(next) > 1;
+        current = next;
+    }
+    if shared {
+        Some(current)
+    } else {
+        None
+    }
+}
+
+// (論理サイズ, 実際に確保しているバイト数)
+pub fn file_size(path: &str) -> Result<(u64, u64), &'static str> {
+    if let Some(content) = procfs::generate(path).or_else(|| fwcfg::read(path)) {
+        return Ok((content.len() as u64, 0));
+    }
+    let (dirs, name) = split_path(path)?;
+    let dirs: Vec<&str> = dirs.iter().map(String::as_str).collect();
     let fs = FS_ROOT.lock();
+    let entries = directory_entries(&fs, &dirs)?;
 
-    if let FSNode::Directory { ref entries, .. } = *fs {
-        if let Some(FSNode::File { ref content, .. }) = entries.get(path) {
-            Ok(content.clone())
-        } else {
-            Err("File not found")
-        }
+    if let Some(FSNode::File { content, .. }) = entries.get(name.as_str()).map(Arc::as_ref) {
+        Ok((content.len(), content.allocated()))
     } else {
-        Err("Root is not a directory")
+        Err("File not found")
     }
 }
 
-pub fn create_file(path: &str, content: Option<Vec<u8>>) -> Result<(), &'static str> {
-    let mut fs = FS_ROOT.lock();
-    let current_path = CURRENT_PATH.lock();
+// offset より前が未書き込みなら穴になる。ファイルが無ければ作る
+pub fn write_file_at(path: &str, offset: u64, data: &[u8]) -> Result<(), &'static str> {
+    let (dirs, name) = split_path(path)?;
+    modify_file(&dirs, &name, |content| content.write(offset, data))?;
+    balance_memory();
+    Ok(())
+}
 
-    let mut current = &mut *fs;
-    for dir in current_path.iter() {
-        if let FSNode::Directory {
-            ref mut entries, ..
-        } = current
-        {
-            current = entries.get_mut(dir).ok_or("Current directory not found")?;
-        } else {
-            return Err("Current path is not a directory");
+pub fn truncate_file(path: &str, len: u64) -> Result<(), &'static str> {
+    let (dirs, name) = split_path(path)?;
+    modify_file(&dirs, &name, |content| {
+        content.set_len(len);
+        Ok(())
+    })
+}
+
+fn modify_file(
+    dirs: &[String],
+    name: &str,
+    f: impl FnOnce(&mut FileData) -> Result<(), &'static str>,
+) -> Result<(), &'static str> {
+    let mut parts = dirs.to_vec();
+    parts.push(String::from(name));
+    check_writable(&parts)?;
+
+    let dirs: Vec<&str> = dirs.iter().map(String::as_str).collect();
+    let mut fs = FS_ROOT.lock();
+    let entries = directory_entries_mut(&mut fs, &dirs)?;
+    if !entries.contains_key(name) {
+        invalidate_listings();
+    }
+    let node = entries.entry(Name::from(name)).or_insert_with(|| {
+        Arc::new(FSNode::File {
+            content: FileData::default(),
+            created: time::now(),
+            modified: time::now(),
+        })
+    });
+    match Arc::make_mut(node) {
+        FSNode::File {
+            content, modified, ..
+        } => {
+            f(content)?;
+            *modified = time::now();
+            Ok(())
         }
+        FSNode::Directory { .. } => Err("Is a directory"),
     }
+}
 
-    let parts: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
-    let filename = parts.last().ok_or("Invalid path")?;
-    let parent_dirs = &parts[..parts.len() - 1];
+// 途中のディレクトリが無ければ作る
+pub fn create_file(path: &str, content: Option<Vec<u8>>) -> Result<(), &'static str> {
+    let parts = resolve_path(path);
+    check_writable(&parts)?;
+    let (filename, parent_dirs) = parts.split_last().ok_or("Invalid path")?;
 
-    for &dir in parent_dirs {
+    let mut fs = FS_ROOT.lock();
+    let mut current = &mut *fs;
+    for dir in parent_dirs {
         if let FSNode::Directory {
             ref mut entries, ..
         } = current
         {
-            current = entries
-                .entry(String::from(dir))
-                .or_insert_with(|| FSNode::Directory {
+            current = Arc::make_mut(entries.entry(Name::from(dir)).or_insert_with(|| {
+                Arc::new(FSNode::Directory {
                     entries: BTreeMap::new(),
-                    created: 0,
-                    modified: 0,
-                });
+                    created: time::now(),
+                    modified: time::now(),
+                })
+            }));
         } else {
             return Err("Path component is not a directory");
         }
@@ -170,13 +622,14 @@ pub fn create_file(path: &str, content: Option<Vec<u8>>) -> Result<(), &'static
     } = current
     {
         entries.insert(
-            String::from(*filename),
-            FSNode::File {
-                content: content.unwrap_or_default(),
-                created: 0,
-                modified: 0,
-            },
+            Name::from(filename),
+            Arc::new(FSNode::File {
+                content: FileData::from_bytes(&content.unwrap_or_default()),
+                created: time::now(),
+                modified: time::now(),
+            }),
         );
+        invalidate_listings();
         Ok(())
     } else {
         Err("Parent is not a directory")
@@ -184,88 +637,444 @@ pub fn create_file(path: &str, content: Option<Vec<u8>>) -> Result<(), &'static
 }
 
 pub fn write_file(path: &str, content: &[u8], append: bool) -> Result<(), &'static str> {
+    let parts = resolve_path(path);
+    check_writable(&parts)?;
+    let (name, dirs) = parts.split_last().ok_or("Invalid path")?;
+    let dirs: Vec<&str> = dirs.iter().map(String::as_str).collect();
+
     let mut fs = FS_ROOT.lock();
+    let entries = directory_entries_mut(&mut fs, &dirs)?;
 
-    if let FSNode::Directory {
-        ref mut entries, ..
-    } = *fs
-    {
-        if append {
-            if let Some(FSNode::File {
-                content: ref mut file_content,
-                ..
-            }) = entries.get_mut(path)
-            {
-                file_content.extend_from_slice(content);
-            } else {
-                entries.insert(
-                    String::from(path),
-                    FSNode::File {
-                        content: content.to_vec(),
-                        created: 0,
-                        modified: 0,
-                    },
-                );
-            }
+    if append {
+        if let Some(FSNode::File {
+            content: ref mut file_content,
+            ref mut modified,
+            ..
+        }) = entries.get_mut(name.as_str()).map(Arc::make_mut)
+        {
+            let end = file_content.len();
+            file_content.write(end, content)?;
+            *modified = time::now();
         } else {
             entries.insert(
-                String::from(path),
-                FSNode::File {
-                    content: content.to_vec(),
-                    created: 0,
-                    modified: 0,
-                },
+                Name::from(name),
+                Arc::new(FSNode::File {
+                    content: FileData::from_bytes(content),
+                    created: time::now(),
+                    modified: time::now(),
+                }),
             );
         }
-        Ok(())
     } else {
-        Err("Root is not a directory")
+        entries.insert(
+            Name::from(name),
+            Arc::new(FSNode::File {
+                content: FileData::from_bytes(content),
+                created: time::now(),
+                modified: time::now(),
+            }),
+        );
     }
+    drop(fs);
+    invalidate_listings();
+    balance_memory();
+    Ok(())
 }
 
-pub fn change_directory(path: &str) -> Result<(), &'static str> {
-    let mut current_path = CURRENT_PATH.lock();
-    match path {
-        "/" => {
-            current_path.clear();
+pub fn remove_file(path: &str) -> Result<(), &'static str> {
+    let parts = resolve_path(path);
+    check_writable(&parts)?;
+    let (name, dirs) = parts.split_last().ok_or("Invalid path")?;
+    let dirs: Vec<&str> = dirs.iter().map(String::as_str).collect();
+
+    let mut fs = FS_ROOT.lock();
+    let entries = directory_entries_mut(&mut fs, &dirs)?;
+    match entries.get(name.as_str()).map(Arc::as_ref) {
+        Some(FSNode::File { .. }) => {
+            entries.remove(name.as_str());
+            invalidate_listings();
             Ok(())
         }
-        ".." => {
-            if !current_path.is_empty() {
-                current_path.pop();
-                Ok(())
-            } else {
-                Err("Already at root directory")
-            }
+        Some(FSNode::Directory { .. }) => Err("Is a directory"),
+        None => Err("No such file or directory"),
+    }
+}
+
+// recursive が偽なら空のディレクトリだけ消す。ルート、マウントポイントを含むもの、
+// カレントディレクトリとその祖先は消せない
+pub fn remove_directory(path: &str, recursive: bool) -> Result<(), &'static str> {
+    let parts = resolve_path(path);
+    check_writable(&parts)?;
+    let (name, dirs) = parts
+        .split_last()
+        .ok_or("Cannot remove the root directory")?;
+    if CURRENT_PATH.lock().starts_with(&parts) {
+        return Err("Device or resource busy");
+    }
+    if MOUNTS
+        .lock()
+        .iter()
+        .any(|mount| mount.path.starts_with(&parts))
+    {
+        return Err("Device or resource busy");
+    }
+    let dirs: Vec<&str> = dirs.iter().map(String::as_str).collect();
+
+    let mut fs = FS_ROOT.lock();
+    let entries = directory_entries_mut(&mut fs, &dirs)?;
+    match entries.get(name.as_str()).map(Arc::as_ref) {
+        Some(FSNode::Directory {
+            entries: children, ..
+        }) if !recursive && !children.is_empty() => Err("Directory not empty"),
+        Some(FSNode::Directory { .. }) => {
+            entries.remove(name.as_str());
+            invalidate_listings();
+            Ok(())
         }
-        path => {
-            let fs = FS_ROOT.lock();
-            let mut node = &*fs;
-
-            for dir in current_path.iter() {
-                if let FSNode::Directory { ref entries, .. } = node {
-                    if let Some(next) = entries.get(dir) {
-                        node = next;
-                    } else {
-                        return Err("Path not found");
-                    }
-                }
-            }
+        Some(FSNode::File { .. }) => Err("Not a directory"),
+        None => Err("No such file or directory"),
+    }
+}
 
-            if let FSNode::Directory { ref entries, .. } = node {
-                if let Some(FSNode::Directory { .. }) = entries.get(path) {
-                    current_path.push(String::from(path));
-                    Ok(())
-                } else {
-                    Err("Directory not found")
-                }
-            } else {
-                Err("Not a directory")
+// src を dst という名前で写す。中身は read_file/write_file を通すので、procfs や
+// fw_cfg のファイルも写せる。ディレクトリは recursive のときだけ中身ごと写し、
+// dst が既存のディレクトリならそこに重ねる
+pub fn copy(src: &str, dst: &str, recursive: bool) -> Result<(), &'static str> {
+    let from = resolve_path(src);
+    let to = resolve_path(dst);
+    let target_is_directory = list_path(dst).is_ok();
+    match list_path(src) {
+        Ok(_) if !recursive => Err("Is a directory"),
+        Ok(_) if to.starts_with(&from) => Err("Cannot copy a directory into itself"),
+        Ok(entries) => {
+            if !target_is_directory {
+                create_directory(dst, false)?;
+            }
+            for (name, _) in entries.iter() {
+                copy(
+                    &format!("{}/{}", format_path(&from), name),
+                    &format!("{}/{}", format_path(&to), name),
+                    true,
+                )?;
             }
+            Ok(())
+        }
+        Err(_) if target_is_directory => Err("Cannot overwrite a directory with a file"),
+        Err(_) => {
+            let content = read_file(src)?;
+            write_file(dst, &content, false)
+        }
+    }
+}
+
+// old_path を new_path という名前にする。ディレクトリをまたいでも動かせる。
+// new_path が既にあれば、ファイルはファイルで、ディレクトリは空のディレクトリでだけ置き換える
+pub fn rename(old_path: &str, new_path: &str) -> Result<(), &'static str> {
+    let old = resolve_path(old_path);
+    let new = resolve_path(new_path);
+    check_writable(&old)?;
+    check_writable(&new)?;
+    let (old_name, old_dirs) = old.split_last().ok_or("Cannot move the root directory")?;
+    let (new_name, new_dirs) = new.split_last().ok_or("File exists")?;
+    if old == new {
+        return Ok(());
+    }
+    if new.starts_with(&old) {
+        return Err("Cannot move a directory into itself");
+    }
+    if MOUNTS
+        .lock()
+        .iter()
+        .any(|mount| mount.path.starts_with(&old) || mount.path.starts_with(&new))
+    {
+        return Err("Device or resource busy");
+    }
+    let old_dirs: Vec<&str> = old_dirs.iter().map(String::as_str).collect();
+    let new_dirs: Vec<&str> = new_dirs.iter().map(String::as_str).collect();
+
+    let mut fs = FS_ROOT.lock();
+    let moving_directory = match directory_entries(&fs, &old_dirs)?.get(old_name.as_str()) {
+        Some(node) => is_directory(node),
+        None => return Err("No such file or directory"),
+    };
+    // 取り外す前に行き先を確かめる。new は old の下に無いので、取り外しても変わらない
+    match directory_entries(&fs, &new_dirs)?
+        .get(new_name.as_str())
+        .map(Arc::as_ref)
+    {
+        Some(FSNode::Directory { .. }) if !moving_directory => return Err("Is a directory"),
+        Some(FSNode::Directory { entries, .. }) if !entries.is_empty() => {
+            return Err("Directory not empty")
         }
+        Some(FSNode::File { .. }) if moving_directory => return Err("Not a directory"),
+        _ => {}
+    }
+    let node = directory_entries_mut(&mut fs, &old_dirs)?
+        .remove(old_name.as_str())
+        .ok_or("No such file or directory")?;
+    directory_entries_mut(&mut fs, &new_dirs)?.insert(Name::from(new_name), node);
+    drop(fs);
+    invalidate_listings();
+
+    // カレントディレクトリごと動かしたら、移動先に付いていく
+    let mut current = CURRENT_PATH.lock();
+    if current.starts_with(&old) {
+        let rest = current.split_off(old.len());
+        *current = new;
+        current.extend(rest);
     }
+    Ok(())
+}
+
+pub fn change_directory(path: &str) -> Result<(), &'static str> {
+    let previous = get_current_path();
+    set_current_path(resolve_path(path))?;
+    *PREVIOUS_PATH.lock() = Some(previous);
+    Ok(())
 }
 
 pub fn get_current_path() -> Vec<String> {
     CURRENT_PATH.lock().clone()
 }
+
+pub fn format_path(parts: &[String]) -> String {
+    let mut path = String::new();
+    for part in parts {
+        path.push('/');
+        path.push_str(part);
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    path
+}
+
+// 保存しておいたパスへ移動する。途中で消えていたらエラー
+fn set_current_path(parts: Vec<String>) -> Result<(), &'static str> {
+    let dirs: Vec<&str> = parts.iter().map(String::as_str).collect();
+    directory_entries(&FS_ROOT.lock(), &dirs)?;
+    *CURRENT_PATH.lock() = parts;
+    Ok(())
+}
+
+pub fn change_to_previous() -> Result<(), &'static str> {
+    let target = PREVIOUS_PATH
+        .lock()
+        .clone()
+        .ok_or("No previous directory")?;
+    let previous = get_current_path();
+    set_current_path(target)?;
+    *PREVIOUS_PATH.lock() = Some(previous);
+    Ok(())
+}
+
+pub fn push_directory(path: Option<&str>) -> Result<(), &'static str> {
+    let current = get_current_path();
+    match path {
+        Some(path) => change_directory(path)?,
+        None => {
+            // 引数なしはスタック先頭と入れ替える
+            let top = DIRECTORY_STACK
+                .lock()
+                .pop()
+                .ok_or("Directory stack empty")?;
+            if let Err(e) = set_current_path(top.clone()) {
+                DIRECTORY_STACK.lock().push(top);
+                return Err(e);
+            }
+            *PREVIOUS_PATH.lock() = Some(current.clone());
+        }
+    }
+    DIRECTORY_STACK.lock().push(current);
+    Ok(())
+}
+
+pub fn pop_directory() -> Result<(), &'static str> {
+    let top = DIRECTORY_STACK
+        .lock()
+        .pop()
+        .ok_or("Directory stack empty")?;
+    let previous = get_current_path();
+    set_current_path(top)?;
+    *PREVIOUS_PATH.lock() = Some(previous);
+    Ok(())
+}
+
+// 先頭がカレントディレクトリ
+pub fn directory_stack() -> Vec<String> {
+    let mut stack = Vec::new();
+    stack.push(format_path(&CURRENT_PATH.lock()));
+    for parts in DIRECTORY_STACK.lock().iter().rev() {
+        stack.push(format_path(parts));
+    }
+    stack
+}
+
+// パスを含むマウントのうち最も深いものの read_only を見る
+fn check_writable(parts: &[String]) -> Result<(), &'static str> {
+    let mounts = MOUNTS.lock();
+    let mount = mounts
+        .iter()
+        .filter(|mount| parts.starts_with(&mount.path))
+        .max_by_key(|mount| mount.path.len());
+    match mount {
+        Some(mount) if mount.read_only => Err("Read-only file system"),
+        _ => Ok(()),
+    }
+}
+
+pub fn mount(path: &str, fstype: &'static str, read_only: bool) -> Result<(), &'static str> {
+    let parts = resolve_path(path);
+    let dirs: Vec<&str> = parts.iter().map(String::as_str).collect();
+    directory_entries(&FS_ROOT.lock(), &dirs)?;
+
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|mount| mount.path == parts) {
+        return Err("Already mounted");
+    }
+    mounts.push(Mount {
+        path: parts,
+        fstype,
+        read_only,
+    });
+    Ok(())
+}
+
+pub fn remount(path: &str, read_only: bool) -> Result<(), &'static str> {
+    let parts = resolve_path(path);
+    let mut mounts = MOUNTS.lock();
+    let mount = mounts
+        .iter_mut()
+        .find(|mount| mount.path == parts)
+        .ok_or("Not a mount point")?;
+    mount.read_only = read_only;
+    Ok(())
+}
+
+pub fn mounts() -> Vec<(String, &'static str, bool)> {
+    MOUNTS
+        .lock()
+        .iter()
+        .map(|mount| (format_path(&mount.path), mount.fstype, mount.read_only))
+        .collect()
+}
+
+// スナップショットと共有している節点は、写さないと書き換えられないので入れない
+fn collect_files<'a>(node: &'a mut FSNode, files: &mut Vec<&'a mut FileData>) {
+    match node {
+        FSNode::File { content, .. } => files.push(content),
+        FSNode::Directory { entries, .. } => {
+            for child in entries.values_mut().filter_map(Arc::get_mut) {
+                collect_files(child, files);
+            }
+        }
+    }
+}
+
+fn visit_files(node: &FSNode, f: &mut impl FnMut(&FileData)) {
+    match node {
+        FSNode::File { content, .. } => f(content),
+        FSNode::Directory { entries, .. } => {
+            for child in entries.values() {
+                visit_files(child, f);
+            }
+        }
+    }
+}
+
+// 最近使われていない大きなファイルから追い出す。追い出したファイル数を返す
+pub fn reclaim(force: bool) -> usize {
+    let mut fs = FS_ROOT.lock();
+    let mut files = Vec::new();
+    collect_files(&mut fs, &mut files);
+    files.retain(|file| !file.is_swapped() && file.allocated() >= swap::MIN_FILE_SIZE);
+    files.sort_by_key(|file| file.accessed);
+
+    let mut count = 0;
+    for file in files {
+        if !force && swap::relieved() {
+            break;
+        }
+        if file.swap_out() {
+            count += 1;
+        }
+    }
+    count
+}
+
+fn balance_memory() {
+    if swap::under_pressure() {
+        reclaim(false);
+    }
+}
+
+// (追い出し中のファイル数, 元の大きさ, 圧縮後の大きさ)
+pub fn swap_usage() -> (usize, u64, u64) {
+    let mut usage = (0, 0, 0);
+    visit_files(&FS_ROOT.lock(), &mut |file| {
+        if file.is_swapped() {
+            let (raw, packed) = file.swapped_size();
+            usage = (usage.0 + 1, usage.1 + raw, usage.2 + packed);
+        }
+    });
+    usage
+}
+
+// スナップショットは木の根を写したもの。子は Arc で共有するので、作るのは速く、
+// 書き換えられた節点の分だけメモリを使う。マウントの一覧は含まない
+struct Snapshot {
+    name: String,
+    created: u64,
+    root: FSNode,
+}
+
+static SNAPSHOTS: Mutex<Vec<Snapshot>> = Mutex::new(Vec::new());
+
+pub fn create_snapshot(name: &str) -> Result<(), &'static str> {
+    let mut snapshots = SNAPSHOTS.lock();
+    if snapshots.iter().any(|snapshot| snapshot.name == name) {
+        return Err("Snapshot already exists");
+    }
+    snapshots.push(Snapshot {
+        name: String::from(name),
+        created: time::now(),
+        root: FS_ROOT.lock().clone(),
+    });
+    Ok(())
+}
+
+// (名前, 作った時刻)
+pub fn snapshots() -> Vec<(String, u64)> {
+    SNAPSHOTS
+        .lock()
+        .iter()
+        .map(|snapshot| (snapshot.name.clone(), snapshot.created))
+        .collect()
+}
+
+// スナップショットは残したまま、その時の木に戻す。カレントディレクトリが無くなったらルートに移る
+pub fn restore_snapshot(name: &str) -> Result<(), &'static str> {
+    let root = SNAPSHOTS
+        .lock()
+        .iter()
+        .find(|snapshot| snapshot.name == name)
+        .map(|snapshot| snapshot.root.clone())
+        .ok_or("No such snapshot")?;
+    *FS_ROOT.lock() = root;
+    invalidate_listings();
+    let current = get_current_path();
+    if set_current_path(current).is_err() {
+        *CURRENT_PATH.lock() = Vec::new();
+    }
+    Ok(())
+}
+
+pub fn delete_snapshot(name: &str) -> Result<(), &'static str> {
+    let mut snapshots = SNAPSHOTS.lock();
+    let index = snapshots
+        .iter()
+        .position(|snapshot| snapshot.name == name)
+        .ok_or("No such snapshot")?;
+    snapshots.remove(index);
+    Ok(())
+}
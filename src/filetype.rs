@@ -0,0 +1,201 @@
+// ファイルの種類を先頭のバイト (マジックナンバー) から見分ける。file コマンドのほか、
+// 中身によって扱いを変えたいもの (ページャなど) が使う。先頭の HEADER_SIZE バイトあれば足りる
+use alloc::format;
+use alloc::string::String;
+
+pub const HEADER_SIZE: usize = 512;
+
+// tar のヘッダの "ustar" の位置
+const TAR_MAGIC_OFFSET: usize = 257;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileType {
+    Empty,
+    Elf {
+        bits: u8,
+        executable: bool,
+        machine: u16,
+    },
+    // カーネル拡張 (kmod の .rkx)
+    KernelExtension,
+    Bmp {
+        width: i32,
+        height: i32,
+        bits: u16,
+    },
+    Png {
+        width: u32,
+        height: u32,
+    },
+    Gif,
+    Jpeg,
+    Tar,
+    Gzip,
+    Zip,
+    Pdf,
+    // #! の後ろのインタプリタ
+    Script(String),
+    Text {
+        ascii: bool,
+    },
+    Data,
+}
+
+fn u16_le(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn u32_le(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn u32_be(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn elf(data: &[u8]) -> Option<FileType> {
+    let bits = match data.get(4)? {
+        1 => 32,
+        2 => 64,
+        _ => return None,
+    };
+    // リトルエンディアンのものだけ読む
+    if *data.get(5)? != 1 {
+        return None;
+    }
+    Some(FileType::Elf {
+        bits,
+        // ET_EXEC か ET_DYN
+        executable: matches!(u16_le(data, 16)?, 2 | 3),
+        machine: u16_le(data, 18)?,
+    })
+}
+
+// 制御文字がタブ、改行、改ページ、エスケープだけの UTF-8 ならテキスト。
+// 先頭だけを見ているので、末尾で文字が切れているのは許す
+fn text(data: &[u8]) -> Option<FileType> {
+    let valid = match core::str::from_utf8(data) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => core::str::from_utf8(&data[..e.valid_up_to()]).ok()?,
+        Err(_) => return None,
+    };
+    let printable = valid
+        .chars()
+        .all(|c| !c.is_control() || matches!(c, '\t' | '\n' | '\r' | '\x0c' | '\x1b'));
+    if !printable {
+        return None;
+    }
+    Some(FileType::Text {
+        ascii: data.is_ascii(),
+    })
+}
+
+pub fn detect(data: &[u8]) -> FileType {
+    if data.is_empty() {
+        return FileType::Empty;
+    }
+    if data.starts_with(b"\x7fELF") {
+        if let Some(elf) = elf(data) {
+            return elf;
+        }
+    }
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        if let (Some(width), Some(height)) = (u32_be(data, 16), u32_be(data, 20)) {
+            return FileType::Png { width, height };
+        }
+    }
+    if data.starts_with(b"BM") {
+        let header = (u32_le(data, 18), u32_le(data, 22), u16_le(data, 28));
+        if let (Some(width), Some(height), Some(bits)) = header {
+            return FileType::Bmp {
+                width: width as i32,
+                height: height as i32,
+                bits,
+            };
+        }
+    }
+    if data.get(TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + 5) == Some(&b"ustar"[..]) {
+        return FileType::Tar;
+    }
+    let simple: &[(&[u8], FileType)] = &[
+        (b"RKX1", FileType::KernelExtension),
+        (b"GIF87a", FileType::Gif),
+        (b"GIF89a", FileType::Gif),
+        (b"\xff\xd8\xff", FileType::Jpeg),
+        (b"\x1f\x8b", FileType::Gzip),
+        (b"PK\x03\x04", FileType::Zip),
+        (b"%PDF-", FileType::Pdf),
+    ];
+    for (magic, file_type) in simple {
+        if data.starts_with(magic) {
+            return file_type.clone();
+        }
+    }
+    if let Some(rest) = data.strip_prefix(b"#!") {
+        let line = rest.split(|&byte| byte == b'\n').next().unwrap_or(rest);
+        if let Ok(line) = core::str::from_utf8(line) {
+            let interpreter = line.split_whitespace().next().unwrap_or("");
+            return FileType::Script(String::from(interpreter));
+        }
+    }
+    text(data).unwrap_or(FileType::Data)
+}
+
+impl FileType {
+    // 画面にそのまま出してよいもの
+    pub fn is_text(&self) -> bool {
+        matches!(
+            self,
+            FileType::Empty | FileType::Text { .. } | FileType::Script(_)
+        )
+    }
+
+    pub fn description(&self) -> String {
+        match self {
+            FileType::Empty => String::from("empty"),
+            FileType::Elf {
+                bits,
+                executable,
+                machine,
+            } => {
+                let kind = if *executable {
+                    "executable"
+                } else {
+                    "relocatable"
+                };
+                let machine = match machine {
+                    0x03 => String::from("Intel 80386"),
+                    0x3e => String::from("x86-64"),
+                    0xb7 => String::from("ARM aarch64"),
+                    0xf3 => String::from("RISC-V"),
+                    other => format!("machine {:#x}", other),
+                };
+                format!("ELF {}-bit LSB {}, {}", bits, kind, machine)
+            }
+            FileType::KernelExtension => String::from("ROS kernel extension"),
+            FileType::Bmp {
+                width,
+                height,
+                bits,
+            } => format!("PC bitmap, {} x {} x {}", width, height.abs(), bits),
+            FileType::Png { width, height } => format!("PNG image data, {} x {}", width, height),
+            FileType::Gif => String::from("GIF image data"),
+            FileType::Jpeg => String::from("JPEG image data"),
+            FileType::Tar => String::from("POSIX tar archive"),
+            FileType::Gzip => String::from("gzip compressed data"),
+            FileType::Zip => String::from("Zip archive data"),
+            FileType::Pdf => String::from("PDF document"),
+            FileType::Script(interpreter) if interpreter.is_empty() => String::from("script text"),
+            FileType::Script(interpreter) => format!("{} script text", interpreter),
+            FileType::Text { ascii: true } => String::from("ASCII text"),
+            FileType::Text { ascii: false } => String::from("UTF-8 text"),
+            FileType::Data => String::from("data"),
+        }
+    }
+}
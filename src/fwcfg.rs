@@ -0,0 +1,158 @@
+// QEMU の fw_cfg。ホストが -fw_cfg name=opt/<名前>,file=<パス> で渡したファイルを
+// /host/<名前> として読み出し専用で見せる。procfs と同じく空のファイルを置いておき、
+// 読まれるたびにデバイスから中身を取ってくる
+use crate::device::{self, DeviceClass};
+use crate::filesystem;
+use crate::hal::port::PortRange;
+use crate::klog;
+use crate::log::Level;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+// 0x510 がセレクタ (16 ビット)、0x511 がデータ (1 バイトずつ読む)
+static FW_CFG_PORTS: PortRange = unsafe { PortRange::new("fw_cfg", 0x510, 2) };
+const SELECTOR: u16 = 0;
+const DATA: u16 = 1;
+
+const SIGNATURE_KEY: u16 = 0x0000;
+const FILE_DIR_KEY: u16 = 0x0019;
+const NAME_LEN: usize = 56;
+// ユーザーが渡すファイルは opt/ から始まる決まり。QEMU 自身のものは見せない
+const USER_PREFIX: &str = "opt/";
+const MOUNT_POINT: &str = "host";
+
+struct Entry {
+    // /host より下の要素
+    path: Vec<String>,
+    key: u16,
+    size: u32,
+}
+
+static FILES: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+
+fn select(key: u16) {
+    FW_CFG_PORTS.write::<u16>(SELECTOR, key);
+}
+
+fn read_bytes(buffer: &mut [u8]) {
+    for byte in buffer.iter_mut() {
+        *byte = FW_CFG_PORTS.read(DATA);
+    }
+}
+
+// ファイルディレクトリの数値はビッグエンディアン
+fn read_be<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    read_bytes(&mut bytes);
+    bytes
+}
+
+fn present() -> bool {
+    select(SIGNATURE_KEY);
+    let mut signature = [0; 4];
+    read_bytes(&mut signature);
+    &signature == b"QEMU"
+}
+
+fn read_directory() -> Vec<Entry> {
+    select(FILE_DIR_KEY);
+    let count = u32::from_be_bytes(read_be());
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let size = u32::from_be_bytes(read_be());
+        let key = u16::from_be_bytes(read_be());
+        let _reserved: [u8; 2] = read_be();
+        let name: [u8; NAME_LEN] = read_be();
+        let len = name.iter().position(|&byte| byte == 0).unwrap_or(NAME_LEN);
+        let name = String::from_utf8_lossy(&name[..len]);
+        if let Some(rest) = name.strip_prefix(USER_PREFIX) {
+            let path: Vec<String> = rest
+                .split('/')
+                .filter(|part| !part.is_empty())
+                .map(String::from)
+                .collect();
+            if !path.is_empty() {
+                entries.push(Entry { path, key, size });
+            }
+        }
+    }
+    entries
+}
+
+pub fn init() -> Result<(), &'static str> {
+    if !present() {
+        klog!(Level::Debug, "fw_cfg: not present");
+        return Ok(());
+    }
+    FW_CFG_PORTS.claim()?;
+    let isa = device::find("isa").ok_or("isa bus not registered")?;
+    device::register(
+        "fw_cfg",
+        DeviceClass::Other,
+        Some(isa),
+        &[FW_CFG_PORTS.resource()],
+    );
+
+    let entries = read_directory();
+    filesystem::create_directory(&format!("/{}", MOUNT_POINT), true)?;
+    for entry in entries.iter() {
+        let path = format!("/{}/{}", MOUNT_POINT, entry.path.join("/"));
+        if let Some((parent, _)) = path.rsplit_once('/') {
+            filesystem::create_directory(parent, true)?;
+        }
+        filesystem::touch(&path, true)?;
+    }
+    klog!(
+        Level::Info,
+        "fw_cfg: {} host file(s) in /{}",
+        entries.len(),
+        MOUNT_POINT
+    );
+    *FILES.lock() = entries;
+    filesystem::mount(&format!("/{}", MOUNT_POINT), "fw_cfg", true)
+}
+
+// path が指す /host のファイルの (キー, 大きさ)
+fn lookup(path: &str) -> Option<(u16, u32)> {
+    let files = FILES.lock();
+    if files.is_empty() {
+        return None;
+    }
+    let parts = filesystem::resolve_path(path);
+    let (dir, rest) = parts.split_first()?;
+    if dir.as_str() != MOUNT_POINT {
+        return None;
+    }
+    let entry = files.iter().find(|entry| entry.path == rest)?;
+    Some((entry.key, entry.size))
+}
+
+// path が /host のファイルを指していれば、その中身をデバイスから読んで返す
+pub fn read(path: &str) -> Option<Vec<u8>> {
+    let (key, size) = lookup(path)?;
+    select(key);
+    let mut content = vec![0; size as usize];
+    read_bytes(&mut content);
+    Some(content)
+}
+
+// ヒープに収まらない大きなファイル用。中身を少しずつ f に渡す。/host のファイルでなければ false
+pub fn read_with(path: &str, mut f: impl FnMut(&[u8])) -> bool {
+    let (key, size) = match lookup(path) {
+        Some(found) => found,
+        None => return false,
+    };
+    select(key);
+    let mut chunk = [0; 512];
+    let mut remaining = size as usize;
+    while remaining > 0 {
+        let len = remaining.min(chunk.len());
+        read_bytes(&mut chunk[..len]);
+        f(&chunk[..len]);
+        remaining -= len;
+    }
+    true
+}
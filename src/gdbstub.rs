@@ -0,0 +1,309 @@
+use crate::arch::x86::memory;
+use crate::println;
+use crate::serial::SERIAL2;
+use crate::virtio_console;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+const SIGTRAP: u8 = 5;
+const TRAP_FLAG: u64 = 1 << 8;
+const INT3: u8 = 0xcc;
+
+static ATTACHED: AtomicBool = AtomicBool::new(false);
+static BREAKPOINTS: Mutex<BTreeMap<u64, u8>> = Mutex::new(BTreeMap::new());
+
+pub fn is_attached() -> bool {
+    ATTACHED.load(Ordering::SeqCst)
+}
+
+pub fn attach() {
+    let port = if use_virtio() { "hvc2" } else { "COM2" };
+    println!("gdb: waiting for debugger on {}", port);
+    ATTACHED.store(true, Ordering::SeqCst);
+    x86_64::instructions::interrupts::int3();
+}
+
+pub fn handle_breakpoint(stack_frame: &mut InterruptStackFrame) {
+    // int3 の後ろを指しているので、挿入したブレークポイントなら一つ戻す
+    let hit = stack_frame.instruction_pointer.as_u64() - 1;
+    if BREAKPOINTS.lock().contains_key(&hit) {
+        unsafe {
+            stack_frame
+                .as_mut()
+                .update(|frame| frame.instruction_pointer = VirtAddr::new(hit));
+        }
+    }
+    enter(stack_frame);
+}
+
+pub fn handle_debug(stack_frame: &mut InterruptStackFrame) {
+    enter(stack_frame);
+}
+
+fn enter(stack_frame: &mut InterruptStackFrame) {
+    send_packet(&stop_reply());
+
+    loop {
+        let packet = receive_packet();
+        let packet = packet.as_str();
+        match packet.as_bytes().first() {
+            Some(b'?') => send_packet(&stop_reply()),
+            Some(b'g') => send_packet(&read_registers(stack_frame)),
+            Some(b'm') => send_packet(&read_memory(&packet[1..])),
+            Some(b'M') => send_packet(write_memory(&packet[1..])),
+            Some(b'Z') => send_packet(insert_breakpoint(&packet[1..])),
+            Some(b'z') => send_packet(remove_breakpoint(&packet[1..])),
+            Some(b'c') => {
+                set_trap_flag(stack_frame, false);
+                return;
+            }
+            Some(b's') => {
+                set_trap_flag(stack_frame, true);
+                return;
+            }
+            Some(b'D') | Some(b'k') => {
+                detach();
+                set_trap_flag(stack_frame, false);
+                if packet.starts_with('D') {
+                    send_packet("OK");
+                }
+                return;
+            }
+            _ if packet.starts_with("qSupported") => send_packet("PacketSize=1000"),
+            _ if packet.starts_with("qAttached") => send_packet("1"),
+            _ => send_packet(""),
+        }
+    }
+}
+
+fn stop_reply() -> String {
+    let mut reply = String::new();
+    let _ = write!(reply, "S{:02x}", SIGTRAP);
+    reply
+}
+
+fn detach() {
+    let mut breakpoints = BREAKPOINTS.lock();
+    for (&addr, &original) in breakpoints.iter() {
+        unsafe { write_text_byte(addr, original) };
+    }
+    breakpoints.clear();
+    ATTACHED.store(false, Ordering::SeqCst);
+}
+
+fn set_trap_flag(stack_frame: &mut InterruptStackFrame, enabled: bool) {
+    unsafe {
+        stack_frame.as_mut().update(|frame| {
+            if enabled {
+                frame.cpu_flags |= TRAP_FLAG;
+            } else {
+                frame.cpu_flags &= !TRAP_FLAG;
+            }
+        });
+    }
+}
+
+// 汎用レジスタは割り込みフレームに無いので "unavailable" として返す
+fn read_registers(stack_frame: &InterruptStackFrame) -> String {
+    let mut reply = String::new();
+    for index in 0..16 {
+        if index == 7 {
+            push_hex_le(&mut reply, stack_frame.stack_pointer.as_u64(), 8);
+        } else {
+            reply.push_str("xxxxxxxxxxxxxxxx");
+        }
+    }
+    push_hex_le(&mut reply, stack_frame.instruction_pointer.as_u64(), 8);
+    push_hex_le(&mut reply, stack_frame.cpu_flags, 4);
+    push_hex_le(&mut reply, stack_frame.code_segment, 4);
+    push_hex_le(&mut reply, stack_frame.stack_segment, 4);
+    for _ in 0..4 {
+        reply.push_str("xxxxxxxx");
+    }
+    reply
+}
+
+fn read_memory(args: &str) -> String {
+    let (addr, len) = match parse_addr_len(args) {
+        Some(range) => range,
+        None => return String::from("E01"),
+    };
+    if !memory::is_range_mapped(addr, len, false) {
+        return String::from("E14");
+    }
+
+    let mut reply = String::new();
+    for offset in 0..len {
+        let byte = unsafe { core::ptr::read_volatile((addr + offset) as *const u8) };
+        let _ = write!(reply, "{:02x}", byte);
+    }
+    reply
+}
+
+fn write_memory(args: &str) -> &'static str {
+    let (range, data) = match args.split_once(':') {
+        Some(parts) => parts,
+        None => return "E01",
+    };
+    let (addr, len) = match parse_addr_len(range) {
+        Some(range) => range,
+        None => return "E01",
+    };
+    let bytes = match decode_hex(data) {
+        Some(bytes) if bytes.len() as u64 == len => bytes,
+        _ => return "E01",
+    };
+    if !memory::is_range_mapped(addr, len, false) {
+        return "E14";
+    }
+
+    for (offset, &byte) in bytes.iter().enumerate() {
+        unsafe { write_text_byte(addr + offset as u64, byte) };
+    }
+    "OK"
+}
+
+fn insert_breakpoint(args: &str) -> &'static str {
+    let addr = match parse_breakpoint(args) {
+        Some(addr) => addr,
+        None => return "",
+    };
+    if !memory::is_range_mapped(addr, 1, false) {
+        return "E14";
+    }
+
+    let mut breakpoints = BREAKPOINTS.lock();
+    if !breakpoints.contains_key(&addr) {
+        let original = unsafe { core::ptr::read_volatile(addr as *const u8) };
+        breakpoints.insert(addr, original);
+        unsafe { write_text_byte(addr, INT3) };
+    }
+    "OK"
+}
+
+fn remove_breakpoint(args: &str) -> &'static str {
+    let addr = match parse_breakpoint(args) {
+        Some(addr) => addr,
+        None => return "",
+    };
+
+    if let Some(original) = BREAKPOINTS.lock().remove(&addr) {
+        unsafe { write_text_byte(addr, original) };
+    }
+    "OK"
+}
+
+// ソフトウェアブレークポイントのみ対応 ("0,addr,kind")
+fn parse_breakpoint(args: &str) -> Option<u64> {
+    let mut fields = args.split(',');
+    if fields.next()? != "0" {
+        return None;
+    }
+    u64::from_str_radix(fields.next()?, 16).ok()
+}
+
+// コード領域は読み取り専用なので CR0.WP を一時的に外して書き込む
+unsafe fn write_text_byte(addr: u64, byte: u8) {
+    let flags = Cr0::read();
+    Cr0::write(flags - Cr0Flags::WRITE_PROTECT);
+    core::ptr::write_volatile(addr as *mut u8, byte);
+    Cr0::write(flags);
+}
+
+fn parse_addr_len(args: &str) -> Option<(u64, u64)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((
+        u64::from_str_radix(addr, 16).ok()?,
+        u64::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+fn decode_hex(data: &str) -> Option<Vec<u8>> {
+    if data.len() % 2 != 0 {
+        return None;
+    }
+    (0..data.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(data.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn push_hex_le(out: &mut String, value: u64, bytes: usize) {
+    for byte in value.to_le_bytes().iter().take(bytes) {
+        let _ = write!(out, "{:02x}", byte);
+    }
+}
+
+fn receive_packet() -> String {
+    loop {
+        while receive_byte() != b'$' {}
+
+        let mut packet = String::new();
+        let mut checksum: u8 = 0;
+        loop {
+            let byte = receive_byte();
+            if byte == b'#' {
+                break;
+            }
+            checksum = checksum.wrapping_add(byte);
+            packet.push(byte as char);
+        }
+
+        let high = (receive_byte() as char).to_digit(16);
+        let low = (receive_byte() as char).to_digit(16);
+        match (high, low) {
+            (Some(high), Some(low)) if (high * 16 + low) as u8 == checksum => {
+                send_bytes(b"+");
+                return packet;
+            }
+            _ => send_bytes(b"-"),
+        }
+    }
+}
+
+fn send_packet(data: &str) {
+    let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+    let mut frame = String::new();
+    let _ = write!(frame, "${}#{:02x}", data, checksum);
+    loop {
+        send_bytes(frame.as_bytes());
+        if receive_byte() == b'+' {
+            return;
+        }
+    }
+}
+
+// hvc2 (virtio-console) があればそちらを、無ければ COM2 を使う
+fn use_virtio() -> bool {
+    virtio_console::is_present(virtio_console::GDB)
+}
+
+fn receive_byte() -> u8 {
+    if use_virtio() {
+        loop {
+            if let Some(byte) = virtio_console::try_receive(virtio_console::GDB) {
+                return byte;
+            }
+            core::hint::spin_loop();
+        }
+    }
+    SERIAL2.lock().receive()
+}
+
+fn send_bytes(bytes: &[u8]) {
+    if use_virtio() {
+        virtio_console::write(virtio_console::GDB, bytes);
+        return;
+    }
+    let mut serial = SERIAL2.lock();
+    for &byte in bytes {
+        serial.send(byte);
+    }
+}
@@ -0,0 +1,87 @@
+// I/O ポートへのアクセスをまとめる。
+// ドライバは使う範囲を PortRange として持ち、初期化時に claim して台帳に載せる。
+// 別のドライバがすでに持っている範囲と重なれば claim がエラーになる。
+
+use crate::device::Resource;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::port::{Port, PortRead, PortWrite};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    owner: &'static str,
+    start: u16,
+    len: u16,
+}
+
+static CLAIMS: Mutex<Vec<PortRange>> = Mutex::new(Vec::new());
+
+impl PortRange {
+    // 呼び出し側は、この範囲が owner のデバイスのもので、
+    // 読み書きしてもメモリ安全性を壊さないことを保証する
+    pub const unsafe fn new(owner: &'static str, start: u16, len: u16) -> PortRange {
+        PortRange { owner, start, len }
+    }
+
+    pub fn owner(&self) -> &'static str {
+        self.owner
+    }
+
+    pub fn start(&self) -> u16 {
+        self.start
+    }
+
+    fn end(&self) -> u32 {
+        self.start as u32 + self.len as u32
+    }
+
+    fn overlaps(&self, other: &PortRange) -> bool {
+        (self.start as u32) < other.end() && (other.start as u32) < self.end()
+    }
+
+    pub fn resource(&self) -> Resource {
+        Resource::IoPorts {
+            start: self.start,
+            len: self.len,
+        }
+    }
+
+    pub fn claim(&self) -> Result<(), &'static str> {
+        let mut claims = CLAIMS.lock();
+        match claims.iter().find(|claim| claim.overlaps(self)) {
+            Some(claim) if claim == self => Ok(()),
+            Some(_) => Err("I/O ports already claimed by another driver"),
+            None => {
+                claims.push(*self);
+                Ok(())
+            }
+        }
+    }
+
+    fn port<T>(&self, offset: u16) -> Port<T> {
+        assert!(offset < self.len, "port offset outside of claimed range");
+        Port::new(self.start + offset)
+    }
+
+    pub fn read<T: PortRead>(&self, offset: u16) -> T {
+        unsafe { self.port::<T>(offset).read() }
+    }
+
+    pub fn write<T: PortWrite>(&self, offset: u16, value: T) {
+        unsafe { self.port::<T>(offset).write(value) }
+    }
+}
+
+pub fn owner_of(port: u16) -> Option<&'static str> {
+    CLAIMS
+        .lock()
+        .iter()
+        .find(|claim| (claim.start as u32) <= port as u32 && (port as u32) < claim.end())
+        .map(|claim| claim.owner)
+}
+
+pub fn claims() -> Vec<PortRange> {
+    let mut claims = CLAIMS.lock().clone();
+    claims.sort_by_key(|claim| claim.start);
+    claims
+}
@@ -0,0 +1,95 @@
+use crate::klog;
+use crate::log::Level;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    Early,
+    Irq,
+    Bus,
+    Device,
+    Late,
+}
+
+pub const STAGES: [Stage; 5] = [
+    Stage::Early,
+    Stage::Irq,
+    Stage::Bus,
+    Stage::Device,
+    Stage::Late,
+];
+
+pub struct InitCall {
+    pub name: &'static str,
+    pub stage: Stage,
+    pub depends_on: &'static [&'static str],
+    pub run: fn() -> Result<(), &'static str>,
+}
+
+// ヒープ初期化前にも動くように状態はビットマスクで持つ
+pub fn run_all(calls: &[InitCall]) {
+    assert!(calls.len() <= 64, "too many init calls");
+    let mut done: u64 = 0;
+    let mut failed: u64 = 0;
+
+    let index_of = |name: &str| calls.iter().position(|call| call.name == name);
+
+    for stage in STAGES.iter() {
+        loop {
+            let mut progressed = false;
+            for (index, call) in calls.iter().enumerate() {
+                let bit = 1 << index;
+                if call.stage != *stage || (done | failed) & bit != 0 {
+                    continue;
+                }
+
+                let mut ready = true;
+                let mut blocked_by = None;
+                for dependency in call.depends_on.iter() {
+                    match index_of(dependency) {
+                        Some(dep) if calls[dep].stage > call.stage => {
+                            blocked_by = Some(*dependency);
+                        }
+                        Some(dep) if failed & (1 << dep) != 0 => blocked_by = Some(*dependency),
+                        Some(dep) if done & (1 << dep) == 0 => ready = false,
+                        Some(_) => {}
+                        None => blocked_by = Some(*dependency),
+                    }
+                }
+
+                if let Some(dependency) = blocked_by {
+                    klog!(
+                        Level::Error,
+                        "init: {} skipped, dependency {} unavailable",
+                        call.name,
+                        dependency
+                    );
+                    failed |= bit;
+                    progressed = true;
+                } else if ready {
+                    match (call.run)() {
+                        Ok(()) => {
+                            klog!(Level::Debug, "init: {} ok", call.name);
+                            done |= bit;
+                        }
+                        Err(e) => {
+                            klog!(Level::Error, "init: {} failed: {}", call.name, e);
+                            failed |= bit;
+                        }
+                    }
+                    progressed = true;
+                    crate::splash::progress((done | failed).count_ones() as usize, calls.len());
+                }
+            }
+            if !progressed {
+                break;
+            }
+        }
+
+        for (index, call) in calls.iter().enumerate() {
+            if call.stage == *stage && (done | failed) & (1 << index) == 0 {
+                klog!(Level::Error, "init: {} has a dependency cycle", call.name);
+                failed |= 1 << index;
+            }
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use crate::arch::x86::memory;
+use crate::peek::parse_number;
+use crate::{keyboard, ksym, print, println, task};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, Keyboard, ScancodeSet1};
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::registers::rflags;
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+
+const LINE_MAX: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    Panic,
+    Hotkey,
+}
+
+pub fn enter(reason: Reason) {
+    x86_64::instructions::interrupts::without_interrupts(|| monitor(reason));
+}
+
+fn monitor(reason: Reason) {
+    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
+    println!();
+    println!("kdb: entered ({:?}), type 'help' for commands", reason);
+
+    loop {
+        print!("kdb> ");
+        let mut buffer = [0u8; LINE_MAX];
+        let len = read_line(&mut keyboard, &mut buffer);
+        let line = core::str::from_utf8(&buffer[..len]).unwrap_or("");
+        let mut args = line.split_whitespace();
+
+        match args.next() {
+            Some("help") => {
+                println!("  regs               - Show control registers and flags");
+                println!("  mem <addr> [len]   - Dump memory");
+                println!("  pt                 - Show the top-level page table");
+                println!("  tasks              - List tasks");
+                println!("  bt                 - Show a backtrace of the debugger entry");
+                println!("  sym <addr>         - Resolve an address to a symbol");
+                println!("  resume             - Leave the debugger");
+                println!("  reboot             - Reset the machine");
+            }
+            Some("regs") => dump_registers(),
+            Some("mem") => match args.next().and_then(parse_number) {
+                Some(addr) => dump_memory(addr, args.next().and_then(parse_number).unwrap_or(64)),
+                None => println!("usage: mem <addr> [len]"),
+            },
+            Some("pt") => dump_page_table(),
+            Some("tasks") => dump_tasks(),
+            Some("bt") => ksym::print_backtrace(),
+            Some("sym") => match args.next().and_then(parse_number) {
+                Some(addr) => ksym::print_symbol(addr),
+                None => println!("usage: sym <addr>"),
+            },
+            Some("resume") if reason == Reason::Hotkey => return,
+            Some("resume") => println!("cannot resume after a panic"),
+            Some("reboot") => reboot(),
+            Some(command) => println!("unknown command: {}", command),
+            None => {}
+        }
+    }
+}
+
+// 割り込みが使えないので PS/2 コントローラを直接ポーリングする
+fn read_line(keyboard: &mut Keyboard<layouts::Us104Key, ScancodeSet1>, buffer: &mut [u8]) -> usize {
+    let mut len = 0;
+
+    loop {
+        if keyboard::I8042_COMMAND.read::<u8>(0) & 1 == 0 {
+            core::hint::spin_loop();
+            continue;
+        }
+        let scancode: u8 = keyboard::I8042_DATA.read(0);
+        let key = match keyboard.add_byte(scancode) {
+            Ok(Some(event)) => keyboard.process_keyevent(event),
+            _ => None,
+        };
+
+        match key {
+            Some(DecodedKey::Unicode('\n')) => {
+                println!();
+                return len;
+            }
+            Some(DecodedKey::Unicode('\x08')) | Some(DecodedKey::RawKey(KeyCode::Backspace)) => {
+                if len > 0 {
+                    len -= 1;
+                    if let Some(mut writer) = crate::vga_buffer::WRITER.try_lock() {
+                        writer.backspace();
+                    }
+                }
+            }
+            Some(DecodedKey::Unicode(c)) if c.is_ascii() && !c.is_ascii_control() => {
+                if len < buffer.len() {
+                    buffer[len] = c as u8;
+                    len += 1;
+                    print!("{}", c);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn dump_registers() {
+    let (frame, _) = Cr3::read();
+    println!("cr0    {:?}", Cr0::read());
+    println!("cr2    {:#x}", Cr2::read().as_u64());
+    println!("cr3    {:#x}", frame.start_address().as_u64());
+    println!("cr4    {:?}", Cr4::read());
+    println!("rflags {:?}", rflags::read());
+}
+
+fn dump_memory(addr: u64, len: u64) {
+    if !memory::is_range_mapped(addr, len, false) {
+        println!("{:#x}: not mapped", addr);
+        return;
+    }
+
+    for row in (0..len).step_by(16) {
+        print!("{:016x}:", addr + row);
+        for offset in row..(row + 16).min(len) {
+            let byte = unsafe { core::ptr::read_volatile((addr + offset) as *const u8) };
+            print!(" {:02x}", byte);
+        }
+        println!();
+    }
+}
+
+fn dump_page_table() {
+    let (frame, _) = Cr3::read();
+    let virt = memory::physical_memory_offset() + frame.start_address().as_u64();
+    let table: &PageTable = unsafe { &*virt.as_ptr() };
+
+    for (index, entry) in table.iter().enumerate() {
+        if entry.flags().contains(PageTableFlags::PRESENT) {
+            println!(
+                "pml4[{:3}] -> {:#012x} {:?}",
+                index,
+                entry.addr().as_u64(),
+                entry.flags()
+            );
+        }
+    }
+}
+
+fn dump_tasks() {
+    match task::try_list() {
+        Some(tasks) if tasks.is_empty() => println!("no tasks"),
+        Some(tasks) => {
+            for (id, name) in tasks {
+                println!("{:4} {}", id, name);
+            }
+        }
+        None => println!("task list is locked"),
+    }
+}
+
+pub fn reboot() -> ! {
+    keyboard::I8042_COMMAND.write(0, 0xfeu8);
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
@@ -1,31 +1,243 @@
-use crate::interrupts::InterruptIndex;
+use crate::arch;
+use crate::compose::{self, Composer};
+use crate::device::{self, DeviceClass, Resource};
+use crate::hal::port::PortRange;
+use crate::recovery;
+use crate::sync::Mutex;
+use core::sync::atomic::{AtomicBool, Ordering};
 use lazy_static::lazy_static;
-use pc_keyboard::{layouts, HandleControl, Keyboard, ScancodeSet1};
-use spin::Mutex;
-use x86_64::instructions::port::Port;
+use pc_keyboard::{
+    layouts, DecodedKey, HandleControl, KeyCode, KeyState, Keyboard, KeyboardLayout, ScancodeSet1,
+};
+
+const QUEUE_SIZE: usize = 128;
+
+struct ScancodeQueue {
+    buffer: [u8; QUEUE_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl ScancodeQueue {
+    fn push(&mut self, scancode: u8) {
+        if self.len < QUEUE_SIZE {
+            self.buffer[(self.head + self.len) % QUEUE_SIZE] = scancode;
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let scancode = self.buffer[self.head];
+        self.head = (self.head + 1) % QUEUE_SIZE;
+        self.len -= 1;
+        Some(scancode)
+    }
+}
+
+// 割り込みハンドラではスキャンコードを溜めるだけにする
+static SCANCODES: Mutex<ScancodeQueue> = Mutex::new(
+    "scancodes",
+    ScancodeQueue {
+        buffer: [0; QUEUE_SIZE],
+        head: 0,
+        len: 0,
+    },
+);
+
+enum LayoutKeyboard {
+    Us(Keyboard<layouts::Us104Key, ScancodeSet1>),
+    // US 配列に ' ` ^ ~ " のデッドキーを足したもの
+    UsIntl(Keyboard<layouts::Us104Key, ScancodeSet1>),
+    Uk(Keyboard<layouts::Uk105Key, ScancodeSet1>),
+    Jis(Keyboard<layouts::Jis109Key, ScancodeSet1>),
+}
+
+impl LayoutKeyboard {
+    fn from_name(name: &str) -> Option<LayoutKeyboard> {
+        match name {
+            "us" => Some(LayoutKeyboard::Us(Keyboard::new(
+                layouts::Us104Key,
+                ScancodeSet1,
+                HandleControl::MapLettersToUnicode,
+            ))),
+            "us-intl" => Some(LayoutKeyboard::UsIntl(Keyboard::new(
+                layouts::Us104Key,
+                ScancodeSet1,
+                HandleControl::MapLettersToUnicode,
+            ))),
+            "uk" => Some(LayoutKeyboard::Uk(Keyboard::new(
+                layouts::Uk105Key,
+                ScancodeSet1,
+                HandleControl::MapLettersToUnicode,
+            ))),
+            "jis" => Some(LayoutKeyboard::Jis(Keyboard::new(
+                layouts::Jis109Key,
+                ScancodeSet1,
+                HandleControl::MapLettersToUnicode,
+            ))),
+            _ => None,
+        }
+    }
+
+    fn decode(&mut self, scancode: u8) -> Option<DecodedKey> {
+        match self {
+            LayoutKeyboard::Us(keyboard) | LayoutKeyboard::UsIntl(keyboard) => {
+                decode_with(keyboard, scancode)
+            }
+            LayoutKeyboard::Uk(keyboard) => decode_with(keyboard, scancode),
+            LayoutKeyboard::Jis(keyboard) => decode_with(keyboard, scancode),
+        }
+    }
+
+    fn has_dead_keys(&self) -> bool {
+        matches!(self, LayoutKeyboard::UsIntl(_))
+    }
+}
+
+// デッドキーや Compose の途中の状態
+static COMPOSER: Mutex<Composer> = Mutex::new("compose", Composer::new());
+
+static SHIFT_HELD: AtomicBool = AtomicBool::new(false);
+static CTRL_HELD: AtomicBool = AtomicBool::new(false);
+
+// 今デコードしたキーが Ctrl を押しながらのものか (Ctrl+矢印の単語移動用)
+pub fn ctrl_held() -> bool {
+    CTRL_HELD.load(Ordering::Relaxed)
+}
+
+fn decode_with<L: KeyboardLayout>(
+    keyboard: &mut Keyboard<L, ScancodeSet1>,
+    scancode: u8,
+) -> Option<DecodedKey> {
+    let key_event = match keyboard.add_byte(scancode) {
+        Ok(Some(key_event)) => key_event,
+        _ => return None,
+    };
+    match key_event.code {
+        KeyCode::ShiftLeft | KeyCode::ShiftRight => {
+            SHIFT_HELD.store(key_event.state == KeyState::Down, Ordering::Relaxed);
+        }
+        KeyCode::ControlLeft | KeyCode::ControlRight => {
+            CTRL_HELD.store(key_event.state == KeyState::Down, Ordering::Relaxed);
+        }
+        // 修飾キーなのでレイアウトは何も返さない。押されたことだけ Composer に伝える
+        KeyCode::AltRight if key_event.state == KeyState::Down => {
+            return Some(DecodedKey::RawKey(compose::COMPOSE_KEY));
+        }
+        // Shift+Insert は Ctrl+V と同じ貼り付けにする
+        KeyCode::Insert
+            if key_event.state == KeyState::Down && SHIFT_HELD.load(Ordering::Relaxed) =>
+        {
+            return Some(DecodedKey::Unicode('\u{16}'));
+        }
+        _ => {}
+    }
+    keyboard.process_keyevent(key_event)
+}
 
 lazy_static! {
-    static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(
-        Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore)
+    static ref KEYBOARD: Mutex<LayoutKeyboard> = Mutex::new(
+        "keyboard",
+        LayoutKeyboard::Us(Keyboard::new(
+            layouts::Us104Key,
+            ScancodeSet1,
+            HandleControl::MapLettersToUnicode
+        ))
+    );
+    static ref SHELL: Mutex<crate::shell::Shell> = Mutex::new("shell", crate::shell::Shell::new());
+}
+
+// PS/2 コントローラ。マウスと kdb もこれを使う
+pub static I8042_DATA: PortRange = unsafe { PortRange::new("i8042", 0x60, 1) };
+pub static I8042_COMMAND: PortRange = unsafe { PortRange::new("i8042", 0x64, 1) };
+
+pub fn register_device(bus: usize) -> Result<(), &'static str> {
+    I8042_DATA.claim()?;
+    I8042_COMMAND.claim()?;
+    device::register(
+        "ps2-keyboard",
+        DeviceClass::Input,
+        Some(bus),
+        &[
+            I8042_DATA.resource(),
+            I8042_COMMAND.resource(),
+            Resource::Irq(1),
+        ],
     );
-    static ref SHELL: Mutex<crate::shell::Shell> = Mutex::new(crate::shell::Shell::new());
+    Ok(())
 }
 
 pub fn handle_keyboard_interrupt() {
-    let mut keyboard = KEYBOARD.lock();
-    let mut shell = SHELL.lock();
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
+    let scancode: u8 = I8042_DATA.read(0);
+    SCANCODES.lock().push(scancode);
+}
 
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(decoded_key) = keyboard.process_keyevent(key_event) {
-            shell.handle_key(decoded_key);
+pub fn set_layout(name: &str) -> Result<(), &'static str> {
+    let keyboard = LayoutKeyboard::from_name(name).ok_or("Unknown keyboard layout")?;
+    *KEYBOARD.lock() = keyboard;
+    *COMPOSER.lock() = Composer::new();
+    Ok(())
+}
+
+pub fn has_pending() -> bool {
+    arch::without_interrupts(|| SCANCODES.lock().len > 0)
+}
+
+// Esc が押されていればキューから取り除いて true を返す (起動中の quiet 解除用)
+pub fn take_escape() -> bool {
+    const ESCAPE_PRESSED: u8 = 0x01;
+    arch::without_interrupts(|| {
+        let mut queue = SCANCODES.lock();
+        let len = queue.len;
+        let mut found = false;
+        for _ in 0..len {
+            if let Some(scancode) = queue.pop() {
+                if scancode == ESCAPE_PRESSED && !found {
+                    found = true;
+                } else {
+                    queue.push(scancode);
+                }
+            }
+        }
+        found
+    })
+}
+
+// panic から立ち直るとき用。ロックを握ったまま捨てられたシェルを解放して、
+// 途中だった入力を片付ける
+pub unsafe fn recover_shell() {
+    SHELL.force_unlock();
+    SHELL.lock().recover();
+}
+
+pub fn start_shell() {
+    recovery::guard(|| SHELL.lock().run_startup_script());
+    SHELL.lock().print_prompt();
+}
+
+pub fn process_pending() {
+    while let Some(scancode) = arch::without_interrupts(|| SCANCODES.lock().pop()) {
+        let (decoded_key, dead_keys) = {
+            let mut keyboard = KEYBOARD.lock();
+            (keyboard.decode(scancode), keyboard.has_dead_keys())
+        };
+        if let Some(decoded_key) = decoded_key {
+            let keys = COMPOSER.lock().feed(decoded_key, dead_keys);
+            for key in keys {
+                handle_key(key);
+            }
         }
     }
+    recovery::guard(|| SHELL.lock().replay_pending());
+}
 
-    unsafe {
-        crate::interrupts::PICS
-            .lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+// キーボード以外 (virtio-console など) からの入力もここからシェルに渡す
+pub fn handle_key(key: DecodedKey) {
+    if crate::screensaver::wake() {
+        return;
     }
+    recovery::guard(|| SHELL.lock().handle_key(key));
 }
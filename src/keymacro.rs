@@ -0,0 +1,83 @@
+// キーボードマクロの保存形式: 文字はそのまま、特殊キーは ESC + 1 文字
+use crate::filesystem;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use pc_keyboard::{DecodedKey, KeyCode};
+
+pub const MACRO_DIR: &str = "/etc/macros";
+
+const ESCAPE: char = '\u{1b}';
+
+const RAW_KEYS: &[(KeyCode, char)] = &[
+    (KeyCode::ArrowUp, 'U'),
+    (KeyCode::ArrowDown, 'D'),
+    (KeyCode::ArrowLeft, 'L'),
+    (KeyCode::ArrowRight, 'R'),
+    (KeyCode::Home, 'H'),
+    (KeyCode::End, 'E'),
+    (KeyCode::Insert, 'I'),
+    (KeyCode::Delete, 'X'),
+    (KeyCode::Backspace, 'B'),
+];
+
+pub fn encode(keys: &[DecodedKey]) -> String {
+    let mut text = String::new();
+    for key in keys {
+        match *key {
+            DecodedKey::Unicode(ESCAPE) => {
+                text.push(ESCAPE);
+                text.push(ESCAPE);
+            }
+            DecodedKey::Unicode(c) => text.push(c),
+            DecodedKey::RawKey(code) => {
+                // 保存できない特殊キーは落とす
+                if let Some(&(_, tag)) = RAW_KEYS.iter().find(|(raw, _)| *raw == code) {
+                    text.push(ESCAPE);
+                    text.push(tag);
+                }
+            }
+        }
+    }
+    text
+}
+
+pub fn decode(text: &str) -> Vec<DecodedKey> {
+    let mut keys = Vec::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != ESCAPE {
+            keys.push(DecodedKey::Unicode(c));
+            continue;
+        }
+        match chars.next() {
+            Some(ESCAPE) => keys.push(DecodedKey::Unicode(ESCAPE)),
+            Some(tag) => {
+                if let Some(&(code, _)) = RAW_KEYS.iter().find(|(_, raw)| *raw == tag) {
+                    keys.push(DecodedKey::RawKey(code));
+                }
+            }
+            None => {}
+        }
+    }
+    keys
+}
+
+fn path(name: &str) -> Result<String, &'static str> {
+    if name.is_empty() || name.contains('/') {
+        return Err("invalid macro name");
+    }
+    Ok(format!("{}/{}", MACRO_DIR, name))
+}
+
+pub fn save(name: &str, keys: &[DecodedKey]) -> Result<(), &'static str> {
+    let path = path(name)?;
+    filesystem::create_directory(MACRO_DIR, true)?;
+    filesystem::write_file(&path, encode(keys).as_bytes(), false)
+}
+
+pub fn load(name: &str) -> Result<Vec<DecodedKey>, &'static str> {
+    let content = filesystem::read_file(&path(name)?).map_err(|_| "no such macro")?;
+    let text = core::str::from_utf8(&content).map_err(|_| "macro file is not UTF-8")?;
+    Ok(decode(text))
+}
@@ -0,0 +1,223 @@
+// カーネル拡張 (.rkx) のローダー
+//
+// ファイル形式 (リトルエンディアン):
+//   magic        [u8; 4]   "RKX1"
+//   code_size    u32
+//   reloc_count  u32
+//   entry_offset u32       init 関数のコード内オフセット
+//   name         [u8; 16]  NUL 埋め
+//   code         [u8; code_size]
+//   relocs       [u32; reloc_count]  ロードアドレスを足す u64 のオフセット。
+//                                    u64 の値はイメージ内のオフセット (code_size 以下)
+//
+// init は `extern "C" fn(api: *const ApiTable) -> i32` で、0 を返すと成功。
+// 拡張は ApiTable 経由でしかカーネルに触れない。
+// コードは専用の領域に読み出し専用で置くので、拡張は自分のコードや定数に書き込めない。
+
+use crate::{arch, filesystem, print};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+const MAGIC: &[u8; 4] = b"RKX1";
+const HEADER_SIZE: usize = 32;
+pub const API_VERSION: u32 = 1;
+
+pub type CommandHandler = extern "C" fn(args: *const u8, args_len: usize) -> i32;
+
+#[repr(C)]
+pub struct ApiTable {
+    pub version: u32,
+    pub print: extern "C" fn(text: *const u8, len: usize),
+    pub register_command:
+        extern "C" fn(name: *const u8, name_len: usize, handler: CommandHandler) -> i32,
+}
+
+static API: ApiTable = ApiTable {
+    version: API_VERSION,
+    print: api_print,
+    register_command: api_register_command,
+};
+
+struct Module {
+    name: String,
+    size: usize,
+}
+
+struct Command {
+    name: String,
+    module: String,
+    handler: CommandHandler,
+}
+
+// 拡張のコードを置く仮想アドレスの範囲 (1 GiB)
+const CODE_AREA_START: u64 = 0x_6666_0000_0000;
+const CODE_AREA_END: u64 = CODE_AREA_START + (1 << 30);
+
+static NEXT_CODE: AtomicU64 = AtomicU64::new(CODE_AREA_START);
+static MODULES: Mutex<Vec<Module>> = Mutex::new(Vec::new());
+static COMMANDS: Mutex<Vec<Command>> = Mutex::new(Vec::new());
+static LOADING: Mutex<Option<String>> = Mutex::new(None);
+
+unsafe fn bytes_to_str<'a>(ptr: *const u8, len: usize) -> Option<&'a str> {
+    if ptr.is_null() || len > 4096 {
+        return None;
+    }
+    core::str::from_utf8(core::slice::from_raw_parts(ptr, len)).ok()
+}
+
+extern "C" fn api_print(text: *const u8, len: usize) {
+    if let Some(text) = unsafe { bytes_to_str(text, len) } {
+        print!("{}", text);
+    }
+}
+
+extern "C" fn api_register_command(
+    name: *const u8,
+    name_len: usize,
+    handler: CommandHandler,
+) -> i32 {
+    let name = match unsafe { bytes_to_str(name, name_len) } {
+        Some(name) if !name.is_empty() && !name.contains(char::is_whitespace) => name,
+        _ => return -1,
+    };
+    let module = match LOADING.lock().clone() {
+        Some(module) => module,
+        None => return -1,
+    };
+
+    let mut commands = COMMANDS.lock();
+    if commands.iter().any(|command| command.name == name) {
+        return -2;
+    }
+    commands.push(Command {
+        name: String::from(name),
+        module,
+        handler,
+    });
+    0
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+// 拡張のコードを置く領域を size バイト分マップして先頭を返す。アンロードは無いので
+// 前から順に使い、間に 1 ページ空けて隣の拡張にはみ出したらページフォルトにする
+fn allocate_code_region(size: usize) -> Result<u64, &'static str> {
+    let len = (size as u64 + 4095) & !4095;
+    let start = NEXT_CODE.fetch_add(len + 4096, Ordering::Relaxed);
+    if start + len > CODE_AREA_END {
+        return Err("no room for extension code");
+    }
+    arch::map_range(start, len, true)?;
+    Ok(start)
+}
+
+pub fn load(path: &str) -> Result<String, &'static str> {
+    let data = filesystem::read_file(path)?;
+    if data.len() < HEADER_SIZE || &data[..4] != MAGIC {
+        return Err("not a kernel extension");
+    }
+
+    let code_size = read_u32(&data, 4) as usize;
+    let reloc_count = read_u32(&data, 8) as usize;
+    let entry_offset = read_u32(&data, 12) as usize;
+    let name_bytes = &data[16..32];
+    let name_len = name_bytes.iter().position(|&b| b == 0).unwrap_or(16);
+    let name = core::str::from_utf8(&name_bytes[..name_len]).map_err(|_| "invalid module name")?;
+
+    let relocs_start = HEADER_SIZE + code_size;
+    if data.len() != relocs_start + reloc_count * 4 || entry_offset >= code_size {
+        return Err("truncated or malformed extension");
+    }
+    if MODULES.lock().iter().any(|module| module.name == name) {
+        return Err("module already loaded");
+    }
+
+    // 書き込む前に全部の再配置を確かめる。コード用のページは返せないので、
+    // 壊れたファイルで無駄にしないため
+    let mut relocs = Vec::with_capacity(reloc_count);
+    for index in 0..reloc_count {
+        let offset = read_u32(&data, relocs_start + index * 4) as usize;
+        if offset + 8 > code_size {
+            return Err("relocation out of range");
+        }
+        // 足す前の値はイメージ内のオフセット。終わりを指すものまでは認める
+        let value = read_u64(&data, HEADER_SIZE + offset);
+        if value > code_size as u64 {
+            return Err("relocation points outside the image");
+        }
+        relocs.push((offset, value));
+    }
+
+    // ヒープは実行できないので、コードは専用の領域に書き込める状態でマップして写し、
+    // 再配置を済ませてから読み出し専用・実行可能に切り替える
+    let base = allocate_code_region(code_size)?;
+    let code = base as *mut u8;
+    unsafe {
+        core::ptr::copy_nonoverlapping(data[HEADER_SIZE..].as_ptr(), code, code_size);
+        for &(offset, value) in &relocs {
+            (code.add(offset) as *mut u64).write_unaligned(base + value);
+        }
+    }
+    arch::make_executable(base, code_size as u64)?;
+
+    *LOADING.lock() = Some(String::from(name));
+    let init: extern "C" fn(*const ApiTable) -> i32 =
+        unsafe { core::mem::transmute(code.add(entry_offset)) };
+    let status = init(&API);
+    *LOADING.lock() = None;
+
+    if status != 0 {
+        COMMANDS.lock().retain(|command| command.module != name);
+        return Err("module init failed");
+    }
+
+    MODULES.lock().push(Module {
+        name: String::from(name),
+        size: code_size,
+    });
+    Ok(String::from(name))
+}
+
+pub fn list() -> Vec<(String, usize, Vec<String>)> {
+    let commands = COMMANDS.lock();
+    MODULES
+        .lock()
+        .iter()
+        .map(|module| {
+            let names = commands
+                .iter()
+                .filter(|command| command.module == module.name)
+                .map(|command| command.name.clone())
+                .collect();
+            (module.name.clone(), module.size, names)
+        })
+        .collect()
+}
+
+pub fn has_command(name: &str) -> bool {
+    COMMANDS.lock().iter().any(|command| command.name == name)
+}
+
+pub fn run_command(name: &str, args: &str) -> Option<i32> {
+    let handler = COMMANDS
+        .lock()
+        .iter()
+        .find(|command| command.name == name)
+        .map(|command| command.handler)?;
+    Some(handler(args.as_ptr(), args.len()))
+}
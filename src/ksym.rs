@@ -0,0 +1,62 @@
+use crate::{arch, println};
+
+const MAX_FRAMES: usize = 16;
+
+// build.rs が ksyms.txt ("<addr> <name>" の行) を埋め込む。残りは 0 埋め
+static TABLE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/ksyms.bin"));
+
+fn table_text() -> &'static str {
+    let end = TABLE.iter().position(|&b| b == 0).unwrap_or(TABLE.len());
+    core::str::from_utf8(&TABLE[..end]).unwrap_or("")
+}
+
+pub fn is_available() -> bool {
+    !table_text().is_empty()
+}
+
+pub fn lookup(addr: u64) -> Option<(&'static str, u64)> {
+    let mut best = None;
+    for line in table_text().lines() {
+        let (start, name) = match line.split_once(' ') {
+            Some(entry) => entry,
+            None => continue,
+        };
+        let start = match u64::from_str_radix(start, 16) {
+            Ok(start) => start,
+            Err(_) => continue,
+        };
+        if start > addr {
+            break;
+        }
+        best = Some((name, addr - start));
+    }
+    best
+}
+
+pub fn print_symbol(addr: u64) {
+    match lookup(addr) {
+        Some((name, offset)) => println!("{:#018x} {}+{:#x}", addr, name, offset),
+        None => println!("{:#018x} ??", addr),
+    }
+}
+
+// フレームポインタを辿る (force-frame-pointers が必要)
+pub fn print_backtrace() {
+    let mut rbp = arch::frame_pointer();
+
+    println!("backtrace:");
+    for _ in 0..MAX_FRAMES {
+        if rbp == 0 || rbp % 8 != 0 || !arch::is_range_mapped(rbp, 16, false) {
+            break;
+        }
+        let return_address = unsafe { *((rbp + 8) as *const u64) };
+        if return_address == 0 {
+            break;
+        }
+        print_symbol(return_address);
+        rbp = unsafe { *(rbp as *const u64) };
+    }
+    if !is_available() {
+        println!("(no symbol table embedded, build with tools/ksyms.sh)");
+    }
+}
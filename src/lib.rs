@@ -4,30 +4,312 @@
 
 extern crate alloc;
 
+use bootloader::BootInfo;
+use initcall::{InitCall, Stage};
+use spin::Once;
+
+pub mod acpi;
 pub mod allocator;
+pub mod arch;
+#[cfg(feature = "tests")]
+pub mod bench;
+pub mod bf;
+#[cfg(feature = "disk")]
+pub mod block;
+pub mod clipboard;
+pub mod compose;
+pub mod config;
+pub mod device;
+#[cfg(feature = "graphics")]
+pub mod fbcon;
 pub mod filesystem;
-pub mod gdt;
-pub mod interrupts;
+pub mod filetype;
+pub mod fwcfg;
+pub mod gdbstub;
+pub mod hal;
+pub mod initcall;
+pub mod kdb;
 pub mod keyboard;
-pub mod memory;
+pub mod keymacro;
+pub mod kmod;
+pub mod ksym;
+pub mod log;
+pub mod man;
+pub mod md5;
+pub mod mouse;
+pub mod name;
+#[cfg(feature = "net")]
+pub mod nc;
+#[cfg(feature = "net")]
+pub mod net;
+pub mod pager;
+pub mod pci;
+pub mod peek;
+pub mod power;
+pub mod procfs;
+pub mod rand;
+pub mod recovery;
+pub mod screensaver;
+pub mod secret;
+pub mod serial;
+pub mod service;
 pub mod shell;
+pub mod snake;
+pub mod speaker;
+pub mod splash;
+#[cfg(feature = "tests")]
+pub mod stress;
+pub mod swap;
+pub mod sync;
+pub mod syscall;
+pub mod task;
+pub mod time;
+pub mod timer;
+pub mod top;
+pub mod trash;
 pub mod vga_buffer;
+pub mod virtio;
+pub mod virtio_console;
+pub mod xmodem;
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
     panic!("allocation error: {:?}", layout)
 }
 
-pub fn init() {
-    gdt::init();
-    interrupts::init_idt();
-    unsafe { interrupts::PICS.lock().initialize() };
-    x86_64::instructions::interrupts::enable();
-    println!("Interrupts initialized");
+pub const FEATURES: &[(&str, bool)] = &[
+    ("net", cfg!(feature = "net")),
+    ("graphics", cfg!(feature = "graphics")),
+    // AP を起動するコードがまだ無いので、smp を付けても無効と表示する
+    ("smp", false),
+    ("disk", cfg!(feature = "disk")),
+    ("tests", cfg!(feature = "tests")),
+    ("quiet", cfg!(feature = "quiet")),
+];
+
+static BOOT_INFO: Once<&'static BootInfo> = Once::new();
+
+// 各サブシステムはステージと依存関係を宣言し、順番は initcall が決める
+const INIT_CALLS: &[InitCall] = &[
+    InitCall {
+        name: "cpu",
+        stage: Stage::Early,
+        depends_on: &[],
+        run: || {
+            arch::init_cpu();
+            Ok(())
+        },
+    },
+    InitCall {
+        name: "heap",
+        stage: Stage::Early,
+        depends_on: &[],
+        run: init_heap,
+    },
+    InitCall {
+        name: "smep-smap",
+        stage: Stage::Early,
+        depends_on: &["cpu", "heap"],
+        run: arch::x86::usercopy::init,
+    },
+    InitCall {
+        name: "pit",
+        stage: Stage::Irq,
+        depends_on: &[],
+        run: || {
+            time::init();
+            Ok(())
+        },
+    },
+    InitCall {
+        name: "pic",
+        stage: Stage::Irq,
+        depends_on: &["cpu", "pit"],
+        run: || {
+            arch::init_interrupt_controller();
+            arch::enable_interrupts();
+            klog!(log::Level::Info, "Interrupts initialized");
+            Ok(())
+        },
+    },
+    InitCall {
+        name: "platform",
+        stage: Stage::Bus,
+        depends_on: &["heap"],
+        run: || {
+            let platform = device::register("platform", device::DeviceClass::Bus, None, &[]);
+            device::register("isa", device::DeviceClass::Bus, Some(platform), &[]);
+            device::register("virtual", device::DeviceClass::Bus, None, &[]);
+            Ok(())
+        },
+    },
+    InitCall {
+        name: "pci",
+        stage: Stage::Bus,
+        depends_on: &["platform"],
+        run: pci::init,
+    },
+    InitCall {
+        name: "isa-devices",
+        stage: Stage::Device,
+        depends_on: &["platform", "pic"],
+        run: || {
+            let isa = device::find("isa").ok_or("isa bus not registered")?;
+            arch::x86::interrupts::register_devices(isa)?;
+            time::register_devices(isa)?;
+            keyboard::register_device(isa)?;
+            serial::register_devices(isa)?;
+            speaker::register_device(isa)?;
+            vga_buffer::register_device(isa)?;
+            power::claim_ports()?;
+            Ok(())
+        },
+    },
+    InitCall {
+        name: "apic-timer",
+        stage: Stage::Device,
+        depends_on: &["isa-devices", "heap"],
+        run: time::init_idle_timer,
+    },
+    InitCall {
+        name: "mouse",
+        stage: Stage::Device,
+        depends_on: &["isa-devices"],
+        run: || {
+            mouse::init()?;
+            mouse::register_device(device::find("isa").ok_or("isa bus not registered")?);
+            Ok(())
+        },
+    },
+    InitCall {
+        name: "virtio-console",
+        stage: Stage::Device,
+        depends_on: &["pci"],
+        run: virtio_console::init,
+    },
+    #[cfg(feature = "net")]
+    InitCall {
+        name: "net",
+        stage: Stage::Device,
+        depends_on: &["platform", "pci"],
+        run: net::init,
+    },
+    InitCall {
+        name: "rtc-clock",
+        stage: Stage::Device,
+        depends_on: &["isa-devices"],
+        run: || {
+            time::start_clock();
+            Ok(())
+        },
+    },
+    InitCall {
+        name: "config",
+        stage: Stage::Late,
+        depends_on: &["heap", "isa-devices"],
+        run: || {
+            config::load();
+            Ok(())
+        },
+    },
+    InitCall {
+        name: "procfs",
+        stage: Stage::Late,
+        depends_on: &["heap"],
+        run: procfs::init,
+    },
+    InitCall {
+        name: "fw_cfg",
+        stage: Stage::Late,
+        depends_on: &["heap", "isa-devices"],
+        run: fwcfg::init,
+    },
+    InitCall {
+        name: "man",
+        stage: Stage::Late,
+        depends_on: &["heap"],
+        run: man::install,
+    },
+    InitCall {
+        name: "features",
+        stage: Stage::Late,
+        depends_on: &["heap"],
+        run: || {
+            report_features();
+            Ok(())
+        },
+    },
+];
+
+fn init_heap() -> Result<(), &'static str> {
+    let boot_info = BOOT_INFO.r#try().ok_or("boot info not available")?;
+    arch::init_memory(boot_info);
+    allocator::init_heap()
+}
+
+fn report_features() {
+    let (enabled, disabled): (alloc::vec::Vec<_>, alloc::vec::Vec<_>) =
+        FEATURES.iter().partition(|(_, enabled)| *enabled);
+    let names = |features: &[&(&str, bool)]| -> alloc::string::String {
+        if features.is_empty() {
+            return alloc::string::String::from("none");
+        }
+        let names: alloc::vec::Vec<&str> = features.iter().map(|(name, _)| *name).collect();
+        names.join(", ")
+    };
+    klog!(
+        log::Level::Info,
+        "Features: {} (disabled: {})",
+        names(&enabled),
+        names(&disabled)
+    );
+}
+
+pub fn init(boot_info: &'static BootInfo) {
+    BOOT_INFO.call_once(|| boot_info);
+    initcall::run_all(INIT_CALLS);
+    service::start_all();
+    splash::finish();
+}
+
+// メインループのスタック。ブートローダのスタックから、起動ごとに場所を変えたものへ移る。
+// 下のページは写像しないので、溢れればページフォルトになる
+const MAIN_STACK_START: u64 = 0x_5555_0000_0000;
+const MAIN_STACK_SIZE: u64 = 128 * 1024;
+const MAIN_STACK_SLIDE: usize = 1 << 30;
+
+pub fn run() -> ! {
+    let base = MAIN_STACK_START + rand::boot_offset(MAIN_STACK_SLIDE, 4096) as u64;
+    if let Err(e) = arch::map_range(base, MAIN_STACK_SIZE, true) {
+        klog!(log::Level::Warn, "Cannot map the main stack: {}", e);
+        start_main_loop();
+    }
+    // 一番上のページの中でも 16 バイト単位でずらす
+    let top = base + MAIN_STACK_SIZE - rand::boot_offset(4096, 16) as u64;
+    klog!(log::Level::Info, "Main stack at {:#x}", top);
+    unsafe { arch::call_on_stack(top, start_main_loop) }
+}
+
+extern "C" fn start_main_loop() -> ! {
+    recovery::set_restart_stack(arch::stack_pointer());
+    keyboard::start_shell();
+    main_loop()
+}
+
+pub(crate) fn main_loop() -> ! {
+    loop {
+        vga_buffer::flush_deferred();
+        keyboard::process_pending();
+        virtio_console::process_pending();
+        mouse::process_pending();
+        if !task::run_ready() {
+            time::idle(task::next_wakeup());
+        }
+    }
 }
 
 pub fn hlt_loop() -> ! {
     loop {
-        x86_64::instructions::hlt();
+        arch::halt();
     }
 }
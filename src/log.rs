@@ -0,0 +1,127 @@
+use alloc::string::String;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+impl Level {
+    pub fn from_name(name: &str) -> Option<Level> {
+        match name {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+// ヒープが無くても書けるように固定長のリングバッファに溜める。
+// 溢れたら古い方から捨てる
+const RING_SIZE: usize = 16 * 1024;
+
+struct Ring {
+    buffer: [u8; RING_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl Write for Ring {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.buffer[(self.start + self.len) % RING_SIZE] = byte;
+            if self.len < RING_SIZE {
+                self.len += 1;
+            } else {
+                self.start = (self.start + 1) % RING_SIZE;
+            }
+        }
+        Ok(())
+    }
+}
+
+static RING: Mutex<Ring> = Mutex::new(Ring {
+    buffer: [0; RING_SIZE],
+    start: 0,
+    len: 0,
+});
+
+// リングバッファの中身。先頭が途中で切れていたらその行は落とす
+pub fn contents() -> String {
+    let bytes: alloc::vec::Vec<u8> = crate::arch::without_interrupts(|| {
+        let ring = RING.lock();
+        (0..ring.len)
+            .map(|i| ring.buffer[(ring.start + i) % RING_SIZE])
+            .collect()
+    });
+    let text = String::from_utf8_lossy(&bytes);
+    if bytes.len() < RING_SIZE {
+        return text.into_owned();
+    }
+    match text.split_once('\n') {
+        Some((_, rest)) => String::from(rest),
+        None => String::new(),
+    }
+}
+
+// ログレベルに関係なくリングバッファには全部残す。画面には有効なレベルだけを出し、
+// quiet 起動中は警告とエラー以外をシリアルにだけ送る
+#[doc(hidden)]
+pub fn _log(level: Level, args: fmt::Arguments) {
+    let ms = crate::time::uptime_ms();
+    crate::arch::without_interrupts(|| {
+        let mut ring = RING.lock();
+        let _ = writeln!(ring, "[{:>5}.{:03}] {}", ms / 1000, ms % 1000, args);
+    });
+    // hvc1 にはレベルにかかわらず全部流す
+    let _ = writeln!(
+        crate::virtio_console::Writer(crate::virtio_console::LOG),
+        "[{:>5}.{:03}] {}",
+        ms / 1000,
+        ms % 1000,
+        args
+    );
+    if !enabled(level) {
+        return;
+    }
+    if crate::splash::is_quiet() && level > Level::Warn {
+        crate::serial_println!("{}", args);
+    } else {
+        crate::splash::clear();
+        crate::println!("{}", args);
+    }
+}
+
+#[macro_export]
+macro_rules! klog {
+    ($level:expr, $($arg:tt)*) => {
+        $crate::log::_log($level, format_args!($($arg)*))
+    };
+}
@@ -8,34 +8,32 @@ extern crate alloc;
 
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-use ros::{allocator, memory, print, println};
-use x86_64::VirtAddr;
+use ros::println;
 
 entry_point!(kernel_main);
 
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
-    ros::init();
+    ros::init(boot_info);
 
     println!("Welcome to ROS!");
-    print!("$ ");
-
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator =
-        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
-
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
 
     #[cfg(test)]
     test_main();
 
-    ros::hlt_loop();
+    ros::run();
 }
 
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    if ros::recovery::can_recover() {
+        ros::recovery::recover(info);
+    }
+    // 出力中の panic でもメッセージを出せるように、握られたままのロックを外す
+    unsafe { ros::vga_buffer::force_unlock() };
     println!("{}", info);
+    ros::ksym::print_backtrace();
+    ros::kdb::enter(ros::kdb::Reason::Panic);
     ros::hlt_loop();
 }
 
@@ -57,3 +55,101 @@ fn test_runner(tests: &[&dyn Fn()]) {
 fn trivial_assertion() {
     assert_eq!(1, 1);
 }
+
+#[test_case]
+fn no_writable_executable_mappings() {
+    assert!(ros::arch::x86::memory::wx_violations().is_empty());
+}
+
+// ユーザープロセスはまだ無いので、壊れた引数で dispatch を直接呼ぶ
+#[test_case]
+fn malformed_syscalls_return_errors() {
+    use ros::syscall::{dispatch, Errno, SYS_READ_AT, SYS_WRITE};
+    let error = |errno: Errno| -(errno as i64);
+    let kernel_address = &0u8 as *const u8 as u64;
+
+    assert_eq!(dispatch(999, [0; 6]), error(Errno::NoSys));
+    assert_eq!(dispatch(SYS_WRITE, [7, 0, 0, 0, 0, 0]), error(Errno::BadFd));
+    assert_eq!(
+        dispatch(SYS_WRITE, [1, 0, 16, 0, 0, 0]),
+        error(Errno::Fault)
+    );
+    assert_eq!(
+        dispatch(SYS_WRITE, [1, kernel_address, 1, 0, 0, 0]),
+        error(Errno::Fault)
+    );
+    assert_eq!(
+        dispatch(SYS_WRITE, [1, u64::MAX, 16, 0, 0, 0]),
+        error(Errno::Fault)
+    );
+    assert_eq!(
+        dispatch(SYS_READ_AT, [0, 4096, 0, 0, 16, 0]),
+        error(Errno::NameTooLong)
+    );
+    assert_eq!(dispatch(SYS_WRITE, [1, 0, 0, 0, 0, 0]), 0);
+}
+
+// W^X にした後でも拡張を読み込んで実行できる。init は `xor eax, eax; ret` で、
+// 8 バイト目の u64 (0) に再配置でロードアドレスを足させる
+#[test_case]
+fn kernel_extension_loads_after_wx() {
+    let mut image = alloc::vec::Vec::new();
+    image.extend_from_slice(b"RKX1");
+    image.extend_from_slice(&16u32.to_le_bytes());
+    image.extend_from_slice(&1u32.to_le_bytes());
+    image.extend_from_slice(&0u32.to_le_bytes());
+    image.extend_from_slice(b"wxtest\0\0\0\0\0\0\0\0\0\0");
+    image.extend_from_slice(&[0x31, 0xc0, 0xc3, 0, 0, 0, 0, 0]);
+    image.extend_from_slice(&0u64.to_le_bytes());
+    image.extend_from_slice(&8u32.to_le_bytes());
+    ros::filesystem::write_file("/wxtest.rkx", &image, false).unwrap();
+
+    assert_eq!(ros::kmod::load("/wxtest.rkx").as_deref(), Ok("wxtest"));
+    assert!(ros::arch::x86::memory::wx_violations().is_empty());
+    ros::filesystem::remove_file("/wxtest.rkx").unwrap();
+}
+
+// NIC が無くても lo で UDP と TCP を端から端まで通せる。テストはメインループより前に
+// 走るので、net-rx タスクの代わりに poll を回す
+#[cfg(feature = "net")]
+#[test_case]
+fn loopback_udp_and_tcp() {
+    use ros::net::socket::{self, Endpoint};
+    use ros::net::{poll, tcp::State, Ipv4Addr};
+    let pump = || {
+        for _ in 0..20 {
+            poll();
+        }
+    };
+
+    let server = socket::udp_bind(7).unwrap();
+    let client = socket::udp_bind(0).unwrap();
+    socket::udp_send_to(client, Endpoint::new(Ipv4Addr::LOOPBACK, 7), b"ping").unwrap();
+    pump();
+    let (_, data) = socket::udp_receive_from(server).unwrap().unwrap();
+    assert_eq!(data, b"ping");
+    socket::close(server);
+    socket::close(client);
+
+    let listener = socket::tcp_listen(7).unwrap();
+    let client = socket::tcp_connect(Endpoint::new(Ipv4Addr::LOOPBACK, 7)).unwrap();
+    pump();
+    assert_eq!(socket::tcp_state(client), Ok(State::Established));
+    let server = socket::tcp_accept(listener).unwrap().unwrap();
+    assert_eq!(socket::tcp_send(client, b"hello"), Ok(5));
+    pump();
+    let info = socket::list()
+        .into_iter()
+        .find(|info| info.id == server)
+        .unwrap();
+    assert_eq!((info.state, info.receive_queue), ("ESTABLISHED", 5));
+    assert_eq!(
+        socket::tcp_receive(server).unwrap().as_deref(),
+        Some(&b"hello"[..])
+    );
+    socket::close(client);
+    pump();
+    assert_eq!(socket::tcp_receive(server), Ok(None));
+    socket::close(server);
+    socket::close(listener);
+}
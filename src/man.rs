@@ -0,0 +1,70 @@
+// マニュアルページ。initrd が無いのでカーネルに埋め込んでおき、起動時に
+// /usr/share/man へ書き出す。書き換えたページはそのまま man で読める
+//
+// 書式: "# " で始まる行は見出し、*...* で囲んだ部分は太字
+use crate::filesystem;
+use alloc::format;
+use alloc::string::String;
+
+pub const MAN_DIR: &str = "/usr/share/man";
+
+const PAGES: &[(&str, &str)] = &[
+    ("intro", include_str!("../man/intro")),
+    ("shell", include_str!("../man/shell")),
+    ("config", include_str!("../man/config")),
+    ("dd", include_str!("../man/dd")),
+    ("peek", include_str!("../man/peek")),
+    ("play", include_str!("../man/play")),
+];
+
+const BOLD: &str = "\x1b[1m";
+const NORMAL: &str = "\x1b[0m";
+
+// 既にあるページは上書きしない
+pub fn install() -> Result<(), &'static str> {
+    filesystem::create_directory(MAN_DIR, true)?;
+    for (name, text) in PAGES {
+        let path = format!("{}/{}", MAN_DIR, name);
+        if filesystem::file_size(&path).is_err() {
+            filesystem::write_file(&path, text.as_bytes(), false)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn read(topic: &str) -> Result<String, &'static str> {
+    if topic.contains('/') {
+        return Err("no manual entry");
+    }
+    let content =
+        filesystem::read_file(&format!("{}/{}", MAN_DIR, topic)).map_err(|_| "no manual entry")?;
+    Ok(render(&String::from_utf8_lossy(&content)))
+}
+
+// 書式を ANSI エスケープシーケンスに置き換え、本文は字下げする
+fn render(source: &str) -> String {
+    let mut text = String::new();
+    for line in source.lines() {
+        if let Some(heading) = line.strip_prefix("# ") {
+            text.push_str(&format!("{}{}{}\n", BOLD, heading, NORMAL));
+            continue;
+        }
+        if !line.is_empty() {
+            text.push_str("    ");
+        }
+        let mut bold = false;
+        for c in line.chars() {
+            if c == '*' {
+                bold = !bold;
+                text.push_str(if bold { BOLD } else { NORMAL });
+            } else {
+                text.push(c);
+            }
+        }
+        if bold {
+            text.push_str(NORMAL);
+        }
+        text.push('\n');
+    }
+    text
+}
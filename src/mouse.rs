@@ -0,0 +1,236 @@
+use crate::arch;
+use crate::device::{self, DeviceClass, Resource};
+use crate::keyboard::{I8042_COMMAND, I8042_DATA};
+use crate::{clipboard, vga_buffer};
+use spin::Mutex;
+
+const QUEUE_SIZE: usize = 32;
+// ミッキー数をセル単位に落とす割合
+const CELL_WIDTH: i32 = 8;
+const CELL_HEIGHT: i32 = 16;
+
+#[derive(Clone, Copy)]
+struct Packet {
+    buttons: u8,
+    dx: i16,
+    dy: i16,
+}
+
+struct PacketQueue {
+    bytes: [u8; 3],
+    received: usize,
+    packets: [Packet; QUEUE_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl PacketQueue {
+    // 3 バイトそろったらパケットにする。先頭バイトの bit 3 は常に 1 なので同期に使う
+    fn push_byte(&mut self, byte: u8) {
+        if self.received == 0 && byte & 0x08 == 0 {
+            return;
+        }
+        self.bytes[self.received] = byte;
+        self.received += 1;
+        if self.received < 3 {
+            return;
+        }
+        self.received = 0;
+
+        let [flags, x, y] = self.bytes;
+        if flags & 0xc0 != 0 {
+            // オーバーフローしたパケットは捨てる
+            return;
+        }
+        let dx = x as i16 - if flags & 0x10 != 0 { 256 } else { 0 };
+        let dy = y as i16 - if flags & 0x20 != 0 { 256 } else { 0 };
+        if self.len < QUEUE_SIZE {
+            self.packets[(self.head + self.len) % QUEUE_SIZE] = Packet {
+                buttons: flags & 0x07,
+                dx,
+                dy,
+            };
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<Packet> {
+        if self.len == 0 {
+            return None;
+        }
+        let packet = self.packets[self.head];
+        self.head = (self.head + 1) % QUEUE_SIZE;
+        self.len -= 1;
+        Some(packet)
+    }
+}
+
+static PACKETS: Mutex<PacketQueue> = Mutex::new(PacketQueue {
+    bytes: [0; 3],
+    received: 0,
+    packets: [Packet {
+        buttons: 0,
+        dx: 0,
+        dy: 0,
+    }; QUEUE_SIZE],
+    head: 0,
+    len: 0,
+});
+
+struct Pointer {
+    // サブセル単位の座標
+    x: i32,
+    y: i32,
+    buttons: u8,
+    shown: Option<usize>,
+    anchor: Option<usize>,
+    selection: Option<(usize, usize)>,
+}
+
+static POINTER: Mutex<Pointer> = Mutex::new(Pointer {
+    x: 0,
+    y: 0,
+    buttons: 0,
+    shown: None,
+    anchor: None,
+    selection: None,
+});
+
+fn status() -> u8 {
+    I8042_COMMAND.read(0)
+}
+
+fn wait_input_clear() -> Result<(), &'static str> {
+    for _ in 0..100_000 {
+        if status() & 0x02 == 0 {
+            return Ok(());
+        }
+    }
+    Err("controller not responding")
+}
+
+fn wait_output_full() -> Result<(), &'static str> {
+    for _ in 0..100_000 {
+        if status() & 0x01 != 0 {
+            return Ok(());
+        }
+    }
+    Err("mouse not responding")
+}
+
+fn controller_command(command: u8) -> Result<(), &'static str> {
+    wait_input_clear()?;
+    I8042_COMMAND.write(0, command);
+    Ok(())
+}
+
+fn mouse_command(command: u8) -> Result<(), &'static str> {
+    controller_command(0xd4)?;
+    wait_input_clear()?;
+    I8042_DATA.write(0, command);
+    wait_output_full()?;
+    match I8042_DATA.read::<u8>(0) {
+        0xfa => Ok(()),
+        _ => Err("mouse rejected command"),
+    }
+}
+
+pub fn init() -> Result<(), &'static str> {
+    arch::without_interrupts(|| {
+        controller_command(0xa8)?;
+        controller_command(0x20)?;
+        wait_output_full()?;
+        // IRQ12 を有効にし、マウスのクロックを止めるビットを落とす
+        let config = (I8042_DATA.read::<u8>(0) | 0x02) & !0x20;
+        controller_command(0x60)?;
+        wait_input_clear()?;
+        I8042_DATA.write(0, config);
+
+        mouse_command(0xf6)?;
+        mouse_command(0xf4)?;
+
+        crate::arch::x86::interrupts::unmask_irq(12);
+        Ok(())
+    })
+}
+
+pub fn register_device(bus: usize) {
+    device::register(
+        "ps2-mouse",
+        DeviceClass::Input,
+        Some(bus),
+        &[Resource::Irq(12)],
+    );
+    crate::power::register_pm(
+        || Ok(()),
+        || {
+            // マウスが応答しなくなっても復帰は続ける
+            let _ = init();
+        },
+    );
+}
+
+pub fn handle_interrupt() {
+    let byte = I8042_DATA.read(0);
+    PACKETS.lock().push_byte(byte);
+}
+
+fn cell(pointer: &Pointer) -> usize {
+    let col = (pointer.x / CELL_WIDTH) as usize;
+    let row = (pointer.y / CELL_HEIGHT) as usize;
+    row * vga_buffer::BUFFER_WIDTH + col
+}
+
+fn invert_range(range: Option<(usize, usize)>) {
+    if let Some((start, end)) = range {
+        for index in start..=end {
+            vga_buffer::invert_cell(index);
+        }
+    }
+}
+
+fn ordered(a: usize, b: usize) -> (usize, usize) {
+    (a.min(b), a.max(b))
+}
+
+// メインループから呼ぶ。ポインタを動かし、左ドラッグで選択、離したらクリップボードへ
+pub fn process_pending() {
+    let max_x = vga_buffer::BUFFER_WIDTH as i32 * CELL_WIDTH - 1;
+    let max_y = vga_buffer::BUFFER_HEIGHT as i32 * CELL_HEIGHT - 1;
+
+    while let Some(packet) = arch::without_interrupts(|| PACKETS.lock().pop()) {
+        let mut pointer = POINTER.lock();
+        invert_range(pointer.shown.take().map(|index| (index, index)));
+
+        pointer.x = (pointer.x + packet.dx as i32).clamp(0, max_x);
+        pointer.y = (pointer.y - packet.dy as i32).clamp(0, max_y);
+        let position = cell(&pointer);
+        let pressed = packet.buttons & 0x01 != 0;
+        let was_pressed = pointer.buttons & 0x01 != 0;
+        pointer.buttons = packet.buttons;
+
+        if pressed && !was_pressed {
+            invert_range(pointer.selection.take());
+            pointer.anchor = Some(position);
+        }
+        if let Some(anchor) = pointer.anchor {
+            let selection = Some(ordered(anchor, position));
+            if selection != pointer.selection {
+                invert_range(pointer.selection.take());
+                invert_range(selection);
+                pointer.selection = selection;
+            }
+            if !pressed {
+                pointer.anchor = None;
+                if let Some((start, end)) = pointer.selection {
+                    if start != end {
+                        clipboard::set(&vga_buffer::read_text(start, end));
+                    }
+                }
+            }
+        }
+
+        vga_buffer::invert_cell(position);
+        pointer.shown = Some(position);
+    }
+}
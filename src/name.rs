@@ -0,0 +1,97 @@
+// ファイルシステムの名前。ほとんどの名前は短いので、INLINE バイトまでは構造体の中に持って
+// ヒープを使わない。それより長い名前は Arc<str> で持ち、写しても参照を数えるだけにする。
+// 一覧や補完で名前を何度も写しても、ヒープの確保と解放が起きない
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::borrow::Borrow;
+use core::cmp::Ordering;
+use core::fmt;
+use core::ops::Deref;
+
+// 長さと合わせて、Name 全体が 24 バイト (String と同じ) に収まる長さ
+const INLINE: usize = 22;
+
+#[derive(Clone)]
+pub enum Name {
+    Inline { len: u8, bytes: [u8; INLINE] },
+    Shared(Arc<str>),
+}
+
+impl Name {
+    pub fn as_str(&self) -> &str {
+        match self {
+            // str から写したものなので UTF-8 として正しい
+            Name::Inline { len, bytes } => unsafe {
+                core::str::from_utf8_unchecked(&bytes[..*len as usize])
+            },
+            Name::Shared(name) => name,
+        }
+    }
+}
+
+impl From<&str> for Name {
+    fn from(name: &str) -> Name {
+        if name.len() > INLINE {
+            return Name::Shared(Arc::from(name));
+        }
+        let mut bytes = [0; INLINE];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+        Name::Inline {
+            len: name.len() as u8,
+            bytes,
+        }
+    }
+}
+
+impl From<&String> for Name {
+    fn from(name: &String) -> Name {
+        Name::from(name.as_str())
+    }
+}
+
+impl Deref for Name {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+// BTreeMap<Name, _> を &str で引けるように、順序は str と同じにする
+impl Borrow<str> for Name {
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl PartialEq for Name {
+    fn eq(&self, other: &Name) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for Name {}
+
+impl PartialOrd for Name {
+    fn partial_cmp(&self, other: &Name) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Name {
+    fn cmp(&self, other: &Name) -> Ordering {
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
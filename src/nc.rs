@@ -0,0 +1,222 @@
+// nc: コンソールを TCP か UDP のソケットにつなぐ。打った行は Enter で送り、届いたものは
+// そのまま画面に出す。相手が閉じるか接続が切れたら終わる。bf と同じくタスクとして動き、
+// キーはシェルから handle_key に回してもらう。Ctrl+C か Ctrl+D で閉じる
+use crate::net::socket::{self, Endpoint, SocketId};
+use crate::task::{self, TaskState};
+use crate::{keyboard, print, println};
+use alloc::string::String;
+use alloc::vec::Vec;
+use pc_keyboard::DecodedKey;
+use spin::Mutex;
+
+enum Mode {
+    // 接続するか、待ち受けて最初の 1 つを受け付ける
+    Tcp {
+        connection: Option<SocketId>,
+        listener: Option<SocketId>,
+    },
+    // 待ち受けのときは、最初に届いたデータグラムの送り元に返す
+    Udp {
+        socket: SocketId,
+        peer: Option<Endpoint>,
+    },
+}
+
+struct Session {
+    mode: Mode,
+    // 打っている途中の行
+    line: String,
+    // まだ送れていないもの
+    outgoing: Vec<u8>,
+}
+
+static RUNNING: Mutex<Option<Session>> = Mutex::new(None);
+
+pub fn is_running() -> bool {
+    RUNNING.lock().is_some()
+}
+
+// remote が無ければ port で待ち受ける。input (パイプの入力) は最初に送る
+pub fn start(
+    udp: bool,
+    remote: Option<Endpoint>,
+    port: u16,
+    input: Option<String>,
+) -> Result<(), &'static str> {
+    if is_running() {
+        return Err("another program is running");
+    }
+    let mode = match (udp, remote) {
+        (false, Some(remote)) => Mode::Tcp {
+            connection: Some(socket::tcp_connect(remote)?),
+            listener: None,
+        },
+        (false, None) => Mode::Tcp {
+            connection: None,
+            listener: Some(socket::tcp_listen(port)?),
+        },
+        (true, peer) => Mode::Udp {
+            socket: socket::udp_bind(if peer.is_some() { 0 } else { port })?,
+            peer,
+        },
+    };
+    *RUNNING.lock() = Some(Session {
+        mode,
+        line: String::new(),
+        outgoing: input.unwrap_or_default().into_bytes(),
+    });
+    task::spawn("nc", step);
+    Ok(())
+}
+
+fn step() -> TaskState {
+    let mut running = RUNNING.lock();
+    let session = match running.as_mut() {
+        Some(session) => session,
+        // Ctrl+C で閉じられた
+        None => return TaskState::Done,
+    };
+    match exchange(session) {
+        Ok(Some(received)) => {
+            drop(running);
+            print!("{}", String::from_utf8_lossy(&received));
+            TaskState::Idle
+        }
+        Ok(None) => TaskState::Idle,
+        Err(e) => {
+            if let Some(session) = running.take() {
+                close(&session);
+            }
+            drop(running);
+            finish(e)
+        }
+    }
+}
+
+// 溜まっているものを送って、届いたものを返す。相手が閉じたら Err("")
+fn exchange(session: &mut Session) -> Result<Option<Vec<u8>>, &'static str> {
+    match &mut session.mode {
+        Mode::Tcp {
+            connection,
+            listener,
+        } => exchange_tcp(connection, listener, &mut session.outgoing),
+        Mode::Udp { socket, peer } => exchange_udp(*socket, peer, &mut session.outgoing),
+    }
+}
+
+// 接続が終わる前に書いたものは送信キューで待つ
+fn exchange_tcp(
+    connection: &mut Option<SocketId>,
+    listener: &mut Option<SocketId>,
+    outgoing: &mut Vec<u8>,
+) -> Result<Option<Vec<u8>>, &'static str> {
+    if connection.is_none() {
+        if let Some(id) = *listener {
+            *connection = socket::tcp_accept(id)?;
+            if connection.is_some() {
+                socket::close(id);
+                *listener = None;
+            }
+        }
+    }
+    let id = match *connection {
+        Some(id) => id,
+        None => return Ok(None),
+    };
+    if !outgoing.is_empty() {
+        let sent = socket::tcp_send(id, outgoing)?;
+        outgoing.drain(..sent);
+    }
+    match socket::tcp_receive(id)? {
+        Some(data) if data.is_empty() => Ok(None),
+        Some(data) => Ok(Some(data)),
+        None => Err(""),
+    }
+}
+
+fn exchange_udp(
+    socket: SocketId,
+    peer: &mut Option<Endpoint>,
+    outgoing: &mut Vec<u8>,
+) -> Result<Option<Vec<u8>>, &'static str> {
+    let received = socket::udp_receive_from(socket)?.map(|(from, data)| {
+        peer.get_or_insert(from);
+        data
+    });
+    if let Some(peer) = *peer {
+        if !outgoing.is_empty() {
+            socket::udp_send_to(socket, peer, outgoing)?;
+            outgoing.clear();
+        }
+    }
+    Ok(received)
+}
+
+fn close(session: &Session) {
+    match session.mode {
+        Mode::Tcp {
+            connection,
+            listener,
+        } => {
+            for id in connection.into_iter().chain(listener) {
+                socket::close(id);
+            }
+        }
+        Mode::Udp { socket, .. } => socket::close(socket),
+    }
+}
+
+fn finish(error: &str) -> TaskState {
+    if !error.is_empty() {
+        println!("\nnc: {}", error);
+    }
+    keyboard::start_shell();
+    TaskState::Done
+}
+
+// 実行中のキー入力。文字は画面に返しながら行に足し、Enter で送る。
+// Ctrl+C か Ctrl+D で閉じたら true を返すので、シェルがプロンプトを出す
+pub fn handle_key(key: DecodedKey) -> bool {
+    let mut running = RUNNING.lock();
+    let session = match running.as_mut() {
+        Some(session) => session,
+        None => return true,
+    };
+    match key {
+        DecodedKey::Unicode('\u{03}') => {
+            println!("^C");
+            if let Some(session) = running.take() {
+                close(&session);
+            }
+            return true;
+        }
+        // 打ちかけの行も送ってから閉じる。届いていたものは捨てる
+        DecodedKey::Unicode('\u{04}') => {
+            println!("^D");
+            let line = core::mem::take(&mut session.line);
+            session.outgoing.extend(line.bytes());
+            let _ = exchange(session);
+            if let Some(session) = running.take() {
+                close(&session);
+            }
+            return true;
+        }
+        DecodedKey::Unicode('\n') => {
+            println!();
+            session.line.push('\n');
+            let line = core::mem::take(&mut session.line);
+            session.outgoing.extend(line.bytes());
+        }
+        DecodedKey::Unicode('\u{08}') => {
+            if session.line.pop().is_some() {
+                print!("\x08 \x08");
+            }
+        }
+        DecodedKey::Unicode(c) if !c.is_control() => {
+            print!("{}", c);
+            session.line.push(c);
+        }
+        _ => {}
+    }
+    false
+}
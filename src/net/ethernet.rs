@@ -0,0 +1,149 @@
+// Ethernet と ARP。ARP で覚えた相手の MAC アドレスの表を持ち、自分のアドレスを
+// 聞かれたら答える。MAC アドレスがまだ分からない宛先へのパケットは取っておき、
+// ARP の返事が来たら送る
+use super::{count, pcap, receive_ipv4, virtio_net, Ipv4Addr};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+pub const NAME: &str = "eth0";
+const HEADER_LEN: usize = 14;
+// これより短いフレームは 0 で埋める
+const MIN_FRAME_LEN: usize = 60;
+const BROADCAST: [u8; 6] = [0xff; 6];
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+
+const ARP_LEN: usize = 28;
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+const ARP_TABLE_LEN: usize = 64;
+// ARP の返事を待っているパケットの数の上限。溢れたら古いものから捨てる
+const PENDING_LEN: usize = 16;
+
+static MAC: Mutex<[u8; 6]> = Mutex::new([0; 6]);
+static ARP_TABLE: Mutex<BTreeMap<Ipv4Addr, [u8; 6]>> = Mutex::new(BTreeMap::new());
+static PENDING: Mutex<VecDeque<(Ipv4Addr, Vec<u8>)>> = Mutex::new(VecDeque::new());
+
+pub(super) fn set_mac(mac: [u8; 6]) {
+    *MAC.lock() = mac;
+}
+
+fn send_frame(destination: [u8; 6], ethertype: u16, payload: &[u8]) -> Result<(), &'static str> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&destination);
+    frame.extend_from_slice(&*MAC.lock());
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    if frame.len() < MIN_FRAME_LEN {
+        frame.resize(MIN_FRAME_LEN, 0);
+    }
+    virtio_net::send(&frame)?;
+    pcap::capture_frame(&frame);
+    count(NAME, |counters| {
+        counters.tx_packets += 1;
+        counters.tx_bytes += frame.len() as u64;
+    });
+    Ok(())
+}
+
+// next_hop は宛先か、別のネットワークならゲートウェイ
+pub(super) fn send_ipv4(next_hop: Ipv4Addr, packet: Vec<u8>) -> Result<(), &'static str> {
+    let destination = if next_hop == Ipv4Addr::BROADCAST {
+        Some(BROADCAST)
+    } else {
+        ARP_TABLE.lock().get(&next_hop).copied()
+    };
+    if let Some(destination) = destination {
+        return send_frame(destination, ETHERTYPE_IPV4, &packet);
+    }
+    let mut pending = PENDING.lock();
+    if pending.len() >= PENDING_LEN {
+        pending.pop_front();
+        count(NAME, |counters| counters.dropped += 1);
+    }
+    pending.push_back((next_hop, packet));
+    drop(pending);
+    send_arp(ARP_REQUEST, BROADCAST, next_hop)
+}
+
+fn send_arp(operation: u16, destination: [u8; 6], target: Ipv4Addr) -> Result<(), &'static str> {
+    let address = super::address_of(NAME).ok_or("no network device")?;
+    let target_mac = if operation == ARP_REPLY {
+        destination
+    } else {
+        [0; 6]
+    };
+    let mut packet = Vec::with_capacity(ARP_LEN);
+    packet.extend_from_slice(&1u16.to_be_bytes());
+    packet.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    packet.extend_from_slice(&[6, 4]);
+    packet.extend_from_slice(&operation.to_be_bytes());
+    packet.extend_from_slice(&*MAC.lock());
+    packet.extend_from_slice(&address.0);
+    packet.extend_from_slice(&target_mac);
+    packet.extend_from_slice(&target.0);
+    send_frame(destination, ETHERTYPE_ARP, &packet)
+}
+
+pub(super) fn receive_frame(frame: &[u8]) {
+    if frame.len() < HEADER_LEN {
+        return;
+    }
+    pcap::capture_frame(frame);
+    let destination = &frame[0..6];
+    if destination != BROADCAST && destination != *MAC.lock() {
+        return;
+    }
+    count(NAME, |counters| {
+        counters.rx_packets += 1;
+        counters.rx_bytes += frame.len() as u64;
+    });
+    let payload = &frame[HEADER_LEN..];
+    match u16::from_be_bytes([frame[12], frame[13]]) {
+        ETHERTYPE_IPV4 => receive_ipv4(payload),
+        ETHERTYPE_ARP => receive_arp(payload),
+        _ => {}
+    }
+}
+
+fn receive_arp(packet: &[u8]) {
+    if packet.len() < ARP_LEN || packet[0..6] != [0, 1, 8, 0, 6, 4] {
+        return;
+    }
+    let operation = u16::from_be_bytes([packet[6], packet[7]]);
+    let mut sender_mac = [0; 6];
+    sender_mac.copy_from_slice(&packet[8..14]);
+    let sender = Ipv4Addr([packet[14], packet[15], packet[16], packet[17]]);
+    let target = Ipv4Addr([packet[24], packet[25], packet[26], packet[27]]);
+    let address = match super::address_of(NAME) {
+        Some(address) => address,
+        None => return,
+    };
+    if sender.is_unspecified() {
+        return;
+    }
+
+    // 自分宛てか、既に知っている相手のものだけ覚える (RFC 826)
+    {
+        let mut table = ARP_TABLE.lock();
+        let known = table.contains_key(&sender);
+        if known || (target == address && table.len() < ARP_TABLE_LEN) {
+            table.insert(sender, sender_mac);
+        }
+    }
+    if target == address && operation == ARP_REQUEST {
+        let _ = send_arp(ARP_REPLY, sender_mac, sender);
+    }
+
+    let ready: Vec<Vec<u8>> = {
+        let mut pending = PENDING.lock();
+        let (ready, waiting): (VecDeque<_>, VecDeque<_>) =
+            pending.drain(..).partition(|(hop, _)| *hop == sender);
+        *pending = waiting;
+        ready.into_iter().map(|(_, packet)| packet).collect()
+    };
+    for packet in ready {
+        let _ = send_frame(sender_mac, ETHERTYPE_IPV4, &packet);
+    }
+}
@@ -0,0 +1,30 @@
+// ICMP。エコー要求 (ping) に答えるだけで、自分からは送らない
+use super::{checksum, send_ipv4, Ipv4Addr, Ipv4Packet, PROTOCOL_ICMP};
+use alloc::vec::Vec;
+
+const ECHO_REPLY: u8 = 0;
+const ECHO_REQUEST: u8 = 8;
+const HEADER_LEN: usize = 8;
+
+pub(super) fn receive(packet: &Ipv4Packet) {
+    let data = packet.payload;
+    if data.len() < HEADER_LEN || data[0] != ECHO_REQUEST || checksum(data, 0) != 0 {
+        return;
+    }
+    // ブロードキャストへの ping には答えない
+    if packet.destination == Ipv4Addr::BROADCAST {
+        return;
+    }
+    // 識別子、番号とデータはそのまま返す
+    let mut reply: Vec<u8> = data.to_vec();
+    reply[0] = ECHO_REPLY;
+    reply[2..4].copy_from_slice(&[0, 0]);
+    let sum = checksum(&reply, 0);
+    reply[2..4].copy_from_slice(&sum.to_be_bytes());
+    let _ = send_ipv4(
+        Some(packet.destination),
+        packet.source,
+        PROTOCOL_ICMP,
+        &reply,
+    );
+}
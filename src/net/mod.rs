@@ -0,0 +1,402 @@
+// ネットワーク (IPv4 だけ)。インターフェースは lo (127.0.0.1/8) と、virtio-net があれば eth0。
+// lo に送ったパケットはキューに積んでおき、eth0 に届いたフレームと一緒に net-rx タスクが
+// 処理する。ARP と ping への返事もこのタスクが返すので、シェルが何もしていなくても答える。
+// ソケットの表は socket、TCP の状態遷移は tcp
+mod ethernet;
+mod icmp;
+pub mod pcap;
+pub mod socket;
+pub mod tcp;
+mod virtio_net;
+
+use crate::device::{self, DeviceClass};
+use crate::klog;
+use crate::log::Level;
+use crate::task::{self, TaskState};
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU16, Ordering};
+use spin::Mutex;
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+// 1 つのパケットの大きさの上限。Ethernet に合わせる
+pub const MTU: usize = 1500;
+const IPV4_HEADER_LEN: usize = 20;
+const DEFAULT_TTL: u8 = 64;
+const DONT_FRAGMENT: u16 = 0x4000;
+// lo に溜めておけるパケットの数。溢れたら捨てる
+const LOOPBACK_QUEUE_LEN: usize = 256;
+// 1 回の poll で NIC から取り出すフレームの数
+const FRAMES_PER_POLL: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl Ipv4Addr {
+    pub const UNSPECIFIED: Ipv4Addr = Ipv4Addr([0, 0, 0, 0]);
+    pub const LOOPBACK: Ipv4Addr = Ipv4Addr([127, 0, 0, 1]);
+    pub const BROADCAST: Ipv4Addr = Ipv4Addr([255, 255, 255, 255]);
+
+    // "127.0.0.1" の形だけ受け付ける。名前解決はまだ無いので localhost だけ特別に扱う
+    pub fn parse(text: &str) -> Option<Ipv4Addr> {
+        if text == "localhost" {
+            return Some(Ipv4Addr::LOOPBACK);
+        }
+        let mut octets = [0; 4];
+        let mut parts = text.split('.');
+        for octet in octets.iter_mut() {
+            let part = parts.next()?;
+            if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            *octet = part.parse().ok()?;
+        }
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Ipv4Addr(octets))
+    }
+
+    pub fn is_loopback(self) -> bool {
+        self.0[0] == 127
+    }
+
+    pub fn is_unspecified(self) -> bool {
+        self == Ipv4Addr::UNSPECIFIED
+    }
+
+    // 上位 prefix ビットが同じか
+    fn same_subnet(self, other: Ipv4Addr, prefix: u8) -> bool {
+        let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+        u32::from_be_bytes(self.0) & mask == u32::from_be_bytes(other.0) & mask
+    }
+}
+
+impl fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Counters {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    pub dropped: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Interface {
+    pub name: &'static str,
+    pub mac: Option<[u8; 6]>,
+    pub address: Ipv4Addr,
+    pub prefix: u8,
+    pub counters: Counters,
+}
+
+static INTERFACES: Mutex<Vec<Interface>> = Mutex::new(Vec::new());
+// eth0 のアドレスとゲートウェイ。既定値は QEMU のユーザーネットワークに合わせてあり、
+// 設定の ipaddr と gateway で変えられる
+static ADDRESS: Mutex<(Ipv4Addr, u8)> = Mutex::new((Ipv4Addr([10, 0, 2, 15]), 24));
+static GATEWAY: Mutex<Ipv4Addr> = Mutex::new(Ipv4Addr([10, 0, 2, 2]));
+static LOOPBACK: Mutex<VecDeque<Vec<u8>>> = Mutex::new(VecDeque::new());
+static NEXT_IDENTIFICATION: AtomicU16 = AtomicU16::new(1);
+
+pub fn init() -> Result<(), &'static str> {
+    let bus = device::find("virtual").ok_or("virtual bus not registered")?;
+    device::register("lo", DeviceClass::Network, Some(bus), &[]);
+    INTERFACES.lock().push(Interface {
+        name: "lo",
+        mac: None,
+        address: Ipv4Addr::LOOPBACK,
+        prefix: 8,
+        counters: Counters::default(),
+    });
+    match virtio_net::init() {
+        Ok(Some(mac)) => {
+            ethernet::set_mac(mac);
+            let (address, prefix) = *ADDRESS.lock();
+            INTERFACES.lock().push(Interface {
+                name: ethernet::NAME,
+                mac: Some(mac),
+                address,
+                prefix,
+                counters: Counters::default(),
+            });
+            klog!(Level::Info, "{}: {}/{}", ethernet::NAME, address, prefix);
+        }
+        Ok(None) => klog!(Level::Info, "No network device, only lo is available"),
+        Err(e) => klog!(Level::Warn, "virtio-net: {}", e),
+    }
+    task::spawn("net-rx", || {
+        if poll() {
+            TaskState::Running
+        } else {
+            TaskState::Idle
+        }
+    });
+    Ok(())
+}
+
+pub fn interfaces() -> Vec<Interface> {
+    INTERFACES.lock().clone()
+}
+
+fn address_of(name: &str) -> Option<Ipv4Addr> {
+    INTERFACES
+        .lock()
+        .iter()
+        .find(|interface| interface.name == name)
+        .map(|interface| interface.address)
+}
+
+// "10.0.2.15/24" の形。eth0 が既にあればすぐに変える
+pub fn set_address(value: &str) -> Result<(), &'static str> {
+    const ERROR: &str = "ipaddr must be an address with a prefix length, like 10.0.2.15/24";
+    let (address, prefix) = value.split_once('/').ok_or(ERROR)?;
+    let address = Ipv4Addr::parse(address).ok_or(ERROR)?;
+    let prefix = prefix
+        .parse::<u8>()
+        .ok()
+        .filter(|prefix| (1..=30).contains(prefix))
+        .ok_or(ERROR)?;
+    if address.is_loopback() || address.is_unspecified() {
+        return Err(ERROR);
+    }
+    *ADDRESS.lock() = (address, prefix);
+    if let Some(interface) = INTERFACES
+        .lock()
+        .iter_mut()
+        .find(|interface| interface.name == ethernet::NAME)
+    {
+        interface.address = address;
+        interface.prefix = prefix;
+    }
+    Ok(())
+}
+
+pub fn set_gateway(value: &str) -> Result<(), &'static str> {
+    let gateway = Ipv4Addr::parse(value)
+        .filter(|gateway| !gateway.is_loopback())
+        .ok_or("gateway must be an address like 10.0.2.2")?;
+    *GATEWAY.lock() = gateway;
+    Ok(())
+}
+
+fn count(name: &str, update: impl FnOnce(&mut Counters)) {
+    if let Some(interface) = INTERFACES.lock().iter_mut().find(|i| i.name == name) {
+        update(&mut interface.counters);
+    }
+}
+
+// 自分宛て (lo を通す) か。eth0 のアドレス宛ても lo で返す
+fn is_local(address: Ipv4Addr) -> bool {
+    address.is_loopback()
+        || INTERFACES
+            .lock()
+            .iter()
+            .any(|interface| interface.address == address)
+}
+
+// destination へ送るときの送り元アドレス
+pub fn source_for(destination: Ipv4Addr) -> Result<Ipv4Addr, &'static str> {
+    if destination.is_loopback() {
+        Ok(Ipv4Addr::LOOPBACK)
+    } else if is_local(destination) {
+        Ok(destination)
+    } else {
+        address_of(ethernet::NAME).ok_or("Network is unreachable")
+    }
+}
+
+// eth0 から送るときに次に渡す相手。同じネットワークなら宛先、そうでなければゲートウェイ
+fn next_hop(destination: Ipv4Addr) -> Result<Ipv4Addr, &'static str> {
+    let interfaces = INTERFACES.lock();
+    let interface = interfaces
+        .iter()
+        .find(|interface| interface.name == ethernet::NAME)
+        .ok_or("Network is unreachable")?;
+    if destination == Ipv4Addr::BROADCAST
+        || destination.same_subnet(interface.address, interface.prefix)
+    {
+        Ok(destination)
+    } else {
+        Ok(*GATEWAY.lock())
+    }
+}
+
+// インターネットチェックサム (RFC 1071)。sum には擬似ヘッダなど、先に足しておく分を渡す
+pub fn checksum(data: &[u8], sum: u32) -> u16 {
+    let mut sum = sum as u64;
+    for chunk in data.chunks(2) {
+        let word = match *chunk {
+            [high, low] => u16::from_be_bytes([high, low]),
+            [high] => u16::from_be_bytes([high, 0]),
+            _ => 0,
+        };
+        sum += word as u64;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// TCP と UDP のチェックサムに含める擬似ヘッダの合計
+pub fn pseudo_header_sum(source: Ipv4Addr, destination: Ipv4Addr, protocol: u8, len: usize) -> u32 {
+    let word = |a: u8, b: u8| u16::from_be_bytes([a, b]) as u32;
+    let (s, d) = (source.0, destination.0);
+    word(s[0], s[1])
+        + word(s[2], s[3])
+        + word(d[0], d[1])
+        + word(d[2], d[3])
+        + protocol as u32
+        + len as u32
+}
+
+pub struct Ipv4Packet<'a> {
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+    pub protocol: u8,
+    pub payload: &'a [u8],
+}
+
+// ヘッダを確かめてペイロードを取り出す。分割されたパケットは組み立てないので捨てる
+fn parse_ipv4(packet: &[u8]) -> Option<Ipv4Packet<'_>> {
+    let header_len = (*packet.first()? & 0x0f) as usize * 4;
+    if packet.len() < IPV4_HEADER_LEN || packet[0] >> 4 != 4 || header_len < IPV4_HEADER_LEN {
+        return None;
+    }
+    let total_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+    if total_len < header_len || total_len > packet.len() {
+        return None;
+    }
+    let fragment = u16::from_be_bytes([packet[6], packet[7]]);
+    if fragment & !DONT_FRAGMENT != 0 || checksum(&packet[..header_len], 0) != 0 {
+        return None;
+    }
+    let address = |offset: usize| {
+        Ipv4Addr([
+            packet[offset],
+            packet[offset + 1],
+            packet[offset + 2],
+            packet[offset + 3],
+        ])
+    };
+    Some(Ipv4Packet {
+        source: address(12),
+        destination: address(16),
+        protocol: packet[9],
+        payload: &packet[header_len..total_len],
+    })
+}
+
+fn build_ipv4(source: Ipv4Addr, destination: Ipv4Addr, protocol: u8, payload: &[u8]) -> Vec<u8> {
+    let total_len = (IPV4_HEADER_LEN + payload.len()) as u16;
+    let identification = NEXT_IDENTIFICATION.fetch_add(1, Ordering::Relaxed);
+    let mut packet = vec![0; IPV4_HEADER_LEN];
+    packet[0] = 0x45;
+    packet[2..4].copy_from_slice(&total_len.to_be_bytes());
+    packet[4..6].copy_from_slice(&identification.to_be_bytes());
+    packet[6..8].copy_from_slice(&DONT_FRAGMENT.to_be_bytes());
+    packet[8] = DEFAULT_TTL;
+    packet[9] = protocol;
+    packet[12..16].copy_from_slice(&source.0);
+    packet[16..20].copy_from_slice(&destination.0);
+    let sum = checksum(&packet, 0);
+    packet[10..12].copy_from_slice(&sum.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+// IPv4 のヘッダを付けて送る。送り元を指定しなければ出ていくインターフェースのアドレスにする
+pub fn send_ipv4(
+    source: Option<Ipv4Addr>,
+    destination: Ipv4Addr,
+    protocol: u8,
+    payload: &[u8],
+) -> Result<(), &'static str> {
+    if IPV4_HEADER_LEN + payload.len() > MTU {
+        return Err("Message too long");
+    }
+    let source = match source {
+        Some(source) => source,
+        None => source_for(destination)?,
+    };
+    if !is_local(destination) {
+        let next_hop = next_hop(destination)?;
+        return ethernet::send_ipv4(next_hop, build_ipv4(source, destination, protocol, payload));
+    }
+    let packet = build_ipv4(source, destination, protocol, payload);
+    let mut queue = LOOPBACK.lock();
+    if queue.len() >= LOOPBACK_QUEUE_LEN {
+        drop(queue);
+        count("lo", |counters| counters.dropped += 1);
+        return Err("No buffer space available");
+    }
+    let len = packet.len() as u64;
+    // lo は送ったときにだけ記録する (受け取ったときにも記録すると 2 回ずつになる)
+    pcap::capture_ipv4(&packet);
+    queue.push_back(packet);
+    drop(queue);
+    count("lo", |counters| {
+        counters.tx_packets += 1;
+        counters.tx_bytes += len;
+    });
+    Ok(())
+}
+
+fn receive_ipv4(packet: &[u8]) {
+    let packet = match parse_ipv4(packet) {
+        Some(packet) => packet,
+        None => return,
+    };
+    if !is_local(packet.destination) && packet.destination != Ipv4Addr::BROADCAST {
+        return;
+    }
+    match packet.protocol {
+        PROTOCOL_ICMP => icmp::receive(&packet),
+        PROTOCOL_UDP => socket::receive_udp(&packet),
+        PROTOCOL_TCP => socket::receive_tcp(&packet),
+        _ => {}
+    }
+}
+
+fn next_loopback() -> Option<Vec<u8>> {
+    LOOPBACK.lock().pop_front()
+}
+
+// 届いているパケットを処理して、TCP の再送などの時間切れを進める。何か届いていれば true。
+// 返事が lo に積まれて終わらなくならないように、lo は呼ばれたときに溜まっていた分だけ、
+// NIC は FRAMES_PER_POLL までにする
+pub fn poll() -> bool {
+    let pending = LOOPBACK.lock().len();
+    for _ in 0..pending {
+        if let Some(packet) = next_loopback() {
+            count("lo", |counters| {
+                counters.rx_packets += 1;
+                counters.rx_bytes += packet.len() as u64;
+            });
+            receive_ipv4(&packet);
+        }
+    }
+    let mut received = 0;
+    while received < FRAMES_PER_POLL {
+        match virtio_net::receive() {
+            Some(frame) => ethernet::receive_frame(&frame),
+            None => break,
+        }
+        received += 1;
+    }
+    socket::poll_timers();
+    pcap::flush();
+    pending > 0 || received > 0
+}
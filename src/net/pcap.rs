@@ -0,0 +1,152 @@
+// パケットキャプチャ。lo と eth0 で送受信したパケットを pcap 形式 (リンク層は Ethernet) で
+// ファイルに書き、ホストの Wireshark で読めるようにする。lo のパケットには 0 の MAC アドレスで
+// Ethernet ヘッダを付ける (Linux の lo と同じ)。送受信の途中ではメモリに溜めるだけにして、
+// ファイルへは poll で書き足す。ファイルが上限の大きさに達したら、それ以降は数えるだけにする
+use crate::filesystem;
+use crate::klog;
+use crate::log::Level;
+use crate::time::{self, TICK_HZ};
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+const MAGIC: u32 = 0xa1b2_c3d4;
+const VERSION: (u16, u16) = (2, 4);
+const SNAPLEN: u32 = 65535;
+const LINKTYPE_ETHERNET: u32 = 1;
+const FILE_HEADER_LEN: u64 = 24;
+const RECORD_HEADER_LEN: u64 = 16;
+const ETHERTYPE_IPV4: [u8; 2] = [0x08, 0x00];
+pub const DEFAULT_LIMIT: u64 = 1024 * 1024;
+
+struct Capture {
+    path: String,
+    limit: u64,
+    // ファイルの大きさ (まだ書いていない分も含む)
+    size: u64,
+    // 次の poll で書き足すレコード
+    pending: Vec<u8>,
+    packets: u64,
+    dropped: u64,
+}
+
+pub struct Status {
+    pub path: String,
+    pub limit: u64,
+    pub size: u64,
+    pub packets: u64,
+    pub dropped: u64,
+}
+
+impl Capture {
+    fn status(&self) -> Status {
+        Status {
+            path: self.path.clone(),
+            limit: self.limit,
+            size: self.size,
+            packets: self.packets,
+            dropped: self.dropped,
+        }
+    }
+}
+
+static CAPTURE: Mutex<Option<Capture>> = Mutex::new(None);
+
+// path は今のディレクトリからの相対でもよい。cd しても同じファイルに書く
+pub fn start(path: &str, limit: u64) -> Result<(), &'static str> {
+    let mut capture = CAPTURE.lock();
+    if capture.is_some() {
+        return Err("a capture is already running");
+    }
+    if limit < FILE_HEADER_LEN + RECORD_HEADER_LEN {
+        return Err("size limit is too small");
+    }
+    let path = filesystem::format_path(&filesystem::resolve_path(path));
+    let mut header = Vec::with_capacity(FILE_HEADER_LEN as usize);
+    header.extend_from_slice(&MAGIC.to_le_bytes());
+    header.extend_from_slice(&VERSION.0.to_le_bytes());
+    header.extend_from_slice(&VERSION.1.to_le_bytes());
+    // 時差と時刻の精度
+    header.extend_from_slice(&[0; 8]);
+    header.extend_from_slice(&SNAPLEN.to_le_bytes());
+    header.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+    filesystem::write_file(&path, &header, false)?;
+    *capture = Some(Capture {
+        path,
+        limit,
+        size: FILE_HEADER_LEN,
+        pending: Vec::new(),
+        packets: 0,
+        dropped: 0,
+    });
+    Ok(())
+}
+
+// 溜まっている分を書いてから止める
+pub fn stop() -> Result<Status, &'static str> {
+    flush();
+    let capture = CAPTURE.lock().take().ok_or("no capture is running")?;
+    Ok(capture.status())
+}
+
+pub fn status() -> Option<Status> {
+    CAPTURE.lock().as_ref().map(Capture::status)
+}
+
+pub(super) fn capture_frame(frame: &[u8]) {
+    record(&[frame]);
+}
+
+// lo のパケット。宛先と送り元が 0 の Ethernet ヘッダを付ける
+pub(super) fn capture_ipv4(packet: &[u8]) {
+    let mut header = [0; 14];
+    header[12..].copy_from_slice(&ETHERTYPE_IPV4);
+    record(&[&header, packet]);
+}
+
+fn record(parts: &[&[u8]]) {
+    let mut capture = CAPTURE.lock();
+    let capture = match capture.as_mut() {
+        Some(capture) => capture,
+        None => return,
+    };
+    let len: usize = parts.iter().map(|part| part.len()).sum();
+    let record_len = RECORD_HEADER_LEN + len as u64;
+    if capture.size + record_len > capture.limit {
+        capture.dropped += 1;
+        return;
+    }
+    let ticks = time::ticks();
+    let microseconds = (ticks % TICK_HZ) * (1_000_000 / TICK_HZ);
+    capture
+        .pending
+        .extend_from_slice(&(time::now() as u32).to_le_bytes());
+    capture
+        .pending
+        .extend_from_slice(&(microseconds as u32).to_le_bytes());
+    capture
+        .pending
+        .extend_from_slice(&(len as u32).to_le_bytes());
+    capture
+        .pending
+        .extend_from_slice(&(len as u32).to_le_bytes());
+    for part in parts {
+        capture.pending.extend_from_slice(part);
+    }
+    capture.size += record_len;
+    capture.packets += 1;
+}
+
+// 書けなければ (ファイルシステムが読み取り専用になったなど) キャプチャを止める
+pub(super) fn flush() {
+    let (path, pending) = match CAPTURE.lock().as_mut() {
+        Some(capture) if !capture.pending.is_empty() => {
+            (capture.path.clone(), core::mem::take(&mut capture.pending))
+        }
+        _ => return,
+    };
+    if let Err(e) = filesystem::write_file(&path, &pending, true) {
+        klog!(Level::Warn, "pcap: {}: {}, capture stopped", path, e);
+        *CAPTURE.lock() = None;
+    }
+}
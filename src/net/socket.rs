@@ -0,0 +1,407 @@
+// ソケットの表。UDP と TCP の両方をここに置き、番号で指す。受信は net-rx タスクが、
+// 読み書きは nc などの使う側が、どちらもこの表のロックを取って行う。
+// 表を握ったまま送ってもよい。送る側 (send_ipv4 から先) は表に触らないため
+use super::tcp::{self, Connection, Segment, State};
+use super::{checksum, pseudo_header_sum, send_ipv4, Ipv4Addr, Ipv4Packet, PROTOCOL_UDP};
+use crate::time;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+
+pub type SocketId = u32;
+
+const UDP_HEADER_LEN: usize = 8;
+// ポート 0 を指定したときに割り当てる範囲
+const EPHEMERAL_START: u16 = 49152;
+// UDP ソケットに溜めておけるデータグラムの数。溢れたら捨てる
+const UDP_QUEUE_LEN: usize = 64;
+// accept を待っている接続の数の上限。超えた SYN は捨てる
+const LISTEN_BACKLOG: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Endpoint {
+    pub address: Ipv4Addr,
+    pub port: u16,
+}
+
+impl Endpoint {
+    pub fn new(address: Ipv4Addr, port: u16) -> Endpoint {
+        Endpoint { address, port }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.address, self.port)
+    }
+}
+
+enum Socket {
+    Udp {
+        port: u16,
+        queue: VecDeque<(Endpoint, Vec<u8>)>,
+    },
+    Listener {
+        port: u16,
+        // 接続を終えて accept を待っているもの
+        backlog: VecDeque<SocketId>,
+    },
+    Tcp(Connection),
+}
+
+struct Table {
+    sockets: BTreeMap<SocketId, Socket>,
+    next_id: SocketId,
+    next_port: u16,
+}
+
+impl Table {
+    fn insert(&mut self, socket: Socket) -> SocketId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.sockets.insert(id, socket);
+        id
+    }
+
+    fn tcp_port_in_use(&self, port: u16) -> bool {
+        self.sockets.values().any(|socket| match socket {
+            Socket::Listener { port: p, .. } => *p == port,
+            Socket::Tcp(connection) => connection.local.port == port,
+            Socket::Udp { .. } => false,
+        })
+    }
+
+    fn udp_port_in_use(&self, port: u16) -> bool {
+        self.sockets
+            .values()
+            .any(|socket| matches!(socket, Socket::Udp { port: p, .. } if *p == port))
+    }
+
+    // 使われていないポートを順に探す
+    fn ephemeral_port(&mut self, in_use: fn(&Table, u16) -> bool) -> Result<u16, &'static str> {
+        for _ in EPHEMERAL_START..=u16::MAX {
+            let port = self.next_port;
+            self.next_port = if port == u16::MAX {
+                EPHEMERAL_START
+            } else {
+                port + 1
+            };
+            if !in_use(self, port) {
+                return Ok(port);
+            }
+        }
+        Err("No free ports")
+    }
+
+    fn connection(&mut self, id: SocketId) -> Result<&mut Connection, &'static str> {
+        match self.sockets.get_mut(&id) {
+            Some(Socket::Tcp(connection)) => Ok(connection),
+            Some(_) => Err("Not a TCP connection"),
+            None => Err("Bad socket"),
+        }
+    }
+}
+
+static SOCKETS: Mutex<Table> = Mutex::new(Table {
+    sockets: BTreeMap::new(),
+    next_id: 1,
+    next_port: EPHEMERAL_START,
+});
+
+// UDP のポートを開く。0 なら空いているポートを割り当てる
+pub fn udp_bind(port: u16) -> Result<SocketId, &'static str> {
+    let mut table = SOCKETS.lock();
+    let port = match port {
+        0 => table.ephemeral_port(Table::udp_port_in_use)?,
+        port if table.udp_port_in_use(port) => return Err("Address already in use"),
+        port => port,
+    };
+    Ok(table.insert(Socket::Udp {
+        port,
+        queue: VecDeque::new(),
+    }))
+}
+
+pub fn udp_send_to(id: SocketId, destination: Endpoint, data: &[u8]) -> Result<(), &'static str> {
+    let port = match SOCKETS.lock().sockets.get(&id) {
+        Some(Socket::Udp { port, .. }) => *port,
+        Some(_) => return Err("Not a UDP socket"),
+        None => return Err("Bad socket"),
+    };
+    let source = super::source_for(destination.address)?;
+    let len = UDP_HEADER_LEN + data.len();
+    let mut datagram = vec![0; UDP_HEADER_LEN];
+    datagram[0..2].copy_from_slice(&port.to_be_bytes());
+    datagram[2..4].copy_from_slice(&destination.port.to_be_bytes());
+    datagram[4..6].copy_from_slice(&(len as u16).to_be_bytes());
+    datagram.extend_from_slice(data);
+    let sum = pseudo_header_sum(source, destination.address, PROTOCOL_UDP, len);
+    // 計算結果が 0 なら、チェックサム無しと区別するために 0xffff を送る
+    let sum = match checksum(&datagram, sum) {
+        0 => 0xffff,
+        sum => sum,
+    };
+    datagram[6..8].copy_from_slice(&sum.to_be_bytes());
+    send_ipv4(Some(source), destination.address, PROTOCOL_UDP, &datagram)
+}
+
+// 届いているデータグラムを 1 つ取り出す
+pub fn udp_receive_from(id: SocketId) -> Result<Option<(Endpoint, Vec<u8>)>, &'static str> {
+    match SOCKETS.lock().sockets.get_mut(&id) {
+        Some(Socket::Udp { queue, .. }) => Ok(queue.pop_front()),
+        Some(_) => Err("Not a UDP socket"),
+        None => Err("Bad socket"),
+    }
+}
+
+pub(super) fn receive_udp(packet: &Ipv4Packet) {
+    let data = packet.payload;
+    if data.len() < UDP_HEADER_LEN {
+        return;
+    }
+    let len = u16::from_be_bytes([data[4], data[5]]) as usize;
+    if len < UDP_HEADER_LEN || len > data.len() {
+        return;
+    }
+    let data = &data[..len];
+    let has_checksum = data[6] != 0 || data[7] != 0;
+    let sum = pseudo_header_sum(packet.source, packet.destination, PROTOCOL_UDP, len);
+    if has_checksum && checksum(data, sum) != 0 {
+        return;
+    }
+    let source = Endpoint::new(packet.source, u16::from_be_bytes([data[0], data[1]]));
+    let destination_port = u16::from_be_bytes([data[2], data[3]]);
+    let mut table = SOCKETS.lock();
+    let queue = table.sockets.values_mut().find_map(|socket| match socket {
+        Socket::Udp { port, queue } if *port == destination_port => Some(queue),
+        _ => None,
+    });
+    if let Some(queue) = queue {
+        if queue.len() < UDP_QUEUE_LEN {
+            queue.push_back((source, data[UDP_HEADER_LEN..].to_vec()));
+        }
+    }
+}
+
+pub fn tcp_listen(port: u16) -> Result<SocketId, &'static str> {
+    let mut table = SOCKETS.lock();
+    if port == 0 || table.tcp_port_in_use(port) {
+        return Err("Address already in use");
+    }
+    Ok(table.insert(Socket::Listener {
+        port,
+        backlog: VecDeque::new(),
+    }))
+}
+
+// 接続を始める。つながったかどうかは tcp_state で見る
+pub fn tcp_connect(remote: Endpoint) -> Result<SocketId, &'static str> {
+    let address = super::source_for(remote.address)?;
+    let mut table = SOCKETS.lock();
+    let port = table.ephemeral_port(Table::tcp_port_in_use)?;
+    let connection = Connection::connect(Endpoint::new(address, port), remote);
+    Ok(table.insert(Socket::Tcp(connection)))
+}
+
+// 接続を終えたものがあれば 1 つ取り出す
+pub fn tcp_accept(listener: SocketId) -> Result<Option<SocketId>, &'static str> {
+    let mut table = SOCKETS.lock();
+    loop {
+        let id = match table.sockets.get_mut(&listener) {
+            Some(Socket::Listener { backlog, .. }) => backlog.pop_front(),
+            Some(_) => return Err("Not a listening socket"),
+            None => return Err("Bad socket"),
+        };
+        let id = match id {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        // 並べた後に切れて消えたものは飛ばす
+        if let Ok(connection) = table.connection(id) {
+            connection.parent = None;
+            return Ok(Some(id));
+        }
+    }
+}
+
+pub fn tcp_state(id: SocketId) -> Result<State, &'static str> {
+    let mut table = SOCKETS.lock();
+    let connection = table.connection(id)?;
+    if connection.reset {
+        return Err("Connection reset");
+    }
+    Ok(connection.state)
+}
+
+// 送信キューに入れた量を返す
+pub fn tcp_send(id: SocketId, data: &[u8]) -> Result<usize, &'static str> {
+    SOCKETS.lock().connection(id)?.write(data)
+}
+
+// 届いたデータを全部取り出す。相手が閉じていて残りも無ければ None
+pub fn tcp_receive(id: SocketId) -> Result<Option<Vec<u8>>, &'static str> {
+    let mut table = SOCKETS.lock();
+    let connection = table.connection(id)?;
+    if connection.reset {
+        return Err("Connection reset");
+    }
+    if connection.at_end() {
+        return Ok(None);
+    }
+    Ok(Some(connection.read()))
+}
+
+// TCP の接続は FIN を送って閉じ終わるまで表に残る。待ち受けを閉じると、
+// accept されていない接続も閉じる
+pub fn close(id: SocketId) {
+    let mut table = SOCKETS.lock();
+    match table.sockets.remove(&id) {
+        Some(Socket::Tcp(mut connection)) => {
+            connection.close();
+            table.sockets.insert(id, Socket::Tcp(connection));
+        }
+        Some(Socket::Listener { .. }) => {
+            for socket in table.sockets.values_mut() {
+                if let Socket::Tcp(connection) = socket {
+                    if connection.parent == Some(id) {
+                        connection.parent = None;
+                        connection.close();
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+// netstat 用の、ソケット 1 つの様子
+pub struct SocketInfo {
+    pub id: SocketId,
+    pub protocol: &'static str,
+    pub local: Endpoint,
+    // 相手が決まっていない (UDP と待ち受け) なら None
+    pub remote: Option<Endpoint>,
+    pub state: &'static str,
+    // 受信キューのバイト数。待ち受けなら accept を待っている接続の数
+    pub receive_queue: usize,
+    pub send_queue: usize,
+}
+
+pub fn list() -> Vec<SocketInfo> {
+    let table = SOCKETS.lock();
+    table
+        .sockets
+        .iter()
+        .map(|(&id, socket)| match socket {
+            Socket::Udp { port, queue } => SocketInfo {
+                id,
+                protocol: "udp",
+                local: Endpoint::new(Ipv4Addr::UNSPECIFIED, *port),
+                remote: None,
+                state: "",
+                receive_queue: queue.iter().map(|(_, data)| data.len()).sum(),
+                send_queue: 0,
+            },
+            Socket::Listener { port, backlog } => SocketInfo {
+                id,
+                protocol: "tcp",
+                local: Endpoint::new(Ipv4Addr::UNSPECIFIED, *port),
+                remote: None,
+                state: "LISTEN",
+                receive_queue: backlog.len(),
+                send_queue: 0,
+            },
+            Socket::Tcp(connection) => SocketInfo {
+                id,
+                protocol: "tcp",
+                local: connection.local,
+                remote: Some(connection.remote),
+                state: connection.state.name(),
+                receive_queue: connection.receive_queue_len(),
+                send_queue: connection.send_queue_len(),
+            },
+        })
+        .collect()
+}
+
+pub(super) fn receive_tcp(packet: &Ipv4Packet) {
+    let segment = match Segment::parse(packet) {
+        Some(segment) => segment,
+        None => return,
+    };
+    let local = Endpoint::new(packet.destination, segment.destination_port);
+    let remote = Endpoint::new(packet.source, segment.source_port);
+    let mut table = SOCKETS.lock();
+
+    let found = table
+        .sockets
+        .iter_mut()
+        .find_map(|(id, socket)| match socket {
+            Socket::Tcp(connection)
+                if connection.local == local
+                    && connection.remote == remote
+                    && connection.state != State::Closed =>
+            {
+                Some((*id, connection))
+            }
+            _ => None,
+        });
+    if let Some((id, connection)) = found {
+        let was_handshaking = connection.state == State::SynReceived;
+        connection.receive(&segment);
+        let parent = connection.parent;
+        // 受け付けた接続の手順が終わったら、待ち受けソケットの accept 待ちに並べる
+        if was_handshaking && !matches!(connection.state, State::SynReceived | State::Closed) {
+            if let Some(Socket::Listener { backlog, .. }) =
+                parent.and_then(|parent| table.sockets.get_mut(&parent))
+            {
+                backlog.push_back(id);
+            }
+        }
+        return;
+    }
+
+    let listener = table.sockets.iter().find_map(|(id, socket)| match socket {
+        Socket::Listener { port, .. } if *port == local.port => Some(*id),
+        _ => None,
+    });
+    match listener {
+        Some(listener) if segment.is_syn() => {
+            let pending = table
+                .sockets
+                .values()
+                .filter(|socket| {
+                    matches!(socket, Socket::Tcp(connection) if connection.parent == Some(listener))
+                })
+                .count();
+            if pending < LISTEN_BACKLOG {
+                let connection = Connection::accept(local, remote, &segment, listener);
+                table.insert(Socket::Tcp(connection));
+            }
+        }
+        // どこにも当てはまらなければ RST を返す。RST には返さない
+        _ if !segment.is_reset() => tcp::send_reset(local, remote, &segment),
+        _ => {}
+    }
+}
+
+// 再送と TIME_WAIT を進め、閉じ終わって誰も使っていない接続を消す
+pub(super) fn poll_timers() {
+    let now = time::ticks();
+    let mut table = SOCKETS.lock();
+    for socket in table.sockets.values_mut() {
+        if let Socket::Tcp(connection) = socket {
+            connection.on_timer(now);
+        }
+    }
+    // accept される前に切れた接続も、誰も持っていないので消す
+    table.sockets.retain(|_, socket| match socket {
+        Socket::Tcp(connection) => {
+            let unused = connection.orphaned || connection.parent.is_some();
+            !(unused && connection.state == State::Closed)
+        }
+        _ => true,
+    });
+}
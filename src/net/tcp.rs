@@ -0,0 +1,549 @@
+// TCP の状態遷移 (RFC 793 を簡単にしたもの)。送ったがまだ確認応答の無いデータは
+// 送信キューに残しておき、時間切れで先頭から送り直す。順番の違うセグメントは捨てて、
+// 相手が送り直すのを待つ。オプションは SYN に MSS を付けるだけで、届いたものは読み飛ばす
+use super::socket::{Endpoint, SocketId};
+use super::{checksum, pseudo_header_sum, send_ipv4, Ipv4Packet, PROTOCOL_TCP};
+use crate::rand::XorShift64;
+use crate::time::{self, TICK_HZ};
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const HEADER_LEN: usize = 20;
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+const OPTION_MSS: u8 = 2;
+
+// 1 つのセグメントに載せるデータの上限。MTU から IP と TCP のヘッダを引いたもの
+const MSS: usize = super::MTU - 20 - HEADER_LEN;
+// 受信キューの大きさ。空いている分をウィンドウとして知らせる
+const RECEIVE_BUFFER: usize = 16 * 1024;
+// 送信キューの大きさ。これを超える分は write が受け取らない
+const SEND_BUFFER: usize = 16 * 1024;
+// 最初の再送までの時間。送り直すたびに倍にする
+const RETRANSMIT_TICKS: u64 = TICK_HZ;
+const MAX_RETRIES: u32 = 6;
+// TIME_WAIT に留まる時間。本来は 2MSL だが、ポートを長く塞がないように短くしている
+const TIME_WAIT_TICKS: u64 = 2 * TICK_HZ;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+impl State {
+    pub fn name(self) -> &'static str {
+        match self {
+            State::SynSent => "SYN_SENT",
+            State::SynReceived => "SYN_RECV",
+            State::Established => "ESTABLISHED",
+            State::FinWait1 => "FIN_WAIT1",
+            State::FinWait2 => "FIN_WAIT2",
+            State::CloseWait => "CLOSE_WAIT",
+            State::Closing => "CLOSING",
+            State::LastAck => "LAST_ACK",
+            State::TimeWait => "TIME_WAIT",
+            State::Closed => "CLOSED",
+        }
+    }
+
+    // 相手からのデータをまだ受け取る状態か
+    fn receiving(self) -> bool {
+        matches!(self, State::Established | State::FinWait1 | State::FinWait2)
+    }
+}
+
+// シーケンス番号は一周するので、差の符号で前後を比べる
+fn before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+pub struct Segment<'a> {
+    pub source_port: u16,
+    pub destination_port: u16,
+    sequence: u32,
+    acknowledgment: u32,
+    flags: u8,
+    window: u16,
+    payload: &'a [u8],
+}
+
+impl<'a> Segment<'a> {
+    pub fn parse(packet: &Ipv4Packet<'a>) -> Option<Segment<'a>> {
+        let data = packet.payload;
+        if data.len() < HEADER_LEN {
+            return None;
+        }
+        let data_offset = (data[12] >> 4) as usize * 4;
+        if data_offset < HEADER_LEN || data_offset > data.len() {
+            return None;
+        }
+        let sum = pseudo_header_sum(packet.source, packet.destination, PROTOCOL_TCP, data.len());
+        if checksum(data, sum) != 0 {
+            return None;
+        }
+        let word = |offset: usize| u16::from_be_bytes([data[offset], data[offset + 1]]);
+        let long = |offset: usize| {
+            u32::from_be_bytes([
+                data[offset],
+                data[offset + 1],
+                data[offset + 2],
+                data[offset + 3],
+            ])
+        };
+        Some(Segment {
+            source_port: word(0),
+            destination_port: word(2),
+            sequence: long(4),
+            acknowledgment: long(8),
+            flags: data[13],
+            window: word(14),
+            payload: &data[data_offset..],
+        })
+    }
+
+    pub fn is_syn(&self) -> bool {
+        self.flags & (SYN | ACK | RST) == SYN
+    }
+
+    pub fn is_reset(&self) -> bool {
+        self.flags & RST != 0
+    }
+
+    fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    // SYN と FIN もシーケンス番号を 1 つずつ使う
+    fn sequence_len(&self) -> u32 {
+        self.payload.len() as u32 + self.has(SYN) as u32 + self.has(FIN) as u32
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn send_segment(
+    local: Endpoint,
+    remote: Endpoint,
+    sequence: u32,
+    acknowledgment: u32,
+    flags: u8,
+    window: u16,
+    payload: &[u8],
+) {
+    let options: &[u8] = if flags & SYN != 0 {
+        &[OPTION_MSS, 4, (MSS >> 8) as u8, MSS as u8]
+    } else {
+        &[]
+    };
+    let header_len = HEADER_LEN + options.len();
+    let mut segment = vec![0; header_len];
+    segment[0..2].copy_from_slice(&local.port.to_be_bytes());
+    segment[2..4].copy_from_slice(&remote.port.to_be_bytes());
+    segment[4..8].copy_from_slice(&sequence.to_be_bytes());
+    segment[8..12].copy_from_slice(&acknowledgment.to_be_bytes());
+    segment[12] = ((header_len / 4) as u8) << 4;
+    segment[13] = flags;
+    segment[14..16].copy_from_slice(&window.to_be_bytes());
+    segment[HEADER_LEN..].copy_from_slice(options);
+    segment.extend_from_slice(payload);
+    let sum = pseudo_header_sum(local.address, remote.address, PROTOCOL_TCP, segment.len());
+    let sum = checksum(&segment, sum);
+    segment[16..18].copy_from_slice(&sum.to_be_bytes());
+    // 送れなくても、確認応答が来なければ後で送り直す
+    let _ = send_ipv4(Some(local.address), remote.address, PROTOCOL_TCP, &segment);
+}
+
+// 接続の無いポートに届いたセグメントへの返事
+pub fn send_reset(local: Endpoint, remote: Endpoint, segment: &Segment) {
+    if segment.has(ACK) {
+        send_segment(local, remote, segment.acknowledgment, 0, RST, 0, &[]);
+    } else {
+        let acknowledgment = segment.sequence.wrapping_add(segment.sequence_len());
+        send_segment(local, remote, 0, acknowledgment, RST | ACK, 0, &[]);
+    }
+}
+
+fn initial_sequence() -> u32 {
+    XorShift64::from_tsc().next_u64() as u32
+}
+
+pub struct Connection {
+    pub local: Endpoint,
+    pub remote: Endpoint,
+    pub state: State,
+    // 待ち受けから受け付けた接続なら、accept されるまでその待ち受けソケットを持つ
+    pub parent: Option<SocketId>,
+    // close した後は、閉じ終わったら表から消す
+    pub orphaned: bool,
+    // RST を受け取ったか、再送しても返事が無かった
+    pub reset: bool,
+    // SND.UNA。送信キューの先頭のシーケンス番号
+    send_unacked: u32,
+    // SND.NXT
+    send_next: u32,
+    send_window: u32,
+    // RCV.NXT
+    receive_next: u32,
+    send_queue: VecDeque<u8>,
+    receive_queue: VecDeque<u8>,
+    // close されたので、送るデータが無くなったら FIN を送る
+    fin_queued: bool,
+    fin_sent: bool,
+    retransmit_at: Option<u64>,
+    retries: u32,
+    time_wait_until: u64,
+}
+
+impl Connection {
+    fn new(local: Endpoint, remote: Endpoint, state: State) -> Connection {
+        let sequence = initial_sequence();
+        Connection {
+            local,
+            remote,
+            state,
+            parent: None,
+            orphaned: false,
+            reset: false,
+            send_unacked: sequence,
+            send_next: sequence.wrapping_add(1),
+            send_window: 0,
+            receive_next: 0,
+            send_queue: VecDeque::new(),
+            receive_queue: VecDeque::new(),
+            fin_queued: false,
+            fin_sent: false,
+            retransmit_at: Some(time::ticks() + RETRANSMIT_TICKS),
+            retries: 0,
+            time_wait_until: 0,
+        }
+    }
+
+    // 相手に SYN を送って接続を始める
+    pub fn connect(local: Endpoint, remote: Endpoint) -> Connection {
+        let connection = Connection::new(local, remote, State::SynSent);
+        connection.send(connection.send_unacked, SYN, &[]);
+        connection
+    }
+
+    // 待ち受けているポートに届いた SYN に SYN+ACK を返す
+    pub fn accept(
+        local: Endpoint,
+        remote: Endpoint,
+        syn: &Segment,
+        parent: SocketId,
+    ) -> Connection {
+        let mut connection = Connection::new(local, remote, State::SynReceived);
+        connection.parent = Some(parent);
+        connection.receive_next = syn.sequence.wrapping_add(1);
+        connection.send_window = syn.window as u32;
+        connection.send(connection.send_unacked, SYN | ACK, &[]);
+        connection
+    }
+
+    fn window(&self) -> u16 {
+        (RECEIVE_BUFFER - self.receive_queue.len()).min(u16::MAX as usize) as u16
+    }
+
+    fn send(&self, sequence: u32, flags: u8, payload: &[u8]) {
+        let acknowledgment = if flags & ACK != 0 {
+            self.receive_next
+        } else {
+            0
+        };
+        send_segment(
+            self.local,
+            self.remote,
+            sequence,
+            acknowledgment,
+            flags,
+            self.window(),
+            payload,
+        );
+    }
+
+    fn send_ack(&self) {
+        self.send(self.send_next, ACK, &[]);
+    }
+
+    // 送ったがまだ確認応答の無いデータの量
+    fn in_flight(&self) -> usize {
+        (self.send_next.wrapping_sub(self.send_unacked) as usize)
+            .saturating_sub(self.fin_sent as usize)
+    }
+
+    // ウィンドウの範囲でキューのデータを送り、全部送ったら必要なら FIN を送る
+    fn transmit(&mut self) {
+        if !matches!(self.state, State::Established | State::CloseWait) {
+            return;
+        }
+        loop {
+            let sent = self.in_flight();
+            let room = (self.send_window as usize).saturating_sub(sent);
+            let len = (self.send_queue.len() - sent).min(room).min(MSS);
+            if len == 0 {
+                break;
+            }
+            let payload: Vec<u8> = self
+                .send_queue
+                .iter()
+                .skip(sent)
+                .take(len)
+                .copied()
+                .collect();
+            self.send(self.send_next, ACK | PSH, &payload);
+            self.send_next = self.send_next.wrapping_add(len as u32);
+        }
+        if self.fin_queued && !self.fin_sent && self.in_flight() == self.send_queue.len() {
+            self.send(self.send_next, FIN | ACK, &[]);
+            self.send_next = self.send_next.wrapping_add(1);
+            self.fin_sent = true;
+            self.state = match self.state {
+                State::CloseWait => State::LastAck,
+                _ => State::FinWait1,
+            };
+        }
+        // ウィンドウが 0 で送れないときも、時間切れで 1 セグメント送って様子を見る
+        let waiting = self.send_next != self.send_unacked || !self.send_queue.is_empty();
+        if waiting && self.retransmit_at.is_none() {
+            self.retransmit_at = Some(time::ticks() + RETRANSMIT_TICKS);
+        }
+    }
+
+    pub fn receive(&mut self, segment: &Segment) {
+        if segment.has(RST) {
+            // SYN_SENT では自分の SYN への返事のときだけ受け付ける
+            let valid = match self.state {
+                State::SynSent => segment.has(ACK) && segment.acknowledgment == self.send_next,
+                _ => segment.sequence == self.receive_next,
+            };
+            if valid {
+                self.state = State::Closed;
+                self.reset = true;
+            }
+            return;
+        }
+        match self.state {
+            State::Closed => return,
+            State::SynSent => {
+                if segment.has(SYN) && segment.has(ACK) && segment.acknowledgment == self.send_next
+                {
+                    self.receive_next = segment.sequence.wrapping_add(1);
+                    self.send_unacked = segment.acknowledgment;
+                    self.send_window = segment.window as u32;
+                    self.state = State::Established;
+                    self.retransmit_at = None;
+                    self.retries = 0;
+                    self.send_ack();
+                    self.transmit();
+                }
+                // 同時に開く場合は扱わない
+                return;
+            }
+            _ => {}
+        }
+
+        if segment.has(SYN) {
+            // 自分の ACK が届かずに SYN+ACK が送り直された
+            if segment.sequence.wrapping_add(1) == self.receive_next {
+                self.send_ack();
+            }
+            return;
+        }
+
+        if segment.has(ACK) {
+            self.receive_ack(segment);
+            if self.state == State::Closed {
+                return;
+            }
+        }
+
+        // 既に受け取った部分は切り落とす。残りが期待している番号から始まらなければ捨てる
+        let mut payload = segment.payload;
+        let mut sequence = segment.sequence;
+        if before(sequence, self.receive_next) {
+            let skip = self.receive_next.wrapping_sub(sequence) as usize;
+            payload = payload.get(skip..).unwrap_or(&[]);
+            sequence = self.receive_next;
+        }
+        let mut need_ack = false;
+        if !payload.is_empty() {
+            if sequence == self.receive_next && self.state.receiving() {
+                let len = payload.len().min(RECEIVE_BUFFER - self.receive_queue.len());
+                self.receive_queue.extend(&payload[..len]);
+                self.receive_next = self.receive_next.wrapping_add(len as u32);
+            }
+            need_ack = true;
+        }
+        if segment.has(FIN) {
+            let fin = segment.sequence.wrapping_add(segment.payload.len() as u32);
+            if fin == self.receive_next && self.state.receiving() {
+                self.receive_next = self.receive_next.wrapping_add(1);
+                self.state = match self.state {
+                    State::Established => State::CloseWait,
+                    State::FinWait1 => State::Closing,
+                    _ => self.enter_time_wait(),
+                };
+            }
+            // 送り直された FIN にも ACK を返す
+            need_ack = true;
+        }
+        if need_ack {
+            self.send_ack();
+        }
+        self.transmit();
+    }
+
+    fn receive_ack(&mut self, segment: &Segment) {
+        let acknowledgment = segment.acknowledgment;
+        if self.state == State::SynReceived {
+            if acknowledgment != self.send_next {
+                send_reset(self.local, self.remote, segment);
+                return;
+            }
+            self.state = State::Established;
+            self.send_unacked = acknowledgment;
+            self.send_window = segment.window as u32;
+            self.retransmit_at = None;
+            self.retries = 0;
+            return;
+        }
+        if before(self.send_unacked, acknowledgment) && !before(self.send_next, acknowledgment) {
+            let acked = acknowledgment.wrapping_sub(self.send_unacked) as usize;
+            let data = acked.min(self.send_queue.len());
+            self.send_queue.drain(..data);
+            self.send_unacked = acknowledgment;
+            self.retries = 0;
+            self.retransmit_at = if self.send_unacked == self.send_next {
+                None
+            } else {
+                Some(time::ticks() + RETRANSMIT_TICKS)
+            };
+            if self.fin_sent && acknowledgment == self.send_next {
+                self.state = match self.state {
+                    State::FinWait1 => State::FinWait2,
+                    State::Closing => self.enter_time_wait(),
+                    State::LastAck => State::Closed,
+                    state => state,
+                };
+            }
+        }
+        self.send_window = segment.window as u32;
+    }
+
+    fn enter_time_wait(&mut self) -> State {
+        self.time_wait_until = time::ticks() + TIME_WAIT_TICKS;
+        self.retransmit_at = None;
+        State::TimeWait
+    }
+
+    // 再送と TIME_WAIT の時間切れ
+    pub fn on_timer(&mut self, now: u64) {
+        if self.state == State::TimeWait && now >= self.time_wait_until {
+            self.state = State::Closed;
+            return;
+        }
+        match self.retransmit_at {
+            Some(at) if now >= at => {}
+            _ => return,
+        }
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            self.state = State::Closed;
+            self.reset = true;
+            return;
+        }
+        match self.state {
+            State::SynSent => self.send(self.send_unacked, SYN, &[]),
+            State::SynReceived => self.send(self.send_unacked, SYN | ACK, &[]),
+            State::Closed | State::TimeWait => {}
+            _ if !self.send_queue.is_empty() => {
+                // 先頭の 1 セグメントだけ送り直す。ウィンドウが 0 のときは様子見になる
+                let len = self.send_queue.len().min(MSS);
+                let payload: Vec<u8> = self.send_queue.iter().take(len).copied().collect();
+                self.send(self.send_unacked, ACK | PSH, &payload);
+                let end = self.send_unacked.wrapping_add(len as u32);
+                if before(self.send_next, end) {
+                    self.send_next = end;
+                }
+            }
+            _ if self.fin_sent => self.send(self.send_next.wrapping_sub(1), FIN | ACK, &[]),
+            _ => {}
+        }
+        self.retransmit_at = Some(now + (RETRANSMIT_TICKS << self.retries.min(4)));
+    }
+
+    // 相手からのデータを取り出す。ウィンドウが狭くなっていたら空いたことを知らせる
+    pub fn read(&mut self) -> Vec<u8> {
+        let was_small = (self.window() as usize) < MSS;
+        let data: Vec<u8> = self.receive_queue.drain(..).collect();
+        if was_small && !data.is_empty() && self.state.receiving() {
+            self.send_ack();
+        }
+        data
+    }
+
+    // 送信キューに入れた量を返す。キューが一杯なら 0
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, &'static str> {
+        if self.reset {
+            return Err("Connection reset by peer");
+        }
+        let open = matches!(
+            self.state,
+            State::SynSent | State::SynReceived | State::Established | State::CloseWait
+        );
+        if !open || self.fin_queued {
+            return Err("Broken pipe");
+        }
+        let len = data.len().min(SEND_BUFFER - self.send_queue.len());
+        self.send_queue.extend(&data[..len]);
+        self.transmit();
+        Ok(len)
+    }
+
+    // 確認応答を待っているものと、まだ送っていないもの
+    pub fn send_queue_len(&self) -> usize {
+        self.send_queue.len()
+    }
+
+    // 届いて、まだ読まれていないもの
+    pub fn receive_queue_len(&self) -> usize {
+        self.receive_queue.len()
+    }
+
+    // 相手がもう送ってこず、受け取ったものも読み終わった
+    pub fn at_end(&self) -> bool {
+        self.receive_queue.is_empty()
+            && matches!(
+                self.state,
+                State::CloseWait
+                    | State::Closing
+                    | State::LastAck
+                    | State::TimeWait
+                    | State::Closed
+            )
+    }
+
+    pub fn close(&mut self) {
+        self.orphaned = true;
+        match self.state {
+            State::SynSent => self.state = State::Closed,
+            State::SynReceived | State::Established | State::CloseWait => {
+                self.fin_queued = true;
+                self.transmit();
+            }
+            _ => {}
+        }
+    }
+}
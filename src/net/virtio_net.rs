@@ -0,0 +1,157 @@
+// virtio-net (レガシー PCI インターフェース)。QEMU の -device virtio-net-pci で、最初の 1 つを
+// eth0 として使う。割り込みは使わず、届いたフレームは net-rx タスクが receive で取りに来る。
+// 送信は virtio-console と同じく、1 つのバッファで書き終わるまで待つ
+use crate::device::{self, DeviceClass};
+use crate::hal::port::PortRange;
+use crate::pci;
+use crate::virtio::{
+    Virtqueue, DESCRIPTOR_WRITE, DEVICE_CONFIG, DEVICE_STATUS, GUEST_FEATURES, HOST_FEATURES,
+    PAGE_SIZE, QUEUE_NOTIFY, STATUS_ACKNOWLEDGE, STATUS_DRIVER, STATUS_DRIVER_OK, VENDOR_VIRTIO,
+};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+const DEVICE_NET: u16 = 0x1000;
+const REGISTERS_LEN: u16 = 0x20;
+// MAC アドレスは設定空間の先頭にある
+const FEATURE_MAC: u32 = 1 << 5;
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+
+// フレームの前に付くヘッダ。オフロードは使わないので送るときは 0 で埋める
+const NET_HEADER_LEN: usize = 10;
+// 受信バッファ 1 つに、ヘッダと VLAN タグ付きの最大のフレームが入る
+const RX_BUFFER_SIZE: usize = 2048;
+const RX_PAGES: usize = 8;
+const RX_BUFFERS: usize = RX_PAGES * PAGE_SIZE / RX_BUFFER_SIZE;
+const TX_SPIN_LIMIT: usize = 1_000_000;
+
+struct Nic {
+    registers: PortRange,
+    receive: Virtqueue,
+    transmit: Virtqueue,
+    mac: [u8; 6],
+}
+
+// 中のポインタは DMA 用に取った、この Nic だけが使うページを指している
+unsafe impl Send for Nic {}
+
+impl Nic {
+    fn new(pci: &pci::PciDevice) -> Result<Nic, &'static str> {
+        let base = pci.io_bar(0).ok_or("no I/O BAR")?;
+        let registers = unsafe { PortRange::new("virtio-net", base, REGISTERS_LEN) };
+        registers.claim()?;
+        pci.enable_io_and_dma();
+
+        registers.write::<u8>(DEVICE_STATUS, 0);
+        registers.write::<u8>(DEVICE_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        let features: u32 = registers.read(HOST_FEATURES);
+        if features & FEATURE_MAC == 0 {
+            return Err("device has no MAC address");
+        }
+        registers.write::<u32>(GUEST_FEATURES, FEATURE_MAC);
+        let mut mac = [0; 6];
+        for (index, byte) in mac.iter_mut().enumerate() {
+            *byte = registers.read(DEVICE_CONFIG + index as u16);
+        }
+        let receive = Virtqueue::new(&registers, RECEIVE_QUEUE, RX_PAGES)?;
+        let transmit = Virtqueue::new(&registers, TRANSMIT_QUEUE, 1)?;
+        registers.write::<u8>(
+            DEVICE_STATUS,
+            STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_DRIVER_OK,
+        );
+
+        let mut nic = Nic {
+            registers,
+            receive,
+            transmit,
+            mac,
+        };
+        let buffers = RX_BUFFERS.min(nic.receive.size as usize) as u16;
+        for index in 0..buffers {
+            let offset = index as usize * RX_BUFFER_SIZE;
+            nic.receive
+                .set_descriptor(index, offset, RX_BUFFER_SIZE, DESCRIPTOR_WRITE);
+            nic.receive.make_available(index);
+        }
+        nic.notify(RECEIVE_QUEUE);
+        Ok(nic)
+    }
+
+    fn notify(&self, queue: u16) {
+        self.registers.write::<u16>(QUEUE_NOTIFY, queue);
+    }
+
+    fn send(&mut self, frame: &[u8]) {
+        let len = NET_HEADER_LEN + frame.len();
+        unsafe {
+            core::ptr::write_bytes(self.transmit.buffer, 0, NET_HEADER_LEN);
+            core::ptr::copy_nonoverlapping(
+                frame.as_ptr(),
+                self.transmit.buffer.add(NET_HEADER_LEN),
+                frame.len(),
+            );
+        }
+        self.transmit.set_descriptor(0, 0, len, 0);
+        self.transmit.make_available(0);
+        self.notify(TRANSMIT_QUEUE);
+        let mut spins = 0;
+        while self.transmit.pop_used().is_none() && spins < TX_SPIN_LIMIT {
+            core::hint::spin_loop();
+            spins += 1;
+        }
+    }
+
+    // 届いたフレームを 1 つ写して、バッファをデバイスに返す
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        let (index, len) = self.receive.pop_used()?;
+        let offset = index as usize * RX_BUFFER_SIZE;
+        let len = len.min(RX_BUFFER_SIZE);
+        let frame = unsafe {
+            core::slice::from_raw_parts(self.receive.buffer.add(offset), len)
+                .get(NET_HEADER_LEN..)
+                .unwrap_or(&[])
+                .to_vec()
+        };
+        self.receive.make_available(index);
+        self.notify(RECEIVE_QUEUE);
+        Some(frame)
+    }
+}
+
+static NIC: Mutex<Option<Nic>> = Mutex::new(None);
+
+// 見つかれば MAC アドレスを返す
+pub fn init() -> Result<Option<[u8; 6]>, &'static str> {
+    let pci = match pci::find(VENDOR_VIRTIO, DEVICE_NET).first() {
+        Some(pci) => *pci,
+        None => return Ok(None),
+    };
+    let bus = device::find("pci").ok_or("pci bus not registered")?;
+    let nic = Nic::new(&pci)?;
+    device::register(
+        "eth0",
+        DeviceClass::Network,
+        Some(bus),
+        &[nic.registers.resource()],
+    );
+    let mac = nic.mac;
+    *NIC.lock() = Some(nic);
+    Ok(Some(mac))
+}
+
+// フレームの大きさはヘッダを足して送信バッファに収まること (呼ぶ側が MTU で抑えている)
+pub fn send(frame: &[u8]) -> Result<(), &'static str> {
+    match NIC.lock().as_mut() {
+        Some(nic) => {
+            nic.send(frame);
+            Ok(())
+        }
+        None => Err("no network device"),
+    }
+}
+
+pub fn receive() -> Option<Vec<u8>> {
+    NIC.lock().as_mut()?.receive()
+}
@@ -0,0 +1,94 @@
+// 画面に収まらない出力を 1 画面ずつ見せる。top と同じく、表示中のキー入力は
+// シェルから handle_key に回してもらう
+use crate::vga_buffer::{self, BUFFER_HEIGHT, BUFFER_WIDTH};
+use crate::{print, println};
+use alloc::string::String;
+use alloc::vec::Vec;
+use pc_keyboard::{DecodedKey, KeyCode};
+use spin::Mutex;
+
+// 最後の行は --More-- に使う
+const PAGE_ROWS: usize = BUFFER_HEIGHT - 1;
+const PROMPT: &str = "--More--";
+
+struct Pager {
+    lines: Vec<String>,
+    next: usize,
+}
+
+impl Pager {
+    // rows 行分を表示する。表示し終わったら true
+    fn show(&mut self, rows: usize) -> bool {
+        let mut shown = 0;
+        while self.next < self.lines.len() && shown < rows {
+            let line = &self.lines[self.next];
+            println!("{}", line);
+            shown += screen_rows(line);
+            self.next += 1;
+        }
+        self.next >= self.lines.len()
+    }
+}
+
+static PAGER: Mutex<Option<Pager>> = Mutex::new(None);
+
+// 画面上で何行になるか。エスケープシーケンスは幅に数えない
+fn screen_rows(line: &str) -> usize {
+    let mut width: usize = 0;
+    let mut in_escape = false;
+    for c in line.chars() {
+        match c {
+            '\u{1b}' => in_escape = true,
+            c if in_escape => in_escape = !c.is_ascii_alphabetic(),
+            _ => width += 1,
+        }
+    }
+    width.max(1).div_ceil(BUFFER_WIDTH)
+}
+
+pub fn is_active() -> bool {
+    PAGER.lock().is_some()
+}
+
+// 1 画面に収まるか、出力をキャプチャ中ならそのまま出す。表示中のページャがあれば
+// その後ろに足す (1 行で続けて実行したコマンドの出力など)
+pub fn page(text: &str) {
+    if let Some(pager) = PAGER.lock().as_mut() {
+        pager.lines.extend(text.lines().map(String::from));
+        return;
+    }
+    let lines: Vec<String> = text.lines().map(String::from).collect();
+    let rows: usize = lines.iter().map(|line| screen_rows(line)).sum();
+    if rows <= PAGE_ROWS || vga_buffer::is_capturing() {
+        print!("{}", text);
+        return;
+    }
+
+    let mut pager = Pager { lines, next: 0 };
+    pager.show(PAGE_ROWS);
+    print!("{}", PROMPT);
+    *PAGER.lock() = Some(pager);
+}
+
+// Space で次の画面、Enter で 1 行、q で終わる。閉じたら true
+pub fn handle_key(key: DecodedKey) -> bool {
+    let mut guard = PAGER.lock();
+    let pager = match guard.as_mut() {
+        Some(pager) => pager,
+        None => return true,
+    };
+    let rows = match key {
+        DecodedKey::Unicode(' ') | DecodedKey::RawKey(KeyCode::PageDown) => PAGE_ROWS,
+        DecodedKey::Unicode('\n') | DecodedKey::RawKey(KeyCode::ArrowDown) => 1,
+        DecodedKey::Unicode('q') | DecodedKey::Unicode('Q') => 0,
+        _ => return false,
+    };
+
+    print!("\r{:width$}\r", "", width = PROMPT.len());
+    if rows == 0 || pager.show(rows) {
+        *guard = None;
+        return true;
+    }
+    print!("{}", PROMPT);
+    false
+}
@@ -0,0 +1,168 @@
+// PCI の構成空間 (ポート 0xcf8/0xcfc を使う方式 1)。起動時に全部のバスを一度だけ
+// 調べて、見つけた機能をデバイスツリーの pci バスの下に登録する
+use crate::device::{self, DeviceClass, Resource};
+use crate::hal::port::PortRange;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+static CONFIG_PORTS: PortRange = unsafe { PortRange::new("pci", 0xcf8, 8) };
+const ADDRESS: u16 = 0;
+const DATA: u16 = 4;
+
+const VENDOR_NONE: u16 = 0xffff;
+const COMMAND: u8 = 0x04;
+const COMMAND_IO: u16 = 1 << 0;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const HEADER_TYPE: u8 = 0x0e;
+const MULTI_FUNCTION: u32 = 0x80;
+const BAR0: u8 = 0x10;
+const BAR_IO: u32 = 1;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub slot: u8,
+    pub function: u8,
+    pub vendor: u16,
+    pub device: u16,
+    pub class: u8,
+    pub subclass: u8,
+}
+
+impl PciDevice {
+    pub fn read(&self, offset: u8) -> u32 {
+        read_config(self.bus, self.slot, self.function, offset)
+    }
+
+    pub fn write(&self, offset: u8, value: u32) {
+        write_config(self.bus, self.slot, self.function, offset, value);
+    }
+
+    // I/O 空間の BAR なら先頭のポート番号
+    pub fn io_bar(&self, index: u8) -> Option<u16> {
+        let bar = self.read(BAR0 + index * 4);
+        if bar & BAR_IO == 0 || bar & !0x3 == 0 {
+            return None;
+        }
+        Some((bar & !0x3) as u16)
+    }
+
+    // I/O ポートの読み書きと、デバイスからメモリへのアクセス (DMA) を許す
+    pub fn enable_io_and_dma(&self) {
+        let value = self.read(COMMAND);
+        let command = value as u16 | COMMAND_IO | COMMAND_BUS_MASTER;
+        self.write(COMMAND, (value & 0xffff_0000) | command as u32);
+    }
+
+    pub fn name(&self) -> String {
+        format!("{:02x}:{:02x}.{}", self.bus, self.slot, self.function)
+    }
+}
+
+static DEVICES: Mutex<Vec<PciDevice>> = Mutex::new(Vec::new());
+
+fn address(bus: u8, slot: u8, function: u8, offset: u8) -> u32 {
+    0x8000_0000
+        | (bus as u32) << 16
+        | (slot as u32) << 11
+        | (function as u32) << 8
+        | (offset as u32 & 0xfc)
+}
+
+fn read_config(bus: u8, slot: u8, function: u8, offset: u8) -> u32 {
+    crate::arch::without_interrupts(|| {
+        CONFIG_PORTS.write::<u32>(ADDRESS, address(bus, slot, function, offset));
+        CONFIG_PORTS.read::<u32>(DATA)
+    })
+}
+
+fn write_config(bus: u8, slot: u8, function: u8, offset: u8, value: u32) {
+    crate::arch::without_interrupts(|| {
+        CONFIG_PORTS.write::<u32>(ADDRESS, address(bus, slot, function, offset));
+        CONFIG_PORTS.write::<u32>(DATA, value);
+    });
+}
+
+fn probe(bus: u8, slot: u8, function: u8) -> Option<PciDevice> {
+    let id = read_config(bus, slot, function, 0);
+    let vendor = id as u16;
+    if vendor == VENDOR_NONE {
+        return None;
+    }
+    let class = read_config(bus, slot, function, 0x08);
+    Some(PciDevice {
+        bus,
+        slot,
+        function,
+        vendor,
+        device: (id >> 16) as u16,
+        class: (class >> 24) as u8,
+        subclass: (class >> 16) as u8,
+    })
+}
+
+fn device_class(class: u8) -> DeviceClass {
+    match class {
+        0x01 => DeviceClass::Storage,
+        0x02 => DeviceClass::Network,
+        0x03 => DeviceClass::Display,
+        0x06 => DeviceClass::Bus,
+        0x07 => DeviceClass::Serial,
+        0x09 => DeviceClass::Input,
+        _ => DeviceClass::Other,
+    }
+}
+
+pub fn init() -> Result<(), &'static str> {
+    CONFIG_PORTS.claim()?;
+    let platform = device::find("platform").ok_or("platform bus not registered")?;
+    let bus_id = device::register(
+        "pci",
+        DeviceClass::Bus,
+        Some(platform),
+        &[CONFIG_PORTS.resource()],
+    );
+
+    let mut found = Vec::new();
+    for bus in 0..=255u8 {
+        for slot in 0..32 {
+            let first = match probe(bus, slot, 0) {
+                Some(device) => device,
+                None => continue,
+            };
+            found.push(first);
+            if read_config(bus, slot, 0, HEADER_TYPE) >> 16 & MULTI_FUNCTION == 0 {
+                continue;
+            }
+            found.extend((1..8).filter_map(|function| probe(bus, slot, function)));
+        }
+    }
+
+    for pci in found.iter() {
+        // ブリッジの BAR の後ろはバス番号などなので見ない
+        let bars = if pci.class == 0x06 { 0 } else { 6 };
+        let resources: Vec<Resource> = (0..bars)
+            .filter_map(|index| pci.io_bar(index))
+            .map(|start| Resource::IoPorts { start, len: 0 })
+            .collect();
+        device::register(
+            &format!("{} {:04x}:{:04x}", pci.name(), pci.vendor, pci.device),
+            device_class(pci.class),
+            Some(bus_id),
+            &resources,
+        );
+    }
+    *DEVICES.lock() = found;
+    Ok(())
+}
+
+pub fn find(vendor: u16, device: u16) -> Vec<PciDevice> {
+    DEVICES
+        .lock()
+        .iter()
+        .filter(|pci| pci.vendor == vendor && pci.device == device)
+        .copied()
+        .collect()
+}
@@ -0,0 +1,136 @@
+use crate::arch;
+use crate::hal::port;
+use x86_64::instructions::port::Port;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Width {
+    Byte,
+    Word,
+    Dword,
+    Qword,
+}
+
+impl Width {
+    pub fn from_name(name: &str) -> Option<Width> {
+        match name {
+            "b" => Some(Width::Byte),
+            "w" => Some(Width::Word),
+            "d" => Some(Width::Dword),
+            "q" => Some(Width::Qword),
+            _ => None,
+        }
+    }
+
+    pub fn bytes(self) -> u64 {
+        match self {
+            Width::Byte => 1,
+            Width::Word => 2,
+            Width::Dword => 4,
+            Width::Qword => 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Virtual(u64),
+    Physical(u64),
+    Port(u16),
+}
+
+impl Target {
+    // 書き込みやポート読み込みは副作用があるので unsafe モードが必要
+    pub fn needs_unsafe(self, write: bool) -> bool {
+        write || matches!(self, Target::Port(_))
+    }
+}
+
+pub fn parse_number(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn memory_address(target: Target, width: Width, write: bool) -> Result<u64, &'static str> {
+    let addr = match target {
+        Target::Virtual(addr) => addr,
+        Target::Physical(addr) => {
+            arch::phys_to_virt(addr).ok_or("physical memory is not mapped")?
+        }
+        Target::Port(_) => return Err("not a memory address"),
+    };
+
+    if addr % width.bytes() != 0 {
+        return Err("address is not aligned");
+    }
+    if !arch::is_range_mapped(addr, width.bytes(), write) {
+        return Err("address is not mapped");
+    }
+    Ok(addr)
+}
+
+// ドライバが claim しているポートはシェルから触らせない
+fn check_port(port: u16, width: Width) -> Result<(), &'static str> {
+    let last = port
+        .checked_add(width.bytes() as u16 - 1)
+        .ok_or("port out of range")?;
+    if (port..=last).any(|port| port::owner_of(port).is_some()) {
+        return Err("port is claimed by a driver (see ioports)");
+    }
+    Ok(())
+}
+
+pub fn read(target: Target, width: Width) -> Result<u64, &'static str> {
+    if let Target::Port(port) = target {
+        check_port(port, width)?;
+        return unsafe {
+            match width {
+                Width::Byte => Ok(Port::<u8>::new(port).read() as u64),
+                Width::Word => Ok(Port::<u16>::new(port).read() as u64),
+                Width::Dword => Ok(Port::<u32>::new(port).read() as u64),
+                Width::Qword => Err("ports are at most 32 bits wide"),
+            }
+        };
+    }
+
+    let addr = memory_address(target, width, false)?;
+    unsafe {
+        Ok(match width {
+            Width::Byte => core::ptr::read_volatile(addr as *const u8) as u64,
+            Width::Word => core::ptr::read_volatile(addr as *const u16) as u64,
+            Width::Dword => core::ptr::read_volatile(addr as *const u32) as u64,
+            Width::Qword => core::ptr::read_volatile(addr as *const u64),
+        })
+    }
+}
+
+pub fn write(target: Target, width: Width, value: u64) -> Result<(), &'static str> {
+    if value > u64::MAX >> (64 - 8 * width.bytes()) {
+        return Err("value does not fit in the given width");
+    }
+
+    if let Target::Port(port) = target {
+        check_port(port, width)?;
+        return unsafe {
+            match width {
+                Width::Byte => Port::<u8>::new(port).write(value as u8),
+                Width::Word => Port::<u16>::new(port).write(value as u16),
+                Width::Dword => Port::<u32>::new(port).write(value as u32),
+                Width::Qword => return Err("ports are at most 32 bits wide"),
+            }
+            Ok(())
+        };
+    }
+
+    let addr = memory_address(target, width, true)?;
+    unsafe {
+        match width {
+            Width::Byte => core::ptr::write_volatile(addr as *mut u8, value as u8),
+            Width::Word => core::ptr::write_volatile(addr as *mut u16, value as u16),
+            Width::Dword => core::ptr::write_volatile(addr as *mut u32, value as u32),
+            Width::Qword => core::ptr::write_volatile(addr as *mut u64, value),
+        }
+    }
+    Ok(())
+}
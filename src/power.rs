@@ -0,0 +1,96 @@
+use crate::arch::x86::sleep;
+use crate::hal::port::PortRange;
+use crate::{acpi, arch};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+// QEMU の ACPI PM1a 制御レジスタと isa-debug-exit デバイス
+static PM1A_CONTROL: PortRange = unsafe { PortRange::new("acpi-pm", 0x604, 2) };
+static QEMU_EXIT: PortRange = unsafe { PortRange::new("qemu-exit", 0xf4, 4) };
+
+const SLP_EN: u16 = 1 << 13;
+
+// スリープの前後にドライバが呼んでほしい処理
+#[derive(Clone, Copy)]
+struct PmOps {
+    suspend: fn() -> Result<(), &'static str>,
+    resume: fn(),
+}
+
+static PM_OPS: Mutex<Vec<PmOps>> = Mutex::new(Vec::new());
+// enter_s3 は引数を取れないので書き込む値をここに置く
+static SLEEP_CONTROL: Mutex<Vec<(PortRange, u16)>> = Mutex::new(Vec::new());
+
+pub fn claim_ports() -> Result<(), &'static str> {
+    PM1A_CONTROL.claim()?;
+    QEMU_EXIT.claim()
+}
+
+// 止めるときは登録の逆順、戻すときは登録順に呼ぶ
+pub fn register_pm(suspend: fn() -> Result<(), &'static str>, resume: fn()) {
+    PM_OPS.lock().push(PmOps { suspend, resume });
+}
+
+pub fn shutdown() -> ! {
+    PM1A_CONTROL.write(0, 0x2000u16);
+    QEMU_EXIT.write(0, 0x10u32);
+
+    arch::disable_interrupts();
+    loop {
+        arch::halt();
+    }
+}
+
+extern "C" fn enter_s3() -> u64 {
+    let control = SLEEP_CONTROL.lock().clone();
+    sleep::flush_caches();
+    for (port, sleep_type) in control {
+        let value: u16 = port.read(0);
+        port.write(0, value & !(7 << 10) | sleep_type << 10 | SLP_EN);
+    }
+    // 書き込んでもすぐには止まらないので少し待つ
+    for _ in 0..1_000_000 {
+        core::hint::spin_loop();
+    }
+    1
+}
+
+pub fn suspend() -> Result<(), &'static str> {
+    let info = acpi::sleep_info(3)?;
+    let mut control = Vec::new();
+    for (port, sleep_type) in [
+        (info.pm1a_control, info.sleep_type_a),
+        (info.pm1b_control, info.sleep_type_b),
+    ] {
+        if port != 0 {
+            let range = unsafe { PortRange::new("acpi-pm", port, 2) };
+            range.claim()?;
+            control.push((range, sleep_type));
+        }
+    }
+    if control.is_empty() {
+        return Err("no PM1 control register");
+    }
+    *SLEEP_CONTROL.lock() = control;
+
+    let vector = sleep::prepare_trampoline()?;
+    acpi::set_waking_vector(info.facs, vector)?;
+
+    let ops = PM_OPS.lock().clone();
+    arch::disable_interrupts();
+    for (index, op) in ops.iter().enumerate().rev() {
+        if let Err(e) = (op.suspend)() {
+            ops[index + 1..].iter().for_each(|op| (op.resume)());
+            arch::enable_interrupts();
+            return Err(e);
+        }
+    }
+
+    let status = sleep::suspend(enter_s3);
+    ops.iter().for_each(|op| (op.resume)());
+    arch::enable_interrupts();
+    match status {
+        0 => Ok(()),
+        _ => Err("firmware did not enter S3"),
+    }
+}
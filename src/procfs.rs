@@ -0,0 +1,54 @@
+// /proc 以下のファイル。ファイル自体は空の読み出し専用ファイルとして置いておき、
+// 読まれるたびに中身を作り直す
+use crate::arch::x86::cpu;
+use crate::filesystem;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+type Generator = fn() -> String;
+
+const FILES: &[(&str, Generator)] = &[("cpuinfo", cpuinfo), ("sensors", sensors)];
+
+pub fn init() -> Result<(), &'static str> {
+    filesystem::create_directory("/proc", true)?;
+    for (name, _) in FILES {
+        filesystem::touch(&format!("/proc/{}", name), true)?;
+    }
+    filesystem::mount("/proc", "proc", true)
+}
+
+// path が /proc のファイルを指していれば、その中身を作って返す
+pub fn generate(path: &str) -> Option<Vec<u8>> {
+    match filesystem::resolve_path(path).as_slice() {
+        [dir, name] if dir == "proc" => FILES
+            .iter()
+            .find(|(file, _)| file == name)
+            .map(|(_, contents)| contents().into_bytes()),
+        _ => None,
+    }
+}
+
+fn cpuinfo() -> String {
+    let (family, model, stepping) = cpu::signature();
+    let mut text = String::new();
+    let _ = writeln!(text, "vendor_id\t: {}", cpu::vendor());
+    let _ = writeln!(text, "cpu family\t: {}", family);
+    let _ = writeln!(text, "model\t\t: {}", model);
+    if let Some(brand) = cpu::brand() {
+        let _ = writeln!(text, "model name\t: {}", brand);
+    }
+    let _ = writeln!(text, "stepping\t: {}", stepping);
+    if let Some(mhz) = cpu::tsc_mhz() {
+        let _ = writeln!(text, "cpu MHz\t\t: {}", mhz);
+    }
+    text
+}
+
+pub fn sensors() -> String {
+    match cpu::temperature() {
+        Some((current, tj_max)) => format!("cpu0: {}C (TjMax {}C)\n", current, tj_max),
+        None => String::from("no digital thermal sensor\n"),
+    }
+}
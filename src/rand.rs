@@ -0,0 +1,32 @@
+pub struct XorShift64(u64);
+
+impl XorShift64 {
+    pub fn new(seed: u64) -> Self {
+        XorShift64(if seed == 0 {
+            0x2545_f491_4f6c_dd1d
+        } else {
+            seed
+        })
+    }
+
+    pub fn from_tsc() -> Self {
+        Self::new(crate::arch::cycle_counter())
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+}
+
+// 起動時に配置をずらす量。align の倍数で limit 未満。種は TSC なので、ずれは
+// 毎回変わるが推測できないほどではない
+pub fn boot_offset(limit: usize, align: usize) -> usize {
+    XorShift64::from_tsc().below(limit / align) * align
+}
@@ -0,0 +1,52 @@
+// シェルのコマンドが panic してもカーネル全体を止めないための仕組み。
+// panic = "abort" なので巻き戻しはできない。代わりにメインループに入るときの
+// スタックポインタを覚えておき、コマンドの実行中に panic したらそこからメインループを
+// やり直す。捨てたフレームの drop は走らないのでそのメモリはリークし、握っていた
+// ロックは分かっているもの (シェル、画面、ファイルシステム) だけを解放する
+use crate::{arch, filesystem, keyboard, println, trash, vga_buffer};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+static RESTART_STACK: AtomicU64 = AtomicU64::new(0);
+// guard の入れ子の深さ
+static DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_restart_stack(stack: u64) {
+    RESTART_STACK.store(stack, Ordering::Relaxed);
+}
+
+// f の中で panic したら、f の呼び出し元には戻らずメインループからやり直す
+pub fn guard<R>(f: impl FnOnce() -> R) -> R {
+    DEPTH.fetch_add(1, Ordering::Relaxed);
+    let result = f();
+    DEPTH.fetch_sub(1, Ordering::Relaxed);
+    result
+}
+
+pub fn can_recover() -> bool {
+    // 割り込みハンドラの中からは戻れない (EOI も送られず、ハンドラの数も合わなくなる)
+    DEPTH.load(Ordering::Relaxed) > 0
+        && RESTART_STACK.load(Ordering::Relaxed) != 0
+        && !arch::in_interrupt()
+}
+
+// panic ハンドラから呼ぶ。can_recover() が true のときだけ使える
+pub fn recover(info: &PanicInfo) -> ! {
+    arch::disable_interrupts();
+    DEPTH.store(0, Ordering::Relaxed);
+    unsafe {
+        vga_buffer::force_unlock();
+        filesystem::recover();
+        trash::recover();
+    }
+    println!();
+    println!("command aborted: {}", info);
+    crate::ksym::print_backtrace();
+    unsafe { arch::call_on_stack(RESTART_STACK.load(Ordering::Relaxed), restart) }
+}
+
+extern "C" fn restart() -> ! {
+    unsafe { keyboard::recover_shell() };
+    arch::enable_interrupts();
+    crate::main_loop()
+}
@@ -0,0 +1,58 @@
+use crate::task::{self, TaskState};
+use crate::time;
+use crate::vga_buffer;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+static TIMEOUT_TICKS: AtomicU64 = AtomicU64::new(5 * 60 * time::TICK_HZ);
+static LAST_INPUT: AtomicU64 = AtomicU64::new(0);
+static BLANKED: AtomicBool = AtomicBool::new(false);
+
+// 0 で無効。ティックに直すと u64 に収まらないときは Err で、設定は変えない
+pub fn set_timeout_minutes(minutes: u64) -> Result<(), &'static str> {
+    let ticks = minutes
+        .checked_mul(60 * time::TICK_HZ)
+        .ok_or("timeout is too long")?;
+    TIMEOUT_TICKS.store(ticks, Ordering::Relaxed);
+    Ok(())
+}
+
+// キー入力ごとに呼ぶ。画面を戻したときは true を返し、そのキーは捨てる
+pub fn wake() -> bool {
+    LAST_INPUT.store(time::ticks(), Ordering::Relaxed);
+    if BLANKED.swap(false, Ordering::Relaxed) {
+        vga_buffer::set_display_enabled(true);
+        true
+    } else {
+        false
+    }
+}
+
+// 締め切りまで眠るタスクとして動かす。設定が変わっても 1 秒以内に気付けるよう、
+// それより長くは眠らない
+pub fn start() -> Result<u64, &'static str> {
+    Ok(task::spawn("screensaver", || {
+        poll();
+        let limit = time::ticks() + time::TICK_HZ;
+        TaskState::Sleep(next_deadline().map_or(limit, |deadline| deadline.min(limit)))
+    }))
+}
+
+fn poll() {
+    let timeout = TIMEOUT_TICKS.load(Ordering::Relaxed);
+    if timeout == 0 || BLANKED.load(Ordering::Relaxed) {
+        return;
+    }
+    if time::ticks().saturating_sub(LAST_INPUT.load(Ordering::Relaxed)) >= timeout {
+        BLANKED.store(true, Ordering::Relaxed);
+        vga_buffer::set_display_enabled(false);
+    }
+}
+
+fn next_deadline() -> Option<u64> {
+    let timeout = TIMEOUT_TICKS.load(Ordering::Relaxed);
+    if timeout == 0 || BLANKED.load(Ordering::Relaxed) {
+        None
+    } else {
+        Some(LAST_INPUT.load(Ordering::Relaxed).saturating_add(timeout))
+    }
+}
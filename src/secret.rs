@@ -0,0 +1,80 @@
+// パスワードや鍵のように、使い終わったらメモリに残したくないバイト列。
+// 捨てるときに中身を 0 で埋める。ヒープのブロックにはアロケータの印も付けておくので、
+// Drop を通らずに解放されても (mem::forget の後に Box を作り直したときなど) 0 で埋まる。
+// 長さは作ったときに決まり、伸ばさない。伸ばすと元の場所に写しが残るため
+use crate::allocator;
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{compiler_fence, Ordering};
+
+pub struct SecretBytes {
+    bytes: Box<[u8]>,
+}
+
+impl SecretBytes {
+    pub fn new(bytes: &[u8]) -> SecretBytes {
+        let mut secret = SecretBytes {
+            bytes: vec![0; bytes.len()].into_boxed_slice(),
+        };
+        if !secret.bytes.is_empty() {
+            allocator::scrub_on_free(secret.bytes.as_ptr());
+        }
+        secret.bytes.copy_from_slice(bytes);
+        secret
+    }
+
+    // 渡された Vec の中身は写したあとで 0 で埋める
+    pub fn from_vec(mut bytes: Vec<u8>) -> SecretBytes {
+        let secret = SecretBytes::new(&bytes);
+        scrub(&mut bytes);
+        secret
+    }
+
+    pub fn expose(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    // 比べる時間が、どこまで一致したかで変わらないようにする
+    pub fn matches(&self, other: &[u8]) -> bool {
+        if self.bytes.len() != other.len() {
+            return false;
+        }
+        let difference = self
+            .bytes
+            .iter()
+            .zip(other)
+            .fold(0, |difference, (a, b)| difference | (a ^ b));
+        difference == 0
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        scrub(&mut self.bytes);
+    }
+}
+
+// 中身は表示しない
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecretBytes([REDACTED; {}])", self.bytes.len())
+    }
+}
+
+// 使われない書き込みとして最適化で消されないように、volatile で 0 を書く
+pub fn scrub(buffer: &mut [u8]) {
+    for byte in buffer.iter_mut() {
+        unsafe { core::ptr::write_volatile(byte, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
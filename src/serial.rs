@@ -0,0 +1,82 @@
+use crate::device::{self, DeviceClass, Resource};
+use crate::hal::port::PortRange;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use uart_16550::SerialPort;
+
+static COM1_PORTS: PortRange = unsafe { PortRange::new("com1", 0x3f8, 8) };
+static COM2_PORTS: PortRange = unsafe { PortRange::new("com2", 0x2f8, 8) };
+
+lazy_static! {
+    pub static ref SERIAL1: Mutex<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(COM1_PORTS.start()) };
+        serial_port.init();
+        Mutex::new(serial_port)
+    };
+    pub static ref SERIAL2: Mutex<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(COM2_PORTS.start()) };
+        serial_port.init();
+        Mutex::new(serial_port)
+    };
+}
+
+pub fn register_devices(bus: usize) -> Result<(), &'static str> {
+    COM1_PORTS.claim()?;
+    COM2_PORTS.claim()?;
+    device::register(
+        "com1",
+        DeviceClass::Serial,
+        Some(bus),
+        &[COM1_PORTS.resource(), Resource::Irq(4)],
+    );
+    device::register(
+        "com2",
+        DeviceClass::Serial,
+        Some(bus),
+        &[COM2_PORTS.resource(), Resource::Irq(3)],
+    );
+    crate::power::register_pm(
+        || Ok(()),
+        || {
+            SERIAL1.lock().init();
+            SERIAL2.lock().init();
+        },
+    );
+    Ok(())
+}
+
+// COM2 に届いているバイトがあれば読む。無ければ待たずに None (LSR の Data Ready を見る)
+pub fn try_receive_com2() -> Option<u8> {
+    let mut serial = SERIAL2.lock();
+    if COM2_PORTS.read::<u8>(5) & 1 == 0 {
+        return None;
+    }
+    Some(serial.receive())
+}
+
+#[doc(hidden)]
+pub fn _print(args: ::core::fmt::Arguments) {
+    use core::fmt::Write;
+
+    crate::arch::without_interrupts(|| {
+        SERIAL1
+            .lock()
+            .write_fmt(args)
+            .expect("Printing to serial failed");
+    });
+}
+
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => {
+        $crate::serial::_print(format_args!($($arg)*));
+    };
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($fmt:expr) => ($crate::serial_print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
+        concat!($fmt, "\n"), $($arg)*));
+}
@@ -0,0 +1,197 @@
+// 起動時に動かすサービス。initcall がハードウェアとサブシステムを用意したあと、
+// /etc/services に書かれた順に起動する。一行に一つ名前を書き、# から行末はコメント。
+// panic は abort なので捕まえられない。タスクが Done や Failed で終わったら落ちたとみなす
+use crate::log::Level;
+use crate::task::{self, TaskState};
+use crate::{filesystem, klog, screensaver, time};
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+pub const SERVICES_PATH: &str = "/etc/services";
+
+const DEFAULT_SERVICES: &str = "\
+# Services started at boot, in order
+screensaver
+";
+
+pub struct Service {
+    pub name: &'static str,
+    pub description: &'static str,
+    // 起動したタスクの ID を返す。タスクが終わったら落ちたとみなして再起動する
+    pub start: fn() -> Result<u64, &'static str>,
+}
+
+const SERVICES: &[Service] = &[Service {
+    name: "screensaver",
+    description: "Blank the screen after the configured idle time",
+    start: screensaver::start,
+}];
+
+// 再起動の間隔は 1 秒から倍々に延ばし、1 分で頭打ちにする。
+// 1 分以上動き続けたら落ちても最初の間隔に戻す
+const FIRST_BACKOFF: u64 = time::TICK_HZ;
+const MAX_BACKOFF: u64 = 60 * time::TICK_HZ;
+const STABLE_TICKS: u64 = 60 * time::TICK_HZ;
+const CHECK_INTERVAL: u64 = time::TICK_HZ / 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Stopped,
+    Running,
+    // at ティックに再起動する
+    Restarting { at: u64 },
+    Failed(&'static str),
+}
+
+struct Record {
+    service: &'static Service,
+    state: State,
+    task: u64,
+    started_at: u64,
+    backoff: u64,
+    restarts: u32,
+}
+
+static RECORDS: Mutex<Vec<Record>> = Mutex::new(Vec::new());
+
+pub fn all() -> &'static [Service] {
+    SERVICES
+}
+
+// (状態, 再起動した回数)
+pub fn state(name: &str) -> (State, u32) {
+    RECORDS
+        .lock()
+        .iter()
+        .find(|record| record.service.name == name)
+        .map_or((State::Stopped, 0), |record| {
+            (record.state, record.restarts)
+        })
+}
+
+fn launch(record: &mut Record) -> Result<(), &'static str> {
+    match (record.service.start)() {
+        Ok(task) => {
+            record.state = State::Running;
+            record.task = task;
+            record.started_at = time::ticks();
+            Ok(())
+        }
+        Err(e) => {
+            record.state = State::Failed(e);
+            Err(e)
+        }
+    }
+}
+
+pub fn start(name: &str) -> Result<(), &'static str> {
+    let service = SERVICES
+        .iter()
+        .find(|service| service.name == name)
+        .ok_or("unknown service")?;
+    let mut records = RECORDS.lock();
+    let index = match records
+        .iter()
+        .position(|record| record.service.name == name)
+    {
+        Some(index) => index,
+        None => {
+            records.push(Record {
+                service,
+                state: State::Stopped,
+                task: 0,
+                started_at: 0,
+                backoff: FIRST_BACKOFF,
+                restarts: 0,
+            });
+            records.len() - 1
+        }
+    };
+    let record = &mut records[index];
+    if record.state == State::Running {
+        return Err("already running");
+    }
+    record.backoff = FIRST_BACKOFF;
+    launch(record)
+}
+
+// 落ちたサービスを見つけて、間隔を空けて起動し直す
+fn supervise() {
+    let now = time::ticks();
+    for record in RECORDS.lock().iter_mut() {
+        match record.state {
+            State::Running if !task::is_alive(record.task) => {
+                if now - record.started_at >= STABLE_TICKS {
+                    record.backoff = FIRST_BACKOFF;
+                }
+                klog!(
+                    Level::Warn,
+                    "service: {} stopped unexpectedly, restarting in {} s",
+                    record.service.name,
+                    record.backoff / time::TICK_HZ
+                );
+                record.state = State::Restarting {
+                    at: now + record.backoff,
+                };
+                record.backoff = (record.backoff * 2).min(MAX_BACKOFF);
+            }
+            State::Restarting { at } if now >= at => {
+                record.restarts += 1;
+                match launch(record) {
+                    Ok(()) => klog!(
+                        Level::Info,
+                        "service: {} restarted ({} restarts)",
+                        record.service.name,
+                        record.restarts
+                    ),
+                    Err(e) => klog!(
+                        Level::Error,
+                        "service: {} failed to restart: {}",
+                        record.service.name,
+                        e
+                    ),
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn enabled_services() -> String {
+    match filesystem::read_file(SERVICES_PATH) {
+        Ok(content) => String::from_utf8_lossy(&content).into_owned(),
+        Err(_) => {
+            let _ = filesystem::create_directory("/etc", true);
+            if let Err(e) =
+                filesystem::write_file(SERVICES_PATH, DEFAULT_SERVICES.as_bytes(), false)
+            {
+                klog!(
+                    Level::Warn,
+                    "service: cannot write {}: {}",
+                    SERVICES_PATH,
+                    e
+                );
+            }
+            String::from(DEFAULT_SERVICES)
+        }
+    }
+}
+
+pub fn start_all() {
+    task::spawn("supervisor", || {
+        supervise();
+        TaskState::Sleep(time::ticks() + CHECK_INTERVAL)
+    });
+
+    for line in enabled_services().lines() {
+        let name = line.split('#').next().unwrap_or("").trim();
+        if name.is_empty() {
+            continue;
+        }
+        match start(name) {
+            Ok(()) => klog!(Level::Info, "[ OK ] Started {}", name),
+            Err(e) => klog!(Level::Error, "[FAIL] {}: {}", name, e),
+        }
+    }
+}
@@ -0,0 +1,154 @@
+// 整数の式の計算。$(( ... )) で使う。演算子の優先順位は C と同じで、
+// 比較と論理演算は真なら 1、偽なら 0 になる。未定義の変数は 0
+
+type Lookup<'a> = &'a dyn Fn(&str) -> Option<i64>;
+
+const SYNTAX_ERROR: &str = "syntax error in expression";
+
+// 二項演算子と優先順位 (大きいほど強く結び付く)。長いものを先に並べる
+const BINARY: &[(&str, u8)] = &[
+    ("||", 1),
+    ("&&", 2),
+    ("==", 6),
+    ("!=", 6),
+    ("<=", 7),
+    (">=", 7),
+    ("<<", 8),
+    (">>", 8),
+    ("|", 3),
+    ("^", 4),
+    ("&", 5),
+    ("<", 7),
+    (">", 7),
+    ("+", 9),
+    ("-", 9),
+    ("*", 10),
+    ("/", 10),
+    ("%", 10),
+];
+
+struct Parser<'a> {
+    rest: &'a str,
+    lookup: Lookup<'a>,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_spaces(&mut self) {
+        self.rest = self.rest.trim_start();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_spaces();
+        match self.rest.strip_prefix(token) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn binary_operator(&mut self) -> Option<(&'static str, u8)> {
+        self.skip_spaces();
+        BINARY
+            .iter()
+            .find(|(operator, _)| self.rest.starts_with(operator))
+            .copied()
+    }
+
+    fn take_while(&mut self, accept: impl Fn(char) -> bool) -> &'a str {
+        let end = self.rest.find(|c| !accept(c)).unwrap_or(self.rest.len());
+        let (taken, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        taken
+    }
+
+    fn primary(&mut self) -> Result<i64, &'static str> {
+        if self.eat("(") {
+            let value = self.expression(0)?;
+            return if self.eat(")") {
+                Ok(value)
+            } else {
+                Err(SYNTAX_ERROR)
+            };
+        }
+        if self.eat("-") {
+            return Ok(self.primary()?.wrapping_neg());
+        }
+        if self.eat("+") {
+            return self.primary();
+        }
+        if self.eat("!") {
+            return Ok((self.primary()? == 0) as i64);
+        }
+        if self.eat("~") {
+            return Ok(!self.primary()?);
+        }
+
+        self.skip_spaces();
+        let token = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_');
+        match token.chars().next() {
+            None => Err(SYNTAX_ERROR),
+            Some(c) if c.is_ascii_digit() => {
+                let parsed = match token.strip_prefix("0x") {
+                    Some(hex) => i64::from_str_radix(hex, 16),
+                    None => token.parse(),
+                };
+                parsed.map_err(|_| "invalid number")
+            }
+            Some(_) => Ok((self.lookup)(token).unwrap_or(0)),
+        }
+    }
+
+    // min_precedence 以上の演算子だけを読む
+    fn expression(&mut self, min_precedence: u8) -> Result<i64, &'static str> {
+        let mut left = self.primary()?;
+        while let Some((operator, precedence)) = self.binary_operator() {
+            if precedence < min_precedence {
+                break;
+            }
+            self.rest = &self.rest[operator.len()..];
+            let right = self.expression(precedence + 1)?;
+            left = apply(operator, left, right)?;
+        }
+        Ok(left)
+    }
+}
+
+fn apply(operator: &str, left: i64, right: i64) -> Result<i64, &'static str> {
+    Ok(match operator {
+        "||" => (left != 0 || right != 0) as i64,
+        "&&" => (left != 0 && right != 0) as i64,
+        "|" => left | right,
+        "^" => left ^ right,
+        "&" => left & right,
+        "==" => (left == right) as i64,
+        "!=" => (left != right) as i64,
+        "<" => (left < right) as i64,
+        "<=" => (left <= right) as i64,
+        ">" => (left > right) as i64,
+        ">=" => (left >= right) as i64,
+        "<<" => left.wrapping_shl(right as u32),
+        ">>" => left.wrapping_shr(right as u32),
+        "+" => left.wrapping_add(right),
+        "-" => left.wrapping_sub(right),
+        "*" => left.wrapping_mul(right),
+        "/" | "%" if right == 0 => return Err("division by zero"),
+        "/" => left.wrapping_div(right),
+        _ => left.wrapping_rem(right),
+    })
+}
+
+pub fn evaluate(expression: &str, lookup: Lookup) -> Result<i64, &'static str> {
+    let mut parser = Parser {
+        rest: expression,
+        lookup,
+    };
+    let value = parser.expression(0)?;
+    parser.skip_spaces();
+    if parser.rest.is_empty() {
+        Ok(value)
+    } else {
+        Err(SYNTAX_ERROR)
+    }
+}
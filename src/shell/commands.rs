@@ -0,0 +1,885 @@
+// シェルの組み込みコマンドの一覧。help と補完はここを見る
+
+use alloc::format;
+use alloc::string::String;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    Shell,
+    Files,
+    System,
+    Debug,
+    Tests,
+}
+
+impl Category {
+    pub const ALL: [Category; 5] = [
+        Category::Shell,
+        Category::Files,
+        Category::System,
+        Category::Debug,
+        Category::Tests,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::Shell => "Shell",
+            Category::Files => "Files",
+            Category::System => "System",
+            Category::Debug => "Debugging",
+            Category::Tests => "Tests",
+        }
+    }
+}
+
+pub struct Command {
+    pub name: &'static str,
+    pub category: Category,
+    pub summary: &'static str,
+    pub usage: &'static str,
+    // (フラグ, 説明)
+    pub options: &'static [(&'static str, &'static str)],
+    pub examples: &'static [&'static str],
+    pub notes: &'static str,
+    // 出力が画面より長くなりうる。autopage が on ならページャを通して出す
+    pub paged: bool,
+}
+
+const DEFAULT: Command = Command {
+    name: "",
+    category: Category::Shell,
+    summary: "",
+    usage: "",
+    options: &[],
+    examples: &[],
+    notes: "",
+    paged: false,
+};
+
+static COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        summary: "Show commands, or the usage of one command",
+        usage: "help [command]",
+        examples: &["help", "help dd"],
+        paged: true,
+        ..DEFAULT
+    },
+    Command {
+        name: "man",
+        summary: "Show a manual page",
+        usage: "man <topic>",
+        examples: &["man intro", "man config"],
+        notes: "Pages live in /usr/share/man. Space pages, Enter scrolls a line, q quits.",
+        ..DEFAULT
+    },
+    Command {
+        name: "clear",
+        summary: "Clear screen",
+        usage: "clear",
+        ..DEFAULT
+    },
+    Command {
+        name: "history",
+        summary: "Show command history",
+        usage: "history",
+        notes: "A repeated command is kept only once, as the newest entry. At most\n`histsize` commands are kept (see config); the oldest are dropped first.",
+        paged: true,
+        ..DEFAULT
+    },
+    Command {
+        name: "echo",
+        summary: "Display a line of text",
+        usage: "echo <text>...",
+        ..DEFAULT
+    },
+    Command {
+        name: "export",
+        summary: "Set environment variables",
+        usage: "export [NAME=value]...",
+        examples: &["export NAME=world", "export GREETING=\"hello $NAME\"", "echo $GREETING"],
+        notes: "Variables are expanded as $NAME or ${NAME} in commands and in the prompt.\nWithout arguments, lists the variables.",
+        paged: true,
+        ..DEFAULT
+    },
+    Command {
+        name: "env",
+        summary: "List environment variables",
+        usage: "env",
+        paged: true,
+        ..DEFAULT
+    },
+    Command {
+        name: "unset",
+        summary: "Remove environment variables",
+        usage: "unset <NAME>...",
+        ..DEFAULT
+    },
+    Command {
+        name: "alias",
+        summary: "Define or list command aliases",
+        usage: "alias [<name>[=<value>]]...",
+        examples: &["alias ll='ls -a'", "alias la", "alias"],
+        notes: "When the first word of a command is an alias, it is replaced by the value\nbefore anything else is expanded. The value may contain pipes, && and ;.\nAn alias is not expanded again inside its own value.",
+        paged: true,
+        ..DEFAULT
+    },
+    Command {
+        name: "unalias",
+        summary: "Remove command aliases",
+        usage: "unalias [-a] <name>...",
+        options: &[("-a", "remove all aliases")],
+        ..DEFAULT
+    },
+    Command {
+        name: "true",
+        summary: "Do nothing and succeed (exit status 0)",
+        usage: "true",
+        examples: &["true && echo yes"],
+        ..DEFAULT
+    },
+    Command {
+        name: "false",
+        summary: "Do nothing and fail (exit status 1)",
+        usage: "false",
+        examples: &["false || echo no", "false; echo $?"],
+        ..DEFAULT
+    },
+    Command {
+        name: "config",
+        summary: "Show or change system settings",
+        usage: "config [get <key> | set <key> <value>]",
+        examples: &[
+            "config",
+            "config set timezone 0",
+            "config set prompt \"# \"",
+        ],
+        notes: "Settings are stored in /etc/system.conf and applied immediately.",
+        ..DEFAULT
+    },
+    Command {
+        name: "macro",
+        summary: "Record and replay keyboard macros",
+        usage: "macro record <name> | stop | play <name> | list",
+        examples: &["macro record build", "macro stop", "macro play build"],
+        notes: "Keystrokes are saved in /etc/macros/<name>.",
+        ..DEFAULT
+    },
+    Command {
+        name: "clip",
+        summary: "Show or set the clipboard (paste with Ctrl+V)",
+        usage: "clip [set <text> | load <file> | clear]",
+        examples: &["clip set hello", "clip load /etc/system.conf", "ls | clip set"],
+        ..DEFAULT
+    },
+    Command {
+        name: "unsafe",
+        summary: "Allow peek and poke to touch hardware",
+        usage: "unsafe [on|off]",
+        notes: "Without an argument, shows whether unsafe mode is on.",
+        ..DEFAULT
+    },
+    Command {
+        name: "run",
+        summary: "Run a shell script from a file",
+        usage: "run [-e] <file>",
+        options: &[("-e", "stop at the first command that fails")],
+        examples: &["run setup.sh", "run -e build.sh && echo done"],
+        notes: "The file may use everything the command line does: ';', newlines, '#' comments, if, for, '&&', '||' and pipes.\nWith -e, failures in if conditions and before the last command of a '&&'/'||' list do not stop the script.\nExits with the status of the last command run. 'sh' is the same command.",
+        ..DEFAULT
+    },
+    Command {
+        name: "sh",
+        summary: "Same as run",
+        usage: "sh [-e] <file>",
+        ..DEFAULT
+    },
+    Command {
+        name: "timeout",
+        summary: "Run a command and stop it after a time limit",
+        usage: "timeout <seconds> <command>",
+        examples: &["timeout 5 dd if=/dev/loop0 of=copy.img", "timeout 0.5 runtest slow.sh"],
+        notes: "The limit is checked between statements, loop iterations and dd blocks.\nExits with status 124 when the time runs out.",
+        ..DEFAULT
+    },
+    Command {
+        name: "bf",
+        summary: "Run a Brainfuck program",
+        usage: "bf <file>",
+        examples: &["bf hello.bf", "echo abc | bf rot13.bf"],
+        notes: "Reads input from the keyboard (Ctrl+C stops the program), or from a pipe.\nWith piped input, reading past its end gives 0.",
+        ..DEFAULT
+    },
+    Command {
+        name: "snake",
+        summary: "Play snake on the text console",
+        usage: "snake",
+        notes: "Steer with the arrow keys or WASD, q quits. Eat * to grow;\nhitting the edge or yourself ends the game.",
+        ..DEFAULT
+    },
+    Command {
+        name: "ls",
+        category: Category::Files,
+        summary: "List directory contents",
+        usage: "ls [-a] [<path>...]",
+        options: &[("-a", "also show names starting with '.'")],
+        examples: &["ls", "ls -a /", "ls docs/*"],
+        paged: true,
+        ..DEFAULT
+    },
+    Command {
+        name: "cd",
+        category: Category::Files,
+        summary: "Change the current directory",
+        usage: "cd [<dir> | -]",
+        examples: &["cd /etc", "cd ..", "cd -"],
+        notes: "Without an argument, goes to /. '-' returns to the previous directory.",
+        ..DEFAULT
+    },
+    Command {
+        name: "pwd",
+        category: Category::Files,
+        summary: "Print working directory",
+        usage: "pwd",
+        ..DEFAULT
+    },
+    Command {
+        name: "pushd",
+        category: Category::Files,
+        summary: "Push a directory onto the stack and change to it",
+        usage: "pushd [dir]",
+        notes: "Without an argument, swaps the top two directories.",
+        ..DEFAULT
+    },
+    Command {
+        name: "popd",
+        category: Category::Files,
+        summary: "Pop a directory off the stack and change to it",
+        usage: "popd",
+        ..DEFAULT
+    },
+    Command {
+        name: "dirs",
+        category: Category::Files,
+        summary: "Show the directory stack",
+        usage: "dirs",
+        ..DEFAULT
+    },
+    Command {
+        name: "mkdir",
+        category: Category::Files,
+        summary: "Create directories",
+        usage: "mkdir [-p] <directory>...",
+        options: &[(
+            "-p",
+            "create missing parents and accept existing directories",
+        )],
+        examples: &["mkdir -p /home/user/src"],
+        ..DEFAULT
+    },
+    Command {
+        name: "rmdir",
+        category: Category::Files,
+        summary: "Remove empty directories",
+        usage: "rmdir <directory>...",
+        notes: "Use rm -r for directories that still have entries.",
+        ..DEFAULT
+    },
+    Command {
+        name: "rm",
+        category: Category::Files,
+        summary: "Remove files and directories",
+        usage: "rm [-r] <path>...",
+        options: &[("-r", "remove directories and everything in them")],
+        examples: &["rm notes.txt", "rm -r /home/user/src"],
+        notes: "Removed files go to the trash (/.trash) and can be brought back with 'trash restore'; removing something inside /.trash deletes it for good.\nThe root, mount points and the current directory (or its parents) cannot be removed.",
+        ..DEFAULT
+    },
+    Command {
+        name: "snapshot",
+        category: Category::Files,
+        summary: "Save and roll back the state of the file system",
+        usage: "snapshot [list | create <name> | restore <name> | delete <name>]",
+        examples: &[
+            "snapshot create before",
+            "snapshot restore before",
+            "snapshot delete before",
+        ],
+        notes: "A snapshot shares unchanged files and directories with the live tree, so it is taken instantly and only costs memory for what changes afterwards.\nRestoring keeps the snapshot, so it can be restored again. The mount table is not part of a snapshot.\nFiles shared with a snapshot are not swapped out.",
+        ..DEFAULT
+    },
+    Command {
+        name: "trash",
+        category: Category::Files,
+        summary: "List, restore or empty removed files",
+        usage: "trash [list | restore <id> [path] | empty]",
+        examples: &["trash", "trash restore 3", "trash restore 3 /tmp/old.txt", "trash empty"],
+        notes: "The list shows the id, when it was removed, the size and the original path.\nThe oldest entries are discarded when the trash holds more than 32 KiB or the heap runs low.",
+        ..DEFAULT
+    },
+    Command {
+        name: "cat",
+        category: Category::Files,
+        summary: "Print the contents of files",
+        usage: "cat <file>...",
+        examples: &["cat /etc/system.conf", "cat docs/readme.txt ../notes.txt"],
+        notes: "Paths are relative to the current directory unless they start with /.\nWithout files, prints the piped input.",
+        paged: true,
+        ..DEFAULT
+    },
+    Command {
+        name: "less",
+        category: Category::Files,
+        summary: "Show files or piped input one screen at a time",
+        usage: "less [file]...",
+        examples: &["less /etc/system.conf", "dmesg | less"],
+        notes: "Space shows the next screen, Enter the next line and q quits.",
+        ..DEFAULT
+    },
+    Command {
+        name: "more",
+        category: Category::Files,
+        summary: "Same as less",
+        usage: "more [file]...",
+        ..DEFAULT
+    },
+    Command {
+        name: "grep",
+        category: Category::Files,
+        summary: "Print lines that contain a pattern",
+        usage: "grep [-i] [-v] [-n] [-c] <pattern> [file]...",
+        options: &[
+            ("-i", "ignore case"),
+            ("-v", "print the lines that do not match"),
+            ("-n", "print line numbers"),
+            ("-c", "print only the number of matching lines"),
+        ],
+        examples: &["grep timezone /etc/system.conf", "grep -n 'TODO*fix' notes.txt", "dmesg | grep -i error"],
+        notes: "The pattern is a plain string; with * or ? in it (quote it so the shell does\nnot expand it) it is a wildcard that may match anywhere in the line.\nWithout files, searches the piped input. Exit status is 0 when a line\nmatched, 1 when none did and 2 on errors.",
+        paged: true,
+        ..DEFAULT
+    },
+    Command {
+        name: "head",
+        category: Category::Files,
+        summary: "Print the first lines of files",
+        usage: "head [-n <count>] [file]...",
+        options: &[("-n <count>", "number of lines (default 10)")],
+        examples: &["head /etc/rc", "head -n 3 notes.txt", "dmesg | head -n 5"],
+        notes: "Without files, reads the piped input. With several files, each is\npreceded by a ==> name <== header.",
+        paged: true,
+        ..DEFAULT
+    },
+    Command {
+        name: "tail",
+        category: Category::Files,
+        summary: "Print the last lines of files",
+        usage: "tail [-n <count>] [file]...",
+        options: &[("-n <count>", "number of lines (default 10)")],
+        examples: &["tail /etc/system.conf", "tail -n 1 notes.txt", "history | tail -n 5"],
+        notes: "Without files, reads the piped input. With several files, each is\npreceded by a ==> name <== header.",
+        paged: true,
+        ..DEFAULT
+    },
+    Command {
+        name: "wc",
+        category: Category::Files,
+        summary: "Count lines, words and bytes",
+        usage: "wc [-l] [-w] [-c] [file]...",
+        options: &[
+            ("-l", "print the number of lines"),
+            ("-w", "print the number of words"),
+            ("-c", "print the number of bytes"),
+        ],
+        examples: &["wc /etc/rc", "wc -l notes.txt todo.txt", "history | wc -l"],
+        notes: "Without options, prints all three counts. Words are separated by ASCII\nwhitespace. With several files, a total line follows. Without files, counts\nthe piped input.",
+        ..DEFAULT
+    },
+    Command {
+        name: "hexdump",
+        category: Category::Files,
+        summary: "Show the bytes of a file in hex and ASCII",
+        usage: "hexdump [-s <offset>] [-n <length>] [file]",
+        options: &[
+            ("-s <offset>", "start at this byte (decimal or 0x hex)"),
+            ("-n <length>", "show this many bytes"),
+        ],
+        examples: &["hexdump /host/disk.img", "hexdump -s 0x200 -n 64 /host/disk.img", "echo hi | hexdump"],
+        notes: "Each row shows the offset, 16 bytes in hex and the same bytes as\ncharacters ('.' for non-printable ones). Long output opens in the pager.\nWithout -n, at most the first 4096 bytes are shown. Without a file, dumps\nthe piped input.",
+        ..DEFAULT
+    },
+    Command {
+        name: "xxd",
+        category: Category::Files,
+        summary: "Same as hexdump",
+        usage: "xxd [-s <offset>] [-n <length>] [file]",
+        ..DEFAULT
+    },
+    Command {
+        name: "cp",
+        category: Category::Files,
+        summary: "Copy files and directories",
+        usage: "cp [-r] <source>... <destination>",
+        options: &[("-r", "copy directories with everything in them")],
+        examples: &["cp notes.txt notes.bak", "cp -r /host/src /home/user"],
+        notes: "If the destination is an existing directory, the sources are copied into it.\nFiles from /proc and /host are copied like any other file.",
+        ..DEFAULT
+    },
+    Command {
+        name: "file",
+        category: Category::Files,
+        summary: "Guess the type of files from their contents",
+        usage: "file <path>...",
+        examples: &["file /host/kernel.elf", "file notes.txt"],
+        notes: "Recognizes ELF, kernel extensions, BMP, PNG, GIF, JPEG, tar, gzip, zip, PDF, scripts and text.",
+        ..DEFAULT
+    },
+    Command {
+        name: "cmp",
+        category: Category::Files,
+        summary: "Compare two files byte by byte",
+        usage: "cmp <file1> <file2>",
+        notes: "Prints the first differing byte and line.\nExits with 0 if the files are the same, 1 if they differ and 2 on errors.",
+        ..DEFAULT
+    },
+    Command {
+        name: "md5sum",
+        category: Category::Files,
+        summary: "Print the MD5 hash of files",
+        usage: "md5sum [file]...",
+        examples: &["md5sum /host/disk.img", "cat notes.txt | md5sum"],
+        notes: "Without files, hashes the piped input.",
+        ..DEFAULT
+    },
+    Command {
+        name: "dedup",
+        category: Category::Files,
+        summary: "Find duplicate files in a directory tree",
+        usage: "dedup <dir>",
+        examples: &["dedup /", "dedup /home"],
+        notes: "Files of the same size are compared by MD5 hash; empty files are skipped.\nReports how many bytes removing the copies would free.",
+        ..DEFAULT
+    },
+    Command {
+        name: "mv",
+        category: Category::Files,
+        summary: "Move or rename files and directories",
+        usage: "mv <source>... <destination>",
+        examples: &["mv notes.txt todo.txt", "mv a.txt b.txt /home/user"],
+        notes: "If the destination is an existing directory, the sources are moved into it.\nAn existing file is replaced; an existing directory only if it is empty.",
+        ..DEFAULT
+    },
+    Command {
+        name: "rx",
+        category: Category::Files,
+        summary: "Receive a file over the second serial port with XMODEM",
+        usage: "rx <file>",
+        examples: &["rx hello.bf"],
+        notes: "Start QEMU with a second serial port (e.g. -serial pty) and send from the host with sx.\nCRC-16 and checksum modes and 1K blocks are supported; trailing 0x1a padding is removed.\nGives up after 60 seconds without a sender.",
+        ..DEFAULT
+    },
+    Command {
+        name: "touch",
+        category: Category::Files,
+        summary: "Create files or update their modification time",
+        usage: "touch [-c] <file>...",
+        options: &[("-c", "never create files")],
+        ..DEFAULT
+    },
+    Command {
+        name: "truncate",
+        category: Category::Files,
+        summary: "Shrink or extend a file (extending leaves a hole)",
+        usage: "truncate -s <size> <file>",
+        options: &[("-s <size>", "new size in bytes")],
+        examples: &["truncate -s 1048576 disk.img"],
+        ..DEFAULT
+    },
+    Command {
+        name: "stat",
+        category: Category::Files,
+        summary: "Show the size and allocated bytes of a file",
+        usage: "stat <file>",
+        ..DEFAULT
+    },
+    Command {
+        name: "dd",
+        category: Category::Files,
+        summary: "Copy blocks between files and block devices",
+        usage: "dd if=<src> of=<dst> [bs=<n>] [count=<n>] [skip=<n>] [seek=<n>]",
+        options: &[
+            ("bs=<n>", "block size in bytes (default 512)"),
+            (
+                "count=<n>",
+                "blocks to copy (default: up to the end of src)",
+            ),
+            ("skip=<n>", "blocks to skip at the start of src"),
+            ("seek=<n>", "blocks to skip at the start of dst"),
+        ],
+        examples: &["dd if=boot.bin of=/dev/loop0 bs=512 count=1"],
+        notes: "The destination is never truncated. Block devices need the disk feature.",
+        paged: false,
+    },
+    Command {
+        name: "free",
+        category: Category::System,
+        summary: "Show heap usage and the memory held by the shell history",
+        usage: "free",
+        ..DEFAULT
+    },
+    Command {
+        name: "swap",
+        category: Category::Files,
+        summary: "Show or force swapping of cold file contents",
+        usage: "swap [out]",
+        ..DEFAULT
+    },
+    Command {
+        name: "mount",
+        category: Category::Files,
+        summary: "List mounted file systems",
+        usage: "mount",
+        ..DEFAULT
+    },
+    Command {
+        name: "remount",
+        category: Category::Files,
+        summary: "Make a mount read-only or read-write",
+        usage: "remount <path> <ro|rw>",
+        examples: &["remount / ro"],
+        ..DEFAULT
+    },
+    Command {
+        name: "exit",
+        category: Category::System,
+        summary: "Shutdown the system",
+        usage: "exit",
+        ..DEFAULT
+    },
+    Command {
+        name: "suspend",
+        category: Category::System,
+        summary: "Suspend to RAM (ACPI S3)",
+        usage: "suspend",
+        ..DEFAULT
+    },
+    Command {
+        name: "power",
+        category: Category::System,
+        summary: "Show ACPI power profile, AC adapter and battery",
+        usage: "power",
+        ..DEFAULT
+    },
+    Command {
+        name: "cpufreq",
+        category: Category::System,
+        summary: "Measure the current CPU frequency",
+        usage: "cpufreq",
+        ..DEFAULT
+    },
+    Command {
+        name: "sensors",
+        category: Category::System,
+        summary: "Show the CPU temperature",
+        usage: "sensors",
+        ..DEFAULT
+    },
+    Command {
+        name: "time",
+        category: Category::System,
+        summary: "Show the current time, or how long a command takes",
+        usage: "time [command]",
+        examples: &["time", "time dd if=disk.img of=/dev/loop0 bs=4096"],
+        notes: "With a command, runs it and prints the elapsed wall-clock time\n(10 ms resolution) and how many heap allocations it made; the exit\nstatus is the command's.",
+        ..DEFAULT
+    },
+    Command {
+        name: "cal",
+        category: Category::System,
+        summary: "Show a monthly calendar",
+        usage: "cal [month [year]]",
+        examples: &["cal", "cal 2", "cal 12 2030"],
+        notes: "Without arguments, shows the current month with today in bold.",
+        ..DEFAULT
+    },
+    Command {
+        name: "play",
+        category: Category::System,
+        summary: "Play a melody on the PC speaker",
+        usage: "play <file> | play stop",
+        examples: &["play song.txt", "play stop"],
+        notes: "The melody plays in the background. See man play for the file format.",
+        ..DEFAULT
+    },
+    Command {
+        name: "timer",
+        category: Category::System,
+        summary: "Start, stop or show the stopwatch",
+        usage: "timer [start | stop]",
+        examples: &["timer start", "timer", "timer stop"],
+        notes: "While it runs, the elapsed time is shown in the top-right corner.",
+        ..DEFAULT
+    },
+    Command {
+        name: "countdown",
+        category: Category::System,
+        summary: "Start, list or cancel countdown timers",
+        usage: "countdown [-b] <duration> | countdown cancel <id>",
+        options: &[("-b", "Beep on the PC speaker when the time is up")],
+        examples: &["countdown 5m", "countdown -b 90s", "countdown", "countdown cancel 1"],
+        notes: "Durations take an s, m or h suffix (seconds by default).\nThe nearest countdown is shown in the top-right corner.",
+        ..DEFAULT
+    },
+    Command {
+        name: "top",
+        category: Category::System,
+        summary: "Show CPU usage per task, idle time and memory",
+        usage: "top",
+        notes: "Any key exits.",
+        ..DEFAULT
+    },
+    Command {
+        name: "lsdev",
+        category: Category::System,
+        summary: "Show the device tree",
+        usage: "lsdev",
+        paged: true,
+        ..DEFAULT
+    },
+    Command {
+        name: "ioports",
+        category: Category::System,
+        summary: "Show I/O port ranges claimed by drivers",
+        usage: "ioports",
+        paged: true,
+        ..DEFAULT
+    },
+    Command {
+        name: "insmod",
+        category: Category::System,
+        summary: "Load a kernel extension",
+        usage: "insmod <file>",
+        examples: &["insmod hello.rkx"],
+        ..DEFAULT
+    },
+    Command {
+        name: "lsmod",
+        category: Category::System,
+        summary: "List loaded kernel extensions",
+        usage: "lsmod",
+        ..DEFAULT
+    },
+    Command {
+        name: "gdb",
+        category: Category::Debug,
+        summary: "Wait for a GDB connection on COM2",
+        usage: "gdb",
+        ..DEFAULT
+    },
+    Command {
+        name: "vmmap",
+        category: Category::Debug,
+        summary: "Show mapped virtual memory ranges",
+        usage: "vmmap [-c | -x]",
+        options: &[
+            ("-c", "list only writable and executable ranges; fail if there are any"),
+            ("-x", "call a function placed on the heap (needs 'unsafe on')"),
+        ],
+        notes: "Flags: w writable, x executable, u user, h huge page. Data pages (heap,\nstacks, the physical memory map) are mapped without x; with -x the call\nshould stop with a page fault and return to the prompt.",
+        paged: true,
+        ..DEFAULT
+    },
+    Command {
+        name: "peek",
+        category: Category::Debug,
+        summary: "Read memory or an I/O port",
+        usage: "peek [-p|-io] <addr> [b|w|d|q]",
+        options: &[
+            ("-p", "read a physical address"),
+            ("-io", "read an I/O port (needs 'unsafe on')"),
+        ],
+        examples: &["peek 0xb8000 w", "peek -p 0x400 w"],
+        ..DEFAULT
+    },
+    Command {
+        name: "poke",
+        category: Category::Debug,
+        summary: "Write memory or an I/O port (needs 'unsafe on')",
+        usage: "poke [-p|-io] <addr> <value> [b|w|d|q]",
+        options: &[
+            ("-p", "write a physical address"),
+            ("-io", "write an I/O port"),
+        ],
+        examples: &["poke -io 0x80 0x12"],
+        notes: "Ports claimed by a driver (see ioports) are refused.",
+        paged: false,
+    },
+    Command {
+        name: "ksym",
+        category: Category::Debug,
+        summary: "Resolve an address to a kernel symbol",
+        usage: "ksym <addr>",
+        ..DEFAULT
+    },
+    Command {
+        name: "dmesg",
+        category: Category::System,
+        summary: "Show the kernel log buffer",
+        usage: "dmesg",
+        notes: "Messages of every level are kept, including those hidden by loglevel or a quiet boot.",
+        ..DEFAULT
+    },
+    Command {
+        name: "service",
+        category: Category::System,
+        summary: "List or start boot services",
+        usage: "service [start <name>]",
+        notes: "Services listed in /etc/services are started in order at boot.",
+        ..DEFAULT
+    },
+];
+
+static TEST_COMMANDS: &[Command] = &[
+    Command {
+        name: "runtest",
+        category: Category::Tests,
+        summary: "Run a shell script and compare its output",
+        usage: "runtest <script> [expected]",
+        notes: "The expected output defaults to <script>.expected.",
+        ..DEFAULT
+    },
+    Command {
+        name: "bench",
+        category: Category::Tests,
+        summary: "Run allocator microbenchmarks",
+        usage: "bench",
+        ..DEFAULT
+    },
+    Command {
+        name: "stress",
+        category: Category::Tests,
+        summary: "Stress the filesystem and heap in background tasks",
+        usage: "stress [seconds]",
+        notes: "Files are created in /tmp/stress, which is removed when the run ends.",
+        ..DEFAULT
+    },
+];
+
+static GRAPHICS_COMMANDS: &[Command] = &[Command {
+    name: "fbcon",
+    category: Category::System,
+    summary: "Switch to the framebuffer console, or load its wide font",
+    usage: "fbcon [on|off|load <file>]",
+    examples: &["fbcon load /host/unifont.hex", "fbcon on"],
+    notes: "The font is a GNU Unifont .hex file; kana, kanji and full-width glyphs are kept.",
+    ..DEFAULT
+}];
+
+static DISK_COMMANDS: &[Command] = &[Command {
+    name: "losetup",
+    category: Category::Files,
+    summary: "Attach a file as a loop block device",
+    usage: "losetup [<file> | -d <device>]",
+    options: &[("-d <device>", "detach a loop device")],
+    examples: &["losetup disk.img", "losetup -d /dev/loop0"],
+    notes: "Without an argument, lists loop devices.",
+    paged: false,
+}];
+
+static NET_COMMANDS: &[Command] = &[
+    Command {
+        name: "nc",
+        category: Category::System,
+        summary: "Connect the console to a TCP or UDP socket",
+        usage: "nc [-u] <host> <port> | nc -l [-u] <port>",
+        options: &[
+            ("-u", "Use UDP instead of TCP"),
+            ("-l", "Listen on <port> and talk to the first peer"),
+        ],
+        examples: &["nc 10.0.2.2 8080", "nc -l 7", "echo hello | nc -u localhost 7"],
+        notes: "Each line is sent when Enter is pressed; received data is printed as it arrives.\nCtrl+D sends the unfinished line and closes, Ctrl+C closes at once. Piped input is sent first.",
+        ..DEFAULT
+    },
+    Command {
+        name: "pcap",
+        category: Category::Debug,
+        summary: "Capture sent and received packets into a pcap file",
+        usage: "pcap [start <file> [max-bytes] | stop]",
+        examples: &["pcap start lo.pcap", "pcap", "pcap stop"],
+        notes: "Packets on lo and eth0 are written with Ethernet headers (zero MAC addresses on lo).\nThe file is capped at 1 MiB unless max-bytes is given; later packets are only counted.\nWithout arguments, shows the running capture.",
+        ..DEFAULT
+    },
+    Command {
+        name: "netstat",
+        category: Category::System,
+        summary: "List UDP and TCP sockets, or network interfaces",
+        usage: "netstat [-i]",
+        options: &[("-i", "Show interfaces with their packet and byte counters")],
+        notes: "Recv-Q and Send-Q are bytes waiting to be read and bytes not yet acknowledged;\nfor a listening socket Recv-Q is the number of connections waiting for accept.",
+        paged: true,
+        ..DEFAULT
+    },
+];
+
+// このビルドで使えるコマンド。tests や graphics、net、disk 機能が無効ならその分は含めない
+pub fn all() -> impl Iterator<Item = &'static Command> {
+    let tests: &[Command] = if cfg!(feature = "tests") {
+        TEST_COMMANDS
+    } else {
+        &[]
+    };
+    let graphics: &[Command] = if cfg!(feature = "graphics") {
+        GRAPHICS_COMMANDS
+    } else {
+        &[]
+    };
+    let net: &[Command] = if cfg!(feature = "net") {
+        NET_COMMANDS
+    } else {
+        &[]
+    };
+    let disk: &[Command] = if cfg!(feature = "disk") {
+        DISK_COMMANDS
+    } else {
+        &[]
+    };
+    COMMANDS
+        .iter()
+        .chain(tests)
+        .chain(graphics)
+        .chain(net)
+        .chain(disk)
+}
+
+pub fn find(name: &str) -> Option<&'static Command> {
+    all().find(|command| command.name == name)
+}
+
+impl Command {
+    // help <command> で出す文章
+    pub fn help_text(&self) -> String {
+        let mut text = format!("Usage: {}\n{}\n", self.usage, self.summary);
+        if !self.options.is_empty() {
+            let width = self
+                .options
+                .iter()
+                .map(|(flag, _)| flag.len())
+                .max()
+                .unwrap_or(0);
+            text.push_str("\nOptions:\n");
+            for (flag, description) in self.options {
+                text.push_str(&format!("  {:<width$}  {}\n", flag, description));
+            }
+        }
+        if !self.examples.is_empty() {
+            text.push_str("\nExamples:\n");
+            for example in self.examples {
+                text.push_str(&format!("  {}\n", example));
+            }
+        }
+        if !self.notes.is_empty() {
+            text.push_str(&format!("\n{}\n", self.notes));
+        }
+        text
+    }
+}
@@ -0,0 +1,162 @@
+// コマンドラインの展開。$name は変数の値に、$(...) は中のコマンドを実行した出力に、
+// $((...)) は式の値に置き換える。シングルクォートの中と \ の付いた $ は展開しない。
+// 結果は tokenize に渡すので、置き換えた文字はクォートして入れ、値の中の ' " \ > が
+// 記号として読み直されないようにする。クォートの外の値は空白で別々の単語に分かれる
+use super::{calc, tokenize};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+// s[open..] が "(" で始まるとき、対応する ")" の位置
+fn matching_paren(s: &str, open: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut quote = None;
+    for (index, c) in s[open..].char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(open + index);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+pub trait Context {
+    // コマンドを実行して出力を返す
+    fn run(&mut self, command: &str) -> String;
+    fn variable(&self, name: &str) -> Option<String>;
+}
+
+// $name または ${name} の名前の部分と、その後ろ。$? (直前の終了ステータス) も名前として扱う
+fn variable_name(s: &str) -> Option<(&str, &str)> {
+    if s.starts_with('?') {
+        return Some(s.split_at(1));
+    }
+    if let Some(braced) = s.strip_prefix('{') {
+        let (name, rest) = braced.split_once('}')?;
+        return Some((name, rest));
+    }
+    let end = s
+        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .unwrap_or(s.len());
+    match s.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => Some(s.split_at(end)),
+        _ => None,
+    }
+}
+
+// 変数の名前として使える文字列か。? は読むだけなので入れない
+pub fn is_variable_name(name: &str) -> bool {
+    name != "?" && variable_name(name).is_some_and(|(found, rest)| found == name && rest.is_empty())
+}
+
+// $name と ${name} だけを置き換える。コマンドは実行しないので、プロンプトのように
+// 何度も描き直すものに使う
+pub fn expand_variables(text: &str, lookup: &dyn Fn(&str) -> Option<String>) -> String {
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(index) = rest.find('$') {
+        result.push_str(&rest[..index]);
+        match variable_name(&rest[index + 1..]) {
+            Some((name, after)) => {
+                result.push_str(&lookup(name).unwrap_or_default());
+                rest = after;
+            }
+            None => {
+                result.push('$');
+                rest = &rest[index + 1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+// 展開した値を、今いるクォートの中で tokenize に通すとそのまま戻る形にして足す。
+// quote_values が偽なら値をそのまま入れる。$((...)) の式は tokenize を通さないため
+fn push_value(result: &mut String, value: &str, quote: Option<char>, quote_values: bool) {
+    if !quote_values {
+        result.push_str(value);
+        return;
+    }
+    match quote {
+        Some(_) => result.push_str(&tokenize::quote_in_double_quotes(value)),
+        None => {
+            // 前後の空白も単語の区切りとして残す (x$(echo " a") は x と a の 2 つ)
+            if value.starts_with(char::is_whitespace) {
+                result.push(' ');
+            }
+            let fields: Vec<String> = value.split_whitespace().map(tokenize::quote).collect();
+            result.push_str(&fields.join(" "));
+            if value.ends_with(char::is_whitespace) && !fields.is_empty() {
+                result.push(' ');
+            }
+        }
+    }
+}
+
+pub fn expand(line: &str, context: &mut dyn Context) -> Result<String, &'static str> {
+    expand_text(line, context, true)
+}
+
+fn expand_text(
+    line: &str,
+    context: &mut dyn Context,
+    quote_values: bool,
+) -> Result<String, &'static str> {
+    let mut result = String::new();
+    let mut quote = None;
+    let mut rest = line;
+    while let Some(c) = rest.chars().next() {
+        // \ とその次の文字は展開せずに残し、tokenize に解かせる
+        if quote != Some('\'') && c == '\\' {
+            let escaped = rest[1..].chars().next().map_or(0, char::len_utf8);
+            result.push_str(&rest[..1 + escaped]);
+            rest = &rest[1 + escaped..];
+            continue;
+        }
+        match (quote, c) {
+            (Some(open), c) if c == open => quote = None,
+            (None, '\'') | (None, '"') => quote = Some(c),
+            _ => {}
+        }
+        if quote != Some('\'') && rest.starts_with("$(") {
+            let close = matching_paren(rest, 1).ok_or("syntax error: unterminated $(")?;
+            // $(( と )) が対になっていれば算術式、そうでなければ $( (サブシェル) ... )
+            if rest[2..].starts_with('(') && matching_paren(rest, 2) == Some(close - 1) {
+                let expression = expand_text(&rest[3..close - 1], context, false)?;
+                let lookup = |name: &str| context.variable(name)?.trim().parse().ok();
+                let value = calc::evaluate(&expression, &lookup)?;
+                push_value(&mut result, &value.to_string(), quote, quote_values);
+            } else {
+                let output = context.run(&rest[2..close]);
+                push_value(
+                    &mut result,
+                    output.trim_end_matches('\n'),
+                    quote,
+                    quote_values,
+                );
+            }
+            rest = &rest[close + 1..];
+            continue;
+        }
+        if quote != Some('\'') && c == '$' {
+            if let Some((name, after)) = variable_name(&rest[1..]) {
+                let value = context.variable(name).unwrap_or_default();
+                push_value(&mut result, &value, quote, quote_values);
+                rest = after;
+                continue;
+            }
+        }
+        result.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    Ok(result)
+}
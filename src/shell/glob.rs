@@ -0,0 +1,141 @@
+// ワイルドカードの展開。* は 0 文字以上、? は 1 文字に合い、\ の次の文字はそのまま比べる。
+// / をまたいでは合わない。. で始まる名前にはパターンも . で始まるときだけ合う
+use crate::filesystem;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+pub fn is_special(c: char) -> bool {
+    matches!(c, '*' | '?' | '\\')
+}
+
+fn has_wildcard(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '*' | '?' => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+// \ を外してただの文字列に戻す
+pub fn literal(pattern: &str) -> String {
+    let mut text = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        text.push(if c == '\\' {
+            chars.next().unwrap_or('\\')
+        } else {
+            c
+        });
+    }
+    text
+}
+
+pub fn matches(pattern: &str, name: &str) -> bool {
+    if name.starts_with('.') && !pattern.starts_with('.') && !pattern.starts_with("\\.") {
+        return false;
+    }
+    matches_text(pattern, name)
+}
+
+// . で始まるものの決まりが無い matches。ファイル名ではない文字列 (grep の行など) に使う
+pub fn matches_text(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    match_chars(&pattern, &text)
+}
+
+// 最後に見た * の位置に戻ってやり直す。再帰しないので長い名前でもスタックを使わない
+fn match_chars(pattern: &[char], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+                continue;
+            }
+            Some('?') => {
+                p += 1;
+                n += 1;
+                continue;
+            }
+            Some('\\') if pattern.get(p + 1) == Some(&name[n]) => {
+                p += 2;
+                n += 1;
+                continue;
+            }
+            Some(&c) if c != '\\' && c == name[n] => {
+                p += 1;
+                n += 1;
+                continue;
+            }
+            _ => {}
+        }
+        match star {
+            Some((star_p, star_n)) => {
+                p = star_p + 1;
+                n = star_n + 1;
+                star = Some((star_p, star_n + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+fn join(prefix: &str, name: &str) -> String {
+    let mut path = String::from(prefix);
+    if !path.is_empty() && !path.ends_with('/') {
+        path.push('/');
+    }
+    path.push_str(name);
+    path
+}
+
+fn exists(path: &str) -> bool {
+    filesystem::list_path(path).is_ok() || filesystem::file_size(path).is_ok()
+}
+
+// pattern に合うパスを名前順に返す。ひとつも無ければ pattern をそのまま (\ は外して) 返す
+pub fn expand(pattern: &str) -> Vec<String> {
+    let mut paths = vec![String::from(if pattern.starts_with('/') {
+        "/"
+    } else {
+        ""
+    })];
+    let segments: Vec<&str> = pattern.split('/').filter(|s| !s.is_empty()).collect();
+    for (index, segment) in segments.iter().enumerate() {
+        let last = index + 1 == segments.len();
+        if !has_wildcard(segment) {
+            let name = literal(segment);
+            for path in &mut paths {
+                *path = join(path, &name);
+            }
+            continue;
+        }
+        let mut next = Vec::new();
+        for path in &paths {
+            let directory = if path.is_empty() { "." } else { path };
+            let entries = filesystem::list_path(directory).unwrap_or_default();
+            for (name, is_directory) in entries.iter() {
+                if (last || *is_directory) && matches(segment, name) {
+                    next.push(join(path, name));
+                }
+            }
+        }
+        paths = next;
+    }
+    paths.retain(|path| exists(path));
+    if paths.is_empty() {
+        return vec![literal(pattern)];
+    }
+    paths
+}
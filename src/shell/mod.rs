@@ -0,0 +1,3326 @@
+use crate::arch::x86::cpu;
+use crate::log::Level;
+use crate::name::Name;
+use crate::{acpi, allocator, config, filesystem, filetype, md5, peek, trash};
+use crate::{klog, print, println};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::format;
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec;
+use alloc::vec::Vec;
+use pc_keyboard::{DecodedKey, KeyCode};
+
+mod calc;
+mod commands;
+mod expand;
+mod glob;
+mod script;
+mod suggest;
+mod tokenize;
+
+use commands::Category;
+use script::{Connector, ParseError, Statement};
+use tokenize::Token;
+
+const KILL_RING_SIZE: usize = 8;
+// 設定 (histsize, linemax) が無いときの履歴の数と入力行の長さ (バイト) の上限
+const DEFAULT_HISTORY_SIZE: usize = 100;
+const DEFAULT_LINE_MAX: usize = 1024;
+// timeout で打ち切られたときの終了ステータス
+const TIMED_OUT: i32 = 124;
+// 起動時に実行するスクリプト。/host/rc があれば最初の起動でそれを写す
+const RC_PATH: &str = "/etc/rc";
+const HOST_RC_PATH: &str = "/host/rc";
+const DEFAULT_RC: &str = "\
+# Commands in this file run once at boot, before the first prompt.
+# Examples:
+# config set timezone 0
+# mkdir -p /home/user
+# cd /home/user
+# export NAME=world
+";
+// run の入れ子の深さの上限。スクリプトが自分を run しても止まるように
+const MAX_RUN_DEPTH: usize = 8;
+
+struct Recording {
+    name: String,
+    keys: Vec<DecodedKey>,
+    // 実行中の行の先頭。macro stop の行は記録から外す
+    line_start: usize,
+}
+
+pub struct Shell {
+    input_buffer: String,
+    cursor_position: usize,
+    insert_mode: bool,
+    command_history: Vec<String>,
+    history_index: usize,
+    history_size: usize,
+    // 続きの行も合わせた入力の長さの上限 (バイト)
+    line_max: usize,
+    // paged なコマンドの出力をページャに通す
+    autopage: bool,
+    timezone_offset: i8, // 追加
+    prompt: String,
+    unsafe_mode: bool,
+    kill_ring: Vec<String>,
+    last_was_kill: bool,
+    // 最後に描いた入力行の桁数
+    drawn_len: usize,
+    continuation: String,
+    recording: Option<Recording>,
+    replay: VecDeque<DecodedKey>,
+    replaying: bool,
+    // cd の打ち間違いを直すか聞いている間の直した後のパス
+    pending_cd: Option<String>,
+    // for の変数。内側のループほど後ろ
+    variables: Vec<(String, String)>,
+    // export で決めた環境変数。for の変数のほうが優先される
+    environment: BTreeMap<String, String>,
+    // timeout の期限 (ティック)。入れ子なら一番早いもの
+    deadline: Option<u64>,
+    // パイプラインの前の段の出力。読むコマンドが取り出す
+    stdin: Option<String>,
+    // 直前のコマンドの終了ステータス。$? で読む
+    last_status: i32,
+    // run -e の実行中。失敗したコマンドでスクリプトを止める
+    errexit: bool,
+    // errexit で止めている途中
+    aborting: bool,
+    // if の条件を実行している深さ。条件の失敗では止めない
+    condition_depth: usize,
+    run_depth: usize,
+    // alias で決めた置き換え (名前, 中身)
+    aliases: BTreeMap<String, String>,
+    // 展開している途中の別名。alias ls='ls -a' のような自分自身への展開を止める
+    expanding_aliases: Vec<String>,
+}
+
+impl expand::Context for Shell {
+    fn run(&mut self, command: &str) -> String {
+        self.capture(command)
+    }
+
+    fn variable(&self, name: &str) -> Option<String> {
+        if name == "?" {
+            return Some(self.last_status.to_string());
+        }
+        self.variables
+            .iter()
+            .rev()
+            .find(|(variable, _)| variable == name)
+            .map(|(_, value)| value.clone())
+            .or_else(|| self.environment.get(name).cloned())
+    }
+}
+
+impl Shell {
+    pub fn new() -> Shell {
+        let mut shell = Shell {
+            input_buffer: String::new(),
+            cursor_position: 0,
+            insert_mode: true,
+            command_history: Vec::new(),
+            history_index: 0,
+            history_size: DEFAULT_HISTORY_SIZE,
+            line_max: DEFAULT_LINE_MAX,
+            autopage: true,
+            timezone_offset: 9,
+            prompt: String::from("$ "),
+            unsafe_mode: false,
+            kill_ring: Vec::new(),
+            last_was_kill: false,
+            drawn_len: 0,
+            continuation: String::new(),
+            recording: None,
+            replay: VecDeque::new(),
+            replaying: false,
+            pending_cd: None,
+            variables: Vec::new(),
+            environment: BTreeMap::new(),
+            deadline: None,
+            stdin: None,
+            last_status: 0,
+            errexit: false,
+            aborting: false,
+            condition_depth: 0,
+            run_depth: 0,
+            aliases: BTreeMap::new(),
+            expanding_aliases: Vec::new(),
+        };
+        shell.apply_config();
+        shell
+    }
+
+    fn apply_config(&mut self) {
+        if let Some(offset) = config::get("timezone").and_then(|v| v.parse().ok()) {
+            self.timezone_offset = offset;
+        }
+        if let Some(prompt) = config::get("prompt") {
+            self.prompt = prompt;
+        }
+        if let Some(size) = config::get("histsize").and_then(|v| v.parse().ok()) {
+            self.history_size = size;
+            let excess = self.command_history.len().saturating_sub(size);
+            self.command_history.drain(..excess);
+            self.history_index = 0;
+        }
+        if let Some(max) = config::get("linemax").and_then(|v| v.parse().ok()) {
+            self.line_max = max;
+        }
+        if let Some(autopage) = config::get("autopage") {
+            self.autopage = autopage == "on";
+        }
+    }
+
+    // 同じコマンドは前のものを消して最後にだけ残し、上限を超えたら古いものから捨てる
+    fn add_history(&mut self, line: String) {
+        self.history_index = 0;
+        if self.history_size == 0 {
+            return;
+        }
+        self.command_history.retain(|entry| *entry != line);
+        if self.command_history.len() >= self.history_size {
+            let excess = self.command_history.len() + 1 - self.history_size;
+            self.command_history.drain(..excess);
+        }
+        self.command_history.push(line);
+    }
+
+    // 入力にあと何バイト入るか
+    fn line_room(&self) -> usize {
+        self.line_max
+            .saturating_sub(self.continuation.len() + self.input_buffer.len())
+    }
+
+    // プロンプトの中の $NAME は描くたびに環境変数で置き換える
+    fn current_prompt(&self) -> String {
+        if !self.continuation.is_empty() {
+            return String::from("> ");
+        }
+        let lookup = |name: &str| expand::Context::variable(self, name);
+        expand::expand_variables(&self.prompt, &lookup)
+    }
+
+    // コマンドが panic したあとに呼ばれる。実行途中の状態を捨ててプロンプトに戻る
+    pub fn recover(&mut self) {
+        self.input_buffer.clear();
+        self.cursor_position = 0;
+        self.drawn_len = 0;
+        self.continuation.clear();
+        self.replay.clear();
+        self.replaying = false;
+        self.pending_cd = None;
+        self.variables.clear();
+        self.deadline = None;
+        self.stdin = None;
+        self.errexit = false;
+        self.aborting = false;
+        self.condition_depth = 0;
+        self.run_depth = 0;
+        self.expanding_aliases.clear();
+        self.print_prompt();
+    }
+
+    // 起動時に一度だけ /etc/rc を実行する。無ければ /host/rc を写すか、既定の中身で作る
+    pub fn run_startup_script(&mut self) {
+        if filesystem::file_size(RC_PATH).is_err() {
+            let content = filesystem::read_file(HOST_RC_PATH)
+                .unwrap_or_else(|_| DEFAULT_RC.as_bytes().to_vec());
+            let _ = filesystem::create_directory("/etc", true);
+            if let Err(e) = filesystem::write_file(RC_PATH, &content, false) {
+                klog!(Level::Warn, "rc: cannot write {}: {}", RC_PATH, e);
+                return;
+            }
+        }
+        klog!(Level::Info, "Running {}", RC_PATH);
+        let status = self.cmd_run(&[RC_PATH]);
+        if status != 0 {
+            klog!(Level::Warn, "rc: {} exited with status {}", RC_PATH, status);
+        }
+    }
+
+    pub fn print_prompt(&self) {
+        print!("{}", self.current_prompt());
+    }
+
+    pub fn handle_key(&mut self, key: DecodedKey) {
+        if crate::pager::is_active() {
+            if crate::pager::handle_key(key) {
+                self.print_prompt();
+            }
+            return;
+        }
+        if crate::bf::is_running() {
+            if crate::bf::handle_key(key) {
+                self.print_prompt();
+            }
+            return;
+        }
+        if crate::snake::is_running() {
+            if crate::snake::handle_key(key) {
+                self.print_prompt();
+            }
+            return;
+        }
+        #[cfg(feature = "net")]
+        if crate::nc::is_running() {
+            if crate::nc::handle_key(key) {
+                self.print_prompt();
+            }
+            return;
+        }
+        if let Some(correction) = self.pending_cd.take() {
+            if matches!(key, DecodedKey::Unicode('y') | DecodedKey::Unicode('Y')) {
+                println!("y");
+                if let Err(e) = filesystem::change_directory(&correction) {
+                    println!("cd: {}", e);
+                }
+            } else {
+                println!();
+            }
+            self.print_prompt();
+            return;
+        }
+        if crate::top::is_running() {
+            crate::top::stop();
+            println!();
+            self.redraw_line();
+            return;
+        }
+        if let Some(recording) = self.recording.as_mut() {
+            recording.keys.push(key);
+        }
+        let was_kill = core::mem::replace(&mut self.last_was_kill, false);
+        match key {
+            DecodedKey::Unicode('\n') => {
+                println!();
+                self.execute_command();
+                if let Some(recording) = self.recording.as_mut() {
+                    recording.line_start = recording.keys.len();
+                }
+            }
+            // Ctrl+K / Ctrl+U / Ctrl+W / Ctrl+Y
+            DecodedKey::Unicode('\u{0b}') => self.kill_to_end(was_kill),
+            DecodedKey::Unicode('\u{15}') => self.kill_to_start(was_kill),
+            DecodedKey::Unicode('\u{17}') => self.kill_word(was_kill),
+            DecodedKey::Unicode('\u{19}') => self.yank(),
+            // Ctrl+V
+            DecodedKey::Unicode('\u{16}') => self.paste(),
+            DecodedKey::Unicode('\u{08}') => self.handle_backspace(),
+            DecodedKey::Unicode('\u{7f}') => self.handle_delete(),
+            DecodedKey::Unicode('\t') => self.handle_tab(),
+            DecodedKey::Unicode(c) if c.is_control() => {}
+            // 上書きは 1 文字を 1 文字で置き換えるので、幅が同じなら打った文字を出すだけで
+            // 行の残りは崩れない
+            DecodedKey::Unicode(c)
+                if !self.insert_mode && self.cursor_position < self.input_buffer.len() =>
+            {
+                let replaced = self.input_buffer.remove(self.cursor_position);
+                self.input_buffer.insert(self.cursor_position, c);
+                self.cursor_position += c.len_utf8();
+                if char_width(replaced) == char_width(c) {
+                    print!("{}", c);
+                } else {
+                    self.redraw_line();
+                }
+            }
+            // 上限まで打ったら、それ以上の文字は捨てる
+            DecodedKey::Unicode(c) if c.len_utf8() > self.line_room() => {}
+            DecodedKey::Unicode(c) => {
+                self.input_buffer.insert(self.cursor_position, c);
+                self.cursor_position += c.len_utf8();
+                if self.cursor_position == self.input_buffer.len() {
+                    print!("{}", c);
+                    self.drawn_len = display_width(&self.input_buffer);
+                } else {
+                    self.redraw_line();
+                }
+            }
+            DecodedKey::RawKey(key) => match key {
+                KeyCode::Backspace => self.handle_backspace(),
+                KeyCode::Delete => self.handle_delete(),
+                KeyCode::Tab => self.handle_tab(),
+                KeyCode::Home => self.handle_home(),
+                KeyCode::ArrowLeft if crate::keyboard::ctrl_held() => self.word_left(),
+                KeyCode::ArrowRight if crate::keyboard::ctrl_held() => self.word_right(),
+                KeyCode::ArrowLeft => self.cursor_left(),
+                KeyCode::ArrowRight => self.cursor_right(),
+                KeyCode::End => self.handle_end(),
+                KeyCode::Insert => self.handle_insert(),
+                KeyCode::ArrowUp => self.history_up(),
+                KeyCode::ArrowDown => self.history_down(),
+                KeyCode::F12 => {
+                    crate::kdb::enter(crate::kdb::Reason::Hotkey);
+                    self.redraw_line();
+                }
+                _ => {}
+            },
+        }
+    }
+
+    pub fn handle_backspace(&mut self) {
+        if let Some(c) = self.input_buffer[..self.cursor_position]
+            .chars()
+            .next_back()
+        {
+            self.cursor_position -= c.len_utf8();
+            self.input_buffer.remove(self.cursor_position);
+            self.redraw_line();
+        }
+    }
+
+    pub fn handle_delete(&mut self) {
+        if self.cursor_position < self.input_buffer.len() {
+            self.input_buffer.remove(self.cursor_position);
+            self.redraw_line();
+        }
+    }
+
+    pub fn handle_home(&mut self) {
+        self.cursor_position = 0;
+        self.redraw_line();
+    }
+
+    pub fn handle_end(&mut self) {
+        self.cursor_position = self.input_buffer.len();
+        self.redraw_line();
+    }
+
+    // 1 文字だけなら書き直さずに、戻るときはその文字の幅だけ \x08 を、進むときはその文字を出す
+    fn cursor_left(&mut self) {
+        if let Some(c) = self.input_buffer[..self.cursor_position]
+            .chars()
+            .next_back()
+        {
+            self.cursor_position -= c.len_utf8();
+            for _ in 0..char_width(c) {
+                print!("\x08");
+            }
+        }
+    }
+
+    fn cursor_right(&mut self) {
+        if let Some(c) = self.input_buffer[self.cursor_position..].chars().next() {
+            self.cursor_position += c.len_utf8();
+            print!("{}", c);
+        }
+    }
+
+    // 前の単語の先頭へ (Ctrl+Left)。kill_word と同じく空白で区切る
+    fn word_left(&mut self) {
+        let before = self.input_buffer[..self.cursor_position].trim_end();
+        self.cursor_position = before
+            .rfind(char::is_whitespace)
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        self.redraw_line();
+    }
+
+    // 次の単語の終わりへ (Ctrl+Right)
+    fn word_right(&mut self) {
+        let after = &self.input_buffer[self.cursor_position..];
+        let start = after.len() - after.trim_start().len();
+        self.cursor_position += after[start..]
+            .find(char::is_whitespace)
+            .map(|i| start + i)
+            .unwrap_or(after.len());
+        self.redraw_line();
+    }
+
+    pub fn handle_insert(&mut self) {
+        self.insert_mode = !self.insert_mode;
+    }
+
+    // 前回より短くなった分は空白で消してからカーソルを戻す。長さはバイト数ではなく画面の桁数で数える
+    fn redraw_line(&mut self) {
+        let width = display_width(&self.input_buffer);
+        let erase = self.drawn_len.saturating_sub(width);
+        print!(
+            "\r{}{}{:erase$}",
+            self.current_prompt(),
+            self.input_buffer,
+            "",
+            erase = erase
+        );
+        let behind = display_width(&self.input_buffer[self.cursor_position..]) + erase;
+        for _ in 0..behind {
+            print!("\x08");
+        }
+        self.drawn_len = width;
+    }
+
+    // 連続した kill は直前のエントリにまとめる
+    fn push_kill(&mut self, text: String, append: bool, was_kill: bool) {
+        if text.is_empty() {
+            return;
+        }
+        match self.kill_ring.last_mut() {
+            Some(last) if was_kill => {
+                if append {
+                    last.push_str(&text);
+                } else {
+                    last.insert_str(0, &text);
+                }
+            }
+            _ => {
+                if self.kill_ring.len() >= KILL_RING_SIZE {
+                    self.kill_ring.remove(0);
+                }
+                self.kill_ring.push(text);
+            }
+        }
+        self.last_was_kill = true;
+    }
+
+    fn kill_to_end(&mut self, was_kill: bool) {
+        let killed = self.input_buffer.split_off(self.cursor_position);
+        self.push_kill(killed, true, was_kill);
+        self.redraw_line();
+    }
+
+    fn kill_to_start(&mut self, was_kill: bool) {
+        let killed: String = self.input_buffer.drain(..self.cursor_position).collect();
+        self.cursor_position = 0;
+        self.push_kill(killed, false, was_kill);
+        self.redraw_line();
+    }
+
+    fn kill_word(&mut self, was_kill: bool) {
+        let before = &self.input_buffer[..self.cursor_position];
+        let word_end = before.trim_end().len();
+        let start = before[..word_end]
+            .rfind(char::is_whitespace)
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        let killed: String = self
+            .input_buffer
+            .drain(start..self.cursor_position)
+            .collect();
+        self.cursor_position = start;
+        self.push_kill(killed, false, was_kill);
+        self.redraw_line();
+    }
+
+    fn insert_text(&mut self, text: &str) {
+        let mut end = text.len().min(self.line_room());
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        let text = &text[..end];
+        if !text.is_empty() {
+            self.input_buffer.insert_str(self.cursor_position, text);
+            self.cursor_position += text.len();
+            self.redraw_line();
+        }
+    }
+
+    // 改行は実行せずに空白として貼り付ける
+    fn paste(&mut self) {
+        let text: String = crate::clipboard::get()
+            .chars()
+            .map(|c| if c == '\n' { ' ' } else { c })
+            .filter(|c| !c.is_control())
+            .collect();
+        self.insert_text(&text);
+    }
+
+    fn yank(&mut self) {
+        if let Some(text) = self.kill_ring.last().cloned() {
+            self.insert_text(&text);
+        }
+    }
+
+    // 再生待ちのマクロのキーを通常の入力と同じ経路で流す
+    pub fn replay_pending(&mut self) {
+        self.replaying = true;
+        while let Some(key) = self.replay.pop_front() {
+            self.handle_key(key);
+        }
+        self.replaying = false;
+    }
+
+    // export NAME=value で設定し、引数が無ければ一覧を出す
+    fn cmd_export(&mut self, args: &[&str]) -> i32 {
+        if args.is_empty() {
+            for (name, value) in &self.environment {
+                println!("export {}={}", name, tokenize::quote(value));
+            }
+            return 0;
+        }
+        let mut status = 0;
+        for arg in args {
+            let (name, value) = arg.split_once('=').unwrap_or((arg, ""));
+            if !expand::is_variable_name(name) {
+                println!("export: '{}': not a valid name", name);
+                status = 1;
+                continue;
+            }
+            // export NAME だけなら、まだ無いときに空で作る
+            if arg.contains('=') || !self.environment.contains_key(name) {
+                self.environment
+                    .insert(String::from(name), String::from(value));
+            }
+        }
+        status
+    }
+
+    // alias name=value で設定し、name だけならその中身を、引数が無ければ一覧を出す
+    fn cmd_alias(&mut self, args: &[&str]) -> i32 {
+        if args.is_empty() {
+            for (name, value) in &self.aliases {
+                println!("alias {}={}", name, tokenize::quote(value));
+            }
+            return 0;
+        }
+        let mut status = 0;
+        for arg in args {
+            let Some((name, value)) = arg.split_once('=') else {
+                match self.aliases.get(*arg) {
+                    Some(value) => println!("alias {}={}", arg, tokenize::quote(value)),
+                    None => {
+                        println!("alias: {}: not found", arg);
+                        status = 1;
+                    }
+                }
+                continue;
+            };
+            if !is_alias_name(name) {
+                println!("alias: '{}': invalid alias name", name);
+                status = 1;
+                continue;
+            }
+            self.aliases.insert(String::from(name), String::from(value));
+        }
+        status
+    }
+
+    fn cmd_unalias(&mut self, args: &[&str]) -> i32 {
+        if args == ["-a"] {
+            self.aliases.clear();
+            return 0;
+        }
+        if args.is_empty() {
+            return usage("unalias [-a] <name>...");
+        }
+        let mut status = 0;
+        for name in args {
+            if self.aliases.remove(*name).is_none() {
+                println!("unalias: {}: not found", name);
+                status = 1;
+            }
+        }
+        status
+    }
+
+    // 先頭の単語が別名なら置き換えた行と別名を返す。展開の途中の別名は置き換えない
+    fn expand_alias(&self, line: &str) -> Option<(String, String)> {
+        let line = line.trim_start();
+        let end = line.find(char::is_whitespace).unwrap_or(line.len());
+        let name = &line[..end];
+        if self.expanding_aliases.iter().any(|alias| alias == name) {
+            return None;
+        }
+        let value = self.aliases.get(name)?;
+        Some((String::from(name), format!("{}{}", value, &line[end..])))
+    }
+
+    fn cmd_macro(&mut self, args: &[&str]) -> i32 {
+        match args {
+            ["record", name] => {
+                if self.recording.is_some() {
+                    return failure("macro", "already recording");
+                }
+                self.recording = Some(Recording {
+                    name: String::from(*name),
+                    keys: Vec::new(),
+                    line_start: 0,
+                });
+                println!("Recording macro '{}', finish with 'macro stop'", name);
+                0
+            }
+            ["stop"] => match self.recording.take() {
+                Some(mut recording) => {
+                    recording.keys.truncate(recording.line_start);
+                    match crate::keymacro::save(&recording.name, &recording.keys) {
+                        Ok(()) => {
+                            println!(
+                                "Saved macro '{}' ({} keys)",
+                                recording.name,
+                                recording.keys.len()
+                            );
+                            0
+                        }
+                        Err(e) => {
+                            println!("macro: {}: {}", recording.name, e);
+                            1
+                        }
+                    }
+                }
+                None => failure("macro", "not recording"),
+            },
+            ["play", name] => {
+                if self.replaying {
+                    return failure("macro", "cannot play a macro from a macro");
+                }
+                match crate::keymacro::load(name) {
+                    Ok(keys) => {
+                        self.replay.extend(keys);
+                        0
+                    }
+                    Err(e) => {
+                        println!("macro: {}: {}", name, e);
+                        1
+                    }
+                }
+            }
+            ["list"] => {
+                if let Ok(entries) = filesystem::list_path(crate::keymacro::MACRO_DIR) {
+                    for (name, _) in entries.iter() {
+                        println!("{}", name);
+                    }
+                }
+                0
+            }
+            _ => usage("macro record <name> | stop | play <name> | list"),
+        }
+    }
+
+    fn execute_command(&mut self) {
+        println!();
+
+        let mut line = core::mem::take(&mut self.continuation);
+        line.push_str(&self.input_buffer);
+
+        // 行末の \ や閉じていないクォート、fi や done の無い if / for は次の行に続ける
+        let trailing_backslashes = line.chars().rev().take_while(|&c| c == '\\').count();
+        if trailing_backslashes % 2 == 1 {
+            line.pop();
+            self.continuation = line;
+        } else if has_open_quote(&line)
+            || script::parse(&line).err() == Some(ParseError::Incomplete)
+        {
+            line.push('\n');
+            self.continuation = line;
+        } else if !line.trim().is_empty() {
+            self.run_line(&line);
+            self.add_history(line);
+        }
+
+        self.input_buffer.clear();
+        self.cursor_position = 0;
+        self.drawn_len = 0;
+        // ページャを閉じたときや質問に答えたとき、プログラムが終わったときにプロンプトを出す
+        let program_running = crate::bf::is_running() || crate::snake::is_running();
+        #[cfg(feature = "net")]
+        let program_running = program_running || crate::nc::is_running();
+        if !crate::pager::is_active() && !program_running && self.pending_cd.is_none() {
+            self.print_prompt();
+        }
+    }
+
+    // 1 行 (またはスクリプト全体) を実行し、最後に実行したコマンドの終了ステータスを返す
+    pub fn run_line(&mut self, line: &str) -> i32 {
+        let status = match script::parse(line) {
+            Ok(statements) => self.run_statements(&statements),
+            Err(ParseError::Incomplete) => {
+                println!("syntax error: unexpected end of input");
+                2
+            }
+            Err(ParseError::Syntax(e)) => {
+                println!("syntax error: {}", e);
+                2
+            }
+        };
+        self.last_status = status;
+        status
+    }
+
+    fn run_statements(&mut self, statements: &[Statement]) -> i32 {
+        let mut status = 0;
+        for statement in statements {
+            if self.timed_out() {
+                return TIMED_OUT;
+            }
+            status = match statement {
+                Statement::Command(command) => self.run_command(command),
+                Statement::If {
+                    condition,
+                    then,
+                    otherwise,
+                } => {
+                    self.condition_depth += 1;
+                    let succeeded = self.run_statements(condition) == 0;
+                    self.condition_depth -= 1;
+                    if succeeded {
+                        self.run_statements(then)
+                    } else {
+                        self.run_statements(otherwise)
+                    }
+                }
+                Statement::For {
+                    variable,
+                    words,
+                    body,
+                } => {
+                    let words = match expand::expand(words, self) {
+                        Ok(words) => words,
+                        Err(e) => {
+                            println!("{}", e);
+                            return 1;
+                        }
+                    };
+                    let words = match tokenize::split_words(&words) {
+                        Ok(words) => words,
+                        Err(e) => {
+                            println!("syntax error: {}", e);
+                            return 2;
+                        }
+                    };
+                    let mut status = 0;
+                    for word in words {
+                        if self.timed_out() {
+                            return TIMED_OUT;
+                        }
+                        self.variables.push((variable.clone(), word));
+                        status = self.run_statements(body);
+                        self.variables.pop();
+                        if self.aborting {
+                            break;
+                        }
+                    }
+                    status
+                }
+            };
+            self.last_status = status;
+            if self.aborting {
+                break;
+            }
+        }
+        status
+    }
+
+    // a && b || c は左から順に、直前の終了ステータスを見て実行するかを決める。
+    // run -e で止めるのは、リストの最後のコマンドが失敗したときだけ
+    fn run_command(&mut self, line: &str) -> i32 {
+        let list = script::split_and_or(line);
+        if list.iter().any(|(_, command)| command.is_empty()) {
+            println!("syntax error: empty command in '&&' or '||' list");
+            return 2;
+        }
+        let last = list.len() - 1;
+        let mut status = 0;
+        let mut ran_last = false;
+        for (index, (connector, command)) in list.into_iter().enumerate() {
+            if self.timed_out() {
+                return TIMED_OUT;
+            }
+            let run = match connector {
+                Connector::And => status == 0,
+                Connector::Or => status != 0,
+            };
+            if run {
+                status = self.run_pipeline_command(command);
+                self.last_status = status;
+                ran_last = index == last;
+            }
+        }
+        if self.errexit && status != 0 && ran_last && self.condition_depth == 0 {
+            self.aborting = true;
+        }
+        status
+    }
+
+    fn run_pipeline_command(&mut self, line: &str) -> i32 {
+        let stages = script::split_pipeline(line);
+        if stages.len() == 1 {
+            return self.run_simple_command(line);
+        }
+        if stages.iter().any(|stage| stage.is_empty()) {
+            println!("syntax error: empty command in pipeline");
+            return 2;
+        }
+        self.run_pipeline(&stages)
+    }
+
+    // 各段の出力をキャプチャして次の段の入力にする。最後の段の出力はそのまま出し、
+    // 終了ステータスも最後の段のものを返す
+    fn run_pipeline(&mut self, stages: &[&str]) -> i32 {
+        let mut input = None;
+        let mut status = 0;
+        for (index, stage) in stages.iter().enumerate() {
+            self.stdin = input.take();
+            if index + 1 < stages.len() {
+                crate::vga_buffer::start_capture();
+                status = self.run_simple_command(stage);
+                input = Some(crate::vga_buffer::end_capture());
+            } else {
+                status = self.run_simple_command(stage);
+            }
+        }
+        self.stdin = None;
+        status
+    }
+
+    // 組み込みコマンドは成功なら 0、失敗なら 1、使い方の誤りなら 2 を返す。見つからなければ 127
+    fn run_simple_command(&mut self, line: &str) -> i32 {
+        // 別名の中身には && や | があってもよいので、置き換えた行は 1 行として実行し直す
+        if let Some((name, line)) = self.expand_alias(line) {
+            self.expanding_aliases.push(name);
+            let status = self.run_line(&line);
+            self.expanding_aliases.pop();
+            return status;
+        }
+        let line = match expand::expand(line, self) {
+            Ok(line) => line,
+            Err(e) => {
+                println!("{}", e);
+                return 1;
+            }
+        };
+        let parsed = tokenize::tokenize(&line).and_then(|tokens| self.parse_redirects(tokens));
+        let (words, redirect) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                println!("syntax error: {}", e);
+                return 2;
+            }
+        };
+        let parts: Vec<&str> = words.iter().map(String::as_str).collect();
+
+        if parts.is_empty() {
+            return 0;
+        }
+
+        // > は上書き、>> は追記。出力をキャプチャしてからファイルに書く
+        match redirect {
+            Some((operator, filename)) => {
+                crate::vga_buffer::start_capture();
+                let status = self.dispatch(&parts);
+                let output = crate::vga_buffer::end_capture();
+                if self.write_to_file(&filename, &output, operator == ">>") {
+                    status
+                } else {
+                    1
+                }
+            }
+            // 出力を溜めてから、画面より長ければページャで見せる
+            None if self.should_page(parts[0]) => {
+                crate::vga_buffer::start_capture();
+                let status = self.dispatch(&parts);
+                crate::pager::page(&crate::vga_buffer::end_capture());
+                status
+            }
+            None => self.dispatch(&parts),
+        }
+    }
+
+    // パイプの途中や $(...) の中ならキャプチャ中なので、そのまま出す
+    fn should_page(&self, name: &str) -> bool {
+        self.autopage
+            && !crate::vga_buffer::is_capturing()
+            && commands::find(name).is_some_and(|command| command.paged)
+    }
+
+    fn dispatch(&mut self, parts: &[&str]) -> i32 {
+        match parts[0] {
+            "help" => return self.cmd_help(&parts[1..]),
+            "man" => return self.cmd_man(&parts[1..]),
+            "clear" => self.cmd_clear(),
+            "history" => self.cmd_history(),
+            "free" => self.cmd_free(),
+            "exit" => {
+                self.cmd_exit();
+            }
+            "suspend" => return self.cmd_suspend(),
+            "power" => return self.cmd_power(),
+            "cpufreq" => return self.cmd_cpufreq(),
+            "sensors" => print!("{}", crate::procfs::sensors()),
+            "ls" => return self.cmd_ls(&parts[1..]),
+            "echo" => {
+                if parts.len() > 1 {
+                    print!("{}", self.cmd_echo(&parts[1..]));
+                }
+            }
+            "pwd" => print!("{}", self.current_dir_str()),
+            "time" if parts.len() > 1 => return self.cmd_time_command(&join_quoted(&parts[1..])),
+            "time" => self.cmd_time(),
+            "cal" => return self.cmd_cal(&parts[1..]),
+            "timer" => return self.cmd_timer(&parts[1..]),
+            "play" => return self.cmd_play(&parts[1..]),
+            "countdown" => return self.cmd_countdown(&parts[1..]),
+            "config" => return self.cmd_config(&parts[1..]),
+            "gdb" => crate::gdbstub::attach(),
+            "vmmap" => return self.cmd_vmmap(&parts[1..]),
+            "lsdev" => crate::device::print_tree(),
+            "dmesg" => crate::pager::page(&crate::log::contents()),
+            "ioports" => self.cmd_ioports(),
+            "service" => return self.cmd_service(&parts[1..]),
+            "peek" => return self.cmd_peek(&parts[1..]),
+            "poke" => return self.cmd_poke(&parts[1..]),
+            "ksym" => match parts.get(1).and_then(|addr| peek::parse_number(addr)) {
+                Some(addr) => crate::ksym::print_symbol(addr),
+                None => return usage("ksym <addr>"),
+            },
+            "unsafe" => match parts.get(1) {
+                Some(&"on") => self.unsafe_mode = true,
+                Some(&"off") => self.unsafe_mode = false,
+                None => println!(
+                    "unsafe mode is {}",
+                    if self.unsafe_mode { "on" } else { "off" }
+                ),
+                _ => return usage("unsafe [on|off]"),
+            },
+            "mkdir" => return self.cmd_mkdir(&parts[1..]),
+            "cd" if parts.get(1) == Some(&"-") => match filesystem::change_to_previous() {
+                Ok(()) => print!("{}", self.current_dir_str()),
+                Err(e) => return failure("cd", e),
+            },
+            "pushd" => match filesystem::push_directory(parts.get(1).copied()) {
+                Ok(()) => self.cmd_dirs(),
+                Err(e) => return failure("pushd", e),
+            },
+            "popd" => match filesystem::pop_directory() {
+                Ok(()) => self.cmd_dirs(),
+                Err(e) => return failure("popd", e),
+            },
+            "dirs" => self.cmd_dirs(),
+            "truncate" => match parts.get(1..) {
+                Some(["-s", size, path]) => match size.parse::<u64>() {
+                    Ok(size) => {
+                        if let Err(e) = filesystem::truncate_file(path, size) {
+                            println!("truncate: {}: {}", path, e);
+                            return 1;
+                        }
+                    }
+                    Err(_) => {
+                        println!("truncate: invalid size '{}'", size);
+                        return 2;
+                    }
+                },
+                _ => return usage("truncate -s <size> <file>"),
+            },
+            "dd" => return self.cmd_dd(&parts[1..]),
+            "timeout" => return self.cmd_timeout(&parts[1..]),
+            "run" | "sh" => return self.cmd_run(&parts[1..]),
+            "bf" => match parts.get(1) {
+                Some(path) => return self.cmd_bf(path),
+                None => return usage("bf <file>"),
+            },
+            "top" => crate::top::start(),
+            "snake" if crate::vga_buffer::is_capturing() => {
+                println!("snake: needs the screen");
+                return 1;
+            }
+            "snake" => crate::snake::start(),
+            "macro" => return self.cmd_macro(&parts[1..]),
+            "true" => return 0,
+            "false" => return 1,
+            "export" => return self.cmd_export(&parts[1..]),
+            "alias" => return self.cmd_alias(&parts[1..]),
+            "unalias" => return self.cmd_unalias(&parts[1..]),
+            "unset" => {
+                for name in &parts[1..] {
+                    self.environment.remove(*name);
+                }
+            }
+            "env" => {
+                for (name, value) in &self.environment {
+                    println!("{}={}", name, value);
+                }
+            }
+            "clip" => match parts.get(1) {
+                None => println!("{}", crate::clipboard::get()),
+                // clip set に文字列が無ければパイプの入力を使う
+                Some(&"set") if parts.len() == 2 && self.stdin.is_some() => {
+                    crate::clipboard::set(&self.stdin.take().unwrap_or_default())
+                }
+                Some(&"set") => crate::clipboard::set(&parts[2..].join(" ")),
+                Some(&"clear") => crate::clipboard::clear(),
+                Some(&"load") if parts.len() == 3 => match filesystem::read_file(parts[2]) {
+                    Ok(content) => crate::clipboard::set(&String::from_utf8_lossy(&content)),
+                    Err(e) => {
+                        println!("clip: {}: {}", parts[2], e);
+                        return 1;
+                    }
+                },
+                _ => return usage("clip [set <text> | load <file> | clear]"),
+            },
+            "swap" => match parts.get(1) {
+                None => {
+                    let (files, raw, packed) = filesystem::swap_usage();
+                    let (outs, ins) = crate::swap::counters();
+                    println!(
+                        "{} files swapped: {} bytes stored in {} bytes",
+                        files, raw, packed
+                    );
+                    println!("{} swap-outs, {} swap-ins", outs, ins);
+                    println!("heap free: {} bytes", crate::swap::free_memory());
+                }
+                Some(&"out") => println!("swapped out {} files", filesystem::reclaim(true)),
+                _ => return usage("swap [out]"),
+            },
+            #[cfg(feature = "disk")]
+            "losetup" => match parts.get(1..) {
+                Some([]) => {
+                    for (name, blocks, backing) in crate::block::list() {
+                        println!("/dev/{:<8} {:>8} blocks  {}", name, blocks, backing);
+                    }
+                }
+                Some(["-d", name]) => {
+                    let name = name.strip_prefix("/dev/").unwrap_or(name);
+                    if let Err(e) = crate::block::unregister(name) {
+                        println!("losetup: {}: {}", name, e);
+                        return 1;
+                    }
+                }
+                Some([path]) => match crate::block::attach_loop(path) {
+                    Ok(name) => println!("/dev/{}", name),
+                    Err(e) => {
+                        println!("losetup: {}: {}", path, e);
+                        return 1;
+                    }
+                },
+                _ => return usage("losetup [<file> | -d <device>]"),
+            },
+            "stat" => match parts.get(1) {
+                Some(path) => match filesystem::file_size(path) {
+                    Ok((size, allocated)) => {
+                        println!(
+                            "{}: size {} bytes, {} bytes allocated",
+                            path, size, allocated
+                        )
+                    }
+                    Err(e) => {
+                        println!("stat: {}: {}", path, e);
+                        return 1;
+                    }
+                },
+                None => return usage("stat <file>"),
+            },
+            "mount" => {
+                for (path, fstype, read_only) in filesystem::mounts() {
+                    println!(
+                        "{} on {} ({})",
+                        fstype,
+                        path,
+                        if read_only { "ro" } else { "rw" }
+                    );
+                }
+            }
+            "remount" => match (parts.get(1), parts.get(2)) {
+                (Some(path), Some(&mode)) if mode == "ro" || mode == "rw" => {
+                    if let Err(e) = filesystem::remount(path, mode == "ro") {
+                        println!("remount: {}: {}", path, e);
+                        return 1;
+                    }
+                }
+                _ => return usage("remount <path> <ro|rw>"),
+            },
+            "cd" => return self.cmd_cd(parts.get(1).copied().unwrap_or("/")),
+            "touch" => return self.cmd_touch(&parts[1..]),
+            "cat" => return self.cmd_cat(&parts[1..]),
+            "grep" => return self.cmd_grep(&parts[1..]),
+            "less" | "more" => return self.cmd_less(parts[0], &parts[1..]),
+            "head" => return self.cmd_head_tail("head", &parts[1..], false),
+            "tail" => return self.cmd_head_tail("tail", &parts[1..], true),
+            "wc" => return self.cmd_wc(&parts[1..]),
+            "hexdump" | "xxd" => return self.cmd_hexdump(parts[0], &parts[1..]),
+            "rm" => return self.cmd_rm(&parts[1..]),
+            "rmdir" => return self.cmd_rmdir(&parts[1..]),
+            "trash" => return self.cmd_trash(&parts[1..]),
+            "snapshot" => return self.cmd_snapshot(&parts[1..]),
+            "rx" => return self.cmd_rx(&parts[1..]),
+            "mv" => return self.cmd_mv(&parts[1..]),
+            "cp" => return self.cmd_cp(&parts[1..]),
+            "file" => return self.cmd_file(&parts[1..]),
+            "cmp" => return self.cmd_cmp(&parts[1..]),
+            "md5sum" => return self.cmd_md5sum(&parts[1..]),
+            "dedup" => return self.cmd_dedup(&parts[1..]),
+            #[cfg(feature = "graphics")]
+            "fbcon" => return self.cmd_fbcon(&parts[1..]),
+            #[cfg(feature = "net")]
+            "nc" => return self.cmd_nc(&parts[1..]),
+            #[cfg(feature = "net")]
+            "pcap" => return self.cmd_pcap(&parts[1..]),
+            #[cfg(feature = "net")]
+            "netstat" => return self.cmd_netstat(&parts[1..]),
+            #[cfg(feature = "tests")]
+            "bench" => crate::bench::run_all(),
+            #[cfg(feature = "tests")]
+            "stress" => match parts.get(1).map(|s| s.parse::<u64>()) {
+                None => crate::stress::start(10),
+                Some(Ok(seconds)) => crate::stress::start(seconds),
+                Some(Err(_)) => return usage("stress [seconds]"),
+            },
+            #[cfg(feature = "tests")]
+            "runtest" => match parts.get(1) {
+                Some(script) => return self.cmd_runtest(script, parts.get(2).copied()),
+                None => return usage("runtest <script> [expected]"),
+            },
+            "insmod" => match parts.get(1) {
+                Some(path) => match crate::kmod::load(path) {
+                    Ok(name) => println!("insmod: loaded {}", name),
+                    Err(e) => {
+                        println!("insmod: {}: {}", path, e);
+                        return 1;
+                    }
+                },
+                None => return usage("insmod <file>"),
+            },
+            "lsmod" => {
+                for (name, size, commands) in crate::kmod::list() {
+                    println!("{:<16} {:>8}  {}", name, size, commands.join(" "));
+                }
+            }
+            command => {
+                let args = parts[1..].join(" ");
+                if let Some(status) = crate::kmod::run_command(command, &args) {
+                    return status;
+                }
+                let names = commands::all().map(|command| command.name);
+                match suggest::closest(command, names) {
+                    Some(name) => {
+                        println!("Unknown command: '{}' (did you mean '{}'?)", command, name)
+                    }
+                    None => println!("Unknown command: '{}'", command),
+                }
+                return 127;
+            }
+        }
+        0
+    }
+
+    fn timed_out(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| crate::time::ticks() >= deadline)
+    }
+
+    // 期限は文と文の間、for の周回ごと、dd のブロックごとに確かめる
+    fn cmd_timeout(&mut self, args: &[&str]) -> i32 {
+        let (ticks, command) = match args {
+            [duration, command @ ..] if !command.is_empty() => match parse_duration(duration) {
+                Some(ticks) => (ticks, join_quoted(command)),
+                None => {
+                    println!("timeout: invalid duration '{}'", duration);
+                    return 2;
+                }
+            },
+            _ => {
+                println!("Usage: timeout <seconds> <command>");
+                return 2;
+            }
+        };
+
+        let previous = self.deadline;
+        let deadline = crate::time::ticks().saturating_add(ticks);
+        self.deadline = Some(previous.map_or(deadline, |previous| previous.min(deadline)));
+        let mut status = self.run_line(&command);
+        // 外側の timeout の期限が先なら、それを報告するのは外側
+        if self.timed_out() && self.deadline == Some(deadline) {
+            println!("timeout: '{}' timed out after {}", command, args[0]);
+            status = TIMED_OUT;
+        }
+        self.deadline = previous;
+        status
+    }
+
+    // ファイルをスクリプトとして実行する。-e なら失敗したコマンドで止める
+    fn cmd_run(&mut self, args: &[&str]) -> i32 {
+        let (errexit, path) = match args {
+            ["-e", path] => (true, *path),
+            [path] => (false, *path),
+            _ => return usage("run [-e] <file>"),
+        };
+        if self.run_depth >= MAX_RUN_DEPTH {
+            return failure("run", "scripts nested too deeply");
+        }
+        let content = match filesystem::read_file(path) {
+            Ok(content) => content,
+            Err(e) => {
+                println!("run: {}: {}", path, e);
+                return 1;
+            }
+        };
+        let header = &content[..content.len().min(filetype::HEADER_SIZE)];
+        if !filetype::detect(header).is_text() {
+            println!("run: {}: not a script", path);
+            return 1;
+        }
+        let script = String::from_utf8_lossy(&content).into_owned();
+
+        let previous = self.errexit;
+        self.errexit = errexit;
+        self.run_depth += 1;
+        let status = self.run_line(&script);
+        self.run_depth -= 1;
+        self.errexit = previous;
+        if self.aborting {
+            println!(
+                "run: {}: stopped after a command failed (status {})",
+                path, status
+            );
+            // 外側の run -e も止める
+            self.aborting = previous;
+        }
+        status
+    }
+
+    // time <コマンド>: コマンドを実行し、かかった実時間を出す
+    fn cmd_time_command(&mut self, command: &str) -> i32 {
+        let start = crate::time::uptime_ms();
+        let allocations = allocator::allocations();
+        let status = self.run_line(command);
+        let elapsed = crate::time::uptime_ms() - start;
+        println!("real\t{}.{:03}s", elapsed / 1000, elapsed % 1000);
+        println!("allocs\t{}", allocator::allocations() - allocations);
+        status
+    }
+
+    // コマンドを実行し、画面に出す代わりに出力を返す
+    fn capture(&mut self, line: &str) -> String {
+        crate::vga_buffer::start_capture();
+        self.run_line(line);
+        crate::vga_buffer::end_capture()
+    }
+
+    // リダイレクトを取り除いたコマンドと、最後のリダイレクト (演算子, ファイル名) を返す
+    fn parse_redirects(
+        &self,
+        tokens: Vec<Token>,
+    ) -> Result<(Vec<String>, Option<(&'static str, String)>), &'static str> {
+        let mut command = Vec::new();
+        let mut redirect = None;
+
+        let mut tokens = tokens.into_iter();
+        while let Some(token) = tokens.next() {
+            match token {
+                Token::Redirect(operator) => match tokens.next() {
+                    Some(Token::Word(filename)) => redirect = Some((operator, filename)),
+                    // リダイレクト先は展開しない
+                    Some(Token::Glob(pattern)) => {
+                        redirect = Some((operator, glob::literal(&pattern)))
+                    }
+                    _ => return Err("expected a file name after '>'"),
+                },
+                Token::Word(word) => command.push(word),
+                Token::Glob(pattern) => command.extend(glob::expand(&pattern)),
+            }
+        }
+
+        Ok((command, redirect))
+    }
+
+    fn write_to_file(&self, filename: &str, content: &str, append: bool) -> bool {
+        match filesystem::write_file(filename, content.as_bytes(), append) {
+            Ok(_) => true,
+            Err(e) => {
+                println!("{}: {}", filename, e);
+                false
+            }
+        }
+    }
+
+    fn cmd_help(&self, args: &[&str]) -> i32 {
+        match args {
+            [] => {
+                print!("{}", self.cmd_help_str());
+                0
+            }
+            [name] => match commands::find(name) {
+                Some(command) => {
+                    print!("{}", command.help_text());
+                    0
+                }
+                None if crate::kmod::has_command(name) => {
+                    println!("help: {} is provided by a kernel extension", name);
+                    0
+                }
+                None => failure("help", &format!("no such command '{}'", name)),
+            },
+            _ => usage("help [command]"),
+        }
+    }
+
+    // ページが無いコマンドは help の内容を出す
+    fn cmd_man(&self, args: &[&str]) -> i32 {
+        let topic = match args {
+            [topic] => *topic,
+            _ => return usage("man <topic>"),
+        };
+        match crate::man::read(topic) {
+            Ok(text) => crate::pager::page(&text),
+            Err(e) => match commands::find(topic) {
+                Some(command) => crate::pager::page(&command.help_text()),
+                None => {
+                    println!("man: {}: {}", topic, e);
+                    return 1;
+                }
+            },
+        }
+        0
+    }
+
+    fn cmd_clear(&mut self) {
+        if let Some(mut writer) = crate::vga_buffer::WRITER.try_lock() {
+            writer.clear_screen();
+        }
+    }
+
+    fn cmd_free(&self) {
+        let total = allocator::HEAP_SIZE;
+        let free = crate::swap::free_memory();
+        println!("{:<10}{:>10}{:>10}{:>10}", "", "total", "used", "free");
+        println!(
+            "{:<10}{:>10}{:>10}{:>10}",
+            "heap:",
+            total,
+            total - free,
+            free
+        );
+        println!(
+            "history: {}/{} commands, {} bytes",
+            self.command_history.len(),
+            self.history_size,
+            strings_memory(&self.command_history)
+        );
+        println!(
+            "kill ring: {} entries, {} bytes",
+            self.kill_ring.len(),
+            strings_memory(&self.kill_ring)
+        );
+    }
+
+    fn cmd_history(&self) {
+        for (i, cmd) in self.command_history.iter().enumerate() {
+            println!("{}: {}", i, cmd);
+        }
+    }
+
+    fn cmd_exit(&self) {
+        println!("Shutting down...");
+        crate::power::shutdown();
+    }
+
+    fn cmd_suspend(&self) -> i32 {
+        println!("Suspending...");
+        match crate::power::suspend() {
+            Ok(()) => {
+                println!("Resumed");
+                0
+            }
+            Err(e) => failure("suspend", e),
+        }
+    }
+
+    fn cmd_power(&self) -> i32 {
+        let revision = match acpi::revision() {
+            Some(revision) => revision,
+            None => {
+                return failure("power", "ACPI tables not found");
+            }
+        };
+        println!("ACPI revision: {}", revision);
+        println!("Profile:       {}", acpi::pm_profile().unwrap_or("unknown"));
+        let states: Vec<String> = acpi::sleep_states()
+            .iter()
+            .map(|state| format!("S{}", state))
+            .collect();
+        println!("Sleep states:  {}", states.join(" "));
+
+        // 状態を読むには _PSR / _BST を実行する AML インタプリタが要る
+        let presence = |id| {
+            if acpi::has_device(id) {
+                "present (state unknown)"
+            } else {
+                "not present"
+            }
+        };
+        println!("AC adapter:    {}", presence("ACPI0003"));
+        println!("Battery:       {}", presence("PNP0C0A"));
+        0
+    }
+
+    fn cmd_cpufreq(&self) -> i32 {
+        let frequency = match cpu::measure_frequency() {
+            Some(frequency) => frequency,
+            None => {
+                return failure("cpufreq", "interrupts are disabled");
+            }
+        };
+        if let Some(brand) = cpu::brand() {
+            println!("CPU:      {}", brand);
+        }
+        println!("TSC:      {} MHz", frequency.tsc_mhz);
+        if let Some((base, max)) = cpu::nominal_mhz() {
+            println!("Base/max: {} / {} MHz", base, max);
+        }
+        // ACPI の _PSS は AML を実行しないと読めないので、APERF/MPERF の比だけを見る
+        match frequency.effective_mhz {
+            Some(mhz) => println!("Current:  {} MHz", mhz),
+            None => println!("Current:  not reported by this CPU"),
+        }
+        0
+    }
+
+    // . で始まる名前 (/.trash など) は -a のときだけ出す。ファイルを渡したらその名前を出す
+    fn cmd_ls(&self, args: &[&str]) -> i32 {
+        let all = args.contains(&"-a");
+        let paths: Vec<&str> = args.iter().copied().filter(|&arg| arg != "-a").collect();
+        if paths.is_empty() {
+            print!(
+                "{}",
+                Self::format_listing(&filesystem::list_current_directory(), all)
+            );
+            return 0;
+        }
+
+        let mut status = 0;
+        for (index, path) in paths.iter().enumerate() {
+            match filesystem::list_path(path) {
+                Ok(entries) => {
+                    if paths.len() > 1 {
+                        if index > 0 {
+                            println!();
+                        }
+                        println!("{}:", path);
+                    }
+                    print!("{}", Self::format_listing(&entries, all));
+                }
+                Err(_) if filesystem::file_size(path).is_ok() => println!("{}", path),
+                Err(e) => {
+                    println!("ls: {}: {}", path, e);
+                    status = 1;
+                }
+            }
+        }
+        status
+    }
+
+    fn format_listing(entries: &[(Name, bool)], all: bool) -> String {
+        let mut output = String::new();
+        for (name, is_dir) in entries {
+            if name.starts_with('.') && !all {
+                continue;
+            }
+            if *is_dir {
+                output.push_str(&format!("{}/\n", name));
+            } else {
+                output.push_str(&format!("{}\n", name));
+            }
+        }
+        output
+    }
+
+    fn cmd_mkdir(&self, args: &[&str]) -> i32 {
+        let parents = args.contains(&"-p");
+        let dirs: Vec<&str> = args.iter().copied().filter(|&arg| arg != "-p").collect();
+        if dirs.is_empty() {
+            println!("Usage: mkdir [-p] <directory>...");
+            return 2;
+        }
+
+        let mut status = 0;
+        for dir_name in dirs {
+            if let Err(e) = filesystem::create_directory(dir_name, parents) {
+                println!("mkdir: {}: {}", dir_name, e);
+                status = 1;
+            }
+        }
+        status
+    }
+
+    fn cmd_dd(&self, args: &[&str]) -> i32 {
+        let mut input = None;
+        let mut output = None;
+        let mut block_size = 512;
+        let mut count = None;
+        let mut skip = 0;
+        let mut seek = 0;
+
+        for arg in args {
+            let (key, value) = match arg.split_once('=') {
+                Some(pair) => pair,
+                None => {
+                    println!("dd: invalid operand '{}'", arg);
+                    return 2;
+                }
+            };
+            let number = value.parse::<u64>();
+            match (key, number) {
+                ("if", _) => input = Some(value),
+                ("of", _) => output = Some(value),
+                ("bs", Ok(n)) if n > 0 => block_size = n,
+                ("count", Ok(n)) => count = Some(n),
+                ("skip", Ok(n)) => skip = n,
+                ("seek", Ok(n)) => seek = n,
+                _ => {
+                    println!("dd: invalid operand '{}'", arg);
+                    return 2;
+                }
+            }
+        }
+        let (input, output) = match (input, output) {
+            (Some(input), Some(output)) => (input, output),
+            _ => {
+                return usage("dd if=<src> of=<dst> [bs=<n>] [count=<n>] [skip=<n>] [seek=<n>]");
+            }
+        };
+
+        let (mut full, mut partial, mut copied) = (0u64, 0u64, 0u64);
+        let mut block = 0;
+        let mut status = 0;
+        while count.map_or(true, |count| block < count) {
+            if self.timed_out() {
+                status = TIMED_OUT;
+                break;
+            }
+            let offset = match block_offset(skip, block, block_size) {
+                Some(offset) => offset,
+                None => {
+                    return failure("dd", "offset out of range");
+                }
+            };
+            let data = match dd_read(input, offset, block_size as usize) {
+                Ok(data) => data,
+                Err(e) => {
+                    println!("dd: {}: {}", input, e);
+                    return 1;
+                }
+            };
+            if data.is_empty() {
+                break;
+            }
+            let offset = match block_offset(seek, block, block_size) {
+                Some(offset) => offset,
+                None => {
+                    return failure("dd", "offset out of range");
+                }
+            };
+            if let Err(e) = dd_write(output, offset, &data) {
+                println!("dd: {}: {}", output, e);
+                return 1;
+            }
+
+            copied += data.len() as u64;
+            if data.len() as u64 == block_size {
+                full += 1;
+            } else {
+                partial += 1;
+                break;
+            }
+            block += 1;
+        }
+
+        println!("{}+{} records in", full, partial);
+        println!("{}+{} records out", full, partial);
+        println!("{} bytes copied", copied);
+        status
+    }
+
+    fn cmd_dirs(&self) {
+        println!("{}", filesystem::directory_stack().join(" "));
+    }
+
+    fn cmd_touch(&self, args: &[&str]) -> i32 {
+        let create = !args.contains(&"-c");
+        let files: Vec<&str> = args.iter().copied().filter(|&arg| arg != "-c").collect();
+        if files.is_empty() {
+            println!("Usage: touch [-c] <filename>...");
+            return 2;
+        }
+
+        let mut status = 0;
+        for file_name in files {
+            match filesystem::touch(file_name, create) {
+                Ok(true) => println!("File created: {}", file_name),
+                Ok(false) => {}
+                Err(e) => {
+                    println!("touch: {}: {}", file_name, e);
+                    status = 1;
+                }
+            }
+        }
+        status
+    }
+
+    // ファイルをつないでページャで見せる。ファイルが無ければパイプの入力
+    fn cmd_less(&mut self, command: &str, files: &[&str]) -> i32 {
+        if files.is_empty() {
+            return match self.stdin.take() {
+                Some(input) => {
+                    crate::pager::page(&input);
+                    0
+                }
+                None => {
+                    println!("Usage: {} [file]...", command);
+                    2
+                }
+            };
+        }
+        let mut text = String::new();
+        let mut status = 0;
+        for path in files {
+            match filesystem::read_file(path) {
+                Ok(content) => text.push_str(&String::from_utf8_lossy(&content)),
+                Err(e) => {
+                    text.push_str(&format!("{}: {}: {}\n", command, path, e));
+                    status = 1;
+                }
+            }
+        }
+        crate::pager::page(&text);
+        status
+    }
+
+    // ファイルが無ければパイプの入力をそのまま出す
+    fn cmd_cat(&mut self, files: &[&str]) -> i32 {
+        if files.is_empty() {
+            return match self.stdin.take() {
+                Some(input) => {
+                    print!("{}", input);
+                    0
+                }
+                None => usage("cat <file>..."),
+            };
+        }
+
+        let mut status = 0;
+        for path in files {
+            match filesystem::read_file(path) {
+                Ok(content) => print!("{}", String::from_utf8_lossy(&content)),
+                Err(e) => {
+                    println!("cat: {}: {}", path, e);
+                    status = 1;
+                }
+            }
+        }
+        status
+    }
+
+    // 行に pattern を含むものを出す。* と ? があればワイルドカードとして行のどこかに合うか見る。
+    // ファイルが無ければパイプの入力を探す。見つかれば 0、無ければ 1、誤りは 2 を返す
+    fn cmd_grep(&mut self, args: &[&str]) -> i32 {
+        const USAGE: &str = "grep [-i] [-v] [-n] [-c] <pattern> [file]...";
+        let (mut ignore_case, mut invert, mut numbers, mut count) = (false, false, false, false);
+        let mut rest = args;
+        while let Some(option) = rest
+            .first()
+            .filter(|arg| arg.len() > 1 && arg.starts_with('-'))
+        {
+            rest = &rest[1..];
+            if *option == "--" {
+                break;
+            }
+            for c in option[1..].chars() {
+                match c {
+                    'i' => ignore_case = true,
+                    'v' => invert = true,
+                    'n' => numbers = true,
+                    'c' => count = true,
+                    _ => return usage(USAGE),
+                }
+            }
+        }
+        let Some((pattern, files)) = rest.split_first() else {
+            return usage(USAGE);
+        };
+        let pattern = if ignore_case {
+            pattern.to_lowercase()
+        } else {
+            String::from(*pattern)
+        };
+        let wildcard = pattern.contains(['*', '?']);
+        let pattern = if wildcard {
+            format!("*{}*", pattern)
+        } else {
+            pattern
+        };
+        let matches = |line: &str| {
+            let line = if ignore_case {
+                line.to_lowercase()
+            } else {
+                String::from(line)
+            };
+            let found = if wildcard {
+                glob::matches_text(&pattern, &line)
+            } else {
+                line.contains(pattern.as_str())
+            };
+            found != invert
+        };
+
+        let inputs: Vec<(Option<&str>, Result<Vec<u8>, &'static str>)> = if files.is_empty() {
+            match self.stdin.take() {
+                Some(input) => vec![(None, Ok(input.into_bytes()))],
+                None => return usage(USAGE),
+            }
+        } else {
+            files
+                .iter()
+                .map(|path| (Some(*path), filesystem::read_file(path)))
+                .collect()
+        };
+        let prefix = files.len() > 1;
+
+        let mut found = false;
+        let mut failed = false;
+        for (path, content) in inputs {
+            let content = match content {
+                Ok(content) => content,
+                Err(e) => {
+                    println!("grep: {}: {}", path.unwrap_or("-"), e);
+                    failed = true;
+                    continue;
+                }
+            };
+            let text = String::from_utf8_lossy(&content);
+            let header = &content[..content.len().min(filetype::HEADER_SIZE)];
+            let binary = !filetype::detect(header).is_text();
+            let mut matched = 0;
+            for (number, line) in text.lines().enumerate() {
+                if !matches(line) {
+                    continue;
+                }
+                matched += 1;
+                if count || binary {
+                    continue;
+                }
+                if prefix {
+                    print!("{}:", path.unwrap_or("-"));
+                }
+                if numbers {
+                    print!("{}:", number + 1);
+                }
+                println!("{}", line);
+            }
+            if count && prefix {
+                println!("{}:{}", path.unwrap_or("-"), matched);
+            } else if count {
+                println!("{}", matched);
+            } else if binary && matched > 0 {
+                println!("Binary file {} matches", path.unwrap_or("(standard input)"));
+            }
+            found |= matched > 0;
+        }
+        if failed {
+            2
+        } else if found {
+            0
+        } else {
+            1
+        }
+    }
+
+    // head は先頭の、tail は末尾の count 行 (既定 10) を出す。ファイルが無ければパイプの入力を使う
+    fn cmd_head_tail(&mut self, command: &str, args: &[&str], tail: bool) -> i32 {
+        let (count, files) = match args {
+            ["-n", count, files @ ..] => match count.parse::<usize>() {
+                Ok(count) => (count, files),
+                Err(_) => return failure(command, "invalid number of lines"),
+            },
+            files => (10, files),
+        };
+        let inputs: Vec<(&str, Result<Vec<u8>, &'static str>)> = if files.is_empty() {
+            match self.stdin.take() {
+                Some(input) => vec![("-", Ok(input.into_bytes()))],
+                None => {
+                    println!("Usage: {} [-n <count>] <file>...", command);
+                    return 2;
+                }
+            }
+        } else {
+            files
+                .iter()
+                .map(|path| (*path, filesystem::read_file(path)))
+                .collect()
+        };
+
+        let mut status = 0;
+        for (index, (path, content)) in inputs.iter().enumerate() {
+            let content = match content {
+                Ok(content) => String::from_utf8_lossy(content),
+                Err(e) => {
+                    println!("{}: {}: {}", command, path, e);
+                    status = 1;
+                    continue;
+                }
+            };
+            if files.len() > 1 {
+                if index > 0 {
+                    println!();
+                }
+                println!("==> {} <==", path);
+            }
+            // 改行はそのまま残して、最後の行に改行が無くてもそのまま出す
+            let lines: Vec<&str> = content.split_inclusive('\n').collect();
+            let shown = if tail {
+                &lines[lines.len().saturating_sub(count)..]
+            } else {
+                &lines[..count.min(lines.len())]
+            };
+            for line in shown {
+                print!("{}", line);
+            }
+        }
+        status
+    }
+
+    // 行数、単語数、バイト数を数える。-l -w -c で出すものを選ぶ。ファイルが無ければパイプの入力
+    fn cmd_wc(&mut self, args: &[&str]) -> i32 {
+        const USAGE: &str = "wc [-l] [-w] [-c] [file]...";
+        let mut selected = [false; 3];
+        let mut files = Vec::new();
+        for arg in args {
+            match *arg {
+                "-l" => selected[0] = true,
+                "-w" => selected[1] = true,
+                "-c" => selected[2] = true,
+                option if option.len() > 1 && option.starts_with('-') => return usage(USAGE),
+                file => files.push(file),
+            }
+        }
+        if !selected.contains(&true) {
+            selected = [true; 3];
+        }
+        let print = |counts: &[usize; 3], name: &str| {
+            for (count, _) in counts.iter().zip(selected).filter(|(_, shown)| *shown) {
+                print!("{:>7} ", count);
+            }
+            println!("{}", name);
+        };
+        let count = |content: &[u8]| {
+            let lines = content.iter().filter(|&&byte| byte == b'\n').count();
+            let words = content
+                .split(|byte| byte.is_ascii_whitespace())
+                .filter(|word| !word.is_empty())
+                .count();
+            [lines, words, content.len()]
+        };
+
+        if files.is_empty() {
+            return match self.stdin.take() {
+                Some(input) => {
+                    print(&count(input.as_bytes()), "");
+                    0
+                }
+                None => usage(USAGE),
+            };
+        }
+        let mut status = 0;
+        let mut total = [0; 3];
+        for path in &files {
+            match filesystem::read_file(path) {
+                Ok(content) => {
+                    let counts = count(&content);
+                    for (sum, count) in total.iter_mut().zip(counts) {
+                        *sum += count;
+                    }
+                    print(&counts, path);
+                }
+                Err(e) => {
+                    println!("wc: {}: {}", path, e);
+                    status = 1;
+                }
+            }
+        }
+        if files.len() > 1 {
+            print(&total, "total");
+        }
+        status
+    }
+
+    // 16 バイトずつ、位置と 16 進と文字を並べて出す。ページャは全部をヒープに持つので、
+    // -n が無ければ先頭の HEXDUMP_LIMIT バイトまでにする
+    fn cmd_hexdump(&mut self, command: &str, args: &[&str]) -> i32 {
+        const HEXDUMP_LIMIT: usize = 4096;
+        let mut offset = 0;
+        let mut length = None;
+        let mut file = None;
+        let mut rest = args.iter();
+        while let Some(arg) = rest.next() {
+            let value = match *arg {
+                "-s" | "-n" => rest.next().and_then(|value| peek::parse_number(value)),
+                path if file.is_none() && !path.starts_with('-') => {
+                    file = Some(path);
+                    continue;
+                }
+                _ => None,
+            };
+            match (*arg, value) {
+                ("-s", Some(value)) => offset = value,
+                ("-n", Some(value)) => length = Some(value as usize),
+                _ => {
+                    println!("Usage: {} [-s <offset>] [-n <length>] [file]", command);
+                    return 2;
+                }
+            }
+        }
+
+        let limit = length.unwrap_or(HEXDUMP_LIMIT);
+        let data = match file {
+            Some(path) => match filesystem::read_file_at(path, offset, limit) {
+                Ok(data) => data,
+                Err(e) => return failure(command, e),
+            },
+            None => match self.stdin.take() {
+                Some(input) => {
+                    let bytes = input.as_bytes();
+                    let start = (offset as usize).min(bytes.len());
+                    bytes[start..(start.saturating_add(limit)).min(bytes.len())].to_vec()
+                }
+                None => {
+                    println!("Usage: {} [-s <offset>] [-n <length>] [file]", command);
+                    return 2;
+                }
+            },
+        };
+
+        let mut output = String::new();
+        for (row, chunk) in data.chunks(16).enumerate() {
+            output.push_str(&format!("{:08x} ", offset as usize + row * 16));
+            for index in 0..16 {
+                if index == 8 {
+                    output.push(' ');
+                }
+                match chunk.get(index) {
+                    Some(byte) => output.push_str(&format!(" {:02x}", byte)),
+                    None => output.push_str("   "),
+                }
+            }
+            output.push_str("  |");
+            for &byte in chunk {
+                output.push(if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                });
+            }
+            output.push_str("|\n");
+        }
+        output.push_str(&format!("{:08x}\n", offset as usize + data.len()));
+        let truncated = length.is_none()
+            && data.len() == limit
+            && file.is_some_and(|path| {
+                filesystem::read_file_at(path, offset + limit as u64, 1)
+                    .is_ok_and(|more| !more.is_empty())
+            });
+        if truncated {
+            output.push_str(&format!(
+                "(first {} bytes shown; use -s and -n for the rest)\n",
+                limit
+            ));
+        }
+        crate::pager::page(&output);
+        0
+    }
+
+    fn cmd_rm(&self, args: &[&str]) -> i32 {
+        let recursive = args.contains(&"-r");
+        let paths: Vec<&str> = args.iter().copied().filter(|&arg| arg != "-r").collect();
+        if paths.is_empty() {
+            println!("Usage: rm [-r] <path>...");
+            return 2;
+        }
+
+        let mut status = 0;
+        for path in paths {
+            if let Err(e) = trash::remove(path, recursive) {
+                println!("rm: {}: {}", path, e);
+                status = 1;
+            }
+        }
+        status
+    }
+
+    // UNIX 秒を timezone の時差で "YYYY-MM-DD hh:mm" にする
+    fn local_time(&self, seconds: u64) -> String {
+        let local = (seconds as i64 + self.timezone_offset as i64 * 3600).max(0) as u64;
+        let (year, month, day) = crate::time::civil_from_days(local / 86400);
+        let minutes = local % 86400 / 60;
+        format!(
+            "{}-{:02}-{:02} {:02}:{:02}",
+            year,
+            month,
+            day,
+            minutes / 60,
+            minutes % 60
+        )
+    }
+
+    fn cmd_snapshot(&self, args: &[&str]) -> i32 {
+        let result = match args {
+            [] | ["list"] => {
+                for (name, created) in filesystem::snapshots() {
+                    println!("{}  {}", self.local_time(created), name);
+                }
+                Ok(())
+            }
+            ["create", name] => filesystem::create_snapshot(name),
+            ["restore", name] => filesystem::restore_snapshot(name),
+            ["delete", name] => filesystem::delete_snapshot(name),
+            _ => return usage("snapshot [list | create <name> | restore <name> | delete <name>]"),
+        };
+        match result {
+            Ok(()) => 0,
+            Err(e) => failure("snapshot", e),
+        }
+    }
+
+    fn cmd_trash(&self, args: &[&str]) -> i32 {
+        match args {
+            [] | ["list"] => {
+                for entry in trash::list() {
+                    println!(
+                        "{:>4}  {}  {:>8}  {}{}",
+                        entry.id,
+                        self.local_time(entry.deleted),
+                        entry.size,
+                        entry.original,
+                        if entry.directory { "/" } else { "" }
+                    );
+                }
+                0
+            }
+            ["restore", id] | ["restore", id, _] => {
+                let id = match id.parse() {
+                    Ok(id) => id,
+                    Err(_) => return usage("trash restore <id> [path]"),
+                };
+                match trash::restore(id, args.get(2).copied()) {
+                    Ok(path) => {
+                        println!("trash: restored {}", path);
+                        0
+                    }
+                    Err(e) => failure("trash", e),
+                }
+            }
+            ["empty"] => {
+                println!("trash: discarded {} entries", trash::empty());
+                0
+            }
+            _ => usage("trash [list | restore <id> [path] | empty]"),
+        }
+    }
+
+    fn cmd_rmdir(&self, dirs: &[&str]) -> i32 {
+        if dirs.is_empty() {
+            println!("Usage: rmdir <directory>...");
+            return 2;
+        }
+
+        let mut status = 0;
+        for dir in dirs {
+            if let Err(e) = filesystem::remove_directory(dir, false) {
+                println!("rmdir: {}: {}", dir, e);
+                status = 1;
+            }
+        }
+        status
+    }
+
+    fn cmd_mv(&self, args: &[&str]) -> i32 {
+        let (dest, sources) = match args.split_last() {
+            Some((dest, sources)) if !sources.is_empty() => (*dest, sources),
+            _ => {
+                println!("Usage: mv <source>... <destination>");
+                return 2;
+            }
+        };
+        let into_directory = filesystem::list_path(dest).is_ok();
+        if sources.len() > 1 && !into_directory {
+            println!("mv: {}: Not a directory", dest);
+            return 1;
+        }
+
+        let mut status = 0;
+        for source in sources {
+            let target = match destination(source, dest, into_directory) {
+                Some(target) => target,
+                None => {
+                    println!("mv: {}: Cannot move the root directory", source);
+                    status = 1;
+                    continue;
+                }
+            };
+            if let Err(e) = filesystem::rename(source, &target) {
+                println!("mv: {}: {}", source, e);
+                status = 1;
+            }
+        }
+        status
+    }
+
+    fn cmd_cp(&self, args: &[&str]) -> i32 {
+        let recursive = args.contains(&"-r");
+        let paths: Vec<&str> = args.iter().copied().filter(|&arg| arg != "-r").collect();
+        let (dest, sources) = match paths.split_last() {
+            Some((dest, sources)) if !sources.is_empty() => (*dest, sources),
+            _ => {
+                println!("Usage: cp [-r] <source>... <destination>");
+                return 2;
+            }
+        };
+        let into_directory = filesystem::list_path(dest).is_ok();
+        if sources.len() > 1 && !into_directory {
+            println!("cp: {}: Not a directory", dest);
+            return 1;
+        }
+
+        let mut status = 0;
+        for source in sources {
+            let target = match destination(source, dest, into_directory) {
+                Some(target) => target,
+                None => {
+                    println!("cp: {}: Cannot copy the root directory", source);
+                    status = 1;
+                    continue;
+                }
+            };
+            if let Err(e) = filesystem::copy(source, &target, recursive) {
+                println!("cp: {}: {}", source, e);
+                status = 1;
+            }
+        }
+        status
+    }
+
+    fn cmd_file(&self, paths: &[&str]) -> i32 {
+        if paths.is_empty() {
+            return usage("file <path>...");
+        }
+        let mut status = 0;
+        for path in paths {
+            if filesystem::list_path(path).is_ok() {
+                println!("{}: directory", path);
+                continue;
+            }
+            match filesystem::read_file_at(path, 0, filetype::HEADER_SIZE) {
+                Ok(header) => println!("{}: {}", path, filetype::detect(&header).description()),
+                Err(e) => {
+                    println!("file: {}: {}", path, e);
+                    status = 1;
+                }
+            }
+        }
+        status
+    }
+
+    // 4 KiB ずつ読んで比べる。片方が先に終われば、そのことを報告する
+    fn cmd_cmp(&self, args: &[&str]) -> i32 {
+        const CHUNK: usize = 4096;
+
+        let (first, second) = match args {
+            [first, second] => (*first, *second),
+            _ => {
+                println!("Usage: cmp <file1> <file2>");
+                return 2;
+            }
+        };
+        let mut sizes = [0; 2];
+        for (size, path) in sizes.iter_mut().zip([first, second]) {
+            match filesystem::file_size(path) {
+                Ok((len, _)) => *size = len,
+                Err(e) => {
+                    println!("cmp: {}: {}", path, e);
+                    return 2;
+                }
+            }
+        }
+
+        let mut offset = 0;
+        let mut line = 1;
+        while offset < sizes[0].min(sizes[1]) {
+            let chunks = (
+                filesystem::read_file_at(first, offset, CHUNK),
+                filesystem::read_file_at(second, offset, CHUNK),
+            );
+            let (a, b) = match chunks {
+                (Ok(a), Ok(b)) => (a, b),
+                (Err(e), _) | (_, Err(e)) => {
+                    println!("cmp: {}", e);
+                    return 2;
+                }
+            };
+            for (index, (x, y)) in a.iter().zip(b.iter()).enumerate() {
+                if x != y {
+                    let byte = offset + index as u64 + 1;
+                    println!("{} {} differ: byte {}, line {}", first, second, byte, line);
+                    return 1;
+                }
+                if *x == b'\n' {
+                    line += 1;
+                }
+            }
+            offset += a.len().min(b.len()) as u64;
+        }
+        if sizes[0] != sizes[1] {
+            let shorter = if sizes[0] < sizes[1] { first } else { second };
+            println!("cmp: EOF on {} after byte {}", shorter, offset);
+            return 1;
+        }
+        0
+    }
+
+    fn cmd_md5sum(&mut self, files: &[&str]) -> i32 {
+        if files.is_empty() {
+            return match self.stdin.take() {
+                Some(input) => {
+                    println!("{}  -", md5::to_hex(&md5::digest(input.as_bytes())));
+                    0
+                }
+                None => usage("md5sum [file]..."),
+            };
+        }
+
+        let mut status = 0;
+        for path in files {
+            match md5::file_digest(path) {
+                Ok(digest) => println!("{}  {}", md5::to_hex(&digest), path),
+                Err(e) => {
+                    println!("md5sum: {}: {}", path, e);
+                    status = 1;
+                }
+            }
+        }
+        status
+    }
+
+    // 大きさでふるい分けてから、同じ大きさのものだけハッシュを取って比べる
+    fn cmd_dedup(&self, args: &[&str]) -> i32 {
+        let dir = match args {
+            [dir] => *dir,
+            _ => {
+                println!("Usage: dedup <dir>");
+                return 2;
+            }
+        };
+        let files = match filesystem::walk_files(dir) {
+            Ok(files) => files,
+            Err(e) => {
+                println!("dedup: {}: {}", dir, e);
+                return 1;
+            }
+        };
+
+        let mut by_size: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+        for path in files {
+            if let Ok((size, _)) = filesystem::file_size(&path) {
+                if size > 0 {
+                    by_size.entry(size).or_default().push(path);
+                }
+            }
+        }
+
+        let mut status = 0;
+        let (mut groups, mut copies, mut wasted) = (0, 0, 0);
+        for (size, paths) in by_size.iter().filter(|(_, paths)| paths.len() > 1) {
+            let mut by_digest: BTreeMap<md5::Digest, Vec<&String>> = BTreeMap::new();
+            for path in paths {
+                match md5::file_digest(path) {
+                    Ok(digest) => by_digest.entry(digest).or_default().push(path),
+                    Err(e) => {
+                        println!("dedup: {}: {}", path, e);
+                        status = 1;
+                    }
+                }
+            }
+            for (digest, same) in by_digest.iter().filter(|(_, same)| same.len() > 1) {
+                println!("{} bytes, md5 {}:", size, md5::to_hex(digest));
+                for path in same {
+                    println!("  {}", path);
+                }
+                groups += 1;
+                copies += same.len() - 1;
+                wasted += size * (same.len() as u64 - 1);
+            }
+        }
+
+        if groups == 0 {
+            println!("dedup: no duplicate files under {}", dir);
+        } else {
+            println!(
+                "dedup: {} duplicate file(s) in {} group(s), {} bytes could be freed",
+                copies, groups, wasted
+            );
+        }
+        status
+    }
+
+    fn cmd_rx(&self, args: &[&str]) -> i32 {
+        let path = match args {
+            [path] => *path,
+            _ => {
+                println!("Usage: rx <file>");
+                return 2;
+            }
+        };
+        if crate::gdbstub::is_attached() {
+            println!("rx: COM2 is in use by gdb");
+            return 1;
+        }
+
+        println!("rx: waiting for an XMODEM sender on COM2...");
+        let result = crate::xmodem::receive()
+            .and_then(|data| filesystem::write_file(path, &data, false).map(|_| data.len()));
+        match result {
+            Ok(len) => {
+                println!("rx: received {} bytes into {}", len, path);
+                0
+            }
+            Err(e) => {
+                println!("rx: {}", e);
+                1
+            }
+        }
+    }
+
+    #[cfg(feature = "graphics")]
+    fn cmd_fbcon(&self, args: &[&str]) -> i32 {
+        use crate::fbcon;
+
+        let result = match args {
+            [] => {
+                let mode = if fbcon::is_active() { "on" } else { "off" };
+                println!("fbcon: {}, {} wide glyphs", mode, fbcon::glyph_count());
+                return 0;
+            }
+            ["on"] => fbcon::enable(),
+            ["off"] => fbcon::disable(),
+            ["load", path] => fbcon::load_font(path).map(|(loaded, dropped)| {
+                println!("fbcon: loaded {} wide glyphs from {}", loaded, path);
+                if dropped > 0 {
+                    println!("fbcon: {} more did not fit in the table", dropped);
+                }
+            }),
+            _ => {
+                println!("Usage: fbcon [on|off|load <file>]");
+                return 2;
+            }
+        };
+        match result {
+            Ok(()) => 0,
+            Err(e) => {
+                println!("fbcon: {}", e);
+                1
+            }
+        }
+    }
+
+    // パイプの入力は、つながったら最初に送る
+    #[cfg(feature = "net")]
+    fn cmd_nc(&mut self, args: &[&str]) -> i32 {
+        use crate::net::socket::Endpoint;
+        use crate::net::Ipv4Addr;
+
+        const USAGE: &str = "nc [-u] <host> <port> | nc -l [-u] <port>";
+        let mut udp = false;
+        let mut listen = false;
+        let mut operands = Vec::new();
+        for arg in args {
+            match *arg {
+                "-u" => udp = true,
+                "-l" => listen = true,
+                "-lu" | "-ul" => {
+                    udp = true;
+                    listen = true;
+                }
+                _ => operands.push(*arg),
+            }
+        }
+        let parse_port = |text: &str| text.parse::<u16>().ok().filter(|port| *port != 0);
+        let (remote, port) = match (listen, operands.as_slice()) {
+            (false, [host, port]) => {
+                let address = match Ipv4Addr::parse(host) {
+                    Some(address) => address,
+                    None => return failure("nc", "host must be an IPv4 address or localhost"),
+                };
+                match parse_port(port) {
+                    Some(port) => (Some(Endpoint::new(address, port)), port),
+                    None => return usage(USAGE),
+                }
+            }
+            (true, [port]) => match parse_port(port) {
+                Some(port) => (None, port),
+                None => return usage(USAGE),
+            },
+            _ => return usage(USAGE),
+        };
+        if crate::vga_buffer::is_capturing() {
+            return failure("nc", "needs the screen");
+        }
+        match crate::nc::start(udp, remote, port, self.stdin.take()) {
+            Ok(()) => 0,
+            Err(e) => failure("nc", e),
+        }
+    }
+
+    #[cfg(feature = "net")]
+    fn cmd_netstat(&self, args: &[&str]) -> i32 {
+        use crate::net::{self, socket};
+
+        match args {
+            [] => {
+                println!(
+                    "{:<5} {:>6} {:>6} {:<21} {:<21} State",
+                    "Proto", "Recv-Q", "Send-Q", "Local Address", "Foreign Address"
+                );
+                for info in socket::list() {
+                    let remote = match info.remote {
+                        Some(remote) => format!("{}", remote),
+                        None => String::from("0.0.0.0:*"),
+                    };
+                    println!(
+                        "{:<5} {:>6} {:>6} {:<21} {:<21} {}",
+                        info.protocol,
+                        info.receive_queue,
+                        info.send_queue,
+                        format!("{}", info.local),
+                        remote,
+                        info.state
+                    );
+                }
+                0
+            }
+            ["-i"] => {
+                println!(
+                    "{:<6} {:<18} {:<17} {:>8} {:>10} {:>8} {:>10} {:>6}",
+                    "Iface", "Address", "MAC", "RX-OK", "RX-Bytes", "TX-OK", "TX-Bytes", "Drop"
+                );
+                for interface in net::interfaces() {
+                    let mac = match interface.mac {
+                        Some(mac) => mac
+                            .iter()
+                            .map(|byte| format!("{:02x}", byte))
+                            .collect::<Vec<_>>()
+                            .join(":"),
+                        None => String::from("-"),
+                    };
+                    let counters = &interface.counters;
+                    println!(
+                        "{:<6} {:<18} {:<17} {:>8} {:>10} {:>8} {:>10} {:>6}",
+                        interface.name,
+                        format!("{}/{}", interface.address, interface.prefix),
+                        mac,
+                        counters.rx_packets,
+                        counters.rx_bytes,
+                        counters.tx_packets,
+                        counters.tx_bytes,
+                        counters.dropped
+                    );
+                }
+                0
+            }
+            _ => usage("netstat [-i]"),
+        }
+    }
+
+    #[cfg(feature = "net")]
+    fn cmd_pcap(&self, args: &[&str]) -> i32 {
+        use crate::net::pcap;
+
+        const USAGE: &str = "pcap [start <file> [max-bytes] | stop]";
+        match args {
+            [] => {
+                match pcap::status() {
+                    Some(status) => println!(
+                        "pcap: capturing to {}, {} packets, {} of {} bytes, {} dropped",
+                        status.path, status.packets, status.size, status.limit, status.dropped
+                    ),
+                    None => println!("pcap: not capturing"),
+                }
+                0
+            }
+            ["start", path] | ["start", path, _] => {
+                let limit = match args.get(2) {
+                    Some(limit) => match limit.parse::<u64>() {
+                        Ok(limit) => limit,
+                        Err(_) => return usage(USAGE),
+                    },
+                    None => pcap::DEFAULT_LIMIT,
+                };
+                match pcap::start(path, limit) {
+                    Ok(()) => 0,
+                    Err(e) => failure("pcap", e),
+                }
+            }
+            ["stop"] => match pcap::stop() {
+                Ok(status) => {
+                    println!(
+                        "pcap: {} packets ({} bytes) written to {}",
+                        status.packets, status.size, status.path
+                    );
+                    if status.dropped > 0 {
+                        println!(
+                            "pcap: {} packets dropped, the file reached {} bytes",
+                            status.dropped, status.limit
+                        );
+                    }
+                    0
+                }
+                Err(e) => failure("pcap", e),
+            },
+            _ => usage(USAGE),
+        }
+    }
+
+    #[cfg(feature = "tests")]
+    fn cmd_runtest(&mut self, script: &str, expected: Option<&str>) -> i32 {
+        let expected_path = match expected {
+            Some(path) => String::from(path),
+            None => format!("{}.expected", script),
+        };
+
+        let script_content = match filesystem::read_file(script) {
+            Ok(content) => content,
+            Err(e) => {
+                println!("runtest: {}: {}", script, e);
+                return 1;
+            }
+        };
+        let expected_content = match filesystem::read_file(&expected_path) {
+            Ok(content) => content,
+            Err(e) => {
+                println!("runtest: {}: {}", expected_path, e);
+                return 1;
+            }
+        };
+
+        let script_text = String::from_utf8_lossy(&script_content).into_owned();
+        let expected_text = String::from_utf8_lossy(&expected_content).into_owned();
+
+        crate::vga_buffer::start_capture();
+        self.run_line(&script_text);
+        let actual_text = crate::vga_buffer::end_capture();
+
+        let mut actual_lines = actual_text.lines();
+        let mut expected_lines = expected_text.lines();
+        let mut line_number = 1;
+        loop {
+            match (actual_lines.next(), expected_lines.next()) {
+                (None, None) => {
+                    println!("PASS: {}", script);
+                    return 0;
+                }
+                (actual, expected) if actual == expected => line_number += 1,
+                (actual, expected) => {
+                    println!("FAIL: {} (line {})", script, line_number);
+                    println!("  expected: {}", expected.unwrap_or("<end of output>"));
+                    println!("  actual:   {}", actual.unwrap_or("<end of output>"));
+                    return 1;
+                }
+            }
+        }
+    }
+
+    // 直した候補で移動するかを聞いているときも、この cd 自体は失敗として返す
+    fn cmd_cd(&mut self, dir_name: &str) -> i32 {
+        let e = match filesystem::change_directory(dir_name) {
+            Ok(()) => return 0,
+            Err(e) => e,
+        };
+        match Self::correct_directory(dir_name) {
+            // スクリプトの中では聞き返せないので候補だけ出す
+            Some(correction) if crate::vga_buffer::is_capturing() => {
+                println!("cd: {} (did you mean '{}'?)", e, correction)
+            }
+            Some(correction) => {
+                print!("cd: {}. Did you mean '{}'? [y/N] ", e, correction);
+                self.pending_cd = Some(correction);
+            }
+            None => println!("cd: {}", e),
+        }
+        1
+    }
+
+    // 最後の要素だけを、親ディレクトリにあるディレクトリ名から直す
+    fn correct_directory(path: &str) -> Option<String> {
+        let trimmed = path.trim_end_matches('/');
+        let (parent, name) = match trimmed.rsplit_once('/') {
+            Some(("", name)) => (Some("/"), name),
+            Some((parent, name)) => (Some(parent), name),
+            None => (None, trimmed),
+        };
+        let entries = filesystem::list_path(parent.unwrap_or(".")).ok()?;
+        let dirs = entries
+            .iter()
+            .filter(|(_, is_dir)| *is_dir)
+            .map(|(name, _)| name.as_str());
+        let found = suggest::closest(name, dirs)?;
+        Some(match parent {
+            Some("/") => format!("/{}", found),
+            Some(parent) => format!("{}/{}", parent, found),
+            None => String::from(found),
+        })
+    }
+
+    fn cmd_time(&self) {
+        let seconds_of_day = crate::time::now() % 86400;
+        let hours = (seconds_of_day / 3600) as i16;
+        let minutes = seconds_of_day / 60 % 60;
+        let seconds = seconds_of_day % 60;
+        let hours = (hours + self.timezone_offset as i16).rem_euclid(24);
+
+        println!(
+            "Current time (UTC{:+}): {:02}:{:02}:{:02}",
+            self.timezone_offset, hours, minutes, seconds
+        );
+    }
+
+    fn cmd_cal(&self, args: &[&str]) -> i32 {
+        let today = crate::time::local_date(crate::time::now(), self.timezone_offset);
+        let parse = |value: &str, range: core::ops::RangeInclusive<u64>| {
+            value
+                .parse::<u64>()
+                .ok()
+                .filter(|value| range.contains(value))
+        };
+        let (year, month) = match args {
+            [] => (Some(today.0), Some(today.1)),
+            [month] => (Some(today.0), parse(*month, 1..=12)),
+            [month, year] => (parse(*year, 1970..=9999), parse(*month, 1..=12)),
+            _ => (None, None),
+        };
+        match (year, month) {
+            (Some(year), Some(month)) => {
+                print!("{}", Self::render_month(year, month, today));
+                0
+            }
+            _ => usage("cal [month [year]] (1970-9999)"),
+        }
+    }
+
+    // 月のカレンダーを 7 列の表にする。今日は太字にする
+    fn render_month(year: u64, month: u64, today: (u64, u64, u64)) -> String {
+        const MONTHS: [&str; 12] = [
+            "January",
+            "February",
+            "March",
+            "April",
+            "May",
+            "June",
+            "July",
+            "August",
+            "September",
+            "October",
+            "November",
+            "December",
+        ];
+        let title = format!("{} {}", MONTHS[month as usize - 1], year);
+        let mut text = format!("{:^20}\nSu Mo Tu We Th Fr Sa\n", title);
+        text.push_str(&"   ".repeat(crate::time::weekday(year, month, 1) as usize));
+        for day in 1..=crate::time::days_in_month(year, month) {
+            let column = crate::time::weekday(year, month, day);
+            if (year, month, day) == today {
+                text.push_str(&format!("\x1b[1m{:>2}\x1b[0m", day));
+            } else {
+                text.push_str(&format!("{:>2}", day));
+            }
+            text.push(if column == 6 { '\n' } else { ' ' });
+        }
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        text
+    }
+
+    // パイプの入力があるときや出力をキャプチャしているときはその場で最後まで動かし、
+    // それ以外は画面とキーボードにつないでバックグラウンドで動かす
+    fn cmd_bf(&mut self, path: &str) -> i32 {
+        let source = match filesystem::read_file(path) {
+            Ok(source) => source,
+            Err(e) => {
+                println!("bf: {}: {}", path, e);
+                return 1;
+            }
+        };
+        if self.stdin.is_some() || crate::vga_buffer::is_capturing() {
+            return crate::bf::run_to_end(&source, self.stdin.take());
+        }
+        match crate::bf::start(&source) {
+            Ok(()) => 0,
+            Err(e) => {
+                println!("bf: {}", e);
+                2
+            }
+        }
+    }
+
+    fn cmd_play(&self, args: &[&str]) -> i32 {
+        let path = match args {
+            ["stop"] => {
+                crate::speaker::stop();
+                return 0;
+            }
+            [path] => path,
+            _ => {
+                println!("Usage: play <file> | play stop");
+                return 2;
+            }
+        };
+        let text = match filesystem::read_file(path) {
+            Ok(content) => String::from_utf8_lossy(&content).into_owned(),
+            Err(e) => {
+                println!("play: {}: {}", path, e);
+                return 1;
+            }
+        };
+        match crate::speaker::parse_melody(&text) {
+            Ok(notes) => {
+                crate::speaker::play(notes);
+                0
+            }
+            Err((line, e)) => {
+                println!("play: {}: line {}: {}", path, line, e);
+                1
+            }
+        }
+    }
+
+    fn cmd_timer(&self, args: &[&str]) -> i32 {
+        // ストップウォッチは 1/100 秒まで出す
+        let format_ticks = |ticks: u64| {
+            let hundredths = ticks % crate::time::TICK_HZ * 100 / crate::time::TICK_HZ;
+            format!(
+                "{}.{:02}",
+                crate::timer::format_seconds(ticks / crate::time::TICK_HZ),
+                hundredths
+            )
+        };
+        match args {
+            [] => match crate::timer::stopwatch_elapsed() {
+                Some(ticks) => {
+                    println!("stopwatch: {}", format_ticks(ticks));
+                    0
+                }
+                None => {
+                    println!("stopwatch is not running");
+                    0
+                }
+            },
+            ["start"] => match crate::timer::start_stopwatch() {
+                Ok(()) => 0,
+                Err(e) => failure("timer", e),
+            },
+            ["stop"] => match crate::timer::stop_stopwatch() {
+                Ok(ticks) => {
+                    println!("stopwatch: {}", format_ticks(ticks));
+                    0
+                }
+                Err(e) => failure("timer", e),
+            },
+            _ => usage("timer [start | stop]"),
+        }
+    }
+
+    fn cmd_countdown(&self, args: &[&str]) -> i32 {
+        match args {
+            [] => {
+                let now = crate::time::ticks();
+                for countdown in crate::timer::countdowns() {
+                    let left = countdown
+                        .deadline
+                        .saturating_sub(now)
+                        .div_ceil(crate::time::TICK_HZ);
+                    println!(
+                        "{:>3}  {} left{}",
+                        countdown.id,
+                        crate::timer::format_seconds(left),
+                        if countdown.beep { " (beep)" } else { "" }
+                    );
+                }
+                0
+            }
+            ["cancel", id] => match id.parse() {
+                Ok(id) if crate::timer::cancel_countdown(id) => 0,
+                _ => failure("countdown", &format!("{}: no such countdown", id)),
+            },
+            ["-b", duration] | [duration] => match parse_duration(duration) {
+                Some(ticks) if ticks > 0 => {
+                    let id = crate::timer::start_countdown(ticks, args[0] == "-b");
+                    println!("countdown {} started ({})", id, duration);
+                    0
+                }
+                _ => {
+                    println!("countdown: invalid duration '{}'", duration);
+                    2
+                }
+            },
+            _ => usage("countdown [-b] <duration> | countdown cancel <id>"),
+        }
+    }
+
+    fn cmd_config(&mut self, args: &[&str]) -> i32 {
+        match args {
+            [] => {
+                for (key, value) in config::entries() {
+                    println!("{}={}", key, value);
+                }
+                0
+            }
+            ["get", key] => match config::get(key) {
+                Some(value) => {
+                    println!("{}", value);
+                    0
+                }
+                None => failure("config", &format!("{}: not set", key)),
+            },
+            ["set", key, value @ ..] if !value.is_empty() => {
+                let value = value.join(" ");
+                match config::set(key, &value) {
+                    Ok(()) => {
+                        self.apply_config();
+                        0
+                    }
+                    Err(e) => {
+                        println!("config: {}: {}", key, e);
+                        1
+                    }
+                }
+            }
+            _ => usage("config [get <key> | set <key> <value>]"),
+        }
+    }
+
+    fn cmd_vmmap(&self, args: &[&str]) -> i32 {
+        use crate::arch::x86::memory;
+        use x86_64::structures::paging::PageTableFlags;
+
+        let ranges = match args {
+            [] => memory::mapped_ranges(),
+            // 書き込めて実行もできる範囲だけを出す。あれば失敗にする
+            ["-c"] => {
+                let violations = memory::wx_violations();
+                if violations.is_empty() {
+                    println!("W^X: no writable and executable mappings");
+                    return 0;
+                }
+                violations
+            }
+            // ret だけの関数をヒープに置いて呼ぶ。NX が効いていればページフォルトでコマンドが止まる
+            ["-x"] => {
+                if !self.unsafe_mode {
+                    println!("vmmap: refusing without 'unsafe on'");
+                    return 1;
+                }
+                let code = alloc::boxed::Box::new(0xc3u8);
+                let function: extern "C" fn() =
+                    unsafe { core::mem::transmute(&*code as *const u8) };
+                function();
+                println!(
+                    "vmmap: executed code on the heap at {:p}: NX is not enforced",
+                    code
+                );
+                return 1;
+            }
+            _ => return usage("vmmap [-c | -x]"),
+        };
+        println!(
+            "{:<16}  {:<16}  {:>10}  {:<12} flags",
+            "start", "end", "size", "phys"
+        );
+        for range in ranges.iter() {
+            let flag = |set: bool, c: char| if set { c } else { '-' };
+            println!(
+                "{:016x}  {:016x}  {:>9}K  {:012x} r{}{}{}{}",
+                range.start,
+                range.end(),
+                range.size / 1024,
+                range.phys.as_u64(),
+                flag(range.flags.contains(PageTableFlags::WRITABLE), 'w'),
+                flag(!range.flags.contains(PageTableFlags::NO_EXECUTE), 'x'),
+                flag(range.flags.contains(PageTableFlags::USER_ACCESSIBLE), 'u'),
+                flag(range.flags.contains(PageTableFlags::HUGE_PAGE), 'h'),
+            );
+        }
+        println!("{} ranges", ranges.len());
+        if args.is_empty() {
+            0
+        } else {
+            1
+        }
+    }
+
+    fn parse_peek_target<'a>(args: &'a [&'a str]) -> Option<(peek::Target, &'a [&'a str])> {
+        match args {
+            ["-p", addr, rest @ ..] => {
+                Some((peek::Target::Physical(peek::parse_number(addr)?), rest))
+            }
+            ["-io", port, rest @ ..] => {
+                let port = u16::try_from(peek::parse_number(port)?).ok()?;
+                Some((peek::Target::Port(port), rest))
+            }
+            [addr, rest @ ..] => Some((peek::Target::Virtual(peek::parse_number(addr)?), rest)),
+            [] => None,
+        }
+    }
+
+    fn check_unsafe(&self, command: &str, target: peek::Target, write: bool) -> bool {
+        if target.needs_unsafe(write) && !self.unsafe_mode {
+            println!("{}: refusing without 'unsafe on'", command);
+            return false;
+        }
+        true
+    }
+
+    fn cmd_ioports(&self) {
+        for range in crate::hal::port::claims() {
+            println!("{:<16} {}", format!("{}", range.resource()), range.owner());
+        }
+    }
+
+    fn cmd_service(&self, args: &[&str]) -> i32 {
+        use crate::service::{self, State};
+        match args {
+            [] => {
+                for entry in service::all() {
+                    let (state, restarts) = service::state(entry.name);
+                    let state = match state {
+                        State::Stopped => String::from("stopped"),
+                        State::Running => String::from("running"),
+                        State::Restarting { .. } => String::from("restarting"),
+                        State::Failed(e) => format!("failed: {}", e),
+                    };
+                    println!(
+                        "{:<12} {:<20} {:>3}  {}",
+                        entry.name, state, restarts, entry.description
+                    );
+                }
+                0
+            }
+            ["start", name] => match service::start(name) {
+                Ok(()) => {
+                    println!("service: started {}", name);
+                    0
+                }
+                Err(e) => {
+                    println!("service: {}: {}", name, e);
+                    1
+                }
+            },
+            _ => usage("service [start <name>]"),
+        }
+    }
+
+    fn cmd_peek(&self, args: &[&str]) -> i32 {
+        let (target, width) = match Self::parse_peek_target(args) {
+            Some((target, [])) => (target, peek::Width::Byte),
+            Some((target, [width])) => match peek::Width::from_name(width) {
+                Some(width) => (target, width),
+                None => return failure("peek", &format!("unknown width '{}'", width)),
+            },
+            _ => return usage("peek [-p|-io] <addr> [b|w|d|q]"),
+        };
+        if !self.check_unsafe("peek", target, false) {
+            return 1;
+        }
+
+        match peek::read(target, width) {
+            Ok(value) => {
+                println!(
+                    "{:#0width$x}",
+                    value,
+                    width = 2 + 2 * width.bytes() as usize
+                );
+                0
+            }
+            Err(e) => failure("peek", e),
+        }
+    }
+
+    fn cmd_poke(&self, args: &[&str]) -> i32 {
+        let (target, value, width) = match Self::parse_peek_target(args) {
+            Some((target, [value, rest @ ..])) if rest.len() <= 1 => {
+                let value = match peek::parse_number(value) {
+                    Some(value) => value,
+                    None => return failure("poke", &format!("invalid value '{}'", value)),
+                };
+                match rest.first().map(|w| peek::Width::from_name(w)) {
+                    None => (target, value, peek::Width::Byte),
+                    Some(Some(width)) => (target, value, width),
+                    Some(None) => return failure("poke", &format!("unknown width '{}'", rest[0])),
+                }
+            }
+            _ => return usage("poke [-p|-io] <addr> <value> [b|w|d|q]"),
+        };
+        if !self.check_unsafe("poke", target, true) {
+            return 1;
+        }
+
+        match peek::write(target, width, value) {
+            Ok(()) => 0,
+            Err(e) => failure("poke", e),
+        }
+    }
+
+    fn cmd_pwd(&self) {
+        print!("{}", self.current_dir_str());
+    }
+
+    pub fn history_up(&mut self) {
+        if !self.command_history.is_empty() && self.history_index < self.command_history.len() {
+            self.history_index += 1;
+            let index = self.command_history.len() - self.history_index;
+            self.input_buffer = self.command_history[index].clone();
+            self.cursor_position = self.input_buffer.len();
+            self.redraw_line();
+        }
+    }
+
+    pub fn history_down(&mut self) {
+        if self.history_index > 0 {
+            self.history_index -= 1;
+            if self.history_index == 0 {
+                self.input_buffer.clear();
+            } else {
+                let index = self.command_history.len() - self.history_index;
+                self.input_buffer = self.command_history[index].clone();
+            }
+            self.cursor_position = self.input_buffer.len();
+            self.redraw_line();
+        }
+    }
+
+    // カーソルの前の単語を補完する。先頭の単語ならコマンド名、それ以外はパス
+    pub fn handle_tab(&mut self) {
+        let before = &self.input_buffer[..self.cursor_position];
+        if before.trim().is_empty() {
+            println!();
+            self.cmd_help(&[]);
+            self.drawn_len = 0;
+            self.redraw_line();
+            return;
+        }
+
+        let word_start = before
+            .rfind(char::is_whitespace)
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        let word = String::from(&before[word_start..]);
+        let command_position = before[..word_start].trim().is_empty();
+        let candidates = self.get_completion_candidates(&word, command_position);
+        let common = common_prefix(&candidates);
+
+        match candidates.len() {
+            0 => (),
+            1 => {
+                // ディレクトリならそのまま中身の補完を続けられるように空白を足さない
+                let mut completion = String::from(&candidates[0][word.len()..]);
+                if !completion.ends_with('/') {
+                    completion.push(' ');
+                }
+                self.insert_text(&completion);
+            }
+            _ if common.len() > word.len() => self.insert_text(&common[word.len()..]),
+            _ => {
+                println!("\nPossible completions:");
+                for candidate in candidates {
+                    println!("{}", candidate);
+                }
+                self.drawn_len = 0;
+                self.redraw_line();
+            }
+        }
+    }
+
+    // word で始まる候補を、word を置き換える形で返す
+    fn get_completion_candidates(&self, word: &str, command_position: bool) -> Vec<String> {
+        if command_position {
+            let extensions = crate::kmod::list()
+                .into_iter()
+                .flat_map(|(_, _, commands)| commands);
+            let mut candidates: Vec<String> = commands::all()
+                .map(|command| String::from(command.name))
+                .chain(extensions)
+                .filter(|name| name.starts_with(word))
+                .collect();
+            candidates.sort();
+            candidates.dedup();
+            return candidates;
+        }
+
+        // 最後の / までをディレクトリ、その後ろを名前の先頭として扱う
+        let (dir, prefix) = match word.rfind('/') {
+            Some(i) => (&word[..=i], &word[i + 1..]),
+            None => ("", word),
+        };
+        let listing = filesystem::list_path(if dir.is_empty() { "." } else { dir });
+        listing
+            .unwrap_or_default()
+            .iter()
+            .filter(|(name, _)| name.starts_with(prefix))
+            .map(|(name, is_dir)| format!("{}{}{}", dir, name, if *is_dir { "/" } else { "" }))
+            .collect()
+    }
+
+    fn cmd_echo(&self, args: &[&str]) -> String {
+        format!("{}\n", args.join(" "))
+    }
+
+    fn cmd_help_str(&self) -> String {
+        let mut output = String::from("Available commands:\n");
+        for category in Category::ALL {
+            let mut entries = commands::all()
+                .filter(|command| command.category == category)
+                .peekable();
+            if entries.peek().is_none() {
+                continue;
+            }
+            output.push_str(&format!("{}:\n", category.name()));
+            for command in entries {
+                output.push_str(&format!("  {:<8} - {}\n", command.name, command.summary));
+            }
+        }
+        let extensions: Vec<String> = crate::kmod::list()
+            .into_iter()
+            .flat_map(|(_, _, commands)| commands)
+            .collect();
+        if !extensions.is_empty() {
+            output.push_str(&format!("Extensions:\n  {}\n", extensions.join(" ")));
+        }
+        output.push_str("Type 'help <command>' for usage and examples.\n");
+        output
+    }
+
+    fn current_dir_str(&self) -> String {
+        let current_path = filesystem::get_current_path();
+        if current_path.is_empty() {
+            "/\n".to_string()
+        } else {
+            format!("/{}\n", current_path.join("/"))
+        }
+    }
+}
+
+// mv と cp の行き先。dest が既存のディレクトリならその中の同じ名前、そうでなければ dest。
+// source がルートなら名前が無いので None
+fn destination(source: &str, dest: &str, into_directory: bool) -> Option<String> {
+    if !into_directory {
+        return Some(String::from(dest));
+    }
+    let name = filesystem::resolve_path(source).pop()?;
+    Some(format!("{}/{}", dest.trim_end_matches('/'), name))
+}
+
+// dd の (start + block) * block_size。大きな skip= や bs= で溢れるなら None
+fn block_offset(start: u64, block: u64, block_size: u64) -> Option<u64> {
+    start.checked_add(block)?.checked_mul(block_size)
+}
+
+// dd の 1 ブロック分の読み書き。disk 機能があればブロックデバイス (/dev/loop0) にも使える
+fn dd_read(path: &str, offset: u64, len: usize) -> Result<Vec<u8>, &'static str> {
+    #[cfg(feature = "disk")]
+    if let Some(device) = crate::block::device_for_path(path) {
+        return crate::block::read_at(device, offset, len);
+    }
+    filesystem::read_file_at(path, offset, len)
+}
+
+fn dd_write(path: &str, offset: u64, data: &[u8]) -> Result<(), &'static str> {
+    #[cfg(feature = "disk")]
+    if let Some(device) = crate::block::device_for_path(path) {
+        return crate::block::write_at(device, offset, data);
+    }
+    filesystem::write_file_at(path, offset, data)
+}
+
+// 候補すべてに共通する先頭部分
+fn common_prefix(candidates: &[String]) -> String {
+    let mut prefix = match candidates.first() {
+        Some(first) => first.clone(),
+        None => return String::new(),
+    };
+    for candidate in &candidates[1..] {
+        let len = prefix
+            .char_indices()
+            .zip(candidate.chars())
+            .take_while(|((_, a), b)| a == b)
+            .last()
+            .map(|((i, a), _)| i + a.len_utf8())
+            .unwrap_or(0);
+        prefix.truncate(len);
+    }
+    prefix
+}
+
+// "5"、"0.5"、"90s"、"5m"、"1.5h" のような長さをティック数にする。単位が無ければ秒
+fn parse_duration(text: &str) -> Option<u64> {
+    let (text, unit) = if let Some(text) = text.strip_suffix('h') {
+        (text, 3600 * crate::time::TICK_HZ)
+    } else if let Some(text) = text.strip_suffix('m') {
+        (text, 60 * crate::time::TICK_HZ)
+    } else {
+        (text.strip_suffix('s').unwrap_or(text), crate::time::TICK_HZ)
+    };
+    let (whole, fraction) = text.split_once('.').unwrap_or((text, ""));
+    if whole.is_empty() && fraction.is_empty() {
+        return None;
+    }
+    let whole: u64 = if whole.is_empty() {
+        0
+    } else {
+        whole.parse().ok()?
+    };
+    let mut ticks = whole.checked_mul(unit)?;
+    let mut scale = unit;
+    for c in fraction.chars() {
+        scale /= 10;
+        ticks = ticks.checked_add(c.to_digit(10)? as u64 * scale)?;
+    }
+    Some(ticks)
+}
+
+fn char_width(c: char) -> usize {
+    crate::vga_buffer::char_width(c)
+}
+
+fn display_width(text: &str) -> usize {
+    text.chars().map(char_width).sum()
+}
+
+// 文字列の並びが使っているヒープ (確保済みの容量) のバイト数
+fn strings_memory(strings: &Vec<String>) -> usize {
+    strings.capacity() * core::mem::size_of::<String>()
+        + strings.iter().map(String::capacity).sum::<usize>()
+}
+
+// 区切りやクォート、展開に使う文字を含まない名前だけを別名にできる
+fn is_alias_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| !c.is_whitespace() && !"'\"\\<>$`;|&()#*?=/".contains(c))
+}
+
+// 組み込みコマンドの終了ステータスは、使い方の誤りが 2、それ以外の失敗が 1
+fn usage(text: &str) -> i32 {
+    println!("Usage: {}", text);
+    2
+}
+
+fn failure(command: &str, error: &str) -> i32 {
+    println!("{}: {}", command, error);
+    1
+}
+
+// 単語を、もう一度 run_line に渡しても同じ単語に分かれるようにつなぐ
+fn join_quoted(words: &[&str]) -> String {
+    let quoted: Vec<String> = words.iter().map(|word| tokenize::quote(word)).collect();
+    quoted.join(" ")
+}
+
+fn has_open_quote(line: &str) -> bool {
+    let mut quote = None;
+    let mut escaped = false;
+    for c in line.chars() {
+        match (quote, c) {
+            _ if escaped => escaped = false,
+            (None, '\\') | (Some('"'), '\\') => escaped = true,
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (Some(open), c) if c == open => quote = None,
+            _ => {}
+        }
+    }
+    quote.is_some()
+}
@@ -0,0 +1,252 @@
+// シェルスクリプトの構文。; と改行でコマンドを区切り、if と for を組み立てる。
+// コマンド自体は文字列のまま持ち、展開は実行するときに行う
+//
+//   if <コマンド>; then ...; [elif <コマンド>; then ...;] [else ...;] fi
+//   for <名前> in <単語>...; do ...; done
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+pub enum Statement {
+    Command(String),
+    If {
+        condition: Vec<Statement>,
+        then: Vec<Statement>,
+        otherwise: Vec<Statement>,
+    },
+    For {
+        variable: String,
+        // 実行するときに展開してから空白で区切る
+        words: String,
+        body: Vec<Statement>,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+    // 続きの行があれば完成する (fi や done がまだ無いなど)
+    Incomplete,
+    Syntax(&'static str),
+}
+
+const KEYWORDS: &[&str] = &["then", "elif", "else", "fi", "do", "done"];
+
+// クォートと $( ) の外にある ; と改行で区切る。# から行末まではコメント
+fn split(source: &str) -> Result<VecDeque<String>, ParseError> {
+    let mut segments = VecDeque::new();
+    let mut current = String::new();
+    let mut quote = None;
+    let mut escaped = false;
+    let mut depth: usize = 0;
+    let mut comment = false;
+    for c in source.chars() {
+        if comment {
+            if c == '\n' {
+                comment = false;
+                segments.push_back(core::mem::take(&mut current));
+            }
+            continue;
+        }
+        match (quote, c) {
+            _ if escaped => escaped = false,
+            (None, '\\') | (Some('"'), '\\') => escaped = true,
+            (Some(open), c) if c == open => quote = None,
+            (Some(_), _) => {}
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => depth = depth.saturating_sub(1),
+            (None, ';') | (None, '\n') if depth == 0 => {
+                segments.push_back(core::mem::take(&mut current));
+                continue;
+            }
+            (None, '#') if current.is_empty() || current.ends_with(char::is_whitespace) => {
+                comment = true;
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    if quote.is_some() || depth > 0 || escaped {
+        return Err(ParseError::Incomplete);
+    }
+    segments.push_back(current);
+    Ok(segments
+        .into_iter()
+        .map(|segment| String::from(segment.trim()))
+        .filter(|segment| !segment.is_empty())
+        .collect())
+}
+
+// && と || のリストで、コマンドの前にある区切り
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Connector {
+    // 前のコマンドが成功したときだけ実行する (&&)
+    And,
+    // 前のコマンドが失敗したときだけ実行する (||)
+    Or,
+}
+
+// クォートと $( ) の外にある && と || で区切る。先頭の要素は And として返すので、
+// 状態 0 から始めれば必ず実行される
+pub fn split_and_or(command: &str) -> Vec<(Connector, &str)> {
+    let mut list = Vec::new();
+    let mut connector = Connector::And;
+    let mut start = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    let mut depth: usize = 0;
+    let mut chars = command.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        match (quote, c) {
+            _ if escaped => escaped = false,
+            (None, '\\') | (Some('"'), '\\') => escaped = true,
+            (Some(open), c) if c == open => quote = None,
+            (Some(_), _) => {}
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => depth = depth.saturating_sub(1),
+            (None, '&') | (None, '|') if depth == 0 => {
+                if chars.peek().map(|&(_, next)| next) == Some(c) {
+                    chars.next();
+                    list.push((connector, command[start..index].trim()));
+                    connector = if c == '&' {
+                        Connector::And
+                    } else {
+                        Connector::Or
+                    };
+                    start = index + 2;
+                }
+            }
+            _ => {}
+        }
+    }
+    list.push((connector, command[start..].trim()));
+    list
+}
+
+// クォートと $( ) の外にある | でパイプラインの段に分ける。|| は区切りにしない
+pub fn split_pipeline(command: &str) -> Vec<&str> {
+    let mut stages = Vec::new();
+    let mut start = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    let mut depth: usize = 0;
+    let mut chars = command.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        match (quote, c) {
+            _ if escaped => escaped = false,
+            (None, '\\') | (Some('"'), '\\') => escaped = true,
+            (Some(open), c) if c == open => quote = None,
+            (Some(_), _) => {}
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => depth = depth.saturating_sub(1),
+            (None, '|') if depth == 0 => {
+                if chars.peek().map(|&(_, next)| next) == Some('|') {
+                    chars.next();
+                } else {
+                    stages.push(command[start..index].trim());
+                    start = index + 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    stages.push(command[start..].trim());
+    stages
+}
+
+fn split_first_word(segment: &str) -> (&str, &str) {
+    match segment.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim_start()),
+        None => (segment, ""),
+    }
+}
+
+fn is_name(word: &str) -> bool {
+    let mut chars = word.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// terminators のどれかが来るまで読む。来たキーワードを返す (最上位では None)
+fn parse_list(
+    queue: &mut VecDeque<String>,
+    terminators: &[&'static str],
+) -> Result<(Vec<Statement>, Option<&'static str>), ParseError> {
+    let mut statements = Vec::new();
+    while let Some(segment) = queue.pop_front() {
+        let (word, rest) = split_first_word(&segment);
+        if let Some(&terminator) = terminators.iter().find(|&&t| t == word) {
+            if !rest.is_empty() {
+                queue.push_front(String::from(rest));
+            }
+            return Ok((statements, Some(terminator)));
+        }
+        match word {
+            "if" => {
+                if !rest.is_empty() {
+                    queue.push_front(String::from(rest));
+                }
+                statements.push(parse_if(queue)?);
+            }
+            "for" => statements.push(parse_for(rest, queue)?),
+            word if KEYWORDS.contains(&word) => {
+                return Err(ParseError::Syntax("unexpected keyword"));
+            }
+            _ => statements.push(Statement::Command(segment)),
+        }
+    }
+    if terminators.is_empty() {
+        Ok((statements, None))
+    } else {
+        Err(ParseError::Incomplete)
+    }
+}
+
+// if (または elif) の後ろから対応する fi までを読む
+fn parse_if(queue: &mut VecDeque<String>) -> Result<Statement, ParseError> {
+    let (condition, _) = parse_list(queue, &["then"])?;
+    if condition.is_empty() {
+        return Err(ParseError::Syntax("if: missing condition"));
+    }
+    let (then, terminator) = parse_list(queue, &["elif", "else", "fi"])?;
+    let otherwise = match terminator {
+        Some("elif") => alloc::vec![parse_if(queue)?],
+        Some("else") => parse_list(queue, &["fi"])?.0,
+        _ => Vec::new(),
+    };
+    Ok(Statement::If {
+        condition,
+        then,
+        otherwise,
+    })
+}
+
+fn parse_for(header: &str, queue: &mut VecDeque<String>) -> Result<Statement, ParseError> {
+    let (variable, rest) = split_first_word(header);
+    if !is_name(variable) {
+        return Err(ParseError::Syntax("for: invalid variable name"));
+    }
+    let (keyword, words) = split_first_word(rest);
+    if keyword != "in" {
+        return Err(ParseError::Syntax("for: expected 'in'"));
+    }
+    let (before_do, _) = parse_list(queue, &["do"])?;
+    if !before_do.is_empty() {
+        return Err(ParseError::Syntax("for: expected 'do'"));
+    }
+    let (body, _) = parse_list(queue, &["done"])?;
+    Ok(Statement::For {
+        variable: String::from(variable),
+        words: String::from(words),
+        body,
+    })
+}
+
+pub fn parse(source: &str) -> Result<Vec<Statement>, ParseError> {
+    let mut queue = split(source)?;
+    let (statements, _) = parse_list(&mut queue, &[])?;
+    Ok(statements)
+}
@@ -0,0 +1,43 @@
+// 打ち間違いの候補探し。大文字と小文字の違いと、1 文字の置換・挿入・削除・
+// 隣り合う文字の入れ替えを「近い」とみなす
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+// 隣り合う文字の入れ替えも 1 と数える編集距離
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    // 2 行前まで持っておけば入れ替えを数えられる
+    let mut before: Vec<usize> = Vec::new();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        let mut current = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let cost = (a[i - 1] != b[j - 1]) as usize;
+            current[j] = (previous[j] + 1)
+                .min(current[j - 1] + 1)
+                .min(previous[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                current[j] = current[j].min(before[j - 2] + 1);
+            }
+        }
+        before = core::mem::replace(&mut previous, current);
+    }
+    previous[b.len()]
+}
+
+// candidates の中で name に最も近いもの。name と同じものや遠いものしか無ければ None
+pub fn closest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let name_lower = name.to_lowercase();
+    candidates
+        .into_iter()
+        .filter(|&candidate| candidate != name)
+        .map(|candidate| {
+            let lower: String = candidate.to_lowercase();
+            (edit_distance(&name_lower, &lower), candidate)
+        })
+        .filter(|&(distance, _)| distance <= 1)
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, candidate)| candidate)
+}
@@ -0,0 +1,162 @@
+// コマンドラインを単語に分ける。空白で区切り、クォートとバックスラッシュを解く
+//
+//   '...'  中は全部そのまま
+//   "..."  中の \" \\ \$ \` だけがエスケープになる
+//   \c     クォートの外では次の 1 文字をそのまま
+//
+// クォートの外の > と >> はリダイレクトの記号として別に返す。クォートの外に * か ? が
+// ある単語は Glob として返し、クォートの中の文字は \ を付けてパターンに入れる。
+// 展開 (expand) のあとで使う。expand は変数や $(...) の値をクォートして入れるので、
+// 値の中のクォートや > がここで記号として読まれることはない
+use super::glob;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Token {
+    Word(String),
+    // ">" か ">>"
+    Redirect(&'static str),
+    // glob::expand に渡すパターン
+    Glob(String),
+}
+
+// 作りかけの単語。ワイルドカードが出てくるまでは text だけを使う
+#[derive(Default)]
+struct Word {
+    text: String,
+    pattern: String,
+    glob: bool,
+    // "" のような空の単語も 1 つと数える
+    started: bool,
+}
+
+impl Word {
+    fn literal(&mut self, c: char) {
+        self.started = true;
+        self.text.push(c);
+        if glob::is_special(c) {
+            self.pattern.push('\\');
+        }
+        self.pattern.push(c);
+    }
+
+    fn wildcard(&mut self, c: char) {
+        self.started = true;
+        self.glob = true;
+        self.text.push(c);
+        self.pattern.push(c);
+    }
+
+    fn finish(&mut self, tokens: &mut Vec<Token>) {
+        if !self.started {
+            return;
+        }
+        let word = core::mem::take(self);
+        tokens.push(if word.glob {
+            Token::Glob(word.pattern)
+        } else {
+            Token::Word(word.text)
+        });
+    }
+}
+
+pub fn tokenize(line: &str) -> Result<Vec<Token>, &'static str> {
+    let mut tokens = Vec::new();
+    let mut word = Word::default();
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                word.started = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.literal(c),
+                        None => return Err("unterminated quote"),
+                    }
+                }
+            }
+            '"' => {
+                word.started = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => word.literal(c),
+                            Some(c) => {
+                                word.literal('\\');
+                                word.literal(c);
+                            }
+                            None => return Err("unterminated quote"),
+                        },
+                        Some(c) => word.literal(c),
+                        None => return Err("unterminated quote"),
+                    }
+                }
+            }
+            // 行末の \ はそのまま残す
+            '\\' => word.literal(chars.next().unwrap_or('\\')),
+            '>' => {
+                word.finish(&mut tokens);
+                if chars.next_if_eq(&'>').is_some() {
+                    tokens.push(Token::Redirect(">>"));
+                } else {
+                    tokens.push(Token::Redirect(">"));
+                }
+            }
+            c if c.is_whitespace() => word.finish(&mut tokens),
+            '*' | '?' => word.wildcard(c),
+            c => word.literal(c),
+        }
+    }
+    word.finish(&mut tokens);
+    Ok(tokens)
+}
+
+// 単語だけの行を分ける。リダイレクトの記号も普通の単語として扱い、ワイルドカードは展開する
+pub fn split_words(line: &str) -> Result<Vec<String>, &'static str> {
+    let mut words = Vec::new();
+    for token in tokenize(line)? {
+        match token {
+            Token::Word(word) => words.push(word),
+            Token::Redirect(operator) => words.push(String::from(operator)),
+            Token::Glob(pattern) => words.extend(glob::expand(&pattern)),
+        }
+    }
+    Ok(words)
+}
+
+// tokenize に通すと word に戻るように、必要ならシングルクォートで囲む
+pub fn quote(word: &str) -> String {
+    let plain = !word.is_empty()
+        && word
+            .chars()
+            .all(|c| !c.is_whitespace() && !"'\"\\>$`;|&()#*?".contains(c));
+    if plain {
+        return String::from(word);
+    }
+    let mut quoted = String::from("'");
+    for c in word.chars() {
+        if c == '\'' {
+            // ' はクォートを閉じてから \' で入れる
+            quoted.push_str("'\\''");
+        } else {
+            quoted.push(c);
+        }
+    }
+    quoted.push('\'');
+    quoted
+}
+
+// "..." の中に入れても text のまま読まれるように、" \ $ ` の前に \ を付ける
+pub fn quote_in_double_quotes(text: &str) -> String {
+    let mut quoted = String::new();
+    for c in text.chars() {
+        if matches!(c, '"' | '\\' | '$' | '`') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted
+}
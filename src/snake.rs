@@ -0,0 +1,222 @@
+// テキスト画面のスネークゲーム。盤面は 2 行目から下の画面全体で、端にぶつかるか
+// 自分の体に当たると終わり。top と同じくタスクで動かし、キー入力はシェルから
+// handle_key に回してもらう。画面には vga_buffer::put_char で直接描く
+use crate::rand::XorShift64;
+use crate::task::{self, TaskState};
+use crate::time::{self, TICK_HZ};
+use crate::vga_buffer::{self, Color, BUFFER_HEIGHT, BUFFER_WIDTH};
+use alloc::collections::VecDeque;
+use alloc::format;
+use pc_keyboard::{DecodedKey, KeyCode};
+use spin::Mutex;
+
+// 1 マス進む間隔 (ティック)
+const STEP_TICKS: u64 = TICK_HZ / 8;
+// 1 行目はスコアと時計に使う。スコアは右側の時計やタイマーの表示より左に出す
+const TOP_ROW: usize = 1;
+const STATUS_COLUMNS: usize = 60;
+const INITIAL_LENGTH: usize = 4;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl Direction {
+    fn opposite(self) -> Direction {
+        match self {
+            Direction::Up => Direction::Down,
+            Direction::Down => Direction::Up,
+            Direction::Left => Direction::Right,
+            Direction::Right => Direction::Left,
+        }
+    }
+}
+
+struct Game {
+    // 先頭が頭。(行, 列)
+    body: VecDeque<(usize, usize)>,
+    direction: Direction,
+    // 次に進むときの向き。1 ステップの間に何度押されても最後のものだけを使う
+    next_direction: Direction,
+    food: (usize, usize),
+    score: u32,
+    over: bool,
+    rng: XorShift64,
+}
+
+impl Game {
+    fn new() -> Game {
+        let row = (TOP_ROW + BUFFER_HEIGHT) / 2;
+        let body = (0..INITIAL_LENGTH)
+            .map(|i| (row, BUFFER_WIDTH / 2 - i))
+            .collect();
+        let mut game = Game {
+            body,
+            direction: Direction::Right,
+            next_direction: Direction::Right,
+            food: (0, 0),
+            score: 0,
+            over: false,
+            rng: XorShift64::from_tsc(),
+        };
+        game.food = game.place_food();
+        game
+    }
+
+    fn place_food(&mut self) -> (usize, usize) {
+        loop {
+            let cell = (
+                TOP_ROW + self.rng.below(BUFFER_HEIGHT - TOP_ROW),
+                self.rng.below(BUFFER_WIDTH),
+            );
+            if !self.body.contains(&cell) {
+                return cell;
+            }
+        }
+    }
+
+    // 1 マス進める。ぶつかったら over にする
+    fn advance(&mut self) {
+        self.direction = self.next_direction;
+        let (row, col) = self.body[0];
+        let next = match self.direction {
+            Direction::Up if row > TOP_ROW => (row - 1, col),
+            Direction::Down if row + 1 < BUFFER_HEIGHT => (row + 1, col),
+            Direction::Left if col > 0 => (row, col - 1),
+            Direction::Right if col + 1 < BUFFER_WIDTH => (row, col + 1),
+            _ => {
+                self.over = true;
+                return;
+            }
+        };
+
+        // 尻尾は同時に動くので、餌を食べないときは今の尻尾の場所に入れる
+        let eating = next == self.food;
+        let keep = self.body.len() - if eating { 0 } else { 1 };
+        if self.body.iter().take(keep).any(|&cell| cell == next) {
+            self.over = true;
+            return;
+        }
+
+        if !eating {
+            if let Some(tail) = self.body.pop_back() {
+                draw_cell(tail, b' ', Color::Black);
+            }
+        }
+        self.body.push_front(next);
+        if eating {
+            self.score += 1;
+            self.food = self.place_food();
+            draw_cell(self.food, b'*', Color::LightRed);
+        }
+        draw_cell(self.body[1], b'o', Color::LightGreen);
+        draw_cell(next, b'@', Color::LightGreen);
+        draw_score(self.score);
+    }
+
+    fn draw(&self) {
+        for (index, &cell) in self.body.iter().enumerate() {
+            draw_cell(
+                cell,
+                if index == 0 { b'@' } else { b'o' },
+                Color::LightGreen,
+            );
+        }
+        draw_cell(self.food, b'*', Color::LightRed);
+    }
+}
+
+static GAME: Mutex<Option<Game>> = Mutex::new(None);
+
+fn draw_cell((row, col): (usize, usize), byte: u8, color: Color) {
+    vga_buffer::put_char(row, col, byte, color, Color::Black);
+}
+
+// 1 行目の左側に出す。右側の時計には触らない
+fn draw_status(text: &str) {
+    for col in 0..STATUS_COLUMNS {
+        let byte = text.as_bytes().get(col).copied().unwrap_or(b' ');
+        vga_buffer::put_char(0, col, byte, Color::Black, Color::LightGray);
+    }
+}
+
+fn draw_score(score: u32) {
+    draw_status(&format!(
+        " Snake  score: {}  (arrows/WASD to move, q to quit)",
+        score
+    ));
+}
+
+pub fn is_running() -> bool {
+    GAME.lock().is_some()
+}
+
+pub fn start() {
+    let game = Game::new();
+    crate::arch::without_interrupts(|| vga_buffer::WRITER.lock().clear_screen());
+    game.draw();
+    draw_score(0);
+    *GAME.lock() = Some(game);
+
+    let mut next_step = time::ticks() + STEP_TICKS;
+    task::spawn("snake", move || {
+        if time::ticks() < next_step {
+            return TaskState::Sleep(next_step);
+        }
+        next_step = time::ticks() + STEP_TICKS;
+        let mut guard = GAME.lock();
+        let game = match guard.as_mut() {
+            Some(game) => game,
+            None => return TaskState::Done,
+        };
+        game.advance();
+        if game.over {
+            draw_status(&format!(
+                " Game over - score: {}  (press any key)",
+                game.score
+            ));
+            return TaskState::Done;
+        }
+        TaskState::Sleep(next_step)
+    });
+}
+
+// ゲーム中のキー入力。終わって画面を戻したら true を返すので、シェルがプロンプトを出す
+pub fn handle_key(key: DecodedKey) -> bool {
+    let mut guard = GAME.lock();
+    let game = match guard.as_mut() {
+        Some(game) => game,
+        None => return true,
+    };
+    let direction = match key {
+        _ if game.over => None,
+        DecodedKey::RawKey(KeyCode::ArrowUp) | DecodedKey::Unicode('w') => Some(Direction::Up),
+        DecodedKey::RawKey(KeyCode::ArrowDown) | DecodedKey::Unicode('s') => Some(Direction::Down),
+        DecodedKey::RawKey(KeyCode::ArrowLeft) | DecodedKey::Unicode('a') => Some(Direction::Left),
+        DecodedKey::RawKey(KeyCode::ArrowRight) | DecodedKey::Unicode('d') => {
+            Some(Direction::Right)
+        }
+        DecodedKey::Unicode('q') | DecodedKey::Unicode('\u{1b}') => {
+            game.over = true;
+            None
+        }
+        _ => return false,
+    };
+    match direction {
+        Some(direction) if direction != game.direction.opposite() => {
+            game.next_direction = direction;
+            false
+        }
+        Some(_) => false,
+        None => {
+            *guard = None;
+            drop(guard);
+            crate::arch::without_interrupts(|| vga_buffer::WRITER.lock().clear_screen());
+            true
+        }
+    }
+}
@@ -0,0 +1,206 @@
+// PC スピーカー。音程は PIT のチャンネル 2 で作り、ポート 0x61 のビット 0 (チャンネル 2 の
+// ゲート) とビット 1 (スピーカーの出力) で鳴らしたり止めたりする
+use crate::device::{self, DeviceClass};
+use crate::hal::port::PortRange;
+use crate::task::{self, TaskState};
+use crate::time;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+static SPEAKER_PORT: PortRange = unsafe { PortRange::new("speaker", 0x61, 1) };
+
+const SPEAKER_ENABLE: u8 = 0x03;
+
+// 鳴っている beep を止めるティック。後から鳴らした beep が先の beep のタスクに止められないように使う
+static BEEP_UNTIL: AtomicU64 = AtomicU64::new(0);
+
+pub fn register_device(bus: usize) -> Result<(), &'static str> {
+    SPEAKER_PORT.claim()?;
+    device::register(
+        "pcspkr",
+        DeviceClass::Other,
+        Some(bus),
+        &[SPEAKER_PORT.resource()],
+    );
+    Ok(())
+}
+
+pub fn tone(frequency: u32) {
+    time::set_tone_frequency(frequency);
+    let value: u8 = SPEAKER_PORT.read(0);
+    SPEAKER_PORT.write(0, value | SPEAKER_ENABLE);
+}
+
+pub fn silence() {
+    let value: u8 = SPEAKER_PORT.read(0);
+    SPEAKER_PORT.write(0, value & !SPEAKER_ENABLE);
+}
+
+// 鳴らしたまますぐに戻り、duration_ms 経ったらタスクが止める
+pub fn beep(frequency: u32, duration_ms: u64) {
+    let until = time::ticks() + (duration_ms * time::TICK_HZ / 1000).max(1);
+    BEEP_UNTIL.store(until, Ordering::Relaxed);
+    tone(frequency);
+    task::spawn("beep", || {
+        let until = BEEP_UNTIL.load(Ordering::Relaxed);
+        if time::ticks() < until {
+            return TaskState::Sleep(until);
+        }
+        silence();
+        TaskState::Done
+    });
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Note {
+    // None は休符
+    pub frequency: Option<u32>,
+    pub duration_ms: u64,
+}
+
+// 4 オクターブ目の C から B までの周波数 (1/100 Hz)
+const OCTAVE_4: [u32; 12] = [
+    26163, 27718, 29366, 31113, 32963, 34923, 36999, 39200, 41530, 44000, 46616, 49388,
+];
+// 音と音の間に入れる無音の割合 (1/10)。同じ音が続いても切れて聞こえるようにする
+const GAP_TENTHS: u64 = 1;
+
+// メロディの書式。語の頭の # から行末はコメント、tempo <bpm> で以降のテンポ (既定 120) を変える。
+// 音符は <音名>[#|b]<オクターブ>[/<長さ>][.] で、長さは 1 が全音符、4 が 4 分音符 (既定)、
+// . を付けると付点。R[/<長さ>] は休符。例: C4/4 E4/8 G4/8. R/2 A#4
+pub fn parse_melody(text: &str) -> Result<Vec<Note>, (usize, &'static str)> {
+    let mut notes = Vec::new();
+    let mut tempo = 120;
+    for (number, line) in text.lines().enumerate() {
+        let error = |message| (number + 1, message);
+        let mut words = line.split_whitespace();
+        while let Some(word) = words.next() {
+            // C#4 の # と区別するため、コメントは語の頭の # から
+            if word.starts_with('#') {
+                break;
+            }
+            if word == "tempo" {
+                tempo = words
+                    .next()
+                    .and_then(|bpm| bpm.parse::<u64>().ok())
+                    .filter(|bpm| (1..=1000).contains(bpm))
+                    .ok_or(error("tempo must be between 1 and 1000"))?;
+                continue;
+            }
+            notes.push(parse_note(word, tempo).ok_or(error("invalid note"))?);
+        }
+    }
+    Ok(notes)
+}
+
+fn parse_note(word: &str, tempo: u64) -> Option<Note> {
+    let (pitch, length) = match word.split_once('/') {
+        Some((pitch, length)) => (pitch, length),
+        None => match word.strip_suffix('.') {
+            Some(pitch) => (pitch, "4."),
+            None => (word, "4"),
+        },
+    };
+    let (length, dotted) = match length.strip_suffix('.') {
+        Some(length) => (length, true),
+        None => (length, false),
+    };
+    let length: u64 = length.parse().ok().filter(|&length| length > 0)?;
+    // 4 分音符が 1 拍
+    let mut duration_ms = 60_000 * 4 / tempo / length;
+    if dotted {
+        duration_ms += duration_ms / 2;
+    }
+
+    let frequency = if pitch.eq_ignore_ascii_case("r") {
+        None
+    } else {
+        let mut chars = pitch.chars();
+        let mut semitone: i32 = match chars.next()?.to_ascii_uppercase() {
+            'C' => 0,
+            'D' => 2,
+            'E' => 4,
+            'F' => 5,
+            'G' => 7,
+            'A' => 9,
+            'B' => 11,
+            _ => return None,
+        };
+        let rest = chars.as_str();
+        let octave = match rest.chars().next()? {
+            '#' => {
+                semitone += 1;
+                &rest[1..]
+            }
+            'b' => {
+                semitone -= 1;
+                &rest[1..]
+            }
+            _ => rest,
+        };
+        let mut octave: u32 = octave.parse().ok().filter(|&octave| octave <= 8)?;
+        // C の半音下は前のオクターブの B、B の半音上は次のオクターブの C
+        if semitone < 0 {
+            octave = octave.checked_sub(1)?;
+            semitone += 12;
+        } else if semitone > 11 {
+            octave += 1;
+            semitone -= 12;
+        }
+        let centi_hz = OCTAVE_4[semitone as usize] as u64 * (1 << octave) / 16;
+        Some(((centi_hz + 50) / 100) as u32)
+    };
+    Some(Note {
+        frequency,
+        duration_ms,
+    })
+}
+
+static MELODY: Mutex<Option<VecDeque<Note>>> = Mutex::new(None);
+
+// 前のメロディは止めて、notes を頭から鳴らす。鳴らしているタスクがあればそれが続きを鳴らす
+pub fn play(notes: Vec<Note>) {
+    let already_running = MELODY.lock().replace(notes.into()).is_some();
+    if already_running {
+        return;
+    }
+    // 1 つの音を鳴らす期間と、その後の短い無音の期間を交互に進める
+    let mut gap_until = None;
+    task::spawn("play", move || {
+        if let Some(until) = gap_until.take() {
+            silence();
+            return TaskState::Sleep(until);
+        }
+        let note = match MELODY.lock().as_mut() {
+            Some(melody) => melody.pop_front(),
+            None => None,
+        };
+        let note = match note {
+            Some(note) => note,
+            None => {
+                silence();
+                *MELODY.lock() = None;
+                return TaskState::Done;
+            }
+        };
+        let now = time::ticks();
+        let ticks = (note.duration_ms * time::TICK_HZ / 1000).max(1);
+        let gap = ticks * GAP_TENTHS / 10;
+        match note.frequency {
+            Some(frequency) => tone(frequency),
+            None => silence(),
+        }
+        gap_until = Some(now + ticks);
+        TaskState::Sleep(now + ticks - gap)
+    });
+}
+
+// 残りの音を捨てる。タスクは次に起きたときに終わる
+pub fn stop() {
+    if let Some(melody) = MELODY.lock().as_mut() {
+        melody.clear();
+    }
+    silence();
+}
@@ -0,0 +1,55 @@
+// quiet 起動。init の途中経過は画面に出さず、進み具合を 1 行のバーで見せる。
+// メッセージはログのリングバッファとシリアルには残り、起動中に Esc を押すと
+// それまでの分を画面に出して以降は普通に表示する
+use crate::{keyboard, log, print};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+static QUIET: AtomicBool = AtomicBool::new(cfg!(feature = "quiet"));
+// 画面にバーが出ている
+static SHOWN: AtomicBool = AtomicBool::new(false);
+
+const BAR_WIDTH: usize = 40;
+const LINE_WIDTH: usize = 70;
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+// init が一つ終わるたびに呼ばれる
+pub fn progress(finished: usize, total: usize) {
+    if !is_quiet() {
+        return;
+    }
+    if keyboard::take_escape() {
+        reveal();
+        return;
+    }
+    let filled = BAR_WIDTH * finished / total.max(1);
+    print!(
+        "\rBooting ROS [{:=<filled$}{:<empty$}] {:>3}%",
+        "",
+        "",
+        100 * finished / total.max(1),
+        filled = filled,
+        empty = BAR_WIDTH - filled,
+    );
+    SHOWN.store(true, Ordering::Relaxed);
+}
+
+// バーを消す。次の progress でまた描く
+pub fn clear() {
+    if SHOWN.swap(false, Ordering::Relaxed) {
+        print!("\r{:width$}\r", "", width = LINE_WIDTH);
+    }
+}
+
+// 起動が終わったらバーを片付ける。quiet のままなら以降のログもシリアルにだけ出る
+pub fn finish() {
+    clear();
+}
+
+fn reveal() {
+    QUIET.store(false, Ordering::Relaxed);
+    clear();
+    print!("{}", log::contents());
+}
@@ -0,0 +1,217 @@
+use crate::rand::XorShift64;
+use crate::task::{self, TaskState};
+use crate::{allocator, filesystem, println, time};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+const FS_WORKERS: usize = 2;
+const HEAP_WORKERS: usize = 2;
+const FILES_PER_WORKER: usize = 8;
+const MAX_FILE_SIZE: usize = 1024;
+const MAX_LIVE_BUFFERS: usize = 16;
+const MAX_BUFFER_SIZE: usize = 1024;
+const OPS_PER_STEP: usize = 16;
+// ファイルはここに作る。絶対パスなので、テスト中に cd しても同じファイルを指す
+const DIRECTORY: &str = "/tmp/stress";
+
+struct Report {
+    workers_left: usize,
+    file_ops: u64,
+    heap_ops: u64,
+    errors: Vec<String>,
+    heap_used_before: usize,
+}
+
+impl Report {
+    fn finish_worker(&mut self) {
+        self.workers_left -= 1;
+        if self.workers_left > 0 {
+            return;
+        }
+
+        let heap_used_after = allocator::stats().fallback_used;
+        println!();
+        println!(
+            "stress: {} file ops, {} heap ops, fallback heap {} -> {} bytes",
+            self.file_ops, self.heap_ops, self.heap_used_before, heap_used_after
+        );
+        if let Err(e) = filesystem::remove_directory(DIRECTORY, true) {
+            println!("stress: {}: {}", DIRECTORY, e);
+        }
+        if self.errors.is_empty() {
+            println!("stress: PASS");
+        } else {
+            println!("stress: FAIL ({} errors)", self.errors.len());
+            for error in self.errors.iter() {
+                println!("  {}", error);
+            }
+        }
+    }
+}
+
+pub fn start(seconds: u64) {
+    if let Err(e) = filesystem::create_directory(DIRECTORY, true) {
+        println!("stress: {}: {}", DIRECTORY, e);
+        return;
+    }
+    let deadline = time::ticks().saturating_add(seconds.saturating_mul(time::TICK_HZ));
+    let report = Arc::new(Mutex::new(Report {
+        workers_left: FS_WORKERS + HEAP_WORKERS,
+        file_ops: 0,
+        heap_ops: 0,
+        errors: Vec::new(),
+        heap_used_before: allocator::stats().fallback_used,
+    }));
+
+    for worker in 0..FS_WORKERS {
+        task::spawn(
+            &format!("stress-fs{}", worker),
+            fs_worker(worker, deadline, report.clone()),
+        );
+    }
+    for worker in 0..HEAP_WORKERS {
+        task::spawn(
+            &format!("stress-heap{}", worker),
+            heap_worker(worker, deadline, report.clone()),
+        );
+    }
+
+    println!(
+        "stress: running {} workers for {} seconds",
+        FS_WORKERS + HEAP_WORKERS,
+        seconds
+    );
+}
+
+fn fs_worker(
+    worker: usize,
+    deadline: u64,
+    report: Arc<Mutex<Report>>,
+) -> impl FnMut() -> TaskState + Send {
+    let mut rng = XorShift64::from_tsc();
+    let mut expected: Vec<Option<Vec<u8>>> = vec![None; FILES_PER_WORKER];
+
+    move || {
+        let name_of = |index: usize| format!("{}/{}-{}", DIRECTORY, worker, index);
+
+        if time::ticks() >= deadline {
+            let mut report = report.lock();
+            for (index, content) in expected.iter().enumerate() {
+                if let Some(content) = content {
+                    check_file(&name_of(index), content, &mut report.errors);
+                }
+            }
+            report.finish_worker();
+            return TaskState::Done;
+        }
+
+        for _ in 0..OPS_PER_STEP {
+            let index = rng.below(FILES_PER_WORKER);
+            let name = name_of(index);
+            let chunk: Vec<u8> = (0..1 + rng.below(64))
+                .map(|_| b'a' + rng.below(26) as u8)
+                .collect();
+
+            match (rng.below(4), expected[index].as_mut()) {
+                (0, Some(content)) => {
+                    check_file(&name, content, &mut report.lock().errors);
+                }
+                (1, Some(content)) if content.len() + chunk.len() <= MAX_FILE_SIZE => {
+                    if let Err(e) = filesystem::write_file(&name, &chunk, true) {
+                        report
+                            .lock()
+                            .errors
+                            .push(format!("{}: append: {}", name, e));
+                    }
+                    content.extend_from_slice(&chunk);
+                }
+                (2, Some(_)) => {
+                    if let Err(e) = filesystem::remove_file(&name) {
+                        report
+                            .lock()
+                            .errors
+                            .push(format!("{}: remove: {}", name, e));
+                    }
+                    if filesystem::read_file(&name).is_ok() {
+                        report
+                            .lock()
+                            .errors
+                            .push(format!("{}: still there after remove", name));
+                    }
+                    expected[index] = None;
+                }
+                _ => {
+                    if let Err(e) = filesystem::write_file(&name, &chunk, false) {
+                        report.lock().errors.push(format!("{}: write: {}", name, e));
+                    }
+                    expected[index] = Some(chunk);
+                }
+            }
+            report.lock().file_ops += 1;
+        }
+
+        TaskState::Running
+    }
+}
+
+fn check_file(name: &str, expected: &[u8], errors: &mut Vec<String>) {
+    match filesystem::read_file(name) {
+        Ok(content) if content == expected => {}
+        Ok(content) => errors.push(format!(
+            "{}: content mismatch ({} bytes, expected {})",
+            name,
+            content.len(),
+            expected.len()
+        )),
+        Err(e) => errors.push(format!("{}: read: {}", name, e)),
+    }
+}
+
+fn heap_worker(
+    worker: usize,
+    deadline: u64,
+    report: Arc<Mutex<Report>>,
+) -> impl FnMut() -> TaskState + Send {
+    let mut rng = XorShift64::from_tsc();
+    let mut live: Vec<(u8, Vec<u8>)> = Vec::new();
+
+    move || {
+        if time::ticks() >= deadline {
+            let mut report = report.lock();
+            for (tag, buffer) in live.drain(..) {
+                check_buffer(worker, tag, &buffer, &mut report.errors);
+            }
+            report.finish_worker();
+            return TaskState::Done;
+        }
+
+        for _ in 0..OPS_PER_STEP {
+            if live.len() < MAX_LIVE_BUFFERS && (live.is_empty() || rng.below(2) == 0) {
+                let tag = rng.next_u64() as u8;
+                let size = 1 + rng.below(MAX_BUFFER_SIZE);
+                live.push((tag, vec![tag; size]));
+            } else {
+                let (tag, buffer) = live.swap_remove(rng.below(live.len()));
+                check_buffer(worker, tag, &buffer, &mut report.lock().errors);
+            }
+            report.lock().heap_ops += 1;
+        }
+
+        TaskState::Running
+    }
+}
+
+fn check_buffer(worker: usize, tag: u8, buffer: &[u8], errors: &mut Vec<String>) {
+    if let Some(offset) = buffer.iter().position(|&byte| byte != tag) {
+        errors.push(format!(
+            "heap{}: buffer of {} bytes corrupted at offset {}",
+            worker,
+            buffer.len(),
+            offset
+        ));
+    }
+}
@@ -0,0 +1,145 @@
+// 使われていないファイルの中身を圧縮して RAM 上の swap 領域に追い出す
+//
+// 圧縮形式:
+//   0x00..=0x7f  続く (c + 1) バイトはリテラル
+//   0x80..=0xff  (c & 0x7f) + 4 バイトを u16 (LE) の距離だけ前からコピー
+
+use crate::allocator::{self, fixed_size_block::BLOCK_SIZES};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const MIN_MATCH: usize = 4;
+const MAX_MATCH: usize = 0x7f + MIN_MATCH;
+const MAX_LITERALS: usize = 0x80;
+const HASH_BITS: u32 = 10;
+
+// これより小さいファイルは追い出しても得が少ない
+pub const MIN_FILE_SIZE: u64 = 1024;
+
+static SWAP_OUTS: AtomicUsize = AtomicUsize::new(0);
+static SWAP_INS: AtomicUsize = AtomicUsize::new(0);
+
+// (追い出した回数, 読み戻した回数)
+pub fn counters() -> (usize, usize) {
+    (
+        SWAP_OUTS.load(Ordering::Relaxed),
+        SWAP_INS.load(Ordering::Relaxed),
+    )
+}
+
+pub fn count_out() {
+    SWAP_OUTS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn count_in() {
+    SWAP_INS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn free_memory() -> usize {
+    let stats = allocator::stats();
+    let blocks: usize = stats
+        .free_blocks
+        .iter()
+        .zip(BLOCK_SIZES.iter())
+        .map(|(count, size)| count * size)
+        .sum();
+    stats.fallback_free + blocks
+}
+
+// ヒープの空きが 1/4 を切ったら追い出しを始め、半分空くまで続ける
+pub fn under_pressure() -> bool {
+    free_memory() < allocator::HEAP_SIZE / 4
+}
+
+pub fn relieved() -> bool {
+    free_memory() >= allocator::HEAP_SIZE / 2
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    (value.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+fn flush_literals(output: &mut Vec<u8>, literals: &[u8]) {
+    for chunk in literals.chunks(MAX_LITERALS) {
+        output.push((chunk.len() - 1) as u8);
+        output.extend_from_slice(chunk);
+    }
+}
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut table = [u32::MAX; 1 << HASH_BITS];
+    let mut literal_start = 0;
+    let mut position = 0;
+
+    while position + MIN_MATCH <= input.len() {
+        let slot = hash(&input[position..]);
+        let candidate = table[slot] as usize;
+        table[slot] = position as u32;
+
+        let distance = position.wrapping_sub(candidate);
+        if candidate != u32::MAX as usize
+            && distance > 0
+            && distance <= u16::MAX as usize
+            && input[candidate..candidate + MIN_MATCH] == input[position..position + MIN_MATCH]
+        {
+            let mut length = MIN_MATCH;
+            while length < MAX_MATCH
+                && position + length < input.len()
+                && input[candidate + length] == input[position + length]
+            {
+                length += 1;
+            }
+
+            flush_literals(&mut output, &input[literal_start..position]);
+            output.push(0x80 | (length - MIN_MATCH) as u8);
+            output.extend_from_slice(&(distance as u16).to_le_bytes());
+            for inside in position + 1..(position + length).min(input.len() - MIN_MATCH + 1) {
+                table[hash(&input[inside..])] = inside as u32;
+            }
+            position += length;
+            literal_start = position;
+        } else {
+            position += 1;
+        }
+    }
+
+    flush_literals(&mut output, &input[literal_start..]);
+    output
+}
+
+pub fn decompress(input: &[u8], raw_len: usize) -> Result<Vec<u8>, &'static str> {
+    let mut output = Vec::with_capacity(raw_len);
+    let mut position = 0;
+
+    while position < input.len() {
+        let control = input[position] as usize;
+        position += 1;
+        if control < 0x80 {
+            let literals = input
+                .get(position..position + control + 1)
+                .ok_or("truncated swap data")?;
+            output.extend_from_slice(literals);
+            position += control + 1;
+        } else {
+            let distance = input
+                .get(position..position + 2)
+                .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+                .ok_or("truncated swap data")?;
+            position += 2;
+            if distance == 0 || distance > output.len() {
+                return Err("corrupt swap data");
+            }
+            let start = output.len() - distance;
+            for index in 0..(control & 0x7f) + MIN_MATCH {
+                output.push(output[start + index]);
+            }
+        }
+    }
+
+    if output.len() != raw_len {
+        return Err("corrupt swap data");
+    }
+    Ok(output)
+}
@@ -0,0 +1,282 @@
+// デバッグビルドでデッドロックを見つけるためのロック。spin::Mutex の代わりに使う。
+// CPU は一つでタスクも協調的なので、既に握られているロックを取ろうとした時点で
+// (割り込みハンドラからでも) 二度と空かないことが分かる。そのときは握った場所と
+// 取ろうとした場所を両方出して panic する。取る順番も覚えておき、A→B と B→A の
+// 両方が出てきたら、実際に詰まる前に panic する。
+// リリースビルドでは spin::Mutex をそのまま使うだけになる
+use core::ops::{Deref, DerefMut};
+#[cfg(debug_assertions)]
+use core::panic::Location;
+#[cfg(debug_assertions)]
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+pub struct Mutex<T> {
+    #[cfg_attr(not(debug_assertions), allow(dead_code))]
+    name: &'static str,
+    inner: spin::Mutex<T>,
+    // 割り込みハンドラで取ったことのある場所
+    #[cfg(debug_assertions)]
+    interrupt_site: AtomicPtr<Location<'static>>,
+}
+
+pub struct MutexGuard<'a, T> {
+    guard: spin::MutexGuard<'a, T>,
+    #[cfg(debug_assertions)]
+    lock: usize,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(name: &'static str, value: T) -> Mutex<T> {
+        Mutex {
+            name,
+            inner: spin::Mutex::new(value),
+            #[cfg(debug_assertions)]
+            interrupt_site: AtomicPtr::new(core::ptr::null_mut()),
+        }
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        #[cfg(debug_assertions)]
+        {
+            let site = Location::caller();
+            self.check_interrupt_safety(site);
+            lockdep::check_order(self.id(), self.name, site);
+            let guard = match self.inner.try_lock() {
+                Some(guard) => guard,
+                None => lockdep::deadlock(self.id(), self.name, site),
+            };
+            lockdep::acquired(self.id(), self.name, site);
+            MutexGuard {
+                guard,
+                lock: self.id(),
+            }
+        }
+        #[cfg(not(debug_assertions))]
+        MutexGuard {
+            guard: self.inner.lock(),
+        }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let guard = self.inner.try_lock()?;
+        #[cfg(debug_assertions)]
+        lockdep::acquired(self.id(), self.name, Location::caller());
+        Some(MutexGuard {
+            guard,
+            #[cfg(debug_assertions)]
+            lock: self.id(),
+        })
+    }
+
+    // panic から立ち直るとき用。握ったまま捨てられたロックを解放する
+    pub unsafe fn force_unlock(&self) {
+        #[cfg(debug_assertions)]
+        lockdep::released(self.id());
+        self.inner.force_unlock();
+    }
+
+    // 割り込みハンドラでも取るロックを割り込み許可のまま取ると、その間に割り込みが来たら
+    // 詰まる。実際に起きるのを待たずに panic する
+    #[cfg(debug_assertions)]
+    fn check_interrupt_safety(&self, site: &'static Location<'static>) {
+        if crate::arch::in_interrupt() {
+            let _ = self.interrupt_site.compare_exchange(
+                core::ptr::null_mut(),
+                site as *const Location<'static> as *mut Location<'static>,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+            return;
+        }
+        let interrupt_site = self.interrupt_site.load(Ordering::Relaxed);
+        if !interrupt_site.is_null() && crate::arch::interrupts_enabled() {
+            panic!(
+                "lock '{}' is taken at {} with interrupts enabled, but also in interrupt context at {}",
+                self.name,
+                site,
+                unsafe { &*interrupt_site }
+            );
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    fn id(&self) -> usize {
+        self as *const Mutex<T> as usize
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+#[cfg(debug_assertions)]
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        lockdep::released(self.lock);
+    }
+}
+
+// ヒープを使わないので、ヒープ初期化前のロックにも使える
+#[cfg(debug_assertions)]
+mod lockdep {
+    use crate::arch;
+    use core::panic::Location;
+
+    const MAX_HELD: usize = 16;
+    const MAX_ORDERS: usize = 64;
+
+    #[derive(Clone, Copy)]
+    struct Held {
+        lock: usize,
+        name: &'static str,
+        site: &'static Location<'static>,
+        in_interrupt: bool,
+    }
+
+    // first を握ったまま second を取ったことがある
+    #[derive(Clone, Copy)]
+    struct Order {
+        first: Held,
+        second: Held,
+    }
+
+    struct State {
+        held: [Option<Held>; MAX_HELD],
+        orders: [Option<Order>; MAX_ORDERS],
+    }
+
+    static STATE: spin::Mutex<State> = spin::Mutex::new(State {
+        held: [None; MAX_HELD],
+        orders: [None; MAX_ORDERS],
+    });
+
+    fn context(in_interrupt: bool) -> &'static str {
+        if in_interrupt {
+            " in interrupt context"
+        } else {
+            ""
+        }
+    }
+
+    pub fn deadlock(lock: usize, name: &'static str, site: &'static Location<'static>) -> ! {
+        let in_interrupt = arch::in_interrupt();
+        let holder = arch::without_interrupts(|| {
+            STATE
+                .lock()
+                .held
+                .iter()
+                .flatten()
+                .find(|held| held.lock == lock)
+                .copied()
+        });
+        match holder {
+            Some(holder) => panic!(
+                "deadlock: lock '{}' taken at {}{} is already held since {}{}",
+                name,
+                site,
+                context(in_interrupt),
+                holder.site,
+                context(holder.in_interrupt)
+            ),
+            None => panic!(
+                "deadlock: lock '{}' taken at {}{} is already held (holder not recorded)",
+                name,
+                site,
+                context(in_interrupt)
+            ),
+        }
+    }
+
+    fn entry(lock: usize, name: &'static str, site: &'static Location<'static>) -> Held {
+        Held {
+            lock,
+            name,
+            site,
+            in_interrupt: arch::in_interrupt(),
+        }
+    }
+
+    // 取る前に順番を確かめる。ここで panic すればこのロックは握ったままにならない
+    pub fn check_order(lock: usize, name: &'static str, site: &'static Location<'static>) {
+        let new = entry(lock, name, site);
+        let inversion = arch::without_interrupts(|| {
+            let mut state = STATE.lock();
+            let State { held, orders } = &mut *state;
+            for held in held.iter().flatten() {
+                // 同じロックを取り直すのは deadlock() で扱う
+                if held.lock == lock {
+                    continue;
+                }
+                if let Some(order) = orders
+                    .iter()
+                    .flatten()
+                    .find(|order| order.first.lock == lock && order.second.lock == held.lock)
+                {
+                    return Some((*order, *held));
+                }
+                let known = orders
+                    .iter()
+                    .flatten()
+                    .any(|order| order.first.lock == held.lock && order.second.lock == lock);
+                if !known {
+                    if let Some(slot) = orders.iter_mut().find(|slot| slot.is_none()) {
+                        *slot = Some(Order {
+                            first: *held,
+                            second: new,
+                        });
+                    }
+                }
+            }
+            None
+        });
+        if let Some((order, held)) = inversion {
+            panic!(
+                "lock order inversion: '{}' taken at {} while holding '{}' (taken at {}), \
+                 but '{}' was taken at {} while holding '{}' (taken at {})",
+                new.name,
+                new.site,
+                held.name,
+                held.site,
+                order.second.name,
+                order.second.site,
+                order.first.name,
+                order.first.site
+            );
+        }
+    }
+
+    pub fn acquired(lock: usize, name: &'static str, site: &'static Location<'static>) {
+        let new = entry(lock, name, site);
+        arch::without_interrupts(|| {
+            if let Some(slot) = STATE.lock().held.iter_mut().find(|slot| slot.is_none()) {
+                *slot = Some(new);
+            }
+        });
+    }
+
+    pub fn released(lock: usize) {
+        arch::without_interrupts(|| {
+            let mut state = STATE.lock();
+            // 同じロックは一度しか握れないので、見つかった一つを消せばよい
+            if let Some(slot) = state
+                .held
+                .iter_mut()
+                .find(|slot| matches!(slot, Some(held) if held.lock == lock))
+            {
+                *slot = None;
+            }
+        });
+    }
+}
@@ -0,0 +1,146 @@
+// システムコールの番号、エラー番号と引数の検査。ユーザープロセスはまだ無いので入口
+// (int 0x80 や syscall 命令) は無く、dispatch を直接呼ぶ。戻り値は成功なら 0 以上、
+// 失敗なら -errno。エラー番号は Linux と同じ値にして、一度決めたら変えない。
+// ユーザーのポインタは全部ここで検査して usercopy を通して読み書きするので、
+// 壊れた引数で呼ばれてもカーネルは panic せずにエラーを返す
+use crate::arch::x86::usercopy;
+use crate::{filesystem, print, time};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_FILE_SIZE: u64 = 2;
+pub const SYS_READ_AT: u64 = 3;
+pub const SYS_UPTIME: u64 = 4;
+
+// 1 回で写す大きさの上限。これより大きいものは短く読み書きして、読み書きした分を返す
+const MAX_IO: usize = 4096;
+const PATH_MAX: usize = 256;
+
+// 標準出力と標準エラー出力だけがある。どちらも画面に出す
+const STDOUT: u64 = 1;
+const STDERR: u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+    NoEntry = 2,
+    Io = 5,
+    BadFd = 9,
+    Fault = 14,
+    Busy = 16,
+    Exists = 17,
+    NotDirectory = 20,
+    IsDirectory = 21,
+    Invalid = 22,
+    FileTooBig = 27,
+    ReadOnly = 30,
+    NameTooLong = 36,
+    NoSys = 38,
+    NotEmpty = 39,
+}
+
+impl Errno {
+    pub fn name(self) -> &'static str {
+        match self {
+            Errno::NoEntry => "ENOENT",
+            Errno::Io => "EIO",
+            Errno::BadFd => "EBADF",
+            Errno::Fault => "EFAULT",
+            Errno::Busy => "EBUSY",
+            Errno::Exists => "EEXIST",
+            Errno::NotDirectory => "ENOTDIR",
+            Errno::IsDirectory => "EISDIR",
+            Errno::Invalid => "EINVAL",
+            Errno::FileTooBig => "EFBIG",
+            Errno::ReadOnly => "EROFS",
+            Errno::NameTooLong => "ENAMETOOLONG",
+            Errno::NoSys => "ENOSYS",
+            Errno::NotEmpty => "ENOTEMPTY",
+        }
+    }
+
+    // ファイルシステムなどが返すエラーの文字列をエラー番号にする。知らないものは EIO
+    pub fn from_error(error: &str) -> Errno {
+        match error {
+            "No such file or directory" | "File not found" => Errno::NoEntry,
+            "Not a directory"
+            | "Path component is not a directory"
+            | "Parent is not a directory" => Errno::NotDirectory,
+            "Is a directory" | "Cannot overwrite a directory with a file" => Errno::IsDirectory,
+            "File exists" | "Already mounted" => Errno::Exists,
+            "Device or resource busy" => Errno::Busy,
+            "Directory not empty" => Errno::NotEmpty,
+            "Read-only file system" => Errno::ReadOnly,
+            "File too large" => Errno::FileTooBig,
+            _ => Errno::Io,
+        }
+    }
+
+    fn to_return(self) -> i64 {
+        -(self as i64)
+    }
+}
+
+pub fn dispatch(number: u64, args: [u64; 6]) -> i64 {
+    let result = match number {
+        SYS_WRITE => sys_write(args[0], args[1], args[2]),
+        SYS_FILE_SIZE => sys_file_size(args[0], args[1]),
+        SYS_READ_AT => sys_read_at(args[0], args[1], args[2], args[3], args[4]),
+        SYS_UPTIME => Ok(time::uptime_ms()),
+        _ => Err(Errno::NoSys),
+    };
+    match result {
+        // 負の値はエラーと区別できないので、大きすぎる結果は EINVAL にする
+        Ok(value) => i64::try_from(value).unwrap_or(Errno::Invalid.to_return()),
+        Err(errno) => errno.to_return(),
+    }
+}
+
+// ポインタと長さの組を検査してカーネルに写す
+fn user_bytes(ptr: u64, len: u64, max: usize) -> Result<Vec<u8>, Errno> {
+    let len = usize::try_from(len).map_err(|_| Errno::Invalid)?;
+    if len > max {
+        return Err(Errno::Invalid);
+    }
+    let mut buffer = vec![0; len];
+    usercopy::copy_from_user(&mut buffer, ptr).map_err(|_| Errno::Fault)?;
+    Ok(buffer)
+}
+
+fn user_path(ptr: u64, len: u64) -> Result<String, Errno> {
+    if len > PATH_MAX as u64 {
+        return Err(Errno::NameTooLong);
+    }
+    let bytes = user_bytes(ptr, len, PATH_MAX)?;
+    let path = String::from_utf8(bytes).map_err(|_| Errno::Invalid)?;
+    if path.is_empty() || path.contains('\0') {
+        return Err(Errno::Invalid);
+    }
+    Ok(path)
+}
+
+fn sys_write(fd: u64, buffer: u64, len: u64) -> Result<u64, Errno> {
+    if fd != STDOUT && fd != STDERR {
+        return Err(Errno::BadFd);
+    }
+    let bytes = user_bytes(buffer, len.min(MAX_IO as u64), MAX_IO)?;
+    print!("{}", String::from_utf8_lossy(&bytes));
+    Ok(bytes.len() as u64)
+}
+
+fn sys_file_size(path: u64, path_len: u64) -> Result<u64, Errno> {
+    let path = user_path(path, path_len)?;
+    filesystem::file_size(&path)
+        .map(|(size, _)| size)
+        .map_err(Errno::from_error)
+}
+
+fn sys_read_at(path: u64, path_len: u64, offset: u64, buffer: u64, len: u64) -> Result<u64, Errno> {
+    let path = user_path(path, path_len)?;
+    let len = len.min(MAX_IO as u64) as usize;
+    let data = filesystem::read_file_at(&path, offset, len).map_err(Errno::from_error)?;
+    usercopy::copy_to_user(buffer, &data).map_err(|_| Errno::Fault)?;
+    Ok(data.len() as u64)
+}
@@ -0,0 +1,121 @@
+use crate::log::Level;
+use crate::{klog, time};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Running,
+    // 今はやることがない。キューには残すが CPU を起こし続けない
+    Idle,
+    // 指定したティックまで呼ばない
+    Sleep(u64),
+    Done,
+    // 続けられないエラー。ログに残してタスクを終える
+    Failed(&'static str),
+}
+
+struct Task {
+    id: u64,
+    name: String,
+    step: Box<dyn FnMut() -> TaskState + Send>,
+    cycles: u64,
+    wake_at: u64,
+}
+
+static TASKS: Mutex<VecDeque<Task>> = Mutex::new(VecDeque::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+pub fn spawn(name: &str, step: impl FnMut() -> TaskState + Send + 'static) -> u64 {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    TASKS.lock().push_back(Task {
+        id,
+        name: String::from(name),
+        step: Box::new(step),
+        cycles: 0,
+        wake_at: 0,
+    });
+    id
+}
+
+// 各タスクを一回ずつ進める。仕事をしたタスクがあれば true を返す
+pub fn run_ready() -> bool {
+    let count = TASKS.lock().len();
+    let mut busy = false;
+    for _ in 0..count {
+        let task = TASKS.lock().pop_front();
+        if let Some(mut task) = task {
+            if task.wake_at > time::ticks() {
+                TASKS.lock().push_back(task);
+                continue;
+            }
+            let start = crate::arch::cycle_counter();
+            let state = (task.step)();
+            task.cycles += crate::arch::cycle_counter() - start;
+            match state {
+                TaskState::Running => {
+                    busy = true;
+                    TASKS.lock().push_back(task);
+                }
+                TaskState::Idle => {
+                    task.wake_at = time::ticks() + 1;
+                    TASKS.lock().push_back(task);
+                }
+                TaskState::Sleep(until) => {
+                    task.wake_at = until;
+                    TASKS.lock().push_back(task);
+                }
+                TaskState::Done => {}
+                TaskState::Failed(e) => {
+                    klog!(
+                        Level::Error,
+                        "task {} ({}) failed: {}",
+                        task.id,
+                        task.name,
+                        e
+                    );
+                }
+            }
+        }
+    }
+    busy
+}
+
+pub fn list() -> Vec<(u64, String)> {
+    TASKS
+        .lock()
+        .iter()
+        .map(|task| (task.id, task.name.clone()))
+        .collect()
+}
+
+pub fn try_list() -> Option<Vec<(u64, String)>> {
+    TASKS.try_lock().map(|tasks| {
+        tasks
+            .iter()
+            .map(|task| (task.id, task.name.clone()))
+            .collect()
+    })
+}
+
+// (id, 名前, これまでに使った TSC サイクル)
+pub fn cpu_usage() -> Vec<(u64, String, u64)> {
+    TASKS
+        .lock()
+        .iter()
+        .map(|task| (task.id, task.name.clone(), task.cycles))
+        .collect()
+}
+
+pub fn is_alive(id: u64) -> bool {
+    TASKS.lock().iter().any(|task| task.id == id)
+}
+
+// 一番早く起こすべきタスクのティック
+pub fn next_wakeup() -> Option<u64> {
+    TASKS.lock().iter().map(|task| task.wake_at).min()
+}
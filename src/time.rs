@@ -0,0 +1,384 @@
+use crate::arch;
+use crate::arch::x86::apic;
+use crate::device::{self, DeviceClass, Resource};
+use crate::hal::port::PortRange;
+use crate::klog;
+use crate::log::Level;
+use core::sync::atomic::{AtomicBool, AtomicI8, AtomicU64, Ordering};
+
+pub const TICK_HZ: u64 = 100;
+const PIT_FREQUENCY: u64 = 1_193_182;
+const DIVISOR: u64 = PIT_FREQUENCY / TICK_HZ;
+// PIT のカウンタは 16 ビットなので、PIT のワンショットで一度に眠れるのはこれだけ
+const MAX_PIT_IDLE_TICKS: u64 = u16::MAX as u64 / DIVISOR;
+// ローカル APIC のタイマーで眠るときの上限。締め切りが無くてもこれだけ経てば一度起きる
+const MAX_IDLE_TICKS: u64 = 60 * TICK_HZ;
+
+const PIT_PERIODIC: u8 = 0x36;
+const PIT_ONE_SHOT: u8 = 0x30;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static IDLE_TICKS: AtomicU64 = AtomicU64::new(0);
+static IDLE: AtomicBool = AtomicBool::new(false);
+// ワンショットで眠っている間のティック数。0 なら周期モード
+static ONE_SHOT_TICKS: AtomicU64 = AtomicU64::new(0);
+// ワンショットをローカル APIC のタイマーで数えているか (偽なら PIT)
+static ONE_SHOT_APIC: AtomicBool = AtomicBool::new(false);
+// ワンショットから途中で起きたときの端数 (ワンショットに使ったタイマーのカウント)
+static LEFTOVER_COUNTS: AtomicU64 = AtomicU64::new(0);
+
+static PIT_PORTS: PortRange = unsafe { PortRange::new("pit", 0x40, 4) };
+static RTC_PORTS: PortRange = unsafe { PortRange::new("rtc", 0x70, 2) };
+
+// PIT_PORTS 内のオフセット
+const PIT_CHANNEL0: u16 = 0;
+const PIT_CHANNEL2: u16 = 2;
+const PIT_COMMAND: u16 = 3;
+
+// チャンネル 2、下位・上位バイトの順、矩形波。出力は PC スピーカーにつながっている
+const PIT_CHANNEL2_SQUARE_WAVE: u8 = 0xb6;
+
+fn program_pit(mode: u8, count: u16) {
+    PIT_PORTS.write(PIT_COMMAND, mode);
+    PIT_PORTS.write(PIT_CHANNEL0, (count & 0xff) as u8);
+    PIT_PORTS.write(PIT_CHANNEL0, (count >> 8) as u8);
+}
+
+// PC スピーカー用にチャンネル 2 の周波数を設定する。鳴らすかどうかは speaker が決める
+pub fn set_tone_frequency(hz: u32) {
+    let count = (PIT_FREQUENCY / hz.max(1) as u64).clamp(1, u16::MAX as u64) as u16;
+    arch::without_interrupts(|| {
+        PIT_PORTS.write(PIT_COMMAND, PIT_CHANNEL2_SQUARE_WAVE);
+        PIT_PORTS.write(PIT_CHANNEL2, (count & 0xff) as u8);
+        PIT_PORTS.write(PIT_CHANNEL2, (count >> 8) as u8);
+    });
+}
+
+fn read_pit_count() -> u64 {
+    PIT_PORTS.write(PIT_COMMAND, 0x00u8);
+    let low = PIT_PORTS.read::<u8>(PIT_CHANNEL0) as u64;
+    let high = PIT_PORTS.read::<u8>(PIT_CHANNEL0) as u64;
+    high << 8 | low
+}
+
+pub fn init() {
+    program_pit(PIT_PERIODIC, DIVISOR as u16);
+    BOOT_TIME.store(read_rtc(), Ordering::Relaxed);
+}
+
+pub fn register_devices(bus: usize) -> Result<(), &'static str> {
+    PIT_PORTS.claim()?;
+    RTC_PORTS.claim()?;
+    device::register(
+        "pit",
+        DeviceClass::Timer,
+        Some(bus),
+        &[PIT_PORTS.resource(), Resource::Irq(0)],
+    );
+    device::register(
+        "rtc",
+        DeviceClass::Clock,
+        Some(bus),
+        &[RTC_PORTS.resource(), Resource::Irq(8)],
+    );
+    crate::power::register_pm(|| Ok(()), resume);
+    Ok(())
+}
+
+// 眠っている間はティックが進まないので、RTC から起動時刻を合わせ直す
+fn resume() {
+    ONE_SHOT_TICKS.store(0, Ordering::Relaxed);
+    if CLOCK_ENABLED.load(Ordering::Relaxed) {
+        set_update_interrupt(true);
+    }
+    program_pit(PIT_PERIODIC, DIVISOR as u16);
+    BOOT_TIME.store(
+        read_rtc().saturating_sub(ticks() / TICK_HZ),
+        Ordering::Relaxed,
+    );
+}
+
+// ワンショットを止めて、実際に経過したティック数をタイマーのカウンタから求める。
+// 満了後の PIT のカウンタは 0xffff から数え直すので、設定値より大きければ満了している
+fn finish_one_shot(armed: u64) -> u64 {
+    let (per_tick, count) = if ONE_SHOT_APIC.load(Ordering::Relaxed) {
+        (apic::counts_per_tick(), apic::stop() as u64)
+    } else {
+        (DIVISOR, read_pit_count())
+    };
+    let total = armed * per_tick;
+    let elapsed = if count == 0 || count > total {
+        total
+    } else {
+        total - count
+    };
+    let counts = elapsed + LEFTOVER_COUNTS.load(Ordering::Relaxed);
+    LEFTOVER_COUNTS.store(counts % per_tick, Ordering::Relaxed);
+    counts / per_tick
+}
+
+pub fn tick() {
+    let elapsed = match ONE_SHOT_TICKS.swap(0, Ordering::Relaxed) {
+        0 => 1,
+        armed => finish_one_shot(armed),
+    };
+    TICKS.fetch_add(elapsed, Ordering::Relaxed);
+    // hlt 中に来たティックはアイドルとして数える
+    if IDLE.load(Ordering::Relaxed) {
+        IDLE_TICKS.fetch_add(elapsed, Ordering::Relaxed);
+    }
+}
+
+// ローカル APIC のタイマーの割り込み。ワンショットを止めた後に届いたものは数えない
+pub fn handle_apic_timer() {
+    if ONE_SHOT_APIC.load(Ordering::Relaxed) && ONE_SHOT_TICKS.load(Ordering::Relaxed) != 0 {
+        tick();
+    }
+}
+
+pub fn idle_ticks() -> u64 {
+    IDLE_TICKS.load(Ordering::Relaxed)
+}
+
+// 次の締め切り (ティック) まで眠る。締め切りが遠ければ周期タイマーを止めて
+// ワンショットで起きるので、その間の無駄な割り込みが無くなる。ローカル APIC のタイマーが
+// 使えれば締め切りまで一度に眠り、使えなければ PIT で MAX_PIT_IDLE_TICKS ずつ眠る
+pub fn idle(deadline: Option<u64>) {
+    arch::disable_interrupts();
+    if crate::keyboard::has_pending() {
+        arch::enable_interrupts();
+        return;
+    }
+
+    let apic_counts = apic::counts_per_tick();
+    let limit = if apic_counts != 0 {
+        MAX_IDLE_TICKS.min(u32::MAX as u64 / apic_counts)
+    } else {
+        MAX_PIT_IDLE_TICKS
+    };
+    let sleep = match deadline {
+        Some(deadline) => deadline.saturating_sub(ticks()).min(limit),
+        None => limit,
+    };
+    if sleep > 1 {
+        ONE_SHOT_TICKS.store(sleep, Ordering::Relaxed);
+        ONE_SHOT_APIC.store(apic_counts != 0, Ordering::Relaxed);
+        if apic_counts != 0 {
+            // モード 0 はカウントを書くまで数えないので、コマンドだけ書けば PIT は止まる
+            PIT_PORTS.write(PIT_COMMAND, PIT_ONE_SHOT);
+            apic::start_one_shot((sleep * apic_counts) as u32);
+        } else {
+            program_pit(PIT_ONE_SHOT, (sleep * DIVISOR) as u16);
+        }
+    }
+
+    IDLE.store(true, Ordering::Relaxed);
+    arch::wait_for_interrupt();
+    arch::disable_interrupts();
+    IDLE.store(false, Ordering::Relaxed);
+
+    if sleep > 1 {
+        // タイマー以外の割り込みで起きたときはここで経過分を足す
+        let armed = ONE_SHOT_TICKS.swap(0, Ordering::Relaxed);
+        if armed != 0 {
+            let elapsed = finish_one_shot(armed);
+            TICKS.fetch_add(elapsed, Ordering::Relaxed);
+            IDLE_TICKS.fetch_add(elapsed, Ordering::Relaxed);
+        }
+        program_pit(PIT_PERIODIC, DIVISOR as u16);
+    }
+    arch::enable_interrupts();
+}
+
+// PIT のティックが動き出してから、ローカル APIC のタイマーをアイドルに使えるか調べる。
+// 使えなければ PIT のワンショットのまま
+pub fn init_idle_timer() -> Result<(), &'static str> {
+    match apic::init() {
+        Ok(()) => {
+            // PIT のワンショットで残った端数は単位が違うので捨てる
+            LEFTOVER_COUNTS.store(0, Ordering::Relaxed);
+            klog!(
+                Level::Info,
+                "Tickless idle: local APIC timer ({} counts per tick)",
+                apic::counts_per_tick()
+            );
+        }
+        Err(e) => klog!(Level::Info, "Tickless idle: PIT one-shot ({})", e),
+    }
+    Ok(())
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+pub fn uptime_ms() -> u64 {
+    ticks() * 1000 / TICK_HZ
+}
+
+// 起動時の RTC 時刻 (UNIX 秒)。以降は PIT のティックで進める
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+// インデックスとデータの間に RTC の割り込みが入るとレジスタがずれるので止めて読む
+fn read_cmos(register: u8) -> u8 {
+    arch::without_interrupts(|| {
+        RTC_PORTS.write(0, register);
+        RTC_PORTS.read(1)
+    })
+}
+
+fn write_cmos(register: u8, value: u8) {
+    arch::without_interrupts(|| {
+        RTC_PORTS.write(0, register);
+        RTC_PORTS.write(1, value);
+    })
+}
+
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// days_from_civil の逆。1970-01-01 からの日数を (年, 月, 日) にする
+pub fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+pub fn is_leap_year(year: u64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+pub fn days_in_month(year: u64, month: u64) -> u64 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// 0 が日曜日。1970-01-01 は木曜日
+pub fn weekday(year: u64, month: u64, day: u64) -> u64 {
+    (days_from_civil(year, month, day) + 4) % 7
+}
+
+// UNIX 秒に時差 (時間) を足した日付
+pub fn local_date(seconds: u64, utc_offset: i8) -> (u64, u64, u64) {
+    let local = (seconds as i64 + utc_offset as i64 * 3600).max(0) as u64;
+    civil_from_days(local / 86400)
+}
+
+pub fn read_rtc() -> u64 {
+    // 更新中は値が不定なので待つ
+    while read_cmos(0x0a) & 0x80 != 0 {
+        core::hint::spin_loop();
+    }
+    let status_b = read_cmos(0x0b);
+    let decode = |value: u8| -> u64 {
+        if status_b & 0x04 != 0 {
+            value as u64
+        } else {
+            ((value >> 4) * 10 + (value & 0x0f)) as u64
+        }
+    };
+
+    let seconds = decode(read_cmos(0x00));
+    let minutes = decode(read_cmos(0x02));
+    let raw_hours = read_cmos(0x04);
+    let mut hours = decode(raw_hours & 0x7f);
+    if status_b & 0x02 == 0 {
+        hours = hours % 12 + if raw_hours & 0x80 != 0 { 12 } else { 0 };
+    }
+    let day = decode(read_cmos(0x07));
+    let month = decode(read_cmos(0x08));
+    let year = 2000 + decode(read_cmos(0x09));
+
+    // CMOS が壊れていると 0 日や 13 月が返ってくる。days_from_civil は 1 を引くので
+    // そのまま渡すとアンダーフローする。割り込みハンドラからも呼ぶので、今の時刻のままにする
+    let valid = (1..=12).contains(&month)
+        && (1..=31).contains(&day)
+        && hours < 24
+        && minutes < 60
+        && seconds < 60;
+    if !valid {
+        return now();
+    }
+    days_from_civil(year, month, day) * 86400 + hours * 3600 + minutes * 60 + seconds
+}
+
+pub fn now() -> u64 {
+    BOOT_TIME.load(Ordering::Relaxed) + ticks() / TICK_HZ
+}
+
+// 画面右上の時計に使う UTC からの時差 (時間)。config の timezone で変わる
+static UTC_OFFSET: AtomicI8 = AtomicI8::new(9);
+static CLOCK_ENABLED: AtomicBool = AtomicBool::new(false);
+
+// ステータスレジスタ B の更新終了割り込み (UIE) とレジスタ C の更新終了フラグ (UF)
+const RTC_UPDATE_INTERRUPT: u8 = 0x10;
+const RTC_IRQ: u8 = 8;
+
+pub fn set_utc_offset(hours: i8) {
+    UTC_OFFSET.store(hours, Ordering::Relaxed);
+}
+
+fn set_update_interrupt(enabled: bool) {
+    arch::without_interrupts(|| {
+        let status_b = read_cmos(0x0b);
+        let status_b = if enabled {
+            status_b | RTC_UPDATE_INTERRUPT
+        } else {
+            status_b & !RTC_UPDATE_INTERRUPT
+        };
+        write_cmos(0x0b, status_b);
+        // 溜まっているフラグを読み捨てないと次の割り込みが来ない
+        read_cmos(0x0c);
+    });
+}
+
+// RTC が毎秒の更新を終えるたびに割り込ませて時計を描き直す。
+// シェルがポートを読みに行く必要は無い
+pub fn start_clock() {
+    CLOCK_ENABLED.store(true, Ordering::Relaxed);
+    set_update_interrupt(true);
+    arch::x86::interrupts::unmask_irq(RTC_IRQ);
+}
+
+pub fn stop_clock() {
+    CLOCK_ENABLED.store(false, Ordering::Relaxed);
+    set_update_interrupt(false);
+    crate::vga_buffer::hide_clock();
+}
+
+// IRQ 8 のハンドラから呼ぶ。レジスタ C を読むまで次の割り込みは来ない
+pub fn handle_rtc_interrupt() {
+    if read_cmos(0x0c) & RTC_UPDATE_INTERRUPT == 0 || !CLOCK_ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let offset = UTC_OFFSET.load(Ordering::Relaxed) as i64 * 3600;
+    let seconds_of_day = (read_rtc() as i64 + offset).rem_euclid(86400);
+    crate::vga_buffer::set_clock(
+        (seconds_of_day / 3600) as u8,
+        (seconds_of_day / 60 % 60) as u8,
+        (seconds_of_day % 60) as u8,
+    );
+}
@@ -0,0 +1,166 @@
+// ストップウォッチとカウントダウン。どちらかが動いている間だけタスクを回し、
+// 毎秒画面右上の表示を更新して、時間になったカウントダウンを知らせる
+use crate::task::{self, TaskState};
+use crate::time::{self, TICK_HZ};
+use crate::{println, speaker, vga_buffer};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+const BEEP_FREQUENCY: u32 = 880;
+const BEEP_MS: u64 = 500;
+
+#[derive(Clone, Copy)]
+pub struct Countdown {
+    pub id: u32,
+    pub deadline: u64,
+    pub beep: bool,
+}
+
+struct Timers {
+    // ストップウォッチを始めたティック
+    stopwatch: Option<u64>,
+    countdowns: Vec<Countdown>,
+    next_id: u32,
+    task_running: bool,
+}
+
+static TIMERS: Mutex<Timers> = Mutex::new(Timers {
+    stopwatch: None,
+    countdowns: Vec::new(),
+    next_id: 1,
+    task_running: false,
+});
+
+pub fn start_stopwatch() -> Result<(), &'static str> {
+    let mut timers = TIMERS.lock();
+    if timers.stopwatch.is_some() {
+        return Err("stopwatch is already running");
+    }
+    timers.stopwatch = Some(time::ticks());
+    ensure_task(&mut timers);
+    Ok(())
+}
+
+// 止めて経過ティックを返す
+pub fn stop_stopwatch() -> Result<u64, &'static str> {
+    let started = TIMERS
+        .lock()
+        .stopwatch
+        .take()
+        .ok_or("stopwatch is not running")?;
+    update_indicator();
+    Ok(time::ticks() - started)
+}
+
+pub fn stopwatch_elapsed() -> Option<u64> {
+    TIMERS
+        .lock()
+        .stopwatch
+        .map(|started| time::ticks() - started)
+}
+
+pub fn start_countdown(ticks: u64, beep: bool) -> u32 {
+    let mut timers = TIMERS.lock();
+    let id = timers.next_id;
+    timers.next_id += 1;
+    timers.countdowns.push(Countdown {
+        id,
+        deadline: time::ticks().saturating_add(ticks),
+        beep,
+    });
+    ensure_task(&mut timers);
+    id
+}
+
+pub fn cancel_countdown(id: u32) -> bool {
+    let mut timers = TIMERS.lock();
+    let before = timers.countdowns.len();
+    timers.countdowns.retain(|countdown| countdown.id != id);
+    let removed = timers.countdowns.len() != before;
+    drop(timers);
+    update_indicator();
+    removed
+}
+
+pub fn countdowns() -> Vec<Countdown> {
+    TIMERS.lock().countdowns.clone()
+}
+
+fn ensure_task(timers: &mut Timers) {
+    if !timers.task_running {
+        timers.task_running = true;
+        task::spawn("timers", poll);
+    }
+}
+
+fn poll() -> TaskState {
+    let now = time::ticks();
+    let expired: Vec<Countdown> = {
+        let mut timers = TIMERS.lock();
+        let (expired, pending) = core::mem::take(&mut timers.countdowns)
+            .into_iter()
+            .partition(|countdown| countdown.deadline <= now);
+        timers.countdowns = pending;
+        expired
+    };
+    for countdown in &expired {
+        println!("\ncountdown {}: time is up", countdown.id);
+        if countdown.beep {
+            speaker::beep(BEEP_FREQUENCY, BEEP_MS);
+        }
+    }
+    update_indicator();
+
+    let mut timers = TIMERS.lock();
+    if timers.stopwatch.is_none() && timers.countdowns.is_empty() {
+        timers.task_running = false;
+        return TaskState::Done;
+    }
+    // 表示は秒単位なので、次の秒の変わり目か一番早い締め切りまで眠る
+    let next_second = now + TICK_HZ - now % TICK_HZ;
+    let next_deadline = timers
+        .countdowns
+        .iter()
+        .map(|countdown| countdown.deadline)
+        .min();
+    TaskState::Sleep(next_deadline.map_or(next_second, |deadline| deadline.min(next_second)))
+}
+
+// 一番早いカウントダウンの残りを優先して出し、無ければストップウォッチの経過時間を出す
+fn update_indicator() {
+    let now = time::ticks();
+    let text = {
+        let timers = TIMERS.lock();
+        match timers
+            .countdowns
+            .iter()
+            .map(|countdown| countdown.deadline)
+            .min()
+        {
+            Some(deadline) => Some(format!(
+                "T-{}",
+                format_seconds((deadline - now).div_ceil(TICK_HZ))
+            )),
+            None => timers
+                .stopwatch
+                .map(|started| format!("SW {}", format_seconds((now - started) / TICK_HZ))),
+        }
+    };
+    vga_buffer::set_indicator(text.as_deref());
+}
+
+// 1 時間未満は mm:ss、それ以上は h:mm:ss
+pub fn format_seconds(seconds: u64) -> String {
+    if seconds < 3600 {
+        format!("{:02}:{:02}", seconds / 60, seconds % 60)
+    } else {
+        format!(
+            "{}:{:02}:{:02}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    }
+}
@@ -0,0 +1,91 @@
+use crate::allocator;
+use crate::task::{self, TaskState};
+use crate::{println, swap, time};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::Relaxed)
+}
+
+pub fn stop() {
+    RUNNING.store(false, Ordering::Relaxed);
+}
+
+pub fn start() {
+    if RUNNING.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    let mut previous_cycles: BTreeMap<u64, u64> = BTreeMap::new();
+    let mut previous_tsc = crate::arch::cycle_counter();
+    let mut previous_ticks = time::ticks();
+    let mut previous_idle = time::idle_ticks();
+    let mut next_refresh = 0;
+
+    task::spawn("top", move || {
+        if !is_running() {
+            return TaskState::Done;
+        }
+        if time::ticks() < next_refresh {
+            return TaskState::Sleep(next_refresh);
+        }
+        next_refresh = time::ticks() + time::TICK_HZ;
+
+        let tsc = crate::arch::cycle_counter();
+        let ticks = time::ticks();
+        let idle = time::idle_ticks();
+        let elapsed_tsc = (tsc - previous_tsc).max(1);
+        let elapsed_ticks = (ticks - previous_ticks).max(1);
+        let idle_percent = (idle - previous_idle) * 100 / elapsed_ticks;
+
+        let mut usage: Vec<(u64, String, u64)> = task::cpu_usage()
+            .into_iter()
+            .map(|(id, name, cycles)| {
+                let delta = cycles - previous_cycles.get(&id).copied().unwrap_or(0);
+                (id, name, delta * 100 / elapsed_tsc)
+            })
+            .collect();
+        usage.sort_by(|a, b| b.2.cmp(&a.2));
+        previous_cycles = task::cpu_usage()
+            .into_iter()
+            .map(|(id, _, cycles)| (id, cycles))
+            .collect();
+        previous_tsc = tsc;
+        previous_ticks = ticks;
+        previous_idle = idle;
+
+        if let Some(mut writer) = crate::vga_buffer::WRITER.try_lock() {
+            writer.clear_screen();
+        }
+        let uptime = time::uptime_ms() / 1000;
+        println!(
+            "top - up {}:{:02}:{:02}, cpu {}% busy, {}% idle",
+            uptime / 3600,
+            uptime / 60 % 60,
+            uptime % 60,
+            100 - idle_percent.min(100),
+            idle_percent.min(100)
+        );
+        let free = swap::free_memory();
+        println!(
+            "heap: {} bytes used, {} bytes free of {}",
+            allocator::HEAP_SIZE - free,
+            free,
+            allocator::HEAP_SIZE
+        );
+        println!("tasks: {}", usage.len());
+        println!();
+        println!("{:>5}  {:<20} {:>4}", "ID", "NAME", "CPU%");
+        for (id, name, percent) in usage {
+            println!("{:>5}  {:<20} {:>4}", id, name, percent);
+        }
+        println!();
+        println!("Press any key to exit");
+        TaskState::Sleep(next_refresh)
+    });
+}
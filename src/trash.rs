@@ -0,0 +1,159 @@
+// rm で消したものをすぐには捨てず、/.trash の下に番号を付けて移しておく。
+// 元のパスなどはここで覚えておき、trash restore で戻す。/.trash の下のものを
+// rm したときは本当に消す。ゴミ箱が大きくなりすぎたりヒープが足りなくなったら、
+// 古いものから捨てる
+use crate::{filesystem, swap, time};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+pub const TRASH_DIR: &str = "/.trash";
+
+// ゴミ箱に置いておく中身の合計の上限 (バイト)
+const LIMIT: u64 = 32 * 1024;
+
+#[derive(Clone)]
+pub struct Entry {
+    pub id: u32,
+    // 消す前の絶対パス
+    pub original: String,
+    pub deleted: u64,
+    pub size: u64,
+    pub directory: bool,
+}
+
+struct Trash {
+    entries: Vec<Entry>,
+    next_id: u32,
+}
+
+static TRASH: Mutex<Trash> = Mutex::new(Trash {
+    entries: Vec::new(),
+    next_id: 1,
+});
+
+// panic から立ち直るとき用
+pub unsafe fn recover() {
+    TRASH.force_unlock();
+}
+
+fn location(id: u32) -> String {
+    format!("{}/{}", TRASH_DIR, id)
+}
+
+fn in_trash(parts: &[String]) -> bool {
+    parts.first().map(String::as_str) == Some(&TRASH_DIR[1..])
+}
+
+fn size_of(path: &str, directory: bool) -> u64 {
+    if !directory {
+        return filesystem::file_size(path).map_or(0, |(size, _)| size);
+    }
+    filesystem::walk_files(path)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|file| filesystem::file_size(file).ok())
+        .map(|(size, _)| size)
+        .sum()
+}
+
+// rm の本体。recursive が偽ならディレクトリは消さない
+pub fn remove(path: &str, recursive: bool) -> Result<(), &'static str> {
+    let parts = filesystem::resolve_path(path);
+    let directory = filesystem::list_path(path).is_ok();
+    if in_trash(&parts) {
+        return if directory {
+            filesystem::remove_directory(path, recursive)
+        } else {
+            filesystem::remove_file(path)
+        };
+    }
+    if directory && !recursive {
+        return Err("Is a directory");
+    }
+    if parts.is_empty() {
+        return Err("Cannot remove the root directory");
+    }
+    if filesystem::get_current_path().starts_with(&parts) {
+        return Err("Device or resource busy");
+    }
+
+    let size = size_of(path, directory);
+    filesystem::create_directory(TRASH_DIR, true)?;
+    let id = {
+        let mut trash = TRASH.lock();
+        trash.next_id += 1;
+        trash.next_id - 1
+    };
+    filesystem::rename(path, &location(id))?;
+    TRASH.lock().entries.push(Entry {
+        id,
+        original: filesystem::format_path(&parts),
+        deleted: time::now(),
+        size,
+        directory,
+    });
+    purge();
+    Ok(())
+}
+
+// 上限を超えているかヒープが足りない間、古いものから捨てる
+fn purge() {
+    loop {
+        let oldest = {
+            let trash = TRASH.lock();
+            let total: u64 = trash.entries.iter().map(|entry| entry.size).sum();
+            if total <= LIMIT && !swap::under_pressure() {
+                return;
+            }
+            match trash.entries.first() {
+                Some(entry) => entry.id,
+                None => return,
+            }
+        };
+        discard(oldest);
+    }
+}
+
+fn discard(id: u32) {
+    TRASH.lock().entries.retain(|entry| entry.id != id);
+    let path = location(id);
+    if filesystem::remove_file(&path).is_err() {
+        let _ = filesystem::remove_directory(&path, true);
+    }
+}
+
+// /.trash の中身が直接消されたものは忘れる
+pub fn list() -> Vec<Entry> {
+    let mut trash = TRASH.lock();
+    trash.entries.retain(|entry| {
+        let path = location(entry.id);
+        filesystem::file_size(&path).is_ok() || filesystem::list_path(&path).is_ok()
+    });
+    trash.entries.clone()
+}
+
+// 元の場所 (to があればそこ) に戻し、戻した先を返す。同じ名前のものがあれば戻さない
+pub fn restore(id: u32, to: Option<&str>) -> Result<String, &'static str> {
+    let entry = list()
+        .into_iter()
+        .find(|entry| entry.id == id)
+        .ok_or("No such entry in the trash")?;
+    let target = to.map_or(entry.original.clone(), String::from);
+    if filesystem::file_size(&target).is_ok() || filesystem::list_path(&target).is_ok() {
+        return Err("File exists");
+    }
+    filesystem::rename(&location(id), &target)?;
+    TRASH.lock().entries.retain(|entry| entry.id != id);
+    Ok(target)
+}
+
+// 捨てたものの数を返す
+pub fn empty() -> usize {
+    let ids: Vec<u32> = list().iter().map(|entry| entry.id).collect();
+    for &id in &ids {
+        discard(id);
+    }
+    ids.len()
+}
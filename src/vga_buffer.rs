@@ -1,4 +1,10 @@
+use crate::device::{self, DeviceClass, Resource};
+use crate::hal::port::PortRange;
+use crate::sync;
+use alloc::string::String;
+use alloc::vec::Vec;
 use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use volatile::Volatile;
@@ -30,7 +36,7 @@ pub enum Color {
 struct ColorCode(u8);
 
 impl ColorCode {
-    fn new(foreground: Color, background: Color) -> ColorCode {
+    const fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
 }
@@ -42,19 +48,62 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
 
 #[repr(transparent)]
 struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+const DEFAULT_COLOR: ColorCode = ColorCode::new(Color::Yellow, Color::Black);
+const BOLD_COLOR: ColorCode = ColorCode::new(Color::White, Color::Black);
+const STATUS_COLOR: ColorCode = ColorCode::new(Color::Black, Color::LightGray);
+
+// VGA のフォント (コードページ 437) の 0x80 から 0xff に並んでいる文字
+const CP437_HIGH: &str = "ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒáíóúñÑªº¿⌐¬½¼¡«»\
+    ░▒▓│┤╡╢╖╕╣║╗╝╜╛┐└┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{a0}";
+
+// 画面に出せない文字 (制御文字を含む) は None
+pub fn to_cp437(c: char) -> Option<u8> {
+    if matches!(c, ' '..='~') {
+        return Some(c as u8);
+    }
+    CP437_HIGH
+        .chars()
+        .position(|high| high == c)
+        .map(|index| 0x80 + index as u8)
+}
+
+// 画面で c が占める桁数。全角の文字はフレームバッファのコンソールでだけ 2 桁になり、
+// テキストモードでは何でも 1 桁 (出せない文字は ■) で描く
+pub fn char_width(c: char) -> usize {
+    if c.is_control() {
+        return 0;
+    }
+    #[cfg(feature = "graphics")]
+    if crate::fbcon::is_active() && crate::fbcon::is_wide(c) {
+        return 2;
+    }
+    1
+}
+
+// ANSI エスケープシーケンスの読み取り状態。解釈するのは SGR (ESC [ n m) だけ
+#[derive(Clone, Copy)]
+enum Escape {
+    None,
+    Started,
+    Parameter(u16),
+}
+
 pub struct Writer {
     column_position: usize,
     row_position: usize,
     color_code: ColorCode,
     buffer: &'static mut Buffer,
+    // カーソルで隠れている文字
+    under_cursor: Option<ScreenChar>,
+    escape: Escape,
 }
 
 impl Writer {
@@ -63,6 +112,8 @@ impl Writer {
 
         match byte {
             b'\n' => self.new_line(),
+            b'\r' => self.column_position = 0,
+            0x08 => self.column_position = self.column_position.saturating_sub(1),
             byte => {
                 if self.column_position >= BUFFER_WIDTH {
                     self.new_line();
@@ -84,20 +135,29 @@ impl Writer {
     }
 
     fn clear_cursor(&mut self) {
-        let blank = ScreenChar {
-            ascii_character: b' ',
-            color_code: self.color_code,
-        };
-        self.buffer.chars[self.row_position][self.column_position].write(blank);
+        if let Some(character) = self.under_cursor.take() {
+            self.buffer.chars[self.row_position][self.column_position].write(character);
+        }
     }
 
     fn draw_cursor(&mut self) {
         if self.column_position < BUFFER_WIDTH {
-            let cursor = ScreenChar {
-                ascii_character: b'_',
-                color_code: self.color_code,
+            let cell = &mut self.buffer.chars[self.row_position][self.column_position];
+            let character = cell.read();
+            self.under_cursor = Some(character);
+            // 空白なら下線、文字の上なら色を反転して文字を残す
+            let cursor = if character.ascii_character == b' ' {
+                ScreenChar {
+                    ascii_character: b'_',
+                    color_code: self.color_code,
+                }
+            } else {
+                ScreenChar {
+                    ascii_character: character.ascii_character,
+                    color_code: ColorCode(character.color_code.0.rotate_left(4)),
+                }
             };
-            self.buffer.chars[self.row_position][self.column_position].write(cursor);
+            cell.write(cursor);
         }
     }
 
@@ -110,6 +170,8 @@ impl Writer {
                 }
             }
             self.clear_row(BUFFER_HEIGHT - 1);
+            // 右上の表示も一緒に上へ流れるので描き直す
+            self.draw_status();
         } else {
             self.row_position += 1;
         }
@@ -127,34 +189,97 @@ impl Writer {
     }
 
     pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                _ => self.write_byte(0xfe),
+        for c in s.chars() {
+            match (self.escape, c) {
+                (Escape::None, '\u{1b}') => self.escape = Escape::Started,
+                // フレームバッファのコンソールでは、色だけここで決めて文字は向こうで描く
+                #[cfg(feature = "graphics")]
+                (Escape::None, c) if crate::fbcon::is_active() => {
+                    crate::fbcon::write_char(c, self.color_code.0)
+                }
+                (Escape::None, ' '..='~' | '\n' | '\r' | '\u{08}') => self.write_byte(c as u8),
+                (Escape::None, c) => self.write_byte(to_cp437(c).unwrap_or(0xfe)),
+                (Escape::Started, '[') => self.escape = Escape::Parameter(0),
+                (Escape::Parameter(value), '0'..='9') => {
+                    let digit = c as u16 - '0' as u16;
+                    self.escape = Escape::Parameter(value.saturating_mul(10).saturating_add(digit));
+                }
+                (Escape::Parameter(value), ';') => {
+                    self.select_graphic_rendition(value);
+                    self.escape = Escape::Parameter(0);
+                }
+                (Escape::Parameter(value), 'm') => {
+                    self.select_graphic_rendition(value);
+                    self.escape = Escape::None;
+                }
+                // SGR 以外のシーケンスは読み捨てる
+                _ => self.escape = Escape::None,
             }
         }
     }
 
+    // 太字は白、それ以外の属性は無視する
+    fn select_graphic_rendition(&mut self, parameter: u16) {
+        match parameter {
+            0 | 22 => self.color_code = DEFAULT_COLOR,
+            1 => self.color_code = BOLD_COLOR,
+            _ => {}
+        }
+    }
+
     pub fn clear_screen(&mut self) {
+        #[cfg(feature = "graphics")]
+        if crate::fbcon::is_active() {
+            crate::fbcon::clear(self.color_code.0);
+            return;
+        }
         self.clear_cursor();
         for row in 0..BUFFER_HEIGHT {
             self.clear_row(row);
         }
         self.column_position = 0;
         self.row_position = 0;
+        self.draw_status();
         self.draw_cursor();
     }
 
-    pub fn backspace(&mut self) {
-        if self.column_position > 0 {
+    // 右上の隅の表示 (タイマーと時計) を描き直す
+    fn draw_status(&mut self) {
+        self.draw_indicator();
+        self.draw_clock();
+    }
+
+    fn draw_clock(&mut self) {
+        if CLOCK_VISIBLE.load(Ordering::Relaxed) {
+            self.write_status_cells(CLOCK_COLUMN, &CLOCK, STATUS_COLOR);
+        }
+    }
+
+    fn draw_indicator(&mut self) {
+        if INDICATOR_VISIBLE.load(Ordering::Relaxed) {
+            self.write_status_cells(INDICATOR_COLUMN, &INDICATOR, STATUS_COLOR);
+        }
+    }
+
+    fn clear_status_cells(&mut self, column: usize, width: usize) {
+        let blank = [const { AtomicU8::new(b' ') }; INDICATOR_WIDTH];
+        self.write_status_cells(column, &blank[..width], self.color_code);
+    }
+
+    // カーソルが描く位置にあれば先に退けて、描いたあとで戻す
+    fn write_status_cells(&mut self, column: usize, text: &[AtomicU8], color_code: ColorCode) {
+        let covers_cursor =
+            self.row_position == 0 && (column..column + text.len()).contains(&self.column_position);
+        if covers_cursor {
             self.clear_cursor();
-            self.column_position -= 1;
-            // 文字を消去
-            let blank = ScreenChar {
-                ascii_character: b' ',
-                color_code: self.color_code,
-            };
-            self.buffer.chars[self.row_position][self.column_position].write(blank);
+        }
+        for (i, slot) in text.iter().enumerate() {
+            self.buffer.chars[0][column + i].write(ScreenChar {
+                ascii_character: slot.load(Ordering::Relaxed),
+                color_code,
+            });
+        }
+        if covers_cursor {
             self.draw_cursor();
         }
     }
@@ -168,12 +293,17 @@ impl fmt::Write for Writer {
 }
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        row_position: 0,
-        color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
-    });
+    pub static ref WRITER: sync::Mutex<Writer> = sync::Mutex::new(
+        "vga writer",
+        Writer {
+            column_position: 0,
+            row_position: 0,
+            color_code: DEFAULT_COLOR,
+            buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+            under_cursor: None,
+            escape: Escape::None,
+        }
+    );
 }
 
 #[macro_export]
@@ -187,12 +317,320 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+pub static VGA_PORTS: PortRange = unsafe { PortRange::new("vga", 0x3c0, 0x20) };
+
+pub fn register_device(bus: usize) -> Result<(), &'static str> {
+    VGA_PORTS.claim()?;
+    device::register(
+        "vga-text",
+        DeviceClass::Display,
+        Some(bus),
+        &[
+            Resource::Memory {
+                start: 0xb8000,
+                len: (BUFFER_WIDTH * BUFFER_HEIGHT * 2) as u64,
+            },
+            VGA_PORTS.resource(),
+        ],
+    );
+    Ok(())
+}
+
+// 画面上のセル (行優先の通し番号) の前景色と背景色を入れ替える。2 回呼ぶと元に戻る
+pub fn invert_cell(index: usize) {
+    let (row, col) = (index / BUFFER_WIDTH, index % BUFFER_WIDTH);
+    if row >= BUFFER_HEIGHT {
+        return;
+    }
+    crate::arch::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let cell = &mut writer.buffer.chars[row][col];
+        let mut screen_char = cell.read();
+        screen_char.color_code = ColorCode(screen_char.color_code.0.rotate_left(4));
+        cell.write(screen_char);
+    });
+}
+
+// 画面のセルに直接書く。カーソルや改行の処理は通らないので、画面全体を自分で描く
+// プログラム (snake など) が使う
+pub fn put_char(row: usize, col: usize, byte: u8, foreground: Color, background: Color) {
+    if row >= BUFFER_HEIGHT || col >= BUFFER_WIDTH {
+        return;
+    }
+    crate::arch::without_interrupts(|| {
+        WRITER.lock().buffer.chars[row][col].write(ScreenChar {
+            ascii_character: byte,
+            color_code: ColorCode::new(foreground, background),
+        });
+    });
+}
+
+// start..=end のセルの文字を取り出す。行末の空白は落として行ごとに改行でつなぐ
+pub fn read_text(start: usize, end: usize) -> String {
+    let end = end.min(BUFFER_WIDTH * BUFFER_HEIGHT - 1);
+    let mut text = String::new();
+    crate::arch::without_interrupts(|| {
+        let writer = WRITER.lock();
+        for row in start / BUFFER_WIDTH..=end / BUFFER_WIDTH {
+            let first = if row == start / BUFFER_WIDTH {
+                start % BUFFER_WIDTH
+            } else {
+                0
+            };
+            let last = if row == end / BUFFER_WIDTH {
+                end % BUFFER_WIDTH
+            } else {
+                BUFFER_WIDTH - 1
+            };
+            let mut line = String::new();
+            for col in first..=last {
+                match writer.buffer.chars[row][col].read().ascii_character {
+                    byte @ 0x20..=0x7e => line.push(byte as char),
+                    _ => line.push(' '),
+                }
+            }
+            if !text.is_empty() {
+                text.push('\n');
+            }
+            text.push_str(line.trim_end());
+        }
+    });
+    text
+}
+
+// シーケンサのクロッキングモードレジスタの Screen Off ビットで表示だけを止める
+pub fn set_display_enabled(enabled: bool) {
+    // 0x3c4 がインデックス、0x3c5 がデータ
+    VGA_PORTS.write(0x04, 0x01u8);
+    let mode: u8 = VGA_PORTS.read(0x05);
+    VGA_PORTS.write(0x05, if enabled { mode & !0x20 } else { mode | 0x20 });
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Console {
+    Vga,
+    Serial,
+    Both,
+}
+
+impl Console {
+    pub fn from_name(name: &str) -> Option<Console> {
+        match name {
+            "vga" => Some(Console::Vga),
+            "serial" => Some(Console::Serial),
+            "both" => Some(Console::Both),
+            _ => None,
+        }
+    }
+}
+
+static CONSOLE: Mutex<Console> = Mutex::new(Console::Vga);
+
+pub fn set_console(console: Console) {
+    crate::arch::without_interrupts(|| {
+        *CONSOLE.lock() = console;
+    });
+}
+
+// キャプチャ中の出力は画面ではなくバッファに溜める
+static CAPTURE_STACK: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+pub fn start_capture() {
+    crate::arch::without_interrupts(|| {
+        CAPTURE_STACK.lock().push(String::new());
+    });
+}
+
+pub fn is_capturing() -> bool {
+    crate::arch::without_interrupts(|| !CAPTURE_STACK.lock().is_empty())
+}
+
+pub fn end_capture() -> String {
+    crate::arch::without_interrupts(|| CAPTURE_STACK.lock().pop().unwrap_or_default())
+}
+
+// panic のあと用。捨てたフレームが握っていた出力まわりのロックを解放し、
+// キャプチャも全部やめる
+pub unsafe fn force_unlock() {
+    WRITER.force_unlock();
+    CONSOLE.force_unlock();
+    CAPTURE_STACK.force_unlock();
+    crate::serial::SERIAL1.force_unlock();
+    CAPTURE_STACK.lock().clear();
+}
+
+// 割り込みハンドラからの出力で、WRITER が取れなかった分をここに溜める。
+// ロックを使わないので、割り込まれた側が何を握っていても書ける。溢れた分は捨てる
+const DEFERRED_SIZE: usize = 1024;
+// 割り込まれた側が WRITER を握っていれば待っても空かないので、少しだけ試して諦める
+const TRY_LOCK_ATTEMPTS: usize = 100;
+
+static DEFERRED: [AtomicU8; DEFERRED_SIZE] = [const { AtomicU8::new(0) }; DEFERRED_SIZE];
+static DEFERRED_LEN: AtomicUsize = AtomicUsize::new(0);
+
+struct DeferredWriter;
+
+impl fmt::Write for DeferredWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let start = DEFERRED_LEN.fetch_add(s.len(), Ordering::Relaxed);
+        for (i, &byte) in s.as_bytes().iter().enumerate() {
+            match DEFERRED.get(start + i) {
+                Some(slot) => slot.store(byte, Ordering::Relaxed),
+                None => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+// 溜まっている割り込みハンドラの出力を書き出す。割り込みを止めて呼ぶこと
+fn flush_deferred_locked() {
+    use core::fmt::Write;
+
+    let len = DEFERRED_LEN.load(Ordering::Relaxed).min(DEFERRED_SIZE);
+    if len == 0 {
+        return;
+    }
+    // ヒープが無くても書けるようにスタックにコピーする
+    let mut bytes = [0u8; DEFERRED_SIZE];
+    for (byte, slot) in bytes.iter_mut().zip(&DEFERRED[..len]) {
+        *byte = slot.load(Ordering::Relaxed);
+    }
+    DEFERRED_LEN.store(0, Ordering::Relaxed);
+    // 溢れたところで文字が切れていたら、その手前までにする
+    let text = match core::str::from_utf8(&bytes[..len]) {
+        Ok(text) => text,
+        Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or(""),
+    };
+    let console = *CONSOLE.lock();
+    if console != Console::Serial {
+        WRITER.lock().write_string(text);
+    }
+    if console != Console::Vga {
+        let _ = crate::serial::SERIAL1.lock().write_str(text);
+    }
+    crate::virtio_console::write(crate::virtio_console::SHELL, text.as_bytes());
+}
+
+// メインループから呼ぶ
+pub fn flush_deferred() {
+    if DEFERRED_LEN.load(Ordering::Relaxed) > 0 {
+        crate::arch::without_interrupts(flush_deferred_locked);
+    }
+    if CLOCK_DIRTY.swap(false, Ordering::Relaxed) {
+        crate::arch::without_interrupts(|| WRITER.lock().draw_clock());
+    }
+}
+
+// 画面右上の時計 (HH:MM:SS)。RTC の割り込みハンドラが毎秒書き換えるので、
+// 文字はロックを使わずに置いておき、WRITER が取れなければメインループで描く
+const CLOCK_WIDTH: usize = 8;
+const CLOCK_COLUMN: usize = BUFFER_WIDTH - CLOCK_WIDTH;
+// 時計の左に 1 文字空けて出す、実行中のタイマーなどの表示の幅
+const INDICATOR_WIDTH: usize = 10;
+const INDICATOR_COLUMN: usize = CLOCK_COLUMN - INDICATOR_WIDTH - 1;
+
+static CLOCK: [AtomicU8; CLOCK_WIDTH] = [const { AtomicU8::new(b' ') }; CLOCK_WIDTH];
+static CLOCK_VISIBLE: AtomicBool = AtomicBool::new(false);
+static CLOCK_DIRTY: AtomicBool = AtomicBool::new(false);
+static INDICATOR: [AtomicU8; INDICATOR_WIDTH] = [const { AtomicU8::new(b' ') }; INDICATOR_WIDTH];
+static INDICATOR_VISIBLE: AtomicBool = AtomicBool::new(false);
+
+pub fn set_clock(hours: u8, minutes: u8, seconds: u8) {
+    let digits = [
+        b'0' + hours / 10,
+        b'0' + hours % 10,
+        b':',
+        b'0' + minutes / 10,
+        b'0' + minutes % 10,
+        b':',
+        b'0' + seconds / 10,
+        b'0' + seconds % 10,
+    ];
+    for (slot, digit) in CLOCK.iter().zip(digits) {
+        slot.store(digit, Ordering::Relaxed);
+    }
+    CLOCK_VISIBLE.store(true, Ordering::Relaxed);
+    crate::arch::without_interrupts(|| match WRITER.try_lock() {
+        Some(mut writer) => writer.draw_clock(),
+        None => CLOCK_DIRTY.store(true, Ordering::Relaxed),
+    });
+}
+
+// 時計を消して、隅を空白に戻す
+pub fn hide_clock() {
+    crate::arch::without_interrupts(|| {
+        if CLOCK_VISIBLE.swap(false, Ordering::Relaxed) {
+            WRITER.lock().clear_status_cells(CLOCK_COLUMN, CLOCK_WIDTH);
+        }
+    });
+}
+
+// 時計の左に短い文字列を右詰めで出す。None で消す。割り込みハンドラからは呼ばない
+pub fn set_indicator(text: Option<&str>) {
+    crate::arch::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        match text {
+            Some(text) => {
+                let text = text.as_bytes();
+                let text = &text[text.len().saturating_sub(INDICATOR_WIDTH)..];
+                let padding = INDICATOR_WIDTH - text.len();
+                for (i, slot) in INDICATOR.iter().enumerate() {
+                    let byte = if i < padding { b' ' } else { text[i - padding] };
+                    slot.store(byte, Ordering::Relaxed);
+                }
+                INDICATOR_VISIBLE.store(true, Ordering::Relaxed);
+                writer.draw_indicator();
+            }
+            None => {
+                if INDICATOR_VISIBLE.swap(false, Ordering::Relaxed) {
+                    writer.clear_status_cells(INDICATOR_COLUMN, INDICATOR_WIDTH);
+                }
+            }
+        }
+    });
+}
+
+// 割り込みハンドラからの出力。キャプチャには入れず、画面に直接書くか後回しにする
+fn print_from_interrupt(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    for _ in 0..TRY_LOCK_ATTEMPTS {
+        if let Some(mut writer) = WRITER.try_lock() {
+            writer.write_fmt(args).unwrap();
+            return;
+        }
+        core::hint::spin_loop();
+    }
+    let _ = DeferredWriter.write_fmt(args);
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
+    crate::arch::without_interrupts(|| {
+        if crate::arch::in_interrupt() {
+            print_from_interrupt(args);
+            return;
+        }
+        flush_deferred_locked();
+
+        let mut capture = CAPTURE_STACK.lock();
+        if let Some(buffer) = capture.last_mut() {
+            buffer.write_fmt(args).unwrap();
+            return;
+        }
+        drop(capture);
+
+        let console = *CONSOLE.lock();
+        if console != Console::Serial {
+            WRITER.lock().write_fmt(args).unwrap();
+        }
+        if console != Console::Vga {
+            crate::serial::SERIAL1.lock().write_fmt(args).unwrap();
+        }
+        // hvc0 は console の設定にかかわらず画面と同じものを出す
+        let _ = crate::virtio_console::Writer(crate::virtio_console::SHELL).write_fmt(args);
     });
 }
@@ -0,0 +1,130 @@
+// virtio のレガシー PCI インターフェースで共通の部分 (レジスタと virtqueue)。
+// virtio-console と virtio-net が使う。どちらも割り込みは使わず、使い終わった記述子を
+// pop_used で取りに行く
+use crate::arch;
+use crate::hal::port::PortRange;
+use core::sync::atomic::{fence, Ordering};
+
+pub const VENDOR_VIRTIO: u16 = 0x1af4;
+
+// レガシーインターフェースのレジスタ (BAR0 の I/O ポート)。デバイス固有の設定は 0x14 から
+pub const HOST_FEATURES: u16 = 0x00;
+pub const GUEST_FEATURES: u16 = 0x04;
+pub const QUEUE_ADDRESS: u16 = 0x08;
+pub const QUEUE_SIZE: u16 = 0x0c;
+pub const QUEUE_SELECT: u16 = 0x0e;
+pub const QUEUE_NOTIFY: u16 = 0x10;
+pub const DEVICE_STATUS: u16 = 0x12;
+pub const DEVICE_CONFIG: u16 = 0x14;
+
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+
+pub const DESCRIPTOR_WRITE: u16 = 2;
+pub const PAGE_SIZE: usize = 4096;
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+fn align(value: usize) -> usize {
+    (value + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+// 連続したページを取って 0 で埋め、(物理アドレス, 仮想アドレス) を返す
+fn allocate(pages: usize) -> Result<(u64, *mut u8), &'static str> {
+    let phys = arch::allocate_dma(pages)?;
+    let virt = arch::phys_to_virt(phys).ok_or("physical memory not mapped")? as *mut u8;
+    unsafe { core::ptr::write_bytes(virt, 0, pages * PAGE_SIZE) };
+    Ok((phys, virt))
+}
+
+pub struct Virtqueue {
+    pub size: u16,
+    descriptors: *mut Descriptor,
+    // flags, idx, ring[size]
+    available: *mut u16,
+    // flags, idx, (id: u32, len: u32)[size]
+    used: *const u8,
+    next_available: u16,
+    last_used: u16,
+    // このキューのデータ用の連続したページ
+    buffer_phys: u64,
+    pub buffer: *mut u8,
+}
+
+impl Virtqueue {
+    pub fn new(
+        registers: &PortRange,
+        index: u16,
+        buffer_pages: usize,
+    ) -> Result<Virtqueue, &'static str> {
+        registers.write::<u16>(QUEUE_SELECT, index);
+        let size: u16 = registers.read(QUEUE_SIZE);
+        if size == 0 {
+            return Err("virtqueue not available");
+        }
+        let count = size as usize;
+        let used_offset = align(16 * count + 6 + 2 * count);
+        let pages = (used_offset + align(6 + 8 * count)) / PAGE_SIZE;
+        let (phys, virt) = allocate(pages)?;
+        let (buffer_phys, buffer) = allocate(buffer_pages)?;
+        registers.write::<u32>(QUEUE_ADDRESS, (phys / PAGE_SIZE as u64) as u32);
+
+        Ok(Virtqueue {
+            size,
+            descriptors: virt as *mut Descriptor,
+            available: unsafe { virt.add(16 * count) } as *mut u16,
+            used: unsafe { virt.add(used_offset) },
+            next_available: 0,
+            last_used: 0,
+            buffer_phys,
+            buffer,
+        })
+    }
+
+    pub fn set_descriptor(&mut self, index: u16, offset: usize, len: usize, flags: u16) {
+        let descriptor = Descriptor {
+            addr: self.buffer_phys + offset as u64,
+            len: len as u32,
+            flags,
+            next: 0,
+        };
+        unsafe { core::ptr::write_volatile(self.descriptors.add(index as usize), descriptor) };
+    }
+
+    pub fn make_available(&mut self, index: u16) {
+        let slot = (self.next_available % self.size) as usize;
+        unsafe {
+            core::ptr::write_volatile(self.available.add(2 + slot), index);
+            fence(Ordering::SeqCst);
+            self.next_available = self.next_available.wrapping_add(1);
+            core::ptr::write_volatile(self.available.add(1), self.next_available);
+        }
+        fence(Ordering::SeqCst);
+    }
+
+    // デバイスが使い終わったものを 1 つ取る。(記述子の番号, 書かれた長さ)
+    pub fn pop_used(&mut self) -> Option<(u16, usize)> {
+        fence(Ordering::SeqCst);
+        let used_index = unsafe { core::ptr::read_volatile(self.used.add(2) as *const u16) };
+        if used_index == self.last_used {
+            return None;
+        }
+        let slot = (self.last_used % self.size) as usize;
+        let element = unsafe { self.used.add(4 + 8 * slot) as *const u32 };
+        let (id, len) = unsafe {
+            (
+                core::ptr::read_volatile(element),
+                core::ptr::read_volatile(element.add(1)),
+            )
+        };
+        self.last_used = self.last_used.wrapping_add(1);
+        Some((id as u16, len as usize))
+    }
+}
@@ -0,0 +1,197 @@
+// virtio-console (レガシー PCI インターフェース)。QEMU の virtio-serial-pci を
+// max_ports=1 で付けると、ポート 0 だけの単純な端末になる。見つけた順に hvc0, hvc1, hvc2 と
+// 呼び、hvc0 はシェル (画面への出力とキー入力)、hvc1 はカーネルログ、hvc2 は gdb スタブに使う。
+// 割り込みは使わず、受信はメインループから、送信は書き終わるまで待って片付ける
+use crate::device::{self, DeviceClass};
+use crate::hal::port::PortRange;
+use crate::klog;
+use crate::log::Level;
+use crate::pci;
+use crate::virtio::{
+    Virtqueue, DESCRIPTOR_WRITE, DEVICE_STATUS, GUEST_FEATURES, PAGE_SIZE, QUEUE_NOTIFY,
+    STATUS_ACKNOWLEDGE, STATUS_DRIVER, STATUS_DRIVER_OK, VENDOR_VIRTIO,
+};
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+pub const SHELL: usize = 0;
+pub const LOG: usize = 1;
+pub const GDB: usize = 2;
+const MAX_CHANNELS: usize = 3;
+
+const DEVICE_CONSOLE: u16 = 0x1003;
+const REGISTERS_LEN: u16 = 0x18;
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+
+// 受信用のページを RX_BUFFER_SIZE ずつに分けて全部デバイスに渡しておく
+const RX_BUFFER_SIZE: usize = 256;
+const RX_BUFFERS: usize = PAGE_SIZE / RX_BUFFER_SIZE;
+// 送信の完了を待つ回数の上限。ホスト側が読まなくても固まらないようにする
+const TX_SPIN_LIMIT: usize = 1_000_000;
+
+struct Channel {
+    registers: PortRange,
+    receive: Virtqueue,
+    transmit: Virtqueue,
+    input: VecDeque<u8>,
+}
+
+// 中のポインタは DMA 用に取った、この Channel だけが使うページを指している
+unsafe impl Send for Channel {}
+
+impl Channel {
+    fn new(pci: &pci::PciDevice) -> Result<Channel, &'static str> {
+        let base = pci.io_bar(0).ok_or("no I/O BAR")?;
+        let registers = unsafe { PortRange::new("virtio-console", base, REGISTERS_LEN) };
+        registers.claim()?;
+        pci.enable_io_and_dma();
+
+        registers.write::<u8>(DEVICE_STATUS, 0);
+        registers.write::<u8>(DEVICE_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        // マルチポートなどの機能は使わない
+        registers.write::<u32>(GUEST_FEATURES, 0);
+        let receive = Virtqueue::new(&registers, RECEIVE_QUEUE, 1)?;
+        let transmit = Virtqueue::new(&registers, TRANSMIT_QUEUE, 1)?;
+        registers.write::<u8>(
+            DEVICE_STATUS,
+            STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_DRIVER_OK,
+        );
+
+        let mut channel = Channel {
+            registers,
+            receive,
+            transmit,
+            input: VecDeque::new(),
+        };
+        let buffers = RX_BUFFERS.min(channel.receive.size as usize) as u16;
+        for index in 0..buffers {
+            let offset = index as usize * RX_BUFFER_SIZE;
+            channel
+                .receive
+                .set_descriptor(index, offset, RX_BUFFER_SIZE, DESCRIPTOR_WRITE);
+            channel.receive.make_available(index);
+        }
+        channel.notify(RECEIVE_QUEUE);
+        Ok(channel)
+    }
+
+    fn notify(&self, queue: u16) {
+        self.registers.write::<u16>(QUEUE_NOTIFY, queue);
+    }
+
+    // 送信バッファは 1 つなので、1 ページずつ渡して使い終わるのを待つ
+    fn send(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(PAGE_SIZE) {
+            unsafe {
+                core::ptr::copy_nonoverlapping(chunk.as_ptr(), self.transmit.buffer, chunk.len())
+            };
+            self.transmit.set_descriptor(0, 0, chunk.len(), 0);
+            self.transmit.make_available(0);
+            self.notify(TRANSMIT_QUEUE);
+            let mut spins = 0;
+            while self.transmit.pop_used().is_none() && spins < TX_SPIN_LIMIT {
+                core::hint::spin_loop();
+                spins += 1;
+            }
+        }
+    }
+
+    // 届いたデータを input に移して、バッファをデバイスに返す
+    fn poll(&mut self) {
+        let mut returned = false;
+        while let Some((index, len)) = self.receive.pop_used() {
+            let offset = index as usize * RX_BUFFER_SIZE;
+            let data = unsafe {
+                core::slice::from_raw_parts(
+                    self.receive.buffer.add(offset),
+                    len.min(RX_BUFFER_SIZE),
+                )
+            };
+            self.input.extend(data);
+            self.receive.make_available(index);
+            returned = true;
+        }
+        if returned {
+            self.notify(RECEIVE_QUEUE);
+        }
+    }
+}
+
+static CHANNELS: [Mutex<Option<Channel>>; MAX_CHANNELS] =
+    [Mutex::new(None), Mutex::new(None), Mutex::new(None)];
+
+pub fn init() -> Result<(), &'static str> {
+    let devices = pci::find(VENDOR_VIRTIO, DEVICE_CONSOLE);
+    let bus = device::find("pci").ok_or("pci bus not registered")?;
+    for (index, pci) in devices.iter().take(MAX_CHANNELS).enumerate() {
+        match Channel::new(pci) {
+            Ok(channel) => {
+                device::register(
+                    &format!("hvc{}", index),
+                    DeviceClass::Serial,
+                    Some(bus),
+                    &[channel.registers.resource()],
+                );
+                *CHANNELS[index].lock() = Some(channel);
+            }
+            Err(e) => klog!(Level::Warn, "virtio-console {}: {}", pci.name(), e),
+        }
+    }
+    Ok(())
+}
+
+pub fn is_present(channel: usize) -> bool {
+    CHANNELS[channel].lock().is_some()
+}
+
+// 割り込みハンドラや panic の途中から呼ばれても固まらないように、使用中なら捨てる
+pub fn write(channel: usize, bytes: &[u8]) {
+    if let Some(mut guard) = CHANNELS[channel].try_lock() {
+        if let Some(channel) = guard.as_mut() {
+            channel.send(bytes);
+        }
+    }
+}
+
+pub fn try_receive(channel: usize) -> Option<u8> {
+    let mut guard = CHANNELS[channel].lock();
+    let channel = guard.as_mut()?;
+    if channel.input.is_empty() {
+        channel.poll();
+    }
+    channel.input.pop_front()
+}
+
+// fmt の出力先として使う
+pub struct Writer(pub usize);
+
+impl core::fmt::Write for Writer {
+    fn write_str(&mut self, text: &str) -> core::fmt::Result {
+        write(self.0, text.as_bytes());
+        Ok(())
+    }
+}
+
+// hvc0 に届いた文字をキー入力としてシェルに渡す。メインループから呼ぶ
+pub fn process_pending() {
+    if !is_present(SHELL) {
+        return;
+    }
+    let mut pending = Vec::new();
+    while let Some(byte) = try_receive(SHELL) {
+        pending.push(byte);
+    }
+    for c in String::from_utf8_lossy(&pending).chars() {
+        let key = match c {
+            '\r' => '\n',
+            '\u{7f}' => '\u{08}',
+            c => c,
+        };
+        crate::keyboard::handle_key(pc_keyboard::DecodedKey::Unicode(key));
+    }
+}
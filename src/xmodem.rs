@@ -0,0 +1,171 @@
+// XMODEM でホストからファイルを受け取る。コンソールやログが流れる COM1 を避けて、
+// gdb と同じ 2 本目のシリアルポート (COM2) を使う。CRC-16 で始めて、送信側が
+// 応じなければチェックサムに切り替える。128 バイトと 1K のブロックの両方を受ける。
+// 受信中はシリアルをポーリングし続けるので、割り込みは使わない
+use crate::serial::{self, SERIAL2};
+use crate::time::{self, TICK_HZ};
+use alloc::vec;
+use alloc::vec::Vec;
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+// 最後のブロックの詰め物
+const SUB: u8 = 0x1a;
+const CRC_REQUEST: u8 = b'C';
+
+// 送信側が始めるまで待つ時間と、開始の合図を送り直す間隔
+const START_TIMEOUT: u64 = 60 * TICK_HZ;
+const START_RETRY: u64 = 3 * TICK_HZ;
+// ブロックの途中で次のバイトを待つ時間と、次のブロックを待つ時間
+const BYTE_TIMEOUT: u64 = TICK_HZ;
+const BLOCK_TIMEOUT: u64 = 10 * TICK_HZ;
+// この回数 'C' を送っても応答がなければチェックサムにする
+const CRC_ATTEMPTS: u32 = 3;
+const MAX_ERRORS: u32 = 10;
+
+fn send(byte: u8) {
+    SERIAL2.lock().send(byte);
+}
+
+fn read_byte(timeout: u64) -> Option<u8> {
+    let deadline = time::ticks() + timeout;
+    loop {
+        if let Some(byte) = serial::try_receive_com2() {
+            return Some(byte);
+        }
+        if time::ticks() >= deadline {
+            return None;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+// 壊れたブロックの残りを読み捨てて、回線が静かになってから NAK を返す
+fn purge() {
+    while read_byte(BYTE_TIMEOUT).is_some() {}
+}
+
+fn cancel() {
+    for _ in 0..3 {
+        send(CAN);
+    }
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+// ヘッダ (SOH/STX) の後ろを読む。(ブロック番号, データ)。途中で途切れたり
+// 検査に失敗したら None
+fn read_block(size: usize, crc: bool) -> Option<(u8, Vec<u8>)> {
+    let number = read_byte(BYTE_TIMEOUT)?;
+    let complement = read_byte(BYTE_TIMEOUT)?;
+    let mut data = vec![0; size];
+    for byte in data.iter_mut() {
+        *byte = read_byte(BYTE_TIMEOUT)?;
+    }
+
+    let valid = if crc {
+        let high = read_byte(BYTE_TIMEOUT)?;
+        let low = read_byte(BYTE_TIMEOUT)?;
+        crc16(&data) == u16::from_be_bytes([high, low])
+    } else {
+        let sum = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        read_byte(BYTE_TIMEOUT)? == sum
+    };
+    if !valid || number != !complement {
+        return None;
+    }
+    Some((number, data))
+}
+
+// 送られてきたファイルの中身を返す。末尾の詰め物 (0x1a) は落とす
+pub fn receive() -> Result<Vec<u8>, &'static str> {
+    let start = time::ticks();
+    let mut crc = true;
+    let mut attempts = 0;
+    let mut header = loop {
+        if time::ticks() - start >= START_TIMEOUT {
+            return Err("timed out waiting for the sender");
+        }
+        if attempts == CRC_ATTEMPTS {
+            crc = false;
+        }
+        send(if crc { CRC_REQUEST } else { NAK });
+        attempts += 1;
+        if let Some(byte) = read_byte(START_RETRY) {
+            break Some(byte);
+        }
+    };
+
+    let mut data = Vec::new();
+    let mut expected: u8 = 1;
+    let mut errors = 0;
+    loop {
+        if errors >= MAX_ERRORS {
+            cancel();
+            return Err("too many errors");
+        }
+        let byte = match header.take().or_else(|| read_byte(BLOCK_TIMEOUT)) {
+            Some(byte) => byte,
+            None => {
+                errors += 1;
+                send(NAK);
+                continue;
+            }
+        };
+
+        match byte {
+            SOH | STX => {
+                let size = if byte == SOH { 128 } else { 1024 };
+                match read_block(size, crc) {
+                    Some((number, block)) if number == expected => {
+                        data.extend_from_slice(&block);
+                        expected = expected.wrapping_add(1);
+                        errors = 0;
+                        send(ACK);
+                    }
+                    // 前のブロックの ACK が届かず再送された
+                    Some((number, _)) if number == expected.wrapping_sub(1) => send(ACK),
+                    Some(_) => {
+                        cancel();
+                        return Err("block out of sequence");
+                    }
+                    None => {
+                        errors += 1;
+                        purge();
+                        send(NAK);
+                    }
+                }
+            }
+            EOT => {
+                send(ACK);
+                break;
+            }
+            CAN if read_byte(BYTE_TIMEOUT) == Some(CAN) => {
+                return Err("cancelled by the sender");
+            }
+            _ => errors += 1,
+        }
+    }
+
+    while data.last() == Some(&SUB) {
+        data.pop();
+    }
+    Ok(data)
+}
@@ -0,0 +1,21 @@
+#!/bin/sh
+# Builds the kernel twice: the first build provides the symbol addresses,
+# the second one embeds them (the table has a fixed size, so addresses do not move).
+set -e
+
+cd "$(dirname "$0")/.."
+
+PROFILE=debug
+for arg in "$@"; do
+    if [ "$arg" = "--release" ]; then
+        PROFILE=release
+    fi
+done
+KERNEL=target/x86_64-ros/$PROFILE/ros
+
+cargo build "$@"
+nm -C --defined-only "$KERNEL" \
+    | awk '$2 ~ /^[tT]$/ { $2 = ""; print }' \
+    | sed -E 's/::h[0-9a-f]{16}$//; s/  / /' \
+    | sort > ksyms.txt
+cargo build "$@"